@@ -0,0 +1,1105 @@
+//! tcpdump-style capture filters compiled to classic BPF.
+//!
+//! The supported language is a subset of pcap-filter(7): `host`, `net`, `port` and
+//! `portrange` with optional `src`/`dst` and protocol qualifiers, the protocols `ip`,
+//! `ip6`, `arp`, `tcp`, `udp`, `icmp` and `icmp6`, combined with `and`/`&&`,
+//! `or`/`||`, `not`/`!` and parentheses.
+
+use std::net::IpAddr;
+
+use crate::pcap::{LINKTYPE_ETHERNET, LINKTYPE_RAW};
+use crate::vlan;
+
+const BPF_LD: u16 = 0x00;
+const BPF_LDX: u16 = 0x01;
+const BPF_ALU: u16 = 0x04;
+const BPF_JMP: u16 = 0x05;
+const BPF_RET: u16 = 0x06;
+const BPF_W: u16 = 0x00;
+const BPF_H: u16 = 0x08;
+const BPF_B: u16 = 0x10;
+const BPF_ABS: u16 = 0x20;
+const BPF_IND: u16 = 0x40;
+const BPF_MSH: u16 = 0xa0;
+const BPF_AND: u16 = 0x50;
+const BPF_JA: u16 = 0x00;
+const BPF_JEQ: u16 = 0x10;
+const BPF_JGT: u16 = 0x20;
+const BPF_JGE: u16 = 0x30;
+const BPF_JSET: u16 = 0x40;
+
+const ETHERTYPE_IPV4: u32 = 0x0800;
+const ETHERTYPE_IPV6: u32 = 0x86dd;
+const ETHERTYPE_ARP: u32 = 0x0806;
+const IPPROTO_ICMP: u32 = 1;
+const IPPROTO_TCP: u32 = 6;
+const IPPROTO_UDP: u32 = 17;
+const IPPROTO_FRAGMENT: u32 = 44;
+const IPPROTO_ICMPV6: u32 = 58;
+const IPPROTO_SCTP: u32 = 132;
+
+/// The most instructions the kernel accepts in one program.
+const BPF_MAXINSNS: usize = 4096;
+/// Accepted packets are passed up whole.
+const ACCEPT_LEN: u32 = 0x0004_0000;
+/// Linux ancillary load of `skb->pkt_type` (`SKF_AD_OFF + SKF_AD_PKTTYPE`).
+const SKF_AD_PKTTYPE: u32 = 0xffff_f000 + 4;
+const PACKET_OUTGOING: u32 = 4;
+
+/// One classic BPF instruction, laid out like `struct sock_filter`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Insn {
+    pub code: u16,
+    pub jt: u8,
+    pub jf: u8,
+    pub k: u32,
+}
+
+/// A compiled filter program.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Program {
+    insns: Vec<Insn>,
+}
+
+impl Program {
+    pub fn instructions(&self) -> &[Insn] {
+        &self.insns
+    }
+
+    /// A program that accepts every packet.
+    pub fn accept_all() -> Self {
+        Self {
+            insns: vec![stmt(BPF_RET, ACCEPT_LEN)],
+        }
+    }
+
+    /// Prepends a Linux-only check that drops packets the host itself sent. On
+    /// loopback every frame reaches packet sockets twice, once outgoing and once
+    /// incoming, and libpcap drops the first copy the same way. The userspace
+    /// interpreter does not know ancillary loads, so only attach the result.
+    pub fn drop_outgoing(&self) -> Self {
+        let mut insns = vec![
+            stmt(BPF_LD | BPF_W | BPF_ABS, SKF_AD_PKTTYPE),
+            Insn {
+                code: BPF_JMP | BPF_JEQ,
+                jt: 0,
+                jf: 1,
+                k: PACKET_OUTGOING,
+            },
+            stmt(BPF_RET, 0),
+        ];
+        insns.extend_from_slice(&self.insns);
+        Self { insns }
+    }
+
+    /// Runs the program in userspace, for sources the kernel cannot filter.
+    pub fn matches(&self, packet: &[u8]) -> bool {
+        let load = |at: u32, size: usize| -> Option<u32> {
+            let at = at as usize;
+            let bytes = packet.get(at..at.checked_add(size)?)?;
+            Some(bytes.iter().fold(0u32, |acc, b| (acc << 8) | *b as u32))
+        };
+        let (mut a, mut x) = (0u32, 0u32);
+        let mut pc = 0usize;
+        while let Some(insn) = self.insns.get(pc) {
+            pc += 1;
+            let size = match insn.code & 0x18 {
+                BPF_H => 2,
+                BPF_B => 1,
+                _ => 4,
+            };
+            match insn.code & 0x07 {
+                BPF_LD => {
+                    let at = match insn.code & 0xe0 {
+                        BPF_IND => x.wrapping_add(insn.k),
+                        _ => insn.k,
+                    };
+                    match load(at, size) {
+                        Some(v) => a = v,
+                        None => return false,
+                    }
+                }
+                BPF_LDX => match load(insn.k, 1) {
+                    Some(v) => x = (v & 0x0f) * 4,
+                    None => return false,
+                },
+                BPF_ALU => a &= insn.k,
+                BPF_JMP => {
+                    let hit = match insn.code & 0xf0 {
+                        BPF_JA => {
+                            pc += insn.k as usize;
+                            continue;
+                        }
+                        BPF_JEQ => a == insn.k,
+                        BPF_JGT => a > insn.k,
+                        BPF_JGE => a >= insn.k,
+                        _ => a & insn.k != 0,
+                    };
+                    pc += if hit { insn.jt } else { insn.jf } as usize;
+                }
+                _ => return insn.k != 0,
+            }
+        }
+        false
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Proto {
+    Ip,
+    Ip6,
+    Arp,
+    Tcp,
+    Udp,
+    Icmp,
+    Icmp6,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Dir {
+    Src,
+    Dst,
+    Either,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Expr {
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    Proto(Proto),
+    Net { dir: Dir, addr: IpAddr, prefix: u8 },
+    Port { dir: Dir, lo: u16, hi: u16 },
+}
+
+/// A parsed capture filter expression.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Filter {
+    expr: Expr,
+}
+
+impl Filter {
+    pub fn parse(text: &str) -> anyhow::Result<Self> {
+        let tokens = tokenize(text);
+        if tokens.is_empty() {
+            return Err(anyhow::anyhow!("empty capture filter"));
+        }
+        let mut parser = Parser { tokens, pos: 0 };
+        let expr = parser.parse_or()?;
+        if let Some(tok) = parser.peek() {
+            return Err(anyhow::anyhow!(
+                "capture filter: unexpected '{}' at token {}",
+                tok,
+                parser.pos + 1
+            ));
+        }
+        Ok(Self { expr })
+    }
+
+    /// Compiles the filter for frames of the given link type.
+    pub fn compile(&self, link_type: u32) -> anyhow::Result<Program> {
+        let link = Link::from_link_type(link_type)?;
+        emit(&lower(&self.expr, link))
+    }
+
+    /// Compiles the filter for the kernel, which can neither reassemble fragmented
+    /// datagrams nor strip VLAN tags: every IP fragment and every tagged frame is
+    /// accepted as well, and the filter is applied to them in userspace once the
+    /// datagram is whole and untagged.
+    pub fn compile_for_kernel(&self, link_type: u32) -> anyhow::Result<Program> {
+        let link = Link::from_link_type(link_type)?;
+        emit(&Cond::Any(vec![
+            fragment(link),
+            vlan_tagged(link),
+            lower(&self.expr, link),
+        ]))
+    }
+}
+
+fn emit(cond: &Cond) -> anyhow::Result<Program> {
+    let mut gen = Codegen::default();
+    gen.emit_cond(cond, Target::Accept, Target::Reject);
+    gen.finish()
+}
+
+fn tokenize(text: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut cur = String::new();
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        let op = match c {
+            '(' | ')' => Some(c.to_string()),
+            '!' => Some("!".to_string()),
+            '&' if chars.peek() == Some(&'&') => {
+                chars.next();
+                Some("&&".to_string())
+            }
+            '|' if chars.peek() == Some(&'|') => {
+                chars.next();
+                Some("||".to_string())
+            }
+            _ => None,
+        };
+        if op.is_some() || c.is_whitespace() {
+            if !cur.is_empty() {
+                tokens.push(std::mem::take(&mut cur));
+            }
+            tokens.extend(op);
+        } else {
+            cur.push(c);
+        }
+    }
+    if !cur.is_empty() {
+        tokens.push(cur);
+    }
+    tokens
+}
+
+struct Parser {
+    tokens: Vec<String>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&str> {
+        self.tokens.get(self.pos).map(String::as_str)
+    }
+
+    fn next(&mut self) -> anyhow::Result<String> {
+        let tok = self
+            .tokens
+            .get(self.pos)
+            .cloned()
+            .ok_or_else(|| anyhow::anyhow!("capture filter: unexpected end of expression"))?;
+        self.pos += 1;
+        Ok(tok)
+    }
+
+    fn eat(&mut self, words: &[&str]) -> bool {
+        if self.peek().is_some_and(|t| words.contains(&t)) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn parse_or(&mut self) -> anyhow::Result<Expr> {
+        let mut lhs = self.parse_and()?;
+        while self.eat(&["or", "||"]) {
+            let rhs = self.parse_and()?;
+            lhs = Expr::Or(Box::new(lhs), Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+
+    fn parse_and(&mut self) -> anyhow::Result<Expr> {
+        let mut lhs = self.parse_unary()?;
+        while self.eat(&["and", "&&"]) {
+            let rhs = self.parse_unary()?;
+            lhs = Expr::And(Box::new(lhs), Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+
+    fn parse_unary(&mut self) -> anyhow::Result<Expr> {
+        if self.eat(&["not", "!"]) {
+            return Ok(Expr::Not(Box::new(self.parse_unary()?)));
+        }
+        if self.eat(&["("]) {
+            let inner = self.parse_or()?;
+            if !self.eat(&[")"]) {
+                return Err(anyhow::anyhow!("capture filter: missing ')'"));
+            }
+            return Ok(inner);
+        }
+        self.parse_primitive()
+    }
+
+    fn parse_primitive(&mut self) -> anyhow::Result<Expr> {
+        let proto = match self.peek() {
+            Some("ip") => Some(Proto::Ip),
+            Some("ip6") => Some(Proto::Ip6),
+            Some("arp") => Some(Proto::Arp),
+            Some("tcp") => Some(Proto::Tcp),
+            Some("udp") => Some(Proto::Udp),
+            Some("icmp") => Some(Proto::Icmp),
+            Some("icmp6") => Some(Proto::Icmp6),
+            _ => None,
+        };
+        if proto.is_some() {
+            self.pos += 1;
+        }
+        let dir = match self.peek() {
+            Some("src") => Some(Dir::Src),
+            Some("dst") => Some(Dir::Dst),
+            _ => None,
+        };
+        if dir.is_some() {
+            self.pos += 1;
+        }
+        let kind = match self.peek() {
+            Some(k @ ("host" | "net" | "port" | "portrange")) => Some(k.to_string()),
+            _ => None,
+        };
+        if kind.is_some() {
+            self.pos += 1;
+        }
+
+        let prim = match (kind.as_deref(), dir) {
+            (None, None) => {
+                return match proto {
+                    Some(p) => Ok(Expr::Proto(p)),
+                    None => match self.peek() {
+                        Some(tok) => Err(anyhow::anyhow!(
+                            "capture filter: expected a primitive, found '{}'",
+                            tok
+                        )),
+                        None => Err(anyhow::anyhow!(
+                            "capture filter: unexpected end of expression"
+                        )),
+                    },
+                };
+            }
+            (None | Some("host"), dir) => {
+                let value = self.next()?;
+                let addr = value.parse::<IpAddr>().map_err(|_| {
+                    anyhow::anyhow!("capture filter: invalid host address '{}'", value)
+                })?;
+                let prefix = if addr.is_ipv4() { 32 } else { 128 };
+                Expr::Net {
+                    dir: dir.unwrap_or(Dir::Either),
+                    addr,
+                    prefix,
+                }
+            }
+            (Some("net"), dir) => {
+                let value = self.next()?;
+                let (addr, prefix) = parse_net(&value)?;
+                Expr::Net {
+                    dir: dir.unwrap_or(Dir::Either),
+                    addr,
+                    prefix,
+                }
+            }
+            (Some("port"), dir) => {
+                let value = self.next()?;
+                let port = parse_port(&value)?;
+                Expr::Port {
+                    dir: dir.unwrap_or(Dir::Either),
+                    lo: port,
+                    hi: port,
+                }
+            }
+            (Some(_), dir) => {
+                let value = self.next()?;
+                let (lo, hi) = value.split_once('-').ok_or_else(|| {
+                    anyhow::anyhow!("capture filter: invalid port range '{}'", value)
+                })?;
+                let (lo, hi) = (parse_port(lo)?, parse_port(hi)?);
+                if lo > hi {
+                    return Err(anyhow::anyhow!(
+                        "capture filter: invalid port range '{}'",
+                        value
+                    ));
+                }
+                Expr::Port {
+                    dir: dir.unwrap_or(Dir::Either),
+                    lo,
+                    hi,
+                }
+            }
+        };
+        Ok(match proto {
+            Some(p) => Expr::And(Box::new(Expr::Proto(p)), Box::new(prim)),
+            None => prim,
+        })
+    }
+}
+
+fn parse_port(value: &str) -> anyhow::Result<u16> {
+    value
+        .parse::<u16>()
+        .map_err(|_| anyhow::anyhow!("capture filter: invalid port '{}'", value))
+}
+
+fn parse_net(value: &str) -> anyhow::Result<(IpAddr, u8)> {
+    let invalid = || anyhow::anyhow!("capture filter: invalid network '{}'", value);
+    let (addr, prefix) = match value.split_once('/') {
+        Some((a, p)) => (a, Some(p)),
+        None => (value, None),
+    };
+    let addr = addr.parse::<IpAddr>().map_err(|_| invalid())?;
+    let max = if addr.is_ipv4() { 32 } else { 128 };
+    let prefix = match prefix {
+        Some(p) => p.parse::<u8>().map_err(|_| invalid())?,
+        None => max,
+    };
+    if prefix > max {
+        return Err(invalid());
+    }
+    Ok((addr, prefix))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Link {
+    Ethernet,
+    Raw,
+}
+
+impl Link {
+    fn from_link_type(link_type: u32) -> anyhow::Result<Self> {
+        match link_type {
+            LINKTYPE_ETHERNET => Ok(Link::Ethernet),
+            LINKTYPE_RAW => Ok(Link::Raw),
+            other => Err(anyhow::anyhow!(
+                "capture filter: unsupported link type {}",
+                other
+            )),
+        }
+    }
+
+    /// Offset of the network header.
+    fn nh(self) -> u32 {
+        match self {
+            Link::Ethernet => 14,
+            Link::Raw => 0,
+        }
+    }
+}
+
+/// Low-level condition tree; leaves are single BPF comparisons.
+#[derive(Debug, Clone)]
+enum Cond {
+    All(Vec<Cond>),
+    Any(Vec<Cond>),
+    Not(Box<Cond>),
+    Test(Test),
+    Never,
+}
+
+#[derive(Debug, Clone)]
+struct Test {
+    setup: Vec<Insn>,
+    jump: u16,
+    k: u32,
+}
+
+fn stmt(code: u16, k: u32) -> Insn {
+    Insn {
+        code,
+        jt: 0,
+        jf: 0,
+        k,
+    }
+}
+
+fn load(size: u16, at: u32) -> Insn {
+    stmt(BPF_LD | size | BPF_ABS, at)
+}
+
+fn test(setup: Vec<Insn>, jump: u16, k: u32) -> Cond {
+    Cond::Test(Test { setup, jump, k })
+}
+
+fn ethertype(link: Link, ty: u32) -> Cond {
+    match (link, ty) {
+        (Link::Ethernet, _) => test(vec![load(BPF_H, 12)], BPF_JEQ, ty),
+        (Link::Raw, ETHERTYPE_IPV4 | ETHERTYPE_IPV6) => test(
+            vec![load(BPF_B, 0), stmt(BPF_ALU | BPF_AND, 0xf0)],
+            BPF_JEQ,
+            if ty == ETHERTYPE_IPV4 { 0x40 } else { 0x60 },
+        ),
+        (Link::Raw, _) => Cond::Never,
+    }
+}
+
+fn ipv4_proto(link: Link, proto: u32) -> Cond {
+    Cond::All(vec![
+        ethertype(link, ETHERTYPE_IPV4),
+        test(vec![load(BPF_B, link.nh() + 9)], BPF_JEQ, proto),
+    ])
+}
+
+fn ipv6_proto(link: Link, proto: u32) -> Cond {
+    Cond::All(vec![
+        ethertype(link, ETHERTYPE_IPV6),
+        test(vec![load(BPF_B, link.nh() + 6)], BPF_JEQ, proto),
+    ])
+}
+
+/// Any IPv4 fragment (more fragments to come, or a non-zero offset), or an IPv6 packet
+/// whose first extension header is a fragment header.
+fn fragment(link: Link) -> Cond {
+    Cond::Any(vec![
+        Cond::All(vec![
+            ethertype(link, ETHERTYPE_IPV4),
+            test(vec![load(BPF_H, link.nh() + 6)], BPF_JSET, 0x3fff),
+        ]),
+        ipv6_proto(link, IPPROTO_FRAGMENT),
+    ])
+}
+
+fn vlan_tagged(link: Link) -> Cond {
+    match link {
+        Link::Ethernet => Cond::Any(
+            vlan::TPIDS
+                .iter()
+                .map(|&tpid| ethertype(link, tpid as u32))
+                .collect(),
+        ),
+        Link::Raw => Cond::Never,
+    }
+}
+
+fn by_dir(dir: Dir, src: Cond, dst: Cond) -> Cond {
+    match dir {
+        Dir::Src => src,
+        Dir::Dst => dst,
+        Dir::Either => Cond::Any(vec![src, dst]),
+    }
+}
+
+/// Compares the 32-bit words at `at` against `words` under `prefix`.
+fn masked_words(at: u32, words: &[u32], prefix: u8) -> Cond {
+    let mut tests = Vec::new();
+    for (i, word) in words.iter().enumerate() {
+        let bits = (prefix as i32 - 32 * i as i32).clamp(0, 32) as u32;
+        if bits == 0 {
+            break;
+        }
+        let mask = if bits == 32 {
+            u32::MAX
+        } else {
+            !(u32::MAX >> bits)
+        };
+        let mut setup = vec![load(BPF_W, at + 4 * i as u32)];
+        if mask != u32::MAX {
+            setup.push(stmt(BPF_ALU | BPF_AND, mask));
+        }
+        tests.push(test(setup, BPF_JEQ, word & mask));
+    }
+    Cond::All(tests)
+}
+
+fn port_cmp(setup: Vec<Insn>, lo: u16, hi: u16) -> Cond {
+    if lo == hi {
+        test(setup, BPF_JEQ, lo as u32)
+    } else {
+        Cond::All(vec![
+            test(setup.clone(), BPF_JGE, lo as u32),
+            Cond::Not(Box::new(test(setup, BPF_JGT, hi as u32))),
+        ])
+    }
+}
+
+/// The terms of a chain of `or`s (or `and`s), which the parser nests to the left, so
+/// that a long allow-list is lowered without recursing once per term.
+fn operands(mut expr: &Expr, or: bool) -> Vec<&Expr> {
+    let mut terms = Vec::new();
+    loop {
+        match (expr, or) {
+            (Expr::Or(a, b), true) | (Expr::And(a, b), false) => {
+                terms.push(&**b);
+                expr = a;
+            }
+            _ => {
+                terms.push(expr);
+                break;
+            }
+        }
+    }
+    terms.reverse();
+    terms
+}
+
+fn lower(expr: &Expr, link: Link) -> Cond {
+    let nh = link.nh();
+    match expr {
+        Expr::And(..) => Cond::All(
+            operands(expr, false)
+                .into_iter()
+                .map(|e| lower(e, link))
+                .collect(),
+        ),
+        Expr::Or(..) => Cond::Any(
+            operands(expr, true)
+                .into_iter()
+                .map(|e| lower(e, link))
+                .collect(),
+        ),
+        Expr::Not(a) => Cond::Not(Box::new(lower(a, link))),
+        Expr::Proto(p) => match p {
+            Proto::Ip => ethertype(link, ETHERTYPE_IPV4),
+            Proto::Ip6 => ethertype(link, ETHERTYPE_IPV6),
+            Proto::Arp => ethertype(link, ETHERTYPE_ARP),
+            Proto::Tcp => Cond::Any(vec![
+                ipv4_proto(link, IPPROTO_TCP),
+                ipv6_proto(link, IPPROTO_TCP),
+            ]),
+            Proto::Udp => Cond::Any(vec![
+                ipv4_proto(link, IPPROTO_UDP),
+                ipv6_proto(link, IPPROTO_UDP),
+            ]),
+            Proto::Icmp => ipv4_proto(link, IPPROTO_ICMP),
+            Proto::Icmp6 => ipv6_proto(link, IPPROTO_ICMPV6),
+        },
+        Expr::Net {
+            dir,
+            addr: IpAddr::V4(a),
+            prefix,
+        } => {
+            let words = [u32::from(*a)];
+            Cond::Any(vec![
+                Cond::All(vec![
+                    ethertype(link, ETHERTYPE_IPV4),
+                    by_dir(
+                        *dir,
+                        masked_words(nh + 12, &words, *prefix),
+                        masked_words(nh + 16, &words, *prefix),
+                    ),
+                ]),
+                Cond::All(vec![
+                    ethertype(link, ETHERTYPE_ARP),
+                    by_dir(
+                        *dir,
+                        masked_words(nh + 14, &words, *prefix),
+                        masked_words(nh + 24, &words, *prefix),
+                    ),
+                ]),
+            ])
+        }
+        Expr::Net {
+            dir,
+            addr: IpAddr::V6(a),
+            prefix,
+        } => {
+            let octets = a.octets();
+            let words: Vec<u32> = octets
+                .chunks(4)
+                .map(|c| u32::from_be_bytes([c[0], c[1], c[2], c[3]]))
+                .collect();
+            Cond::All(vec![
+                ethertype(link, ETHERTYPE_IPV6),
+                by_dir(
+                    *dir,
+                    masked_words(nh + 8, &words, *prefix),
+                    masked_words(nh + 24, &words, *prefix),
+                ),
+            ])
+        }
+        Expr::Port { dir, lo, hi } => {
+            let l4 = |p| {
+                Cond::Any(vec![
+                    test(vec![load(BPF_B, p)], BPF_JEQ, IPPROTO_TCP),
+                    test(vec![load(BPF_B, p)], BPF_JEQ, IPPROTO_UDP),
+                    test(vec![load(BPF_B, p)], BPF_JEQ, IPPROTO_SCTP),
+                ])
+            };
+            let v4_port = |off: u32| {
+                vec![
+                    stmt(BPF_LDX | BPF_B | BPF_MSH, nh),
+                    stmt(BPF_LD | BPF_H | BPF_IND, nh + off),
+                ]
+            };
+            Cond::Any(vec![
+                Cond::All(vec![
+                    ethertype(link, ETHERTYPE_IPV4),
+                    l4(nh + 9),
+                    // Only the first fragment carries the transport header
+                    Cond::Not(Box::new(test(vec![load(BPF_H, nh + 6)], BPF_JSET, 0x1fff))),
+                    by_dir(
+                        *dir,
+                        port_cmp(v4_port(0), *lo, *hi),
+                        port_cmp(v4_port(2), *lo, *hi),
+                    ),
+                ]),
+                Cond::All(vec![
+                    ethertype(link, ETHERTYPE_IPV6),
+                    l4(nh + 6),
+                    by_dir(
+                        *dir,
+                        port_cmp(vec![load(BPF_H, nh + 40)], *lo, *hi),
+                        port_cmp(vec![load(BPF_H, nh + 42)], *lo, *hi),
+                    ),
+                ]),
+            ])
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Target {
+    Accept,
+    Reject,
+    Label(usize),
+}
+
+/// Instruction whose jump targets are still symbolic.
+struct Pending {
+    insn: Insn,
+    jt: Option<Target>,
+    jf: Option<Target>,
+}
+
+#[derive(Default)]
+struct Codegen {
+    code: Vec<Pending>,
+    labels: Vec<usize>,
+}
+
+impl Codegen {
+    fn new_label(&mut self) -> usize {
+        self.labels.push(usize::MAX);
+        self.labels.len() - 1
+    }
+
+    fn place(&mut self, label: usize) {
+        self.labels[label] = self.code.len();
+    }
+
+    fn emit_cond(&mut self, cond: &Cond, t: Target, f: Target) {
+        match cond {
+            Cond::All(items) => {
+                for (i, item) in items.iter().enumerate() {
+                    if i + 1 == items.len() {
+                        self.emit_cond(item, t, f);
+                    } else {
+                        let next = self.new_label();
+                        self.emit_cond(item, Target::Label(next), f);
+                        self.place(next);
+                    }
+                }
+                if items.is_empty() {
+                    self.emit_jump(t);
+                }
+            }
+            Cond::Any(items) => {
+                for (i, item) in items.iter().enumerate() {
+                    if i + 1 == items.len() {
+                        self.emit_cond(item, t, f);
+                    } else {
+                        let next = self.new_label();
+                        self.emit_cond(item, t, Target::Label(next));
+                        self.place(next);
+                    }
+                }
+                if items.is_empty() {
+                    self.emit_jump(f);
+                }
+            }
+            Cond::Not(inner) => self.emit_cond(inner, f, t),
+            Cond::Never => self.emit_jump(f),
+            Cond::Test(test) => {
+                for insn in &test.setup {
+                    self.code.push(Pending {
+                        insn: *insn,
+                        jt: None,
+                        jf: None,
+                    });
+                }
+                self.code.push(Pending {
+                    insn: stmt(BPF_JMP | test.jump, test.k),
+                    jt: Some(t),
+                    jf: Some(f),
+                });
+            }
+        }
+    }
+
+    fn emit_jump(&mut self, to: Target) {
+        self.code.push(Pending {
+            insn: stmt(BPF_JMP | BPF_JA, 0),
+            jt: Some(to),
+            jf: None,
+        });
+    }
+
+    /// Code index of a jump target; accept and reject follow the code.
+    fn resolve(&self, target: Target) -> usize {
+        match target {
+            Target::Accept => self.code.len(),
+            Target::Reject => self.code.len() + 1,
+            Target::Label(l) => self.labels[l],
+        }
+    }
+
+    /// The first conditional jump with a branch beyond the 255 instructions `jt` and
+    /// `jf` can skip, and whether it is the taken branch.
+    fn far_branch(&self) -> Option<(usize, bool)> {
+        let far = |pc: usize, target: Option<Target>| {
+            target.is_some_and(|t| self.resolve(t) - (pc + 1) > u8::MAX as usize)
+        };
+        self.code.iter().enumerate().find_map(|(pc, pending)| {
+            if pending.insn.code == BPF_JMP | BPF_JA {
+                None
+            } else if far(pc, pending.jt) {
+                Some((pc, true))
+            } else if far(pc, pending.jf) {
+                Some((pc, false))
+            } else {
+                None
+            }
+        })
+    }
+
+    fn finish(mut self) -> anyhow::Result<Program> {
+        let too_complex = || anyhow::anyhow!("capture filter is too complex");
+        if self.code.len() + 2 > BPF_MAXINSNS {
+            return Err(too_complex());
+        }
+        // A branch that cannot reach its target goes to an unconditional jump, which has
+        // a 32-bit offset, placed right after the comparison
+        while let Some((pc, taken)) = self.far_branch() {
+            let target = if taken {
+                self.code[pc].jt
+            } else {
+                self.code[pc].jf
+            };
+            for label in &mut self.labels {
+                if *label > pc {
+                    *label += 1;
+                }
+            }
+            self.code.insert(
+                pc + 1,
+                Pending {
+                    insn: stmt(BPF_JMP | BPF_JA, 0),
+                    jt: target,
+                    jf: None,
+                },
+            );
+            let trampoline = self.new_label();
+            self.labels[trampoline] = pc + 1;
+            let branch = Some(Target::Label(trampoline));
+            if taken {
+                self.code[pc].jt = branch;
+            } else {
+                self.code[pc].jf = branch;
+            }
+        }
+        if self.code.len() + 2 > BPF_MAXINSNS {
+            return Err(too_complex());
+        }
+        let mut insns = Vec::with_capacity(self.code.len() + 2);
+        for (pc, pending) in self.code.iter().enumerate() {
+            let mut insn = pending.insn;
+            let offset = |target: Target| self.resolve(target) - (pc + 1);
+            if insn.code == BPF_JMP | BPF_JA {
+                insn.k = offset(pending.jt.unwrap()) as u32;
+            } else if let (Some(jt), Some(jf)) = (pending.jt, pending.jf) {
+                insn.jt = offset(jt) as u8;
+                insn.jf = offset(jf) as u8;
+            }
+            insns.push(insn);
+        }
+        insns.push(stmt(BPF_RET, ACCEPT_LEN));
+        insns.push(stmt(BPF_RET, 0));
+        Ok(Program { insns })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pnet::packet::ethernet::{EtherTypes, MutableEthernetPacket};
+    use pnet::packet::ip::IpNextHeaderProtocols;
+    use pnet::packet::ipv4::MutableIpv4Packet;
+    use pnet::packet::ipv6::MutableIpv6Packet;
+    use pnet::packet::tcp::MutableTcpPacket;
+    use pnet::packet::MutablePacket;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    fn ipv4_tcp(src: [u8; 4], dst: [u8; 4], sport: u16, dport: u16) -> Vec<u8> {
+        let mut buf = vec![0u8; 14 + 20 + 20];
+        {
+            let mut eth = MutableEthernetPacket::new(&mut buf[..]).unwrap();
+            eth.set_ethertype(EtherTypes::Ipv4);
+            let mut ip = MutableIpv4Packet::new(eth.payload_mut()).unwrap();
+            ip.set_version(4);
+            ip.set_header_length(5);
+            ip.set_total_length(40);
+            ip.set_next_level_protocol(IpNextHeaderProtocols::Tcp);
+            ip.set_source(Ipv4Addr::from(src));
+            ip.set_destination(Ipv4Addr::from(dst));
+            let mut tcp = MutableTcpPacket::new(ip.payload_mut()).unwrap();
+            tcp.set_source(sport);
+            tcp.set_destination(dport);
+        }
+        buf
+    }
+
+    fn ipv6_udp(src: Ipv6Addr, dport: u16) -> Vec<u8> {
+        let mut buf = vec![0u8; 14 + 40 + 8];
+        {
+            let mut eth = MutableEthernetPacket::new(&mut buf[..]).unwrap();
+            eth.set_ethertype(EtherTypes::Ipv6);
+            let mut ip = MutableIpv6Packet::new(eth.payload_mut()).unwrap();
+            ip.set_version(6);
+            ip.set_payload_length(8);
+            ip.set_next_header(IpNextHeaderProtocols::Udp);
+            ip.set_source(src);
+            ip.set_destination(Ipv6Addr::LOCALHOST);
+            ip.payload_mut()[2..4].copy_from_slice(&dport.to_be_bytes());
+        }
+        buf
+    }
+
+    fn eth_matches(filter: &str, packet: &[u8]) -> bool {
+        Filter::parse(filter)
+            .unwrap()
+            .compile(LINKTYPE_ETHERNET)
+            .unwrap()
+            .matches(packet)
+    }
+
+    #[test]
+    fn host_and_port_expression() {
+        let hit = ipv4_tcp([10, 0, 0, 5], [10, 0, 0, 9], 40000, 443);
+        let wrong_port = ipv4_tcp([10, 0, 0, 5], [10, 0, 0, 9], 40000, 80);
+        let wrong_host = ipv4_tcp([10, 0, 0, 6], [10, 0, 0, 9], 40000, 443);
+        let f = "host 10.0.0.5 and tcp port 443";
+        assert!(eth_matches(f, &hit));
+        assert!(!eth_matches(f, &wrong_port));
+        assert!(!eth_matches(f, &wrong_host));
+        assert!(eth_matches("src host 10.0.0.5", &hit));
+        assert!(!eth_matches("dst host 10.0.0.5", &hit));
+        assert!(eth_matches("not udp && (port 80 || port 443)", &hit));
+        assert!(eth_matches("dst portrange 400-500", &hit));
+        assert!(!eth_matches("src portrange 400-500", &hit));
+    }
+
+    #[test]
+    fn nets_and_ipv6() {
+        let v4 = ipv4_tcp([192, 168, 7, 1], [10, 0, 0, 9], 1, 2);
+        assert!(eth_matches("net 192.168.0.0/16", &v4));
+        assert!(!eth_matches("net 192.169.0.0/16", &v4));
+        assert!(eth_matches("ip and not ip6", &v4));
+
+        let src: Ipv6Addr = "2001:db8::1".parse().unwrap();
+        let v6 = ipv6_udp(src, 53);
+        assert!(eth_matches("ip6 src host 2001:db8::1", &v6));
+        assert!(eth_matches(
+            "src net 2001:db8::/32 and udp dst port 53",
+            &v6
+        ));
+        assert!(!eth_matches("host 2001:db8::2", &v6));
+        assert!(!eth_matches("tcp", &v6));
+    }
+
+    #[test]
+    fn raw_link_type_skips_ethernet_header() {
+        let frame = ipv4_tcp([10, 0, 0, 5], [10, 0, 0, 9], 40000, 443);
+        let prog = Filter::parse("host 10.0.0.5 and tcp port 443")
+            .unwrap()
+            .compile(LINKTYPE_RAW)
+            .unwrap();
+        assert!(prog.matches(&frame[14..]));
+        assert!(!Filter::parse("arp")
+            .unwrap()
+            .compile(LINKTYPE_RAW)
+            .unwrap()
+            .matches(&frame[14..]));
+    }
+
+    #[test]
+    fn fragments_pass_the_kernel_program() {
+        let filter = Filter::parse("udp port 53").unwrap();
+        let strict = filter.compile(LINKTYPE_ETHERNET).unwrap();
+        let kernel = filter.compile_for_kernel(LINKTYPE_ETHERNET).unwrap();
+        let mut frame = ipv4_tcp([10, 0, 0, 5], [10, 0, 0, 9], 40000, 443);
+        assert!(!strict.matches(&frame) && !kernel.matches(&frame));
+        // A later fragment of some datagram, offset 1480
+        frame[14 + 6..14 + 8].copy_from_slice(&185u16.to_be_bytes());
+        assert!(!strict.matches(&frame));
+        assert!(kernel.matches(&frame));
+        // The first fragment, with more to come
+        frame[14 + 6..14 + 8].copy_from_slice(&0x2000u16.to_be_bytes());
+        assert!(kernel.matches(&frame));
+
+        let mut v6 = ipv6_udp("2001:db8::1".parse().unwrap(), 5353);
+        assert!(!kernel.matches(&v6));
+        v6[14 + 6] = IPPROTO_FRAGMENT as u8;
+        assert!(!strict.matches(&v6));
+        assert!(kernel.matches(&v6));
+    }
+
+    #[test]
+    fn tagged_frames_pass_the_kernel_program() {
+        let filter = Filter::parse("tcp port 80").unwrap();
+        let strict = filter.compile(LINKTYPE_ETHERNET).unwrap();
+        let kernel = filter.compile_for_kernel(LINKTYPE_ETHERNET).unwrap();
+        let frame = ipv4_tcp([10, 0, 0, 5], [10, 0, 0, 9], 40000, 443);
+        for tpid in [[0x81, 0x00], [0x88, 0xa8], [0x91, 0x00]] {
+            let tagged = [&frame[..12], &tpid, &[0, 100], &frame[12..]].concat();
+            assert!(!strict.matches(&tagged));
+            assert!(kernel.matches(&tagged));
+        }
+        assert!(!kernel.matches(&frame));
+        assert!(!filter
+            .compile_for_kernel(LINKTYPE_RAW)
+            .unwrap()
+            .matches(&frame[14..]));
+    }
+
+    #[test]
+    fn long_allow_lists_compile() {
+        let hosts: Vec<String> = (1..=100).map(|i| format!("host 10.0.0.{}", i)).collect();
+        let program = Filter::parse(&hosts.join(" or "))
+            .unwrap()
+            .compile(LINKTYPE_ETHERNET)
+            .unwrap();
+        assert!(program.instructions().len() > 256);
+        for (host, hit) in [(1, true), (77, true), (100, true), (101, false)] {
+            let frame = ipv4_tcp([10, 0, 0, host], [192, 168, 0, 1], 40000, 443);
+            assert_eq!(program.matches(&frame), hit, "10.0.0.{}", host);
+            let reply = ipv4_tcp([192, 168, 0, 1], [10, 0, 0, host], 443, 40000);
+            assert_eq!(program.matches(&reply), hit, "10.0.0.{}", host);
+        }
+        assert!(!program.matches(&ipv6_udp(Ipv6Addr::LOCALHOST, 53)));
+
+        let hosts: Vec<String> = (0..1000)
+            .map(|i| format!("host 10.0.{}.{}", i / 250, i % 250))
+            .collect();
+        let err = Filter::parse(&hosts.join(" or "))
+            .unwrap()
+            .compile(LINKTYPE_ETHERNET)
+            .unwrap_err();
+        assert!(err.to_string().contains("too complex"));
+    }
+
+    #[test]
+    fn short_packets_are_rejected() {
+        assert!(!eth_matches("port 53", &[0u8; 20]));
+    }
+
+    #[test]
+    fn parse_errors_are_descriptive() {
+        let err = |s: &str| format!("{}", Filter::parse(s).unwrap_err());
+        assert!(err("").contains("empty"));
+        assert!(err("host").contains("unexpected end"));
+        assert!(err("port http").contains("invalid port 'http'"));
+        assert!(err("host 10.0.0.300").contains("invalid host address"));
+        assert!(err("(tcp").contains("missing ')'"));
+        assert!(err("tcp udp").contains("unexpected 'udp'"));
+        assert!(err("bogus").contains("expected a primitive, found 'bogus'"));
+    }
+
+    #[test]
+    fn drop_outgoing_prefixes_a_pkttype_check() {
+        assert!(Program::accept_all().matches(&[0u8; 14]));
+        let base = Filter::parse("tcp")
+            .unwrap()
+            .compile(LINKTYPE_ETHERNET)
+            .unwrap();
+        let guarded = base.drop_outgoing();
+        assert_eq!(&guarded.instructions()[3..], base.instructions());
+        assert_eq!(guarded.instructions()[0].k, SKF_AD_PKTTYPE);
+        // The jump skips the reject so the original program runs for other packets
+        assert_eq!(
+            (guarded.instructions()[1].jt, guarded.instructions()[1].jf),
+            (0, 1)
+        );
+        assert_eq!(guarded.instructions()[2], stmt(BPF_RET, 0));
+    }
+}
@@ -1,31 +1,80 @@
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
 use std::net::IpAddr;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::thread;
 
 use pnet::datalink::{
     self, Channel::Ethernet, Config as DlConfig, DataLinkReceiver, NetworkInterface,
 };
 use pnet::packet::ethernet::{EtherTypes, EthernetPacket, MutableEthernetPacket};
-use pnet::packet::ipv4::Ipv4Packet;
 use pnet::util::MacAddr;
-use std::time::Duration;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// How long frames are held back so that a slightly later frame from another
+/// interface with an earlier timestamp can still be emitted first.
+const REORDER_WINDOW: Duration = Duration::from_millis(20);
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+/// Room for a full loopback-MTU frame, so segments are not truncated.
+const READ_BUFFER_SIZE: usize = 65536 + 14;
+/// Length of the Ethernet header put in front of bare IP packets.
+const FAKE_HEADER_LEN: usize = 14;
+
+use crate::bpf;
+use crate::pcap::{LINKTYPE_ETHERNET, LINKTYPE_RAW};
+use crate::source::{Frame, Next, PacketSource};
 
 pub struct Capture {
     interface: NetworkInterface,
     rx: Box<dyn DataLinkReceiver>,
-    buf: [u8; 1600],
+    buf: Vec<u8>,
+    link_type: u32,
 }
 
 impl Capture {
-    pub fn open(iface_name: &str) -> anyhow::Result<Self> {
+    /// Opens a channel on `iface_name`. On Linux a capture filter is compiled and
+    /// attached to the socket so rejected packets never reach userspace; elsewhere
+    /// the caller is expected to filter.
+    pub fn open(iface_name: &str, filter: Option<&bpf::Filter>) -> anyhow::Result<Self> {
         let interfaces: Vec<NetworkInterface> = datalink::interfaces();
         let interface: NetworkInterface = interfaces
             .into_iter()
             .find(|iface: &NetworkInterface| iface.name == iface_name)
             .ok_or_else(|| anyhow::anyhow!("No such network interface: {}", iface_name))?;
 
-        let cfg = DlConfig {
+        // Linux tun/WireGuard/PPP devices deliver bare IP packets
+        let link_type = if cfg!(target_os = "linux")
+            && interface.is_point_to_point()
+            && !interface.is_loopback()
+        {
+            LINKTYPE_RAW
+        } else {
+            LINKTYPE_ETHERNET
+        };
+
+        #[allow(unused_mut)]
+        let mut cfg = DlConfig {
             read_timeout: Some(Duration::from_millis(200)),
+            read_buffer_size: READ_BUFFER_SIZE,
             ..Default::default()
         };
+        #[cfg(target_os = "linux")]
+        {
+            // Fragments and tagged frames are let through to be filtered in userspace
+            let program = filter
+                .map(|f| f.compile_for_kernel(link_type))
+                .transpose()?;
+            let program = match program {
+                Some(p) if interface.is_loopback() => Some(p.drop_outgoing()),
+                None if interface.is_loopback() => Some(bpf::Program::accept_all().drop_outgoing()),
+                other => other,
+            };
+            if let Some(program) = program {
+                cfg.socket_fd = Some(filtered_socket(&program)?);
+            }
+        }
+        #[cfg(not(target_os = "linux"))]
+        let _ = filter;
 
         let (_, rx) = match datalink::channel(&interface, cfg) {
             Ok(Ethernet(tx, rx)) => (tx, rx),
@@ -41,7 +90,8 @@ impl Capture {
         Ok(Self {
             interface,
             rx,
-            buf: [0u8; 1600],
+            buf: vec![0u8; READ_BUFFER_SIZE + FAKE_HEADER_LEN],
+            link_type,
         })
     }
 
@@ -80,33 +130,31 @@ impl Capture {
                         // Maybe is TUN interface
                         payload_offset = 0;
                     }
-                    if packet.len() > payload_offset {
-                        let version = Ipv4Packet::new(&packet[payload_offset..])
-                            .unwrap()
-                            .get_version();
-                        if version == 4 {
-                            let mut fake = MutableEthernetPacket::new(&mut self.buf[..]).unwrap();
-                            fake.set_destination(MacAddr(0, 0, 0, 0, 0, 0));
-                            fake.set_source(MacAddr(0, 0, 0, 0, 0, 0));
-                            fake.set_ethertype(EtherTypes::Ipv4);
-                            fake.set_payload(&packet[payload_offset..]);
-                            let im = EthernetPacket::new(&self.buf[..]).unwrap();
-                            return Ok(Some(im));
-                        } else if version == 6 {
-                            let mut fake = MutableEthernetPacket::new(&mut self.buf[..]).unwrap();
-                            fake.set_destination(MacAddr(0, 0, 0, 0, 0, 0));
-                            fake.set_source(MacAddr(0, 0, 0, 0, 0, 0));
-                            fake.set_ethertype(EtherTypes::Ipv6);
-                            fake.set_payload(&packet[payload_offset..]);
-                            let im = EthernetPacket::new(&self.buf[..]).unwrap();
-                            return Ok(Some(im));
-                        }
+                    let payload = packet.get(payload_offset..).unwrap_or_default();
+                    let ethertype = match payload.first().map(|b| b >> 4) {
+                        Some(4) => Some(EtherTypes::Ipv4),
+                        Some(6) => Some(EtherTypes::Ipv6),
+                        _ => None,
+                    };
+                    let frame_len = FAKE_HEADER_LEN + payload.len();
+                    if let (Some(ethertype), Some(buf)) = (ethertype, self.buf.get_mut(..frame_len))
+                    {
+                        let mut fake = MutableEthernetPacket::new(buf).unwrap();
+                        fake.set_destination(MacAddr(0, 0, 0, 0, 0, 0));
+                        fake.set_source(MacAddr(0, 0, 0, 0, 0, 0));
+                        fake.set_ethertype(ethertype);
+                        fake.set_payload(payload);
+                        return Ok(EthernetPacket::new(&self.buf[..frame_len]));
                     }
                 }
-                Ok(Some(EthernetPacket::new(packet).unwrap()))
+                Ok(EthernetPacket::new(packet))
             }
             Err(e) => {
-                if e.kind() == std::io::ErrorKind::TimedOut {
+                // Ctrl-C interrupts the read; the caller notices the termination flag
+                if matches!(
+                    e.kind(),
+                    std::io::ErrorKind::TimedOut | std::io::ErrorKind::Interrupted
+                ) {
                     Ok(None)
                 } else {
                     Err(anyhow::anyhow!(
@@ -118,3 +166,284 @@ impl Capture {
         }
     }
 }
+
+impl PacketSource for Capture {
+    fn next_frame(&mut self) -> anyhow::Result<Next> {
+        let name = self.interface.name.clone();
+        let link_type = self.link_type;
+        Ok(match self.next_ethernet()? {
+            // pnet does not expose kernel timestamps, so stamp frames once the read
+            // returns rather than when it started waiting
+            Some(frame) => Next::Frame(Frame::new(
+                SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default(),
+                link_type,
+                name,
+                pnet::packet::Packet::packet(&frame).to_vec(),
+            )),
+            None => Next::Idle,
+        })
+    }
+
+    fn host_ips(&self) -> Vec<IpAddr> {
+        Capture::host_ips(self)
+    }
+}
+
+/// Creates an `AF_PACKET` socket with `program` attached. The socket is created with
+/// protocol 0 so nothing is queued before pnet binds it with `ETH_P_ALL`, by which
+/// time the filter is already in place.
+#[cfg(target_os = "linux")]
+fn filtered_socket(program: &bpf::Program) -> anyhow::Result<i32> {
+    let fd = unsafe { libc::socket(libc::AF_PACKET, libc::SOCK_RAW, 0) };
+    if fd == -1 {
+        return Err(anyhow::anyhow!(
+            "packet-flow: unable to create socket: {}",
+            std::io::Error::last_os_error()
+        ));
+    }
+    let mut filters: Vec<libc::sock_filter> = program
+        .instructions()
+        .iter()
+        .map(|i| libc::sock_filter {
+            code: i.code,
+            jt: i.jt,
+            jf: i.jf,
+            k: i.k,
+        })
+        .collect();
+    let fprog = libc::sock_fprog {
+        len: filters.len() as u16,
+        filter: filters.as_mut_ptr(),
+    };
+    let rc = unsafe {
+        libc::setsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_ATTACH_FILTER,
+            &fprog as *const libc::sock_fprog as *const libc::c_void,
+            std::mem::size_of::<libc::sock_fprog>() as libc::socklen_t,
+        )
+    };
+    if rc == -1 {
+        let err = std::io::Error::last_os_error();
+        unsafe { libc::close(fd) };
+        return Err(anyhow::anyhow!(
+            "packet-flow: unable to attach capture filter: {}",
+            err
+        ));
+    }
+    Ok(fd)
+}
+
+/// Names of every interface that is up, used for `-i any`.
+pub fn all_interface_names() -> Vec<String> {
+    datalink::interfaces()
+        .into_iter()
+        .filter(|iface| iface.is_up())
+        .map(|iface| iface.name)
+        .collect()
+}
+
+/// Captures on several interfaces at once. Each interface is read on its own thread
+/// and frames are merged back into timestamp order.
+pub struct MultiCapture {
+    rx: Receiver<anyhow::Result<Frame>>,
+    pending: BinaryHeap<Reverse<(Duration, u64, FrameSlot)>>,
+    seq: u64,
+    host_ips: Vec<IpAddr>,
+}
+
+/// Wrapper so frames can sit in the heap, which orders only by (timestamp, arrival).
+struct FrameSlot(Frame);
+
+impl PartialEq for FrameSlot {
+    fn eq(&self, _: &Self) -> bool {
+        true
+    }
+}
+impl Eq for FrameSlot {}
+impl PartialOrd for FrameSlot {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+impl Ord for FrameSlot {
+    fn cmp(&self, _: &Self) -> std::cmp::Ordering {
+        std::cmp::Ordering::Equal
+    }
+}
+
+impl MultiCapture {
+    /// Opens every named interface. With `skip_failures`, interfaces that cannot be
+    /// opened are reported and skipped (as for `-i any`) instead of aborting.
+    pub fn open(
+        names: &[String],
+        filter: Option<&bpf::Filter>,
+        skip_failures: bool,
+    ) -> anyhow::Result<Self> {
+        let mut captures = Vec::new();
+        for name in names {
+            match Capture::open(name, filter) {
+                Ok(cap) => captures.push(cap),
+                Err(e) if skip_failures => eprintln!("packet-flow: skipping {}: {:#}", name, e),
+                Err(e) => return Err(e),
+            }
+        }
+        if captures.is_empty() {
+            return Err(anyhow::anyhow!("packet-flow: no interface could be opened"));
+        }
+        let sources = captures
+            .into_iter()
+            .map(|cap| Box::new(cap) as Box<dyn PacketSource + Send>)
+            .collect();
+        Ok(Self::from_sources(sources))
+    }
+
+    /// Merges arbitrary sources, each polled on its own thread.
+    pub fn from_sources(sources: Vec<Box<dyn PacketSource + Send>>) -> Self {
+        let host_ips = sources.first().map(|s| s.host_ips()).unwrap_or_default();
+        let (tx, rx) = mpsc::sync_channel(4096);
+        for mut source in sources {
+            let tx = tx.clone();
+            thread::spawn(move || loop {
+                let item = match source.next_frame() {
+                    Ok(Next::Frame(frame)) => Ok(frame),
+                    Ok(Next::Idle) => continue,
+                    Ok(Next::End) => return,
+                    Err(e) => Err(e),
+                };
+                let failed = item.is_err();
+                // The receiver is gone once the main loop stops
+                if tx.send(item).is_err() || failed {
+                    return;
+                }
+            });
+        }
+
+        Self {
+            rx,
+            pending: BinaryHeap::new(),
+            seq: 0,
+            host_ips,
+        }
+    }
+
+    fn push(&mut self, frame: Frame) {
+        self.seq += 1;
+        self.pending
+            .push(Reverse((frame.timestamp, self.seq, FrameSlot(frame))));
+    }
+}
+
+impl PacketSource for MultiCapture {
+    fn next_frame(&mut self) -> anyhow::Result<Next> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let wait = match self.pending.peek() {
+            Some(Reverse((ts, _, _))) => (*ts + REORDER_WINDOW).saturating_sub(now),
+            None => POLL_INTERVAL,
+        };
+        let mut disconnected = false;
+        match self.rx.recv_timeout(wait) {
+            Ok(item) => self.push(item?),
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => disconnected = true,
+        }
+        while let Ok(item) = self.rx.try_recv() {
+            self.push(item?);
+        }
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        match self.pending.peek() {
+            Some(Reverse((ts, _, _))) if *ts + REORDER_WINDOW <= now || disconnected => {
+                let Reverse((_, _, FrameSlot(frame))) = self.pending.pop().unwrap();
+                Ok(Next::Frame(frame))
+            }
+            None if disconnected => Ok(Next::End),
+            _ => Ok(Next::Idle),
+        }
+    }
+
+    fn host_ips(&self) -> Vec<IpAddr> {
+        self.host_ips.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::source::MemorySource;
+
+    #[test]
+    fn multi_capture_merges_all_sources_then_ends() {
+        let a = MemorySource::new(vec![
+            Frame::new(Duration::from_secs(1), LINKTYPE_ETHERNET, "a", vec![1]),
+            Frame::new(Duration::from_secs(3), LINKTYPE_ETHERNET, "a", vec![3]),
+        ]);
+        let b = MemorySource::new(vec![Frame::new(
+            Duration::from_secs(2),
+            LINKTYPE_ETHERNET,
+            "b",
+            vec![2],
+        )]);
+        let mut multi = MultiCapture::from_sources(vec![Box::new(a), Box::new(b)]);
+        let mut seen = Vec::new();
+        loop {
+            match multi.next_frame().unwrap() {
+                Next::Frame(f) => seen.push((f.interface, f.data[0])),
+                Next::Idle => continue,
+                Next::End => break,
+            }
+        }
+        seen.sort();
+        assert_eq!(
+            seen,
+            vec![
+                ("a".to_string(), 1),
+                ("a".to_string(), 3),
+                ("b".to_string(), 2)
+            ]
+        );
+    }
+
+    #[test]
+    fn multi_capture_orders_buffered_frames_by_timestamp() {
+        let (_tx, rx) = mpsc::sync_channel(1);
+        let mut multi = MultiCapture {
+            rx,
+            pending: BinaryHeap::new(),
+            seq: 0,
+            host_ips: Vec::new(),
+        };
+        multi.push(Frame::new(
+            Duration::from_secs(5),
+            LINKTYPE_ETHERNET,
+            "a",
+            vec![5],
+        ));
+        multi.push(Frame::new(
+            Duration::from_secs(4),
+            LINKTYPE_ETHERNET,
+            "b",
+            vec![4],
+        ));
+        multi.push(Frame::new(
+            Duration::from_secs(4),
+            LINKTYPE_ETHERNET,
+            "c",
+            vec![4],
+        ));
+        let mut order = Vec::new();
+        while order.len() < 3 {
+            if let Next::Frame(f) = multi.next_frame().unwrap() {
+                order.push(f.interface);
+            }
+        }
+        assert_eq!(order, vec!["b", "c", "a"]);
+    }
+}
@@ -0,0 +1,587 @@
+//! Checksum verification for IPv4 headers and TCP, UDP, ICMP and ICMPv6 messages.
+//!
+//! [`verify`] recomputes every checksum a frame carries and reports the ones that do
+//! not match as [`BadChecksum`]s. Frames sent by the capturing host are usually
+//! captured before the NIC fills in their TCP and UDP checksums (checksum offload).
+//! Linux leaves the pseudo-header sum in the field for the NIC to finish, which is
+//! accepted wherever it is seen; beyond that, TCP and UDP checksums are skipped when
+//! the caller says the frame is locally generated. A datagram cut short by the snap
+//! length cannot be checked and is skipped too.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::net::Ipv6Addr;
+use std::time::Duration;
+
+use pnet::packet::ethernet::{EtherTypes, EthernetPacket};
+use pnet::packet::ip::IpNextHeaderProtocol;
+use pnet::packet::ipv4::Ipv4Packet;
+use pnet::packet::ipv6::Ipv6Packet;
+use pnet::packet::Packet;
+use pnet::util;
+use serde::ser::SerializeMap;
+use serde::{Serialize, Serializer};
+
+use crate::handler::packets::walk_ipv6_extensions;
+use crate::model::Ipv6Ext;
+use crate::render;
+
+/// The header a checksum belongs to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Layer {
+    Ipv4,
+    Tcp,
+    Udp,
+    Icmp,
+    Icmpv6,
+}
+
+impl fmt::Display for Layer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Layer::Ipv4 => "IPv4",
+            Layer::Tcp => "TCP",
+            Layer::Udp => "UDP",
+            Layer::Icmp => "ICMP",
+            Layer::Icmpv6 => "ICMPv6",
+        })
+    }
+}
+
+/// A checksum that does not match the bytes it covers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct BadChecksum {
+    pub layer: Layer,
+    /// The checksum in the packet.
+    pub found: u16,
+    /// The checksum the packet should have carried.
+    pub expected: u16,
+}
+
+/// Wireshark wording, e.g. `Bad TCP checksum 0x1234, should be 0xabcd`.
+impl fmt::Display for BadChecksum {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Bad {} checksum {:#06x}, should be {:#06x}",
+            self.layer, self.found, self.expected
+        )
+    }
+}
+
+/// Checks every checksum in an Ethernet frame carrying IPv4 or IPv6. With
+/// `locally_generated`, TCP and UDP checksums are left alone as the NIC may not have
+/// computed them yet.
+pub fn verify(ethernet: &EthernetPacket, locally_generated: bool) -> Vec<BadChecksum> {
+    let mut bad = Vec::new();
+    match ethernet.get_ethertype() {
+        EtherTypes::Ipv4 => {
+            let Some(ip) = Ipv4Packet::new(ethernet.payload()) else {
+                return bad;
+            };
+            let header_len = ip.get_header_length() as usize * 4;
+            // An IHL below five words leaves no header checksum, and no transport
+            // header where one would be looked for
+            if header_len < 20 {
+                return bad;
+            }
+            if let Some(header) = ethernet.payload().get(..header_len) {
+                check(&mut bad, Layer::Ipv4, header, 5, util::checksum(header, 5));
+            }
+            // Later fragments have no transport header to check
+            if ip.get_fragment_offset() != 0 {
+                return bad;
+            }
+            let Some(data) = ethernet
+                .payload()
+                .get(header_len..ip.get_total_length() as usize)
+            else {
+                return bad;
+            };
+            let (source, destination) = (ip.get_source(), ip.get_destination());
+            let protocol = ip.get_next_level_protocol();
+            let pseudo = |skipword| {
+                util::ipv4_checksum(data, skipword, &[], &source, &destination, protocol)
+            };
+            let partial = partial_sum(
+                &[&source.octets(), &destination.octets()],
+                protocol.0,
+                data.len(),
+            );
+            check_transport(
+                &mut bad,
+                protocol.0,
+                data,
+                locally_generated,
+                false,
+                pseudo,
+                partial,
+            );
+        }
+        EtherTypes::Ipv6 => {
+            let Some(ip) = Ipv6Packet::new(ethernet.payload()) else {
+                return bad;
+            };
+            let Some(payload) = ethernet
+                .payload()
+                .get(40..40 + ip.get_payload_length() as usize)
+            else {
+                return bad;
+            };
+            let Some((next, data, ext)) = walk_ipv6_extensions(ip.get_next_header().0, payload)
+            else {
+                return bad;
+            };
+            let source = ip.get_source();
+            let destination = final_destination(ip.get_destination(), &ext);
+            let protocol = IpNextHeaderProtocol(next);
+            let pseudo = |skipword| {
+                util::ipv6_checksum(data, skipword, &[], &source, &destination, protocol)
+            };
+            let partial = partial_sum(&[&source.octets(), &destination.octets()], next, data.len());
+            check_transport(
+                &mut bad,
+                next,
+                data,
+                locally_generated,
+                true,
+                pseudo,
+                partial,
+            );
+        }
+        _ => {}
+    }
+    bad
+}
+
+/// The pseudo-header uses the last address of a routing header, where the packet is
+/// finally delivered, rather than the next hop in the fixed header.
+fn final_destination(destination: Ipv6Addr, ext: &[Ipv6Ext]) -> Ipv6Addr {
+    ext.iter()
+        .find_map(|x| match x {
+            Ipv6Ext::Routing {
+                segments_left,
+                segments,
+                ..
+            } if *segments_left > 0 => segments.first().copied(),
+            _ => None,
+        })
+        .unwrap_or(destination)
+}
+
+/// The uncomplemented sum of the pseudo-header alone, which is what Linux puts in the
+/// checksum field of a segment whose checksum is offloaded.
+fn partial_sum(addresses: &[&[u8]], protocol: u8, len: usize) -> u16 {
+    let mut sum: u32 = addresses
+        .iter()
+        .flat_map(|a| a.chunks_exact(2))
+        .map(|w| u32::from(u16::from_be_bytes([w[0], w[1]])))
+        .sum();
+    sum += u32::from(protocol) + (len as u32 >> 16) + (len as u32 & 0xffff);
+    while sum >> 16 != 0 {
+        sum = (sum >> 16) + (sum & 0xffff);
+    }
+    sum as u16
+}
+
+/// `pseudo(skipword)` computes a checksum over `data` and the IP pseudo-header, and
+/// `partial` is the value an offloaded TCP or UDP checksum is left at.
+fn check_transport(
+    bad: &mut Vec<BadChecksum>,
+    protocol: u8,
+    data: &[u8],
+    locally_generated: bool,
+    ipv6: bool,
+    pseudo: impl Fn(usize) -> u16,
+    partial: u16,
+) {
+    let offloaded = |skipword: usize| {
+        locally_generated || data[skipword * 2..skipword * 2 + 2] == partial.to_be_bytes()
+    };
+    match protocol {
+        6 if data.len() >= 20 && !offloaded(8) => {
+            check(bad, Layer::Tcp, data, 8, pseudo(8));
+        }
+        17 if data.len() >= 8 && !offloaded(3) => {
+            // Zero means "no checksum", which only IPv4 allows
+            if !ipv6 && data[6..8] == [0, 0] {
+                return;
+            }
+            // A computed zero is sent as all ones
+            let expected = match pseudo(3) {
+                0 => 0xffff,
+                sum => sum,
+            };
+            check(bad, Layer::Udp, data, 3, expected);
+        }
+        1 if !ipv6 && data.len() >= 4 => {
+            check(bad, Layer::Icmp, data, 1, util::checksum(data, 1));
+        }
+        58 if ipv6 && data.len() >= 4 => {
+            check(bad, Layer::Icmpv6, data, 1, pseudo(1));
+        }
+        _ => {}
+    }
+}
+
+fn check(bad: &mut Vec<BadChecksum>, layer: Layer, data: &[u8], skipword: usize, expected: u16) {
+    let Some(&[high, low]) = data.get(skipword * 2..skipword * 2 + 2) else {
+        return;
+    };
+    let found = u16::from_be_bytes([high, low]);
+    if found != expected {
+        bad.push(BadChecksum {
+            layer,
+            found,
+            expected,
+        });
+    }
+}
+
+/// Bad checksums seen over the capture, by layer, printed once it ends.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ChecksumSummary {
+    /// Capture time of the last packet counted.
+    pub timestamp: Duration,
+    pub packets: u64,
+    pub by_layer: BTreeMap<Layer, u64>,
+}
+
+impl ChecksumSummary {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.packets == 0
+    }
+
+    /// Counts the bad checksums of one packet.
+    pub fn record(&mut self, timestamp: Duration, bad: &[BadChecksum]) {
+        if bad.is_empty() {
+            return;
+        }
+        self.timestamp = self.timestamp.max(timestamp);
+        self.packets += 1;
+        for b in bad {
+            *self.by_layer.entry(b.layer).or_default() += 1;
+        }
+    }
+}
+
+impl render::Report for ChecksumSummary {
+    fn timestamp(&self) -> Duration {
+        self.timestamp
+    }
+
+    fn render_line(&self) -> String {
+        let layers: Vec<String> = self
+            .by_layer
+            .iter()
+            .map(|(layer, n)| format!("{} {}", layer, n))
+            .collect();
+        format!(
+            "{} packet(s) with bad checksums: {}",
+            self.packets,
+            layers.join(", ")
+        )
+    }
+}
+
+/// Tagged with `"event": "checksum_summary"`.
+impl Serialize for ChecksumSummary {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(None)?;
+        map.serialize_entry("event", "checksum_summary")?;
+        map.serialize_entry("timestamp", &render::format_utc(self.timestamp))?;
+        map.serialize_entry("packets", &self.packets)?;
+        map.serialize_entry("by_layer", &self.by_layer)?;
+        map.end()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::render::Report;
+    use pnet::packet::ethernet::MutableEthernetPacket;
+    use pnet::packet::ip::IpNextHeaderProtocols;
+    use pnet::packet::ipv4::{self, MutableIpv4Packet};
+    use pnet::packet::udp::{self, MutableUdpPacket};
+    use pnet::packet::MutablePacket;
+    use std::net::Ipv4Addr;
+
+    const SRC4: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+    const DST4: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+    const SRC6: Ipv6Addr = Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1);
+    const DST6: Ipv6Addr = Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 2);
+
+    /// An IPv4 UDP datagram with correct checksums.
+    fn udp_frame() -> Vec<u8> {
+        let mut frame = vec![0u8; 14 + 20 + 12];
+        MutableEthernetPacket::new(&mut frame)
+            .unwrap()
+            .set_ethertype(EtherTypes::Ipv4);
+        let mut ip = MutableIpv4Packet::new(&mut frame[14..]).unwrap();
+        ip.set_version(4);
+        ip.set_header_length(5);
+        ip.set_total_length(32);
+        ip.set_ttl(64);
+        ip.set_next_level_protocol(IpNextHeaderProtocols::Udp);
+        ip.set_source(Ipv4Addr::new(10, 0, 0, 1));
+        ip.set_destination(Ipv4Addr::new(10, 0, 0, 2));
+        let checksum = ipv4::checksum(&ip.to_immutable());
+        ip.set_checksum(checksum);
+        let mut udp = MutableUdpPacket::new(ip.payload_mut()).unwrap();
+        udp.set_source(5000);
+        udp.set_destination(6000);
+        udp.set_length(12);
+        udp.set_payload(b"ping");
+        let checksum = udp::ipv4_checksum(
+            &udp.to_immutable(),
+            &Ipv4Addr::new(10, 0, 0, 1),
+            &Ipv4Addr::new(10, 0, 0, 2),
+        );
+        udp.set_checksum(checksum);
+        frame
+    }
+
+    /// `transport` behind an IPv4 header from [`SRC4`] to [`DST4`], with the header
+    /// checksum filled in.
+    fn ipv4_frame(protocol: u8, transport: &[u8]) -> Vec<u8> {
+        let mut frame = vec![0u8; 14 + 20];
+        MutableEthernetPacket::new(&mut frame)
+            .unwrap()
+            .set_ethertype(EtherTypes::Ipv4);
+        frame.extend_from_slice(transport);
+        let mut ip = MutableIpv4Packet::new(&mut frame[14..]).unwrap();
+        ip.set_version(4);
+        ip.set_header_length(5);
+        ip.set_total_length((20 + transport.len()) as u16);
+        ip.set_ttl(64);
+        ip.set_next_level_protocol(IpNextHeaderProtocol(protocol));
+        ip.set_source(SRC4);
+        ip.set_destination(DST4);
+        let checksum = ipv4::checksum(&ip.to_immutable());
+        ip.set_checksum(checksum);
+        frame
+    }
+
+    /// `payload` (extension headers and all) behind an IPv6 header from [`SRC6`] to
+    /// `destination`.
+    fn ipv6_frame(next_header: u8, destination: Ipv6Addr, payload: &[u8]) -> Vec<u8> {
+        let mut frame = vec![0u8; 14];
+        frame[12..14].copy_from_slice(&[0x86, 0xdd]);
+        frame.extend([0x60, 0, 0, 0]);
+        frame.extend((payload.len() as u16).to_be_bytes());
+        frame.extend([next_header, 64]);
+        frame.extend(SRC6.octets());
+        frame.extend(destination.octets());
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    /// Overwrites the checksum word `skipword` of the transport header starting at `at`.
+    fn set_checksum(frame: &mut [u8], at: usize, skipword: usize, checksum: u16) {
+        let at = at + skipword * 2;
+        frame[at..at + 2].copy_from_slice(&checksum.to_be_bytes());
+    }
+
+    fn verify_frame(frame: &[u8], locally_generated: bool) -> Vec<BadChecksum> {
+        verify(&EthernetPacket::new(frame).unwrap(), locally_generated)
+    }
+
+    fn layers(bad: &[BadChecksum]) -> Vec<Layer> {
+        bad.iter().map(|b| b.layer).collect()
+    }
+
+    #[test]
+    fn flags_corruption_but_not_offloaded_checksums() {
+        let good = udp_frame();
+        assert!(verify_frame(&good, false).is_empty());
+
+        // One flipped payload bit
+        let mut corrupt = good.clone();
+        corrupt[14 + 20 + 8] ^= 0x01;
+        let bad = verify_frame(&corrupt, false);
+        assert_eq!(bad.len(), 1);
+        assert_eq!(bad[0].layer, Layer::Udp);
+        assert_eq!(bad[0].found, u16::from_be_bytes([good[40], good[41]]));
+        // Sent by this host: the NIC fills the UDP checksum in after capture
+        assert!(verify_frame(&corrupt, true).is_empty());
+        // Left at the pseudo-header sum for the NIC to finish (10.0.0.1 > 10.0.0.2, 12 bytes)
+        let mut partial = corrupt.clone();
+        let sum: u16 = 0x0a00 + 0x0001 + 0x0a00 + 0x0002 + 17 + 12;
+        partial[40..42].copy_from_slice(&sum.to_be_bytes());
+        assert!(verify_frame(&partial, false).is_empty());
+
+        let mut header = good.clone();
+        header[14 + 8] = 1;
+        let bad = verify_frame(&header, true);
+        assert_eq!(bad.len(), 1);
+        assert_eq!(bad[0].layer, Layer::Ipv4);
+        assert!(bad[0].to_string().starts_with("Bad IPv4 checksum 0x"));
+
+        // No UDP checksum at all is fine over IPv4
+        let mut unchecked = good.clone();
+        unchecked[40..42].copy_from_slice(&[0, 0]);
+        assert!(verify_frame(&unchecked, false).is_empty());
+
+        // A header length too short to hold the checksum fields is left alone
+        for ihl in [0, 2, 4] {
+            let mut short = good.clone();
+            short[14] = 0x40 | ihl;
+            assert!(verify_frame(&short, false).is_empty());
+        }
+    }
+
+    #[test]
+    fn checks_tcp_over_ipv4() {
+        let mut segment = vec![0u8; 20];
+        segment[12] = 5 << 4;
+        segment.extend_from_slice(b"hello");
+        let checksum =
+            util::ipv4_checksum(&segment, 8, &[], &SRC4, &DST4, IpNextHeaderProtocols::Tcp);
+        set_checksum(&mut segment, 0, 8, checksum);
+        let good = ipv4_frame(6, &segment);
+        assert!(verify_frame(&good, false).is_empty());
+
+        let mut corrupt = good.clone();
+        corrupt[14 + 20 + 20] ^= 0x20;
+        let bad = verify_frame(&corrupt, false);
+        assert_eq!(layers(&bad), [Layer::Tcp]);
+        assert_eq!(bad[0].found, checksum);
+        assert_ne!(bad[0].expected, checksum);
+        assert!(verify_frame(&corrupt, true).is_empty());
+
+        let mut partial = corrupt;
+        let sum = partial_sum(&[&SRC4.octets(), &DST4.octets()], 6, segment.len());
+        set_checksum(&mut partial, 14 + 20, 8, sum);
+        assert!(verify_frame(&partial, false).is_empty());
+    }
+
+    #[test]
+    fn checks_icmp_without_a_pseudo_header() {
+        let mut message = vec![8, 0, 0, 0, 0x12, 0x34, 0, 1];
+        message.extend_from_slice(b"ping");
+        let checksum = util::checksum(&message, 1);
+        set_checksum(&mut message, 0, 1, checksum);
+        let good = ipv4_frame(1, &message);
+        assert!(verify_frame(&good, false).is_empty());
+
+        let mut corrupt = good.clone();
+        corrupt[14 + 20 + 8] ^= 0x01;
+        assert_eq!(layers(&verify_frame(&corrupt, false)), [Layer::Icmp]);
+        // ICMP is never offloaded, so neither excuse applies
+        assert_eq!(layers(&verify_frame(&corrupt, true)), [Layer::Icmp]);
+        let mut partial = good;
+        let sum = partial_sum(&[&SRC4.octets(), &DST4.octets()], 1, message.len());
+        set_checksum(&mut partial, 14 + 20, 1, sum);
+        assert_eq!(layers(&verify_frame(&partial, false)), [Layer::Icmp]);
+    }
+
+    #[test]
+    fn checks_icmpv6_with_the_ipv6_pseudo_header() {
+        let mut message = vec![128, 0, 0, 0, 0x12, 0x34, 0, 1];
+        message.extend_from_slice(b"ping");
+        let checksum = util::ipv6_checksum(
+            &message,
+            1,
+            &[],
+            &SRC6,
+            &DST6,
+            IpNextHeaderProtocols::Icmpv6,
+        );
+        set_checksum(&mut message, 0, 1, checksum);
+        let good = ipv6_frame(58, DST6, &message);
+        assert!(verify_frame(&good, false).is_empty());
+
+        let mut corrupt = good.clone();
+        corrupt[14 + 40 + 8] ^= 0x01;
+        assert_eq!(layers(&verify_frame(&corrupt, false)), [Layer::Icmpv6]);
+        assert_eq!(layers(&verify_frame(&corrupt, true)), [Layer::Icmpv6]);
+
+        // A checksum that leaves out the pseudo-header, as ICMP for IPv4 does
+        let mut no_pseudo = good.clone();
+        set_checksum(&mut no_pseudo, 14 + 40, 1, 0);
+        let plain = util::checksum(&no_pseudo[14 + 40..], 1);
+        set_checksum(&mut no_pseudo, 14 + 40, 1, plain);
+        let bad = verify_frame(&no_pseudo, false);
+        assert_eq!(layers(&bad), [Layer::Icmpv6]);
+        assert_eq!(bad[0].expected, checksum);
+
+        let mut partial = good;
+        let sum = partial_sum(&[&SRC6.octets(), &DST6.octets()], 58, message.len());
+        set_checksum(&mut partial, 14 + 40, 1, sum);
+        assert_eq!(layers(&verify_frame(&partial, false)), [Layer::Icmpv6]);
+    }
+
+    #[test]
+    fn pseudo_header_uses_the_final_segment_of_a_routing_header() {
+        let final_hop = Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 9);
+        // Segment routing header (type 4) listing one segment, then UDP
+        let mut srh = vec![17, 2, 4, 0, 0, 0, 0, 0];
+        srh.extend(final_hop.octets());
+        let mut datagram = vec![0x13, 0x88, 0x17, 0x70, 0, 12, 0, 0];
+        datagram.extend_from_slice(b"ping");
+        let udp = |destination| {
+            util::ipv6_checksum(
+                &datagram,
+                3,
+                &[],
+                &SRC6,
+                &destination,
+                IpNextHeaderProtocols::Udp,
+            )
+        };
+        let frame = |segments_left: u8, checksum: u16| {
+            let mut payload = srh.clone();
+            payload[3] = segments_left;
+            payload.extend_from_slice(&datagram);
+            set_checksum(&mut payload, srh.len(), 3, checksum);
+            ipv6_frame(43, DST6, &payload)
+        };
+
+        let ext = [Ipv6Ext::Routing {
+            routing_type: 4,
+            segments_left: 1,
+            segments: vec![final_hop],
+        }];
+        assert_eq!(final_destination(DST6, &ext), final_hop);
+        assert!(verify_frame(&frame(1, udp(final_hop)), false).is_empty());
+
+        // Computed against the next hop in the fixed header instead
+        let bad = verify_frame(&frame(1, udp(DST6)), false);
+        assert_eq!(layers(&bad), [Layer::Udp]);
+        assert_eq!(bad[0].expected, udp(final_hop));
+
+        let partial = partial_sum(&[&SRC6.octets(), &final_hop.octets()], 17, datagram.len());
+        assert!(verify_frame(&frame(1, partial), false).is_empty());
+
+        // With no segments left the fixed header already names the final destination
+        assert!(verify_frame(&frame(0, udp(DST6)), false).is_empty());
+    }
+
+    #[test]
+    fn summary_counts_by_layer() {
+        let mut summary = ChecksumSummary::new();
+        summary.record(Duration::from_secs(1), &[]);
+        assert!(summary.is_empty());
+        let bad = |layer| BadChecksum {
+            layer,
+            found: 0,
+            expected: 1,
+        };
+        summary.record(Duration::from_secs(2), &[bad(Layer::Ipv4), bad(Layer::Tcp)]);
+        summary.record(Duration::from_secs(3), &[bad(Layer::Tcp)]);
+        assert_eq!(
+            summary.render_line(),
+            "2 packet(s) with bad checksums: IPv4 1, TCP 2"
+        );
+        assert_eq!(
+            serde_json::to_string(&summary).unwrap(),
+            r#"{"event":"checksum_summary","timestamp":"1970-01-01T00:00:03.000000Z","packets":2,"by_layer":{"ipv4":1,"tcp":2}}"#
+        );
+    }
+}
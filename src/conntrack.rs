@@ -0,0 +1,513 @@
+//! TCP connection tracking.
+//!
+//! Follows each connection through the handshake, data transfer and teardown as seen
+//! on the capture interface, and reports when connections open and close. Handshake
+//! RTT is the time from the (last) SYN to the SYN-ACK, so on a client it approximates
+//! the full network round trip, while on a server it is close to zero.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::time::Duration;
+
+use serde::Serialize;
+
+use crate::expert::IDLE_TIMEOUT;
+use crate::flows::{Endpoint, FlowKey};
+use crate::model::{NetEvent, TcpFlags, Transport};
+use crate::render;
+
+/// How long a closed connection is remembered so that late segments (the final ACK,
+/// retransmitted FINs) are not mistaken for a new mid-stream connection.
+pub const TIME_WAIT: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConnState {
+    SynSent,
+    SynReceived,
+    Established,
+    /// One side has sent a FIN.
+    Closing,
+    /// Both sides have sent a FIN.
+    TimeWait,
+    /// Torn down by a RST.
+    Closed,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CloseReason {
+    /// Orderly shutdown, both sides sent a FIN.
+    Fin,
+    /// A RST on an open connection.
+    Reset,
+    /// The server answered the SYN with a RST.
+    Refused,
+    /// Capture ended before the server answered the SYN.
+    Unanswered,
+    /// Capture ended while the connection was still open.
+    CaptureEnded,
+    /// Nothing was seen on the open connection for [`IDLE_TIMEOUT`].
+    Idle,
+}
+
+impl fmt::Display for CloseReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            CloseReason::Fin => "fin",
+            CloseReason::Reset => "reset",
+            CloseReason::Refused => "refused",
+            CloseReason::Unanswered => "unanswered",
+            CloseReason::CaptureEnded => "capture ended",
+            CloseReason::Idle => "idle",
+        })
+    }
+}
+
+/// One TCP connection. The client is whoever sent the SYN; for connections picked up
+/// mid-stream it is guessed from the port numbers.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Connection {
+    pub interface: String,
+    pub client: Endpoint,
+    pub server: Endpoint,
+    pub state: ConnState,
+    #[serde(serialize_with = "crate::model::serialize_timestamp")]
+    pub first_seen: Duration,
+    #[serde(serialize_with = "crate::model::serialize_timestamp")]
+    pub last_seen: Duration,
+    /// SYN to SYN-ACK time, in seconds when serialised.
+    #[serde(serialize_with = "crate::model::serialize_secs")]
+    pub handshake_rtt: Option<Duration>,
+    /// Payload bytes sent by the client.
+    pub client_bytes: u64,
+    /// Payload bytes sent by the server.
+    pub server_bytes: u64,
+    /// The handshake happened before the capture started.
+    pub midstream: bool,
+    #[serde(skip)]
+    syn_at: Option<Duration>,
+    #[serde(skip)]
+    client_fin: bool,
+    #[serde(skip)]
+    server_fin: bool,
+    /// One of its segments passed the display filter, so its notices are reported.
+    #[serde(skip)]
+    shown: bool,
+}
+
+impl Connection {
+    pub fn duration(&self) -> Duration {
+        self.last_seen.saturating_sub(self.first_seen)
+    }
+
+    fn is_open(&self) -> bool {
+        !matches!(self.state, ConnState::TimeWait | ConnState::Closed)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NoticeKind {
+    Open,
+    Close,
+}
+
+/// A connection opening or closing, ready to be printed.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Notice {
+    pub event: NoticeKind,
+    #[serde(serialize_with = "crate::model::serialize_timestamp")]
+    pub timestamp: Duration,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<CloseReason>,
+    #[serde(flatten)]
+    pub connection: Connection,
+}
+
+impl render::Report for Notice {
+    fn timestamp(&self) -> Duration {
+        self.timestamp
+    }
+
+    fn render_line(&self) -> String {
+        let c = &self.connection;
+        let rtt = c
+            .handshake_rtt
+            .map(|d| format!("{:.3}ms", d.as_secs_f64() * 1000.0));
+        match self.event {
+            NoticeKind::Open => format!(
+                "[{}]: OPEN {} ====> {}; handshake rtt: {}",
+                c.interface,
+                c.client,
+                c.server,
+                rtt.unwrap_or_else(|| "unknown (mid-stream)".to_string())
+            ),
+            NoticeKind::Close => format!(
+                "[{}]: CLOSE {} ====> {}; reason: {}, duration: {:.3}s, client bytes: {}, server bytes: {}",
+                c.interface,
+                c.client,
+                c.server,
+                self.reason.map_or("unknown".to_string(), |r| r.to_string()),
+                c.duration().as_secs_f64(),
+                c.client_bytes,
+                c.server_bytes
+            ),
+        }
+    }
+}
+
+/// Tracks TCP connections keyed on the normalised 5-tuple.
+#[derive(Debug, Default)]
+pub struct ConnTracker {
+    conns: HashMap<FlowKey, Connection>,
+    last_purge: Duration,
+}
+
+impl ConnTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Number of connections currently open.
+    pub fn open_count(&self) -> usize {
+        self.conns.values().filter(|c| c.is_open()).count()
+    }
+
+    pub fn get(&self, key: &FlowKey) -> Option<&Connection> {
+        self.conns.get(key)
+    }
+
+    /// Feeds one event through the tracker; non-TCP events are ignored. Returns the
+    /// open/close notices it triggered.
+    pub fn process(&mut self, ev: &NetEvent) -> Vec<Notice> {
+        self.update(ev, true)
+    }
+
+    /// Feeds an event the display filter rejected. The connection is still followed,
+    /// so that a later segment that is shown is not mistaken for a mid-stream pickup,
+    /// but its notices are only reported once one of its segments has been shown.
+    /// Returns the idle notices of other connections it triggered.
+    pub fn follow(&mut self, ev: &NetEvent) -> Vec<Notice> {
+        self.update(ev, false)
+    }
+
+    fn update(&mut self, ev: &NetEvent, shown: bool) -> Vec<Notice> {
+        if !matches!(ev.transport, Transport::Tcp { .. }) {
+            return Vec::new();
+        }
+        let mut notices = self.purge(ev.timestamp);
+        notices.extend(self.track(ev, shown));
+        notices
+    }
+
+    fn track(&mut self, ev: &NetEvent, shown: bool) -> Vec<Notice> {
+        let Transport::Tcp {
+            flags, payload_len, ..
+        } = &ev.transport
+        else {
+            return Vec::new();
+        };
+        let (flags, payload_len) = (*flags, *payload_len as u64);
+        let ts = ev.timestamp;
+
+        let (key, src, dst) = FlowKey::from_event(ev);
+        let is_syn = flags.contains(TcpFlags::SYN) && !flags.contains(TcpFlags::ACK);
+        let mut notices = Vec::new();
+        // A fresh SYN on a finished 4-tuple is port reuse: start over
+        if is_syn && self.conns.get(&key).is_some_and(|c| !c.is_open()) {
+            self.conns.remove(&key);
+        }
+        let conn = self.conns.entry(key).or_insert_with(|| {
+            let (client, server, state, midstream) = if is_syn {
+                (src, dst, ConnState::SynSent, false)
+            } else if src.port > dst.port {
+                (src, dst, ConnState::Established, true)
+            } else {
+                (dst, src, ConnState::Established, true)
+            };
+            if midstream && !flags.contains(TcpFlags::RST) {
+                notices.push(NoticeKind::Open);
+            }
+            Connection {
+                interface: ev.interface.clone(),
+                client,
+                server,
+                state,
+                first_seen: ts,
+                last_seen: ts,
+                handshake_rtt: None,
+                client_bytes: 0,
+                server_bytes: 0,
+                midstream,
+                syn_at: None,
+                client_fin: false,
+                server_fin: false,
+                shown: false,
+            }
+        });
+        if !conn.is_open() {
+            return Vec::new();
+        }
+        conn.shown |= shown;
+
+        let from_client = src == conn.client;
+        conn.last_seen = ts;
+        if from_client {
+            conn.client_bytes += payload_len;
+        } else {
+            conn.server_bytes += payload_len;
+        }
+
+        let mut reason = None;
+        if flags.contains(TcpFlags::RST) {
+            reason = Some(if conn.state == ConnState::SynSent {
+                CloseReason::Refused
+            } else {
+                CloseReason::Reset
+            });
+            conn.state = ConnState::Closed;
+        } else {
+            match conn.state {
+                ConnState::SynSent if is_syn && from_client => conn.syn_at = Some(ts),
+                ConnState::SynSent if flags.contains(TcpFlags::SYN) && !from_client => {
+                    conn.state = ConnState::SynReceived;
+                    conn.handshake_rtt = conn.syn_at.map(|syn| ts.saturating_sub(syn));
+                }
+                ConnState::SynReceived if from_client && flags.contains(TcpFlags::ACK) => {
+                    conn.state = ConnState::Established;
+                    notices.push(NoticeKind::Open);
+                }
+                _ => {}
+            }
+            if flags.contains(TcpFlags::FIN) {
+                if from_client {
+                    conn.client_fin = true;
+                } else {
+                    conn.server_fin = true;
+                }
+                if conn.client_fin && conn.server_fin {
+                    conn.state = ConnState::TimeWait;
+                    reason = Some(CloseReason::Fin);
+                } else {
+                    conn.state = ConnState::Closing;
+                }
+            }
+        }
+        if reason.is_some() {
+            notices.push(NoticeKind::Close);
+        }
+        if !conn.shown {
+            return Vec::new();
+        }
+        let conn = conn.clone();
+        notices
+            .into_iter()
+            .map(|event| Notice {
+                event,
+                timestamp: ts,
+                reason: (event == NoticeKind::Close).then_some(reason).flatten(),
+                connection: conn.clone(),
+            })
+            .collect()
+    }
+
+    /// Closes every connection still open, e.g. when the capture stops.
+    pub fn finish(&mut self) -> Vec<Notice> {
+        let mut open: Vec<&Connection> = self
+            .conns
+            .values()
+            .filter(|c| c.is_open() && c.shown)
+            .collect();
+        open.sort_by_key(|c| c.first_seen);
+        open.into_iter()
+            .map(|c| Notice {
+                event: NoticeKind::Close,
+                timestamp: c.last_seen,
+                reason: Some(if c.state == ConnState::SynSent {
+                    CloseReason::Unanswered
+                } else {
+                    CloseReason::CaptureEnded
+                }),
+                connection: c.clone(),
+            })
+            .collect()
+    }
+
+    /// Forgets connections that closed more than [`TIME_WAIT`] ago and closes open
+    /// ones idle for longer than [`IDLE_TIMEOUT`], returning their notices. Runs at
+    /// most once per second of capture time.
+    fn purge(&mut self, now: Duration) -> Vec<Notice> {
+        if now.saturating_sub(self.last_purge) < Duration::from_secs(1) {
+            return Vec::new();
+        }
+        self.last_purge = now;
+        self.conns
+            .retain(|_, c| c.is_open() || now.saturating_sub(c.last_seen) < TIME_WAIT);
+        let mut idle: Vec<Connection> = self
+            .conns
+            .extract_if(|_, c| now.saturating_sub(c.last_seen) >= IDLE_TIMEOUT)
+            .map(|(_, c)| c)
+            .filter(|c| c.shown)
+            .collect();
+        idle.sort_by_key(|c| c.first_seen);
+        idle.into_iter()
+            .map(|c| Notice {
+                event: NoticeKind::Close,
+                timestamp: now,
+                reason: Some(CloseReason::Idle),
+                connection: c,
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::{Direction, TcpOptions};
+    use crate::render::Report;
+    use std::net::{IpAddr, Ipv4Addr};
+
+    const CLIENT: ([u8; 4], u16) = ([10, 0, 0, 2], 50000);
+    const SERVER: ([u8; 4], u16) = ([93, 184, 216, 34], 443);
+
+    fn seg(from_client: bool, flags: TcpFlags, payload_len: usize, ms: u64) -> NetEvent {
+        let (src, dst) = if from_client {
+            (CLIENT, SERVER)
+        } else {
+            (SERVER, CLIENT)
+        };
+        NetEvent::new(
+            "eth0",
+            Direction::Outbound,
+            IpAddr::V4(Ipv4Addr::from(src.0)),
+            IpAddr::V4(Ipv4Addr::from(dst.0)),
+            Transport::Tcp {
+                src_port: src.1,
+                dst_port: dst.1,
+                flags,
+                seq: 0,
+                ack: 0,
+                window: 1024,
+                header_len: 20,
+                payload_len,
+                options: TcpOptions::default(),
+            },
+        )
+        .with_timestamp(Duration::from_millis(ms))
+    }
+
+    #[test]
+    fn tracks_handshake_data_and_orderly_close() {
+        let ack = TcpFlags::ACK;
+        let mut t = ConnTracker::new();
+        assert!(t.process(&seg(true, TcpFlags::SYN, 0, 1000)).is_empty());
+        assert!(t
+            .process(&seg(false, TcpFlags::SYN | ack, 0, 1025))
+            .is_empty());
+        let open = t.process(&seg(true, ack, 0, 1026));
+        assert_eq!(open.len(), 1);
+        assert_eq!(open[0].event, NoticeKind::Open);
+        assert_eq!(
+            open[0].connection.handshake_rtt,
+            Some(Duration::from_millis(25))
+        );
+        assert_eq!(
+            open[0].render_line(),
+            "[eth0]: OPEN 10.0.0.2:50000 ====> 93.184.216.34:443; handshake rtt: 25.000ms"
+        );
+        t.process(&seg(true, TcpFlags::PSH | ack, 300, 1030));
+        t.process(&seg(false, TcpFlags::PSH | ack, 4000, 1060));
+        assert!(t
+            .process(&seg(true, TcpFlags::FIN | ack, 0, 1100))
+            .is_empty());
+        let close = t.process(&seg(false, TcpFlags::FIN | ack, 0, 1120));
+        assert_eq!(close[0].reason, Some(CloseReason::Fin));
+        assert_eq!(close[0].connection.state, ConnState::TimeWait);
+        assert_eq!(
+            close[0].render_line(),
+            "[eth0]: CLOSE 10.0.0.2:50000 ====> 93.184.216.34:443; reason: fin, duration: 0.120s, client bytes: 300, server bytes: 4000"
+        );
+        // The last ACK lands in TIME_WAIT rather than opening a mid-stream connection
+        assert!(t.process(&seg(true, ack, 0, 1121)).is_empty());
+        assert_eq!(t.open_count(), 0);
+        assert!(t.finish().is_empty());
+    }
+
+    #[test]
+    fn reports_refused_reset_and_unanswered() {
+        let mut t = ConnTracker::new();
+        t.process(&seg(true, TcpFlags::SYN, 0, 0));
+        let refused = t.process(&seg(false, TcpFlags::RST | TcpFlags::ACK, 0, 1));
+        assert_eq!(refused[0].reason, Some(CloseReason::Refused));
+
+        // Port reuse after the refusal starts a new connection
+        t.process(&seg(true, TcpFlags::SYN, 0, 5000));
+        let closing = t.finish();
+        assert_eq!(closing.len(), 1);
+        assert_eq!(closing[0].reason, Some(CloseReason::Unanswered));
+
+        let mut t = ConnTracker::new();
+        let open = t.process(&seg(false, TcpFlags::ACK, 100, 0));
+        assert_eq!(open[0].event, NoticeKind::Open);
+        assert!(open[0].connection.midstream);
+        assert_eq!(open[0].connection.client.port, Some(50000));
+        let reset = t.process(&seg(true, TcpFlags::RST, 0, 10));
+        assert_eq!(reset[0].reason, Some(CloseReason::Reset));
+        assert_eq!(reset[0].connection.server_bytes, 100);
+    }
+
+    #[test]
+    fn follows_segments_the_display_filter_hides() {
+        let ack = TcpFlags::ACK;
+        let mut t = ConnTracker::new();
+        t.follow(&seg(true, TcpFlags::SYN, 0, 0));
+        t.follow(&seg(false, TcpFlags::SYN | ack, 0, 20));
+        t.follow(&seg(true, ack, 0, 21));
+        // The first shown segment joins a known connection rather than opening one
+        assert!(t
+            .process(&seg(true, TcpFlags::PSH | ack, 300, 30))
+            .is_empty());
+        t.follow(&seg(true, TcpFlags::FIN | ack, 0, 40));
+        let close = t.process(&seg(false, TcpFlags::FIN | ack, 0, 50));
+        assert_eq!(close.len(), 1);
+        assert_eq!(close[0].reason, Some(CloseReason::Fin));
+        assert!(!close[0].connection.midstream);
+        assert_eq!(
+            close[0].connection.handshake_rtt,
+            Some(Duration::from_millis(20))
+        );
+
+        // A connection that is never shown is never reported
+        let mut t = ConnTracker::new();
+        t.follow(&seg(true, TcpFlags::SYN, 0, 0));
+        t.follow(&seg(false, TcpFlags::SYN | ack, 0, 20));
+        t.follow(&seg(true, ack, 0, 21));
+        assert_eq!(t.open_count(), 1);
+        assert!(t.finish().is_empty());
+    }
+
+    #[test]
+    fn closes_idle_connections() {
+        let from_port = |port: u16, ms: u64| {
+            let mut ev = seg(true, TcpFlags::SYN, 0, ms);
+            if let Transport::Tcp { src_port, .. } = &mut ev.transport {
+                *src_port = port;
+            }
+            ev
+        };
+        let idle = IDLE_TIMEOUT.as_millis() as u64;
+        let mut t = ConnTracker::new();
+        t.process(&seg(true, TcpFlags::SYN, 0, 0));
+        t.follow(&from_port(50001, 0));
+        // Only the connection that was shown is reported, whichever segment expires it
+        let closed = t.follow(&from_port(50002, idle + 1000));
+        assert_eq!(closed.len(), 1);
+        assert_eq!(closed[0].reason, Some(CloseReason::Idle));
+        assert_eq!(closed[0].connection.state, ConnState::SynSent);
+        assert!(closed[0].render_line().contains("reason: idle"));
+        assert_eq!(t.open_count(), 1);
+        assert!(t.finish().is_empty());
+    }
+}
@@ -0,0 +1,756 @@
+//! IP fragment reassembly.
+//!
+//! [`Defragmenter`] holds IPv4 and IPv6 fragments back until every piece of their
+//! datagram has arrived, then hands the datagram on as one Ethernet frame, so the
+//! decoders never mistake the middle of a datagram for a transport header. Reassembled
+//! IPv6 datagrams lose their fragment header; atomic fragments (offset 0, no more to
+//! come) pass through untouched. Fragments are grouped by interface, addresses, IP
+//! identification and protocol. Overlapping fragments are
+//! counted and the first copy of each byte wins. A datagram still missing pieces after
+//! [`FRAGMENT_TIMEOUT`], dropped to stay within [`MAX_BUFFERED`], or dropped because it
+//! would not fit in an IP length field, is reported as [`Incomplete`].
+//!
+//! The captured frames of a datagram can be held along with it, so that a capture file
+//! gets the fragments of the datagrams the capture filter accepts and no others.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::Duration;
+
+use pnet::packet::ethernet::{EtherTypes, EthernetPacket};
+use pnet::packet::ipv4::{self, Ipv4Flags, Ipv4Packet, MutableIpv4Packet};
+use pnet::packet::ipv6::{Ipv6Packet, MutableIpv6Packet};
+use serde::ser::SerializeMap;
+use serde::{Serialize, Serializer};
+
+use crate::model::{self, Reassembly};
+use crate::render;
+use crate::source::Frame;
+
+/// How long the pieces of a datagram are kept (in capture time), as Linux does.
+pub const FRAGMENT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Fragment bytes held across all datagrams before the oldest datagram is dropped.
+pub const MAX_BUFFERED: usize = 4 << 20;
+
+/// Datagrams reassembled at once before the oldest is dropped, so that a flood of tiny
+/// fragments cannot grow the table without bound.
+const MAX_DATAGRAMS: usize = 4096;
+
+/// The largest datagram an IP length field can describe.
+const MAX_PAYLOAD: usize = 65535;
+
+const ETHERNET_HEADER_LEN: usize = 14;
+const IPV6_HEADER_LEN: usize = 40;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct FragmentKey {
+    interface: String,
+    source: IpAddr,
+    destination: IpAddr,
+    id: u32,
+    protocol: u8,
+}
+
+/// Why a datagram was given up on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Reason {
+    Timeout,
+    MemoryLimit,
+    CaptureEnded,
+    /// Longer than an IP length field can describe (the "ping of death").
+    Oversized,
+}
+
+/// A datagram whose fragments never all arrived, ready to be printed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Incomplete {
+    /// When it was given up on; for timeouts, [`FRAGMENT_TIMEOUT`] after the first
+    /// fragment.
+    pub timestamp: Duration,
+    pub interface: String,
+    pub source: IpAddr,
+    pub destination: IpAddr,
+    pub id: u32,
+    pub protocol: u8,
+    pub fragments: u16,
+    /// Payload bytes received.
+    pub received: usize,
+    /// Payload length, known once the last fragment has been seen.
+    pub total: Option<usize>,
+    pub reason: Reason,
+}
+
+impl render::Report for Incomplete {
+    fn timestamp(&self) -> Duration {
+        self.timestamp
+    }
+
+    fn render_line(&self) -> String {
+        let version = if self.source.is_ipv4() {
+            "IPv4"
+        } else {
+            "IPv6"
+        };
+        let total = self
+            .total
+            .map_or_else(|| "?".to_string(), |t| t.to_string());
+        let reason = match self.reason {
+            Reason::Timeout => {
+                format!("not complete within {:.3}s", FRAGMENT_TIMEOUT.as_secs_f64())
+            }
+            Reason::MemoryLimit => "dropped to stay within the memory limit".to_string(),
+            Reason::CaptureEnded => "not complete when the capture ended".to_string(),
+            Reason::Oversized => format!("longer than {} bytes", MAX_PAYLOAD),
+        };
+        format!(
+            "[{}]: {} fragments {} ====> {} ({}, id={:#x}); {}: {} fragment(s), {} of {} bytes",
+            self.interface,
+            version,
+            self.source,
+            self.destination,
+            model::protocol_name(self.protocol),
+            self.id,
+            reason,
+            self.fragments,
+            self.received,
+            total
+        )
+    }
+}
+
+/// Tagged with `"event": "fragments_incomplete"`.
+impl Serialize for Incomplete {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(None)?;
+        map.serialize_entry("event", "fragments_incomplete")?;
+        map.serialize_entry("timestamp", &render::format_utc(self.timestamp))?;
+        map.serialize_entry("interface", &self.interface)?;
+        map.serialize_entry("source", &self.source)?;
+        map.serialize_entry("destination", &self.destination)?;
+        map.serialize_entry("id", &self.id)?;
+        map.serialize_entry("protocol", &self.protocol)?;
+        map.serialize_entry("fragments", &self.fragments)?;
+        map.serialize_entry("received", &self.received)?;
+        map.serialize_entry("total", &self.total)?;
+        map.serialize_entry("reason", &self.reason)?;
+        map.end()
+    }
+}
+
+/// What became of a frame passed to [`Defragmenter::process`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    /// Not a fragment: decode the frame as it is.
+    Unfragmented,
+    /// A fragment held back until the rest of its datagram arrives.
+    Held,
+    /// The fragment completed its datagram, returned as one Ethernet frame.
+    Complete {
+        frame: Vec<u8>,
+        reassembly: Reassembly,
+        /// The captured frames passed in with its fragments, in arrival order.
+        captured: Vec<Frame>,
+    },
+}
+
+/// The pieces of one datagram received so far.
+#[derive(Debug)]
+struct Pending {
+    first_seen: Duration,
+    /// Ethernet and IP header of the first fragment, once it has arrived.
+    header: Option<Vec<u8>>,
+    /// Bytes of `header` counted by the IP length field.
+    headers_len: Option<usize>,
+    data: Vec<u8>,
+    /// Byte ranges of `data` received, sorted and merged.
+    received: Vec<(usize, usize)>,
+    /// Payload length, known once the last fragment has arrived.
+    total: Option<usize>,
+    fragments: u16,
+    overlaps: u16,
+    captured: Vec<Frame>,
+}
+
+impl Pending {
+    fn new(first_seen: Duration) -> Self {
+        Self {
+            first_seen,
+            header: None,
+            headers_len: None,
+            data: Vec::new(),
+            received: Vec::new(),
+            total: None,
+            fragments: 0,
+            overlaps: 0,
+            captured: Vec::new(),
+        }
+    }
+
+    /// Copies the bytes of a fragment that were not received before.
+    fn insert(&mut self, offset: usize, bytes: &[u8]) {
+        self.fragments = self.fragments.saturating_add(1);
+        let end = offset + bytes.len();
+        if self.data.len() < end {
+            self.data.resize(end, 0);
+        }
+        let mut at = offset;
+        let mut overlapped = false;
+        for &(start, stop) in &self.received {
+            if stop <= at {
+                continue;
+            }
+            if start >= end {
+                break;
+            }
+            overlapped = true;
+            if start > at {
+                self.data[at..start].copy_from_slice(&bytes[at - offset..start - offset]);
+            }
+            at = at.max(stop);
+        }
+        if at < end {
+            self.data[at..end].copy_from_slice(&bytes[at - offset..]);
+        }
+        if overlapped {
+            self.overlaps = self.overlaps.saturating_add(1);
+        }
+        self.received.push((offset, end));
+        self.received.sort_unstable();
+        let mut merged: Vec<(usize, usize)> = Vec::with_capacity(self.received.len());
+        for &(start, stop) in &self.received {
+            match merged.last_mut() {
+                Some(last) if start <= last.1 => last.1 = last.1.max(stop),
+                _ => merged.push((start, stop)),
+            }
+        }
+        self.received = merged;
+    }
+
+    /// Bytes held for this datagram, counted against [`MAX_BUFFERED`].
+    fn buffered(&self) -> usize {
+        self.data.len() + self.captured.iter().map(|f| f.data.len()).sum::<usize>()
+    }
+
+    fn received_bytes(&self) -> usize {
+        self.received.iter().map(|(start, stop)| stop - start).sum()
+    }
+
+    fn is_complete(&self) -> bool {
+        self.header.is_some()
+            && self
+                .total
+                .is_some_and(|total| self.received == [(0, total)])
+    }
+}
+
+/// Reassembles fragmented datagrams across the capture.
+#[derive(Debug, Default)]
+pub struct Defragmenter {
+    pending: HashMap<FragmentKey, Pending>,
+    buffered: usize,
+    /// Datagrams dropped for memory since the last call to `expire`.
+    dropped: Vec<Incomplete>,
+    last_seen: Duration,
+    last_purge: Duration,
+}
+
+impl Defragmenter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Datagrams waiting for more fragments.
+    pub fn pending_count(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Takes one Ethernet frame. Fragments are held back, and the one that completes
+    /// its datagram returns the whole datagram instead. If `captured` is given and
+    /// `frame` is a fragment, it is held too and handed back with the datagram.
+    pub fn process(
+        &mut self,
+        interface: &str,
+        timestamp: Duration,
+        frame: &[u8],
+        captured: Option<&Frame>,
+    ) -> Outcome {
+        self.last_seen = self.last_seen.max(timestamp);
+        let Some(fragment) = parse_fragment(interface, frame) else {
+            return Outcome::Unfragmented;
+        };
+        let Fragment {
+            key,
+            offset,
+            more,
+            bytes,
+            header,
+            headers_len,
+        } = fragment;
+        let pending = self
+            .pending
+            .entry(key.clone())
+            .or_insert_with(|| Pending::new(timestamp));
+        if let (Some(header), None) = (header, &pending.header) {
+            pending.header = Some(header);
+            pending.headers_len = Some(headers_len);
+        }
+        // The reassembled datagram gets the first fragment's header, so until that has
+        // arrived this fragment's is the best guess
+        let headers_len = pending.headers_len.unwrap_or(headers_len);
+        let end = pending.data.len().max(offset + bytes.len());
+        if headers_len + end > MAX_PAYLOAD {
+            // Would reassemble into an impossible datagram (the "ping of death")
+            pending.fragments = pending.fragments.saturating_add(1);
+            let incomplete = self.give_up(key, timestamp, Reason::Oversized);
+            self.dropped.push(incomplete);
+            return Outcome::Held;
+        }
+        if !more && pending.total.is_none() {
+            pending.total = Some(offset + bytes.len());
+        }
+        let before = pending.buffered();
+        pending.insert(offset, bytes);
+        pending.captured.extend(captured.cloned());
+        self.buffered += pending.buffered() - before;
+        if pending.is_complete() {
+            let pending = self.pending.remove(&key).expect("just inserted");
+            self.buffered -= pending.buffered();
+            // Only headers `parse_fragment` accepted get here, so this always succeeds
+            if let Some(frame) = rebuild(&key, &pending) {
+                return Outcome::Complete {
+                    frame,
+                    reassembly: Reassembly {
+                        fragments: pending.fragments,
+                        overlaps: pending.overlaps,
+                    },
+                    captured: pending.captured,
+                };
+            }
+            return Outcome::Held;
+        }
+        while self.buffered > MAX_BUFFERED || self.pending.len() > MAX_DATAGRAMS {
+            self.drop_oldest(timestamp);
+        }
+        Outcome::Held
+    }
+
+    /// Reports datagrams dropped for memory since the last call, and, at most once per
+    /// second of capture time, those that timed out by `now`.
+    pub fn expire(&mut self, now: Duration) -> Vec<Incomplete> {
+        let mut out = std::mem::take(&mut self.dropped);
+        if now.saturating_sub(self.last_purge) >= Duration::from_secs(1) {
+            self.last_purge = now;
+            let expired: Vec<FragmentKey> = self
+                .pending
+                .iter()
+                .filter(|(_, p)| now.saturating_sub(p.first_seen) >= FRAGMENT_TIMEOUT)
+                .map(|(k, _)| k.clone())
+                .collect();
+            for key in expired {
+                let at = self.pending[&key].first_seen + FRAGMENT_TIMEOUT;
+                out.push(self.give_up(key, at, Reason::Timeout));
+            }
+        }
+        out.sort_by_key(|i| i.timestamp);
+        out
+    }
+
+    /// Reports everything still waiting for fragments. Call once the capture has ended.
+    pub fn finish(&mut self) -> Vec<Incomplete> {
+        let mut out = self.expire(self.last_seen);
+        let keys: Vec<FragmentKey> = self.pending.keys().cloned().collect();
+        for key in keys {
+            out.push(self.give_up(key, self.last_seen, Reason::CaptureEnded));
+        }
+        out.sort_by_key(|i| i.timestamp);
+        out
+    }
+
+    fn drop_oldest(&mut self, now: Duration) {
+        let Some(key) = self
+            .pending
+            .iter()
+            .min_by_key(|(_, p)| p.first_seen)
+            .map(|(k, _)| k.clone())
+        else {
+            return;
+        };
+        let incomplete = self.give_up(key, now, Reason::MemoryLimit);
+        self.dropped.push(incomplete);
+    }
+
+    fn give_up(&mut self, key: FragmentKey, timestamp: Duration, reason: Reason) -> Incomplete {
+        let pending = self.pending.remove(&key).expect("pending datagram");
+        self.buffered -= pending.buffered();
+        Incomplete {
+            timestamp,
+            interface: key.interface,
+            source: key.source,
+            destination: key.destination,
+            id: key.id,
+            protocol: key.protocol,
+            fragments: pending.fragments,
+            received: pending.received_bytes(),
+            total: pending.total,
+            reason,
+        }
+    }
+}
+
+/// One fragment, as found in an Ethernet frame.
+struct Fragment<'a> {
+    key: FragmentKey,
+    /// Where `bytes` go in the reassembled payload.
+    offset: usize,
+    more: bool,
+    bytes: &'a [u8],
+    /// Ethernet and IP headers to put in front of the reassembled payload, taken from
+    /// the first fragment only. For IPv6 the fragment header is left out.
+    header: Option<Vec<u8>>,
+    /// Header bytes counted by the IP length field along with the payload.
+    headers_len: usize,
+}
+
+fn parse_fragment<'a>(interface: &str, frame: &'a [u8]) -> Option<Fragment<'a>> {
+    let ethertype = EthernetPacket::new(frame)?.get_ethertype();
+    let packet = &frame[ETHERNET_HEADER_LEN..];
+    match ethertype {
+        EtherTypes::Ipv4 => parse_ipv4(interface, frame, packet),
+        EtherTypes::Ipv6 => parse_ipv6(interface, frame, packet),
+        _ => None,
+    }
+}
+
+fn parse_ipv4<'a>(interface: &str, frame: &'a [u8], packet: &'a [u8]) -> Option<Fragment<'a>> {
+    let ip = Ipv4Packet::new(packet)?;
+    let offset = ip.get_fragment_offset() as usize * 8;
+    let more = ip.get_flags() & Ipv4Flags::MoreFragments != 0;
+    if offset == 0 && !more {
+        return None;
+    }
+    let header_len = ip.get_header_length() as usize * 4;
+    // The total length excludes link-layer padding. A malformed fragment, including
+    // one whose IHL is below the fixed header's five words, is left for the decoder to
+    // make what it can of
+    if header_len < 20 {
+        return None;
+    }
+    let end = (ip.get_total_length() as usize).min(packet.len());
+    let bytes = packet.get(header_len..end)?;
+    Some(Fragment {
+        key: FragmentKey {
+            interface: interface.to_string(),
+            source: IpAddr::V4(ip.get_source()),
+            destination: IpAddr::V4(ip.get_destination()),
+            id: ip.get_identification() as u32,
+            protocol: ip.get_next_level_protocol().0,
+        },
+        offset,
+        more,
+        bytes,
+        header: (offset == 0).then(|| frame[..ETHERNET_HEADER_LEN + header_len].to_vec()),
+        headers_len: header_len,
+    })
+}
+
+fn parse_ipv6<'a>(interface: &str, frame: &'a [u8], packet: &'a [u8]) -> Option<Fragment<'a>> {
+    let ip = Ipv6Packet::new(packet)?;
+    let end = (IPV6_HEADER_LEN + ip.get_payload_length() as usize).min(packet.len());
+    // Hop-by-hop, routing and destination options headers may come before the fragment
+    // header; every fragment repeats them
+    let mut next_header = ip.get_next_header().0;
+    let mut next_header_at = 6;
+    let mut at = IPV6_HEADER_LEN;
+    while matches!(next_header, 0 | 43 | 60) {
+        let len = (*packet.get(at + 1)? as usize + 1) * 8;
+        next_header_at = at;
+        next_header = packet[at];
+        at += len;
+    }
+    if next_header != 44 {
+        return None;
+    }
+    let fragment_header = packet.get(at..at + 8)?;
+    let offset_flags = u16::from_be_bytes([fragment_header[2], fragment_header[3]]);
+    let offset = (offset_flags & !0x7) as usize;
+    let more = offset_flags & 0x1 != 0;
+    if offset == 0 && !more {
+        // An atomic fragment (RFC 6946) is a whole datagram, never to be combined with
+        // other fragments that happen to share its identification
+        return None;
+    }
+    let bytes = packet.get(at + 8..end)?;
+    let protocol = fragment_header[0];
+    let header = (offset == 0).then(|| {
+        let mut header = frame[..ETHERNET_HEADER_LEN + at].to_vec();
+        header[ETHERNET_HEADER_LEN + next_header_at] = protocol;
+        header
+    });
+    Some(Fragment {
+        key: FragmentKey {
+            interface: interface.to_string(),
+            source: IpAddr::V6(ip.get_source()),
+            destination: IpAddr::V6(ip.get_destination()),
+            id: u32::from_be_bytes(fragment_header[4..8].try_into().expect("4 bytes")),
+            protocol,
+        },
+        offset,
+        more,
+        bytes,
+        header,
+        headers_len: at - IPV6_HEADER_LEN,
+    })
+}
+
+/// The first fragment's headers, rewritten to describe the whole datagram, followed by
+/// the reassembled payload. `None` if the datagram is not complete or its header is
+/// too short to rewrite.
+fn rebuild(key: &FragmentKey, pending: &Pending) -> Option<Vec<u8>> {
+    let total = pending.total?;
+    let mut frame = pending.header.clone()?;
+    let header_len = frame.len().checked_sub(ETHERNET_HEADER_LEN)?;
+    let packet = &mut frame[ETHERNET_HEADER_LEN..];
+    if key.source.is_ipv4() {
+        let mut ip = MutableIpv4Packet::new(packet)?;
+        ip.set_total_length(u16::try_from(header_len + total).ok()?);
+        ip.set_flags(ip.get_flags() & !Ipv4Flags::MoreFragments);
+        ip.set_fragment_offset(0);
+        let checksum = ipv4::checksum(&ip.to_immutable());
+        ip.set_checksum(checksum);
+    } else {
+        let mut ip = MutableIpv6Packet::new(packet)?;
+        ip.set_payload_length(
+            u16::try_from(header_len.checked_sub(IPV6_HEADER_LEN)? + total).ok()?,
+        );
+    }
+    frame.extend_from_slice(pending.data.get(..total)?);
+    Some(frame)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::render::Report;
+    use pnet::packet::ethernet::MutableEthernetPacket;
+    use pnet::packet::ip::IpNextHeaderProtocols;
+    use pnet::packet::Packet;
+    use std::net::Ipv4Addr;
+
+    /// One fragment of datagram `id`, carrying `payload` at byte `offset`.
+    fn fragment(id: u16, offset: usize, more: bool, payload: &[u8]) -> Vec<u8> {
+        let mut frame = vec![0u8; ETHERNET_HEADER_LEN + 20 + payload.len()];
+        MutableEthernetPacket::new(&mut frame)
+            .unwrap()
+            .set_ethertype(EtherTypes::Ipv4);
+        let mut ip = MutableIpv4Packet::new(&mut frame[ETHERNET_HEADER_LEN..]).unwrap();
+        ip.set_version(4);
+        ip.set_header_length(5);
+        ip.set_total_length((20 + payload.len()) as u16);
+        ip.set_identification(id);
+        ip.set_flags(if more { Ipv4Flags::MoreFragments } else { 0 });
+        ip.set_fragment_offset((offset / 8) as u16);
+        ip.set_ttl(64);
+        ip.set_next_level_protocol(IpNextHeaderProtocols::Udp);
+        ip.set_source(Ipv4Addr::new(10, 0, 0, 1));
+        ip.set_destination(Ipv4Addr::new(10, 0, 0, 2));
+        ip.set_payload(payload);
+        frame
+    }
+
+    /// One IPv6 fragment behind a hop-by-hop header, as in `fragment`.
+    fn fragment6(id: u32, offset: usize, more: bool, payload: &[u8]) -> Vec<u8> {
+        let mut frame = vec![0u8; ETHERNET_HEADER_LEN + IPV6_HEADER_LEN];
+        MutableEthernetPacket::new(&mut frame)
+            .unwrap()
+            .set_ethertype(EtherTypes::Ipv6);
+        let mut ip = MutableIpv6Packet::new(&mut frame[ETHERNET_HEADER_LEN..]).unwrap();
+        ip.set_version(6);
+        ip.set_payload_length((8 + 8 + payload.len()) as u16);
+        ip.set_next_header(IpNextHeaderProtocols::Hopopt);
+        ip.set_hop_limit(64);
+        ip.set_source("2001:db8::1".parse().unwrap());
+        ip.set_destination("2001:db8::2".parse().unwrap());
+        frame.extend_from_slice(&[44, 0, 1, 4, 0, 0, 0, 0]);
+        let offset_flags = offset as u16 | more as u16;
+        frame.extend_from_slice(&[17, 0]);
+        frame.extend_from_slice(&offset_flags.to_be_bytes());
+        frame.extend_from_slice(&id.to_be_bytes());
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    fn secs(s: u64) -> Duration {
+        Duration::from_secs(s)
+    }
+
+    #[test]
+    fn reassembles_out_of_order_and_overlapping_fragments() {
+        let datagram: Vec<u8> = (0..40u8).collect();
+        let mut d = Defragmenter::new();
+        let unfragmented = fragment(1, 0, false, &datagram);
+        assert_eq!(
+            d.process("eth0", secs(1), &unfragmented, None),
+            Outcome::Unfragmented
+        );
+
+        // Last piece first, then a bogus piece overlapping the middle one
+        assert_eq!(
+            d.process(
+                "eth0",
+                secs(1),
+                &fragment(7, 32, false, &datagram[32..]),
+                None
+            ),
+            Outcome::Held
+        );
+        assert_eq!(
+            d.process(
+                "eth0",
+                secs(1),
+                &fragment(7, 16, true, &datagram[16..32]),
+                None
+            ),
+            Outcome::Held
+        );
+        assert_eq!(
+            d.process("eth0", secs(1), &fragment(7, 8, true, &[0xff; 16]), None),
+            Outcome::Held
+        );
+        assert_eq!(d.pending_count(), 1);
+        let Outcome::Complete {
+            frame, reassembly, ..
+        } = d.process(
+            "eth0",
+            secs(2),
+            &fragment(7, 0, true, &datagram[..16]),
+            None,
+        )
+        else {
+            panic!("not complete");
+        };
+        assert_eq!(
+            reassembly,
+            Reassembly {
+                fragments: 4,
+                overlaps: 2
+            }
+        );
+        assert_eq!(d.pending_count(), 0);
+        let ip = Ipv4Packet::new(&frame[ETHERNET_HEADER_LEN..]).unwrap();
+        assert_eq!(ip.get_total_length(), 60);
+        assert_eq!((ip.get_flags(), ip.get_fragment_offset()), (0, 0));
+        assert_eq!(ip.get_checksum(), ipv4::checksum(&ip));
+        // The bogus piece arrived first for bytes 8..16, so its copy was kept there
+        let mut expected = datagram.clone();
+        expected[8..16].copy_from_slice(&[0xff; 8]);
+        assert_eq!(ip.payload(), &expected[..]);
+    }
+
+    #[test]
+    fn reassembles_ipv6_without_the_fragment_header() {
+        let datagram: Vec<u8> = (0..24u8).collect();
+        let mut d = Defragmenter::new();
+        assert_eq!(
+            d.process("eth0", secs(1), &fragment6(5, 0, false, &datagram), None),
+            Outcome::Unfragmented
+        );
+        assert_eq!(
+            d.process(
+                "eth0",
+                secs(1),
+                &fragment6(5, 16, false, &datagram[16..]),
+                None
+            ),
+            Outcome::Held
+        );
+        let Outcome::Complete {
+            frame, reassembly, ..
+        } = d.process(
+            "eth0",
+            secs(1),
+            &fragment6(5, 0, true, &datagram[..16]),
+            None,
+        )
+        else {
+            panic!("not complete");
+        };
+        assert_eq!(reassembly.fragments, 2);
+        let ip = Ipv6Packet::new(&frame[ETHERNET_HEADER_LEN..]).unwrap();
+        assert_eq!(ip.get_payload_length(), 8 + 24);
+        // The hop-by-hop header now leads straight to UDP
+        assert_eq!(&ip.payload()[..8], &[17, 0, 1, 4, 0, 0, 0, 0]);
+        assert_eq!(&ip.payload()[8..], &datagram[..]);
+    }
+
+    #[test]
+    fn reports_incomplete_datagrams() {
+        let mut d = Defragmenter::new();
+        d.process("eth0", secs(10), &fragment(9, 0, true, &[0; 1480]), None);
+        d.process("eth0", secs(12), &fragment(3, 1480, false, &[0; 100]), None);
+        assert!(d.expire(secs(39)).is_empty());
+        let timed_out = d.expire(secs(41));
+        assert_eq!(timed_out.len(), 1);
+        assert_eq!(timed_out[0].timestamp, secs(40));
+        assert_eq!(
+            timed_out[0].render_line(),
+            "[eth0]: IPv4 fragments 10.0.0.1 ====> 10.0.0.2 (UDP, id=0x9); not complete \
+             within 30.000s: 1 fragment(s), 1480 of ? bytes"
+        );
+        let ended = d.finish();
+        assert_eq!(ended.len(), 1);
+        assert_eq!(
+            (ended[0].reason, ended[0].received, ended[0].total),
+            (Reason::CaptureEnded, 100, Some(1580))
+        );
+        assert!(serde_json::to_string(&ended[0])
+            .unwrap()
+            .starts_with(r#"{"event":"fragments_incomplete""#));
+    }
+
+    #[test]
+    fn leaves_fragments_with_a_short_header_alone() {
+        let mut d = Defragmenter::new();
+        for ihl in [0, 4] {
+            let mut first = fragment(4, 0, true, &[0; 16]);
+            MutableIpv4Packet::new(&mut first[ETHERNET_HEADER_LEN..])
+                .unwrap()
+                .set_header_length(ihl);
+            assert_eq!(
+                d.process("eth0", secs(1), &first, None),
+                Outcome::Unfragmented
+            );
+        }
+        // The rest of the datagram waits for a first fragment that never comes
+        let last = fragment(4, 16, false, &[0; 8]);
+        assert_eq!(d.process("eth0", secs(1), &last, None), Outcome::Held);
+        assert_eq!(d.finish()[0].received, 8);
+    }
+
+    #[test]
+    fn drops_datagrams_too_long_for_the_first_header() {
+        // The last fragment fits behind its own 20-byte header, but not behind the
+        // first fragment's 60 bytes of header and options
+        let mut first = fragment(8, 0, true, &[0; 80]);
+        first.splice(ETHERNET_HEADER_LEN + 20..ETHERNET_HEADER_LEN + 20, [1; 40]);
+        let mut ip = MutableIpv4Packet::new(&mut first[ETHERNET_HEADER_LEN..]).unwrap();
+        ip.set_header_length(15);
+        ip.set_total_length(60 + 80);
+        let last_len = MAX_PAYLOAD - 20 - 65496;
+        let last = fragment(8, 65496, false, &vec![0; last_len]);
+        let mut d = Defragmenter::new();
+        assert_eq!(d.process("eth0", secs(1), &first, None), Outcome::Held);
+        assert_eq!(d.process("eth0", secs(2), &last, None), Outcome::Held);
+        assert_eq!(d.pending_count(), 0);
+        let dropped = d.expire(secs(2));
+        assert_eq!(dropped.len(), 1);
+        assert_eq!(
+            (dropped[0].reason, dropped[0].fragments, dropped[0].received),
+            (Reason::Oversized, 2, 80)
+        );
+        assert!(dropped[0]
+            .render_line()
+            .ends_with("; longer than 65535 bytes: 2 fragment(s), 80 of ? bytes"));
+    }
+}
@@ -0,0 +1,699 @@
+//! DNS message decoding.
+//!
+//! [`parse`] decodes the header, questions and answer records of a DNS message,
+//! following compression pointers in names. Authority and additional records are
+//! only counted. Decoding is lenient in the same way as the TCP option parser: a
+//! malformed question or record ends the section quietly and keeps what was read.
+//!
+//! DNS over TCP prefixes every message with a two-byte length; [`TcpDecoder`]
+//! extracts messages from the byte streams produced by [`crate::reassembly`].
+
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::time::Duration;
+
+use serde::{Serialize, Serializer};
+
+use crate::reassembly::{Side, StreamEvent};
+
+/// Well-known DNS port, for UDP and TCP alike.
+pub const PORT: u16 = 53;
+
+/// Whether either port is the DNS port.
+pub fn uses_port(src_port: u16, dst_port: u16) -> bool {
+    src_port == PORT || dst_port == PORT
+}
+
+/// Record type, shown by mnemonic where one is known (`A`, `AAAA`, ...).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct RecordType(pub u16);
+
+impl RecordType {
+    pub const A: RecordType = RecordType(1);
+    pub const NS: RecordType = RecordType(2);
+    pub const CNAME: RecordType = RecordType(5);
+    pub const SOA: RecordType = RecordType(6);
+    pub const PTR: RecordType = RecordType(12);
+    pub const MX: RecordType = RecordType(15);
+    pub const TXT: RecordType = RecordType(16);
+    pub const AAAA: RecordType = RecordType(28);
+    pub const SRV: RecordType = RecordType(33);
+    pub const OPT: RecordType = RecordType(41);
+    pub const SVCB: RecordType = RecordType(64);
+    pub const HTTPS: RecordType = RecordType(65);
+    pub const ANY: RecordType = RecordType(255);
+
+    const NAMES: [(RecordType, &'static str); 13] = [
+        (RecordType::A, "A"),
+        (RecordType::NS, "NS"),
+        (RecordType::CNAME, "CNAME"),
+        (RecordType::SOA, "SOA"),
+        (RecordType::PTR, "PTR"),
+        (RecordType::MX, "MX"),
+        (RecordType::TXT, "TXT"),
+        (RecordType::AAAA, "AAAA"),
+        (RecordType::SRV, "SRV"),
+        (RecordType::OPT, "OPT"),
+        (RecordType::SVCB, "SVCB"),
+        (RecordType::HTTPS, "HTTPS"),
+        (RecordType::ANY, "ANY"),
+    ];
+}
+
+/// RFC 3597 notation (`TYPE99`) for types without a mnemonic.
+impl fmt::Display for RecordType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match RecordType::NAMES.iter().find(|(t, _)| t == self) {
+            Some((_, name)) => f.write_str(name),
+            None => write!(f, "TYPE{}", self.0),
+        }
+    }
+}
+
+impl Serialize for RecordType {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// Response code from the header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Rcode(pub u8);
+
+impl Rcode {
+    pub const NOERROR: Rcode = Rcode(0);
+    pub const FORMERR: Rcode = Rcode(1);
+    pub const SERVFAIL: Rcode = Rcode(2);
+    pub const NXDOMAIN: Rcode = Rcode(3);
+    pub const NOTIMP: Rcode = Rcode(4);
+    pub const REFUSED: Rcode = Rcode(5);
+}
+
+impl fmt::Display for Rcode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Rcode::NOERROR => f.write_str("NOERROR"),
+            Rcode::FORMERR => f.write_str("FORMERR"),
+            Rcode::SERVFAIL => f.write_str("SERVFAIL"),
+            Rcode::NXDOMAIN => f.write_str("NXDOMAIN"),
+            Rcode::NOTIMP => f.write_str("NOTIMP"),
+            Rcode::REFUSED => f.write_str("REFUSED"),
+            Rcode(n) => write!(f, "RCODE{}", n),
+        }
+    }
+}
+
+impl Serialize for Rcode {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Question {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub qtype: RecordType,
+    pub class: u16,
+}
+
+/// Decoded record data for the types worth showing; anything else keeps its type and
+/// length only.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RData {
+    A(Ipv4Addr),
+    Aaaa(Ipv6Addr),
+    Cname(String),
+    Ptr(String),
+    Mx {
+        preference: u16,
+        exchange: String,
+    },
+    Txt(Vec<String>),
+    Srv {
+        priority: u16,
+        weight: u16,
+        port: u16,
+        target: String,
+    },
+    /// SVCB and HTTPS records, with parameters already formatted as `key=value`.
+    Svcb {
+        priority: u16,
+        target: String,
+        params: Vec<String>,
+    },
+    Other(usize),
+}
+
+/// Presentation format as in zone files, e.g. `10 mail.example.com` for MX.
+impl fmt::Display for RData {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RData::A(addr) => write!(f, "{}", addr),
+            RData::Aaaa(addr) => write!(f, "{}", addr),
+            RData::Cname(name) | RData::Ptr(name) => f.write_str(name),
+            RData::Mx {
+                preference,
+                exchange,
+            } => write!(f, "{} {}", preference, exchange),
+            RData::Txt(strings) => {
+                let quoted: Vec<String> = strings.iter().map(|s| format!("{:?}", s)).collect();
+                f.write_str(&quoted.join(" "))
+            }
+            RData::Srv {
+                priority,
+                weight,
+                port,
+                target,
+            } => write!(f, "{} {} {} {}", priority, weight, port, target),
+            RData::Svcb {
+                priority,
+                target,
+                params,
+            } => {
+                write!(f, "{} {}", priority, target)?;
+                for param in params {
+                    write!(f, " {}", param)?;
+                }
+                Ok(())
+            }
+            RData::Other(len) => write!(f, "{} bytes", len),
+        }
+    }
+}
+
+impl Serialize for RData {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Record {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub rtype: RecordType,
+    pub ttl: u32,
+    pub data: RData,
+}
+
+/// One DNS query or response.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Message {
+    pub id: u16,
+    pub response: bool,
+    pub opcode: u8,
+    pub rcode: Rcode,
+    pub authoritative: bool,
+    pub truncated: bool,
+    pub recursion_desired: bool,
+    pub recursion_available: bool,
+    pub questions: Vec<Question>,
+    pub answers: Vec<Record>,
+    pub authority_count: u16,
+    pub additional_count: u16,
+    /// Time since the matching query, on responses; filled in by
+    /// [`crate::dnstrack::DnsTracker`].
+    #[serde(
+        skip_serializing_if = "Option::is_none",
+        serialize_with = "crate::model::serialize_secs"
+    )]
+    pub latency: Option<Duration>,
+}
+
+/// One-line summary: `DNS query A example.com (0x1a2b)`, or for responses the rcode
+/// when it is not NOERROR, the latency when known and the answers, e.g.
+/// `DNS response A example.com (0x1a2b) in 12.345ms: CNAME edge.example.net, A 192.0.2.1`.
+impl fmt::Display for Message {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(if self.response {
+            "DNS response"
+        } else {
+            "DNS query"
+        })?;
+        if self.response && self.rcode != Rcode::NOERROR {
+            write!(f, " {}", self.rcode)?;
+        }
+        for q in &self.questions {
+            write!(f, " {} {}", q.qtype, q.name)?;
+        }
+        write!(f, " ({:#06x})", self.id)?;
+        if let Some(latency) = self.latency {
+            write!(f, " in {:.3}ms", latency.as_secs_f64() * 1000.0)?;
+        }
+        if self.truncated {
+            f.write_str(" truncated")?;
+        }
+        if !self.answers.is_empty() {
+            let answers: Vec<String> = self
+                .answers
+                .iter()
+                .map(|r| format!("{} {}", r.rtype, r.data))
+                .collect();
+            write!(f, ": {}", answers.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+/// Compression pointers followed per name before giving up on a loop.
+const MAX_POINTERS: usize = 32;
+
+struct Reader<'a> {
+    msg: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, n: usize) -> Option<&'a [u8]> {
+        let b = self.msg.get(self.pos..self.pos.checked_add(n)?)?;
+        self.pos += n;
+        Some(b)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.bytes(1).map(|b| b[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.bytes(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        self.bytes(4)
+            .map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    /// Reads a possibly compressed name, leaving the reader after its in-place part.
+    fn name(&mut self) -> Option<String> {
+        let mut labels: Vec<String> = Vec::new();
+        let mut pos = self.pos;
+        let mut resume = None;
+        let mut pointers = 0;
+        loop {
+            let len = *self.msg.get(pos)? as usize;
+            match len {
+                0 => {
+                    pos += 1;
+                    break;
+                }
+                l if l & 0xc0 == 0xc0 => {
+                    let low = *self.msg.get(pos + 1)? as usize;
+                    pointers += 1;
+                    if pointers > MAX_POINTERS {
+                        return None;
+                    }
+                    resume.get_or_insert(pos + 2);
+                    pos = (l & 0x3f) << 8 | low;
+                }
+                l if l & 0xc0 == 0 => {
+                    let label = self.msg.get(pos + 1..pos + 1 + l)?;
+                    labels.push(escape_label(label));
+                    pos += 1 + l;
+                }
+                // Extended label types (0x40, 0x80) are obsolete
+                _ => return None,
+            }
+        }
+        self.pos = resume.unwrap_or(pos);
+        Some(if labels.is_empty() {
+            ".".to_string()
+        } else {
+            labels.join(".")
+        })
+    }
+}
+
+/// Label text with dots and unprintable bytes escaped as `\DDD`, as dig does.
+fn escape_label(label: &[u8]) -> String {
+    let mut text = String::with_capacity(label.len());
+    for &b in label {
+        if b.is_ascii_graphic() && b != b'.' && b != b'\\' {
+            text.push(b as char);
+        } else {
+            text.push_str(&format!("\\{:03}", b));
+        }
+    }
+    text
+}
+
+/// Decodes a DNS message. Returns `None` when even the header is incomplete.
+pub fn parse(msg: &[u8]) -> Option<Message> {
+    let mut r = Reader { msg, pos: 0 };
+    let id = r.u16()?;
+    let flags = r.u16()?;
+    let qdcount = r.u16()?;
+    let ancount = r.u16()?;
+    let authority_count = r.u16()?;
+    let additional_count = r.u16()?;
+    let bit = |n: u16| flags & (1 << n) != 0;
+    let mut message = Message {
+        id,
+        response: bit(15),
+        opcode: ((flags >> 11) & 0xf) as u8,
+        rcode: Rcode((flags & 0xf) as u8),
+        authoritative: bit(10),
+        truncated: bit(9),
+        recursion_desired: bit(8),
+        recursion_available: bit(7),
+        questions: Vec::new(),
+        answers: Vec::new(),
+        authority_count,
+        additional_count,
+        latency: None,
+    };
+    for _ in 0..qdcount {
+        let Some(q) = question(&mut r) else {
+            return Some(message);
+        };
+        message.questions.push(q);
+    }
+    for _ in 0..ancount {
+        let Some(rr) = record(&mut r) else { break };
+        message.answers.push(rr);
+    }
+    Some(message)
+}
+
+fn question(r: &mut Reader) -> Option<Question> {
+    Some(Question {
+        name: r.name()?,
+        qtype: RecordType(r.u16()?),
+        class: r.u16()?,
+    })
+}
+
+fn record(r: &mut Reader) -> Option<Record> {
+    let name = r.name()?;
+    let rtype = RecordType(r.u16()?);
+    let _class = r.u16()?;
+    let ttl = r.u32()?;
+    let len = r.u16()? as usize;
+    let start = r.pos;
+    let rdata = r.bytes(len)?;
+    // Names inside the data may point anywhere in the message, so read them in place
+    let mut inner = Reader {
+        msg: &r.msg[..start + len],
+        pos: start,
+    };
+    let data = match rtype {
+        RecordType::A if len == 4 => {
+            RData::A(Ipv4Addr::new(rdata[0], rdata[1], rdata[2], rdata[3]))
+        }
+        RecordType::AAAA if len == 16 => {
+            RData::Aaaa(Ipv6Addr::from(<[u8; 16]>::try_from(rdata).ok()?))
+        }
+        RecordType::CNAME => RData::Cname(inner.name()?),
+        RecordType::PTR => RData::Ptr(inner.name()?),
+        RecordType::MX => RData::Mx {
+            preference: inner.u16()?,
+            exchange: inner.name()?,
+        },
+        RecordType::TXT => {
+            let mut strings = Vec::new();
+            while inner.pos < start + len {
+                let n = inner.u8()? as usize;
+                strings.push(String::from_utf8_lossy(inner.bytes(n)?).into_owned());
+            }
+            RData::Txt(strings)
+        }
+        RecordType::SRV => RData::Srv {
+            priority: inner.u16()?,
+            weight: inner.u16()?,
+            port: inner.u16()?,
+            target: inner.name()?,
+        },
+        RecordType::SVCB | RecordType::HTTPS => {
+            let priority = inner.u16()?;
+            let target = inner.name()?;
+            let mut params = Vec::new();
+            while inner.pos < start + len {
+                let key = inner.u16()?;
+                let n = inner.u16()? as usize;
+                params.push(svc_param(key, inner.bytes(n)?));
+            }
+            RData::Svcb {
+                priority,
+                target,
+                params,
+            }
+        }
+        _ => RData::Other(len),
+    };
+    Some(Record {
+        name,
+        rtype,
+        ttl,
+        data,
+    })
+}
+
+/// Formats one SVCB parameter the way RFC 9460 presentation format does.
+fn svc_param(key: u16, value: &[u8]) -> String {
+    let join = |parts: Vec<String>| parts.join(",");
+    match key {
+        1 => {
+            let mut ids = Vec::new();
+            let mut rest = value;
+            while let Some((&n, tail)) = rest.split_first() {
+                let Some(id) = tail.get(..n as usize) else {
+                    break;
+                };
+                ids.push(String::from_utf8_lossy(id).into_owned());
+                rest = &tail[n as usize..];
+            }
+            format!("alpn={}", join(ids))
+        }
+        2 => "no-default-alpn".to_string(),
+        3 if value.len() == 2 => format!("port={}", u16::from_be_bytes([value[0], value[1]])),
+        4 => format!(
+            "ipv4hint={}",
+            join(
+                value
+                    .chunks_exact(4)
+                    .map(|b| Ipv4Addr::new(b[0], b[1], b[2], b[3]).to_string())
+                    .collect()
+            )
+        ),
+        5 => "ech".to_string(),
+        6 => format!(
+            "ipv6hint={}",
+            join(
+                value
+                    .chunks_exact(16)
+                    .map(|b| Ipv6Addr::from(<[u8; 16]>::try_from(b).unwrap()).to_string())
+                    .collect()
+            )
+        ),
+        _ => format!("key{}", key),
+    }
+}
+
+/// Per-direction buffer for a DNS-over-TCP stream.
+#[derive(Debug, Default)]
+struct TcpBuffer {
+    pending: Vec<u8>,
+    /// Set after a gap: message boundaries are lost for the rest of the stream.
+    lost: bool,
+}
+
+/// Splits reassembled DNS-over-TCP streams into messages.
+#[derive(Debug, Default)]
+pub struct TcpDecoder {
+    buffers: HashMap<(u64, Side), TcpBuffer>,
+}
+
+impl TcpDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Consumes one stream event and returns the messages it completes. Streams that
+    /// do not use the DNS port are ignored.
+    pub fn handle(&mut self, event: &StreamEvent) -> Vec<Message> {
+        match event {
+            StreamEvent::Data {
+                stream, side, data, ..
+            } => {
+                let (Some(client), Some(server)) = (stream.client.port, stream.server.port) else {
+                    return Vec::new();
+                };
+                if !uses_port(client, server) {
+                    return Vec::new();
+                }
+                let buffer = self.buffers.entry((stream.id, *side)).or_default();
+                if buffer.lost {
+                    return Vec::new();
+                }
+                buffer.pending.extend_from_slice(data);
+                let mut messages = Vec::new();
+                while buffer.pending.len() >= 2 {
+                    let len = u16::from_be_bytes([buffer.pending[0], buffer.pending[1]]) as usize;
+                    if buffer.pending.len() < 2 + len {
+                        break;
+                    }
+                    messages.extend(parse(&buffer.pending[2..2 + len]));
+                    buffer.pending.drain(..2 + len);
+                }
+                messages
+            }
+            StreamEvent::Gap { stream, side, .. } => {
+                let buffer = self.buffers.entry((stream.id, *side)).or_default();
+                buffer.pending.clear();
+                buffer.lost = true;
+                Vec::new()
+            }
+            StreamEvent::Closed { stream } => {
+                self.buffers.remove(&(stream.id, Side::Client));
+                self.buffers.remove(&(stream.id, Side::Server));
+                Vec::new()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds a message with the given header fields, questions and raw answer section.
+    fn message(
+        id: u16,
+        flags: u16,
+        questions: &[(&str, u16)],
+        answers: &[u8],
+        ancount: u16,
+    ) -> Vec<u8> {
+        let mut m = Vec::new();
+        m.extend_from_slice(&id.to_be_bytes());
+        m.extend_from_slice(&flags.to_be_bytes());
+        m.extend_from_slice(&(questions.len() as u16).to_be_bytes());
+        m.extend_from_slice(&ancount.to_be_bytes());
+        m.extend_from_slice(&[0, 0, 0, 0]);
+        for (name, qtype) in questions {
+            for label in name.split('.') {
+                m.push(label.len() as u8);
+                m.extend_from_slice(label.as_bytes());
+            }
+            m.push(0);
+            m.extend_from_slice(&qtype.to_be_bytes());
+            m.extend_from_slice(&1u16.to_be_bytes());
+        }
+        m.extend_from_slice(answers);
+        m
+    }
+
+    /// An answer record owned by the first question's name (pointer to offset 12).
+    fn answer(rtype: u16, rdata: &[u8]) -> Vec<u8> {
+        let mut rr = vec![0xc0, 12];
+        rr.extend_from_slice(&rtype.to_be_bytes());
+        rr.extend_from_slice(&1u16.to_be_bytes());
+        rr.extend_from_slice(&300u32.to_be_bytes());
+        rr.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+        rr.extend_from_slice(rdata);
+        rr
+    }
+
+    #[test]
+    fn parses_queries_and_compressed_answers() {
+        let q = parse(&message(0x1a2b, 0x0100, &[("example.com", 1)], &[], 0)).unwrap();
+        assert!(!q.response && q.recursion_desired);
+        assert_eq!(q.to_string(), "DNS query A example.com (0x1a2b)");
+
+        // CNAME to "www" + pointer to "example.com", then an A record for that name
+        let mut answers = answer(5, &[3, b'w', b'w', b'w', 0xc0, 12]);
+        answers.extend(answer(1, &[192, 0, 2, 1]));
+        answers.extend(answer(
+            28,
+            &[0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1],
+        ));
+        answers.extend(answer(15, &[0, 10, 4, b'm', b'a', b'i', b'l', 0xc0, 12]));
+        answers.extend(answer(16, b"\x05hello\x05world"));
+        answers.extend(answer(33, &[0, 1, 0, 5, 0x01, 0xbb, 0xc0, 12]));
+        answers.extend(answer(
+            65,
+            &[0, 1, 0, 0, 1, 0, 3, 2, b'h', b'2', 0, 3, 0, 2, 0x01, 0xbb],
+        ));
+        answers.extend(answer(99, &[1, 2, 3]));
+        let r = parse(&message(0x1a2b, 0x8180, &[("example.com", 1)], &answers, 8)).unwrap();
+        assert!(r.response && r.recursion_available);
+        assert_eq!(r.rcode, Rcode::NOERROR);
+        assert_eq!(r.answers.len(), 8);
+        assert_eq!(r.answers[0].name, "example.com");
+        assert_eq!(r.answers[0].ttl, 300);
+        assert_eq!(
+            r.to_string(),
+            "DNS response A example.com (0x1a2b): CNAME www.example.com, A 192.0.2.1, \
+             AAAA 2001:db8::1, MX 10 mail.example.com, TXT \"hello\" \"world\", \
+             SRV 1 5 443 example.com, HTTPS 1 . alpn=h2 port=443, TYPE99 3 bytes"
+        );
+    }
+
+    #[test]
+    fn reports_errors_and_survives_malformed_input() {
+        let nx = parse(&message(7, 0x8183, &[("nope.example", 28)], &[], 0)).unwrap();
+        assert_eq!(nx.rcode, Rcode::NXDOMAIN);
+        assert_eq!(
+            nx.to_string(),
+            "DNS response NXDOMAIN AAAA nope.example (0x0007)"
+        );
+
+        assert!(parse(&[0, 1, 2]).is_none());
+        // A name pointing at itself is a loop, not a hang
+        let mut looped = message(8, 0x8180, &[], &[], 1);
+        looped.extend_from_slice(&[0xc0, 12, 0, 1, 0, 1, 0, 0, 0, 0, 0, 4, 1, 2, 3, 4]);
+        let msg = parse(&looped).unwrap();
+        assert!(msg.answers.is_empty());
+        // Answers cut short by the snap length keep whatever was complete
+        let truncated = message(9, 0x8180, &[("a.example", 1)], &answer(1, &[1, 2, 3, 4]), 2);
+        assert_eq!(parse(&truncated).unwrap().answers.len(), 1);
+        assert_eq!(escape_label(b"a.b\x01"), "a\\046b\\001");
+    }
+
+    #[test]
+    fn splits_tcp_streams_into_messages() {
+        use crate::flows::Endpoint;
+        use crate::reassembly::StreamInfo;
+        use std::rc::Rc;
+
+        let stream = Rc::new(StreamInfo {
+            id: 1,
+            interface: "eth0".to_string(),
+            client: Endpoint {
+                addr: Ipv4Addr::new(10, 0, 0, 5).into(),
+                port: Some(40000),
+            },
+            server: Endpoint {
+                addr: Ipv4Addr::new(10, 0, 0, 53).into(),
+                port: Some(PORT),
+            },
+            first_seen: Duration::ZERO,
+        });
+        let data = |bytes: &[u8]| StreamEvent::Data {
+            stream: Rc::clone(&stream),
+            side: Side::Client,
+            offset: 0,
+            data: bytes.to_vec(),
+        };
+        let mut framed = Vec::new();
+        for id in [1u16, 2] {
+            let m = message(id, 0x0100, &[("example.com", 1)], &[], 0);
+            framed.extend_from_slice(&(m.len() as u16).to_be_bytes());
+            framed.extend(m);
+        }
+        let mut d = TcpDecoder::new();
+        // The first message arrives split across segments, the second completes with it
+        assert!(d.handle(&data(&framed[..10])).is_empty());
+        let msgs = d.handle(&data(&framed[10..]));
+        assert_eq!(msgs.iter().map(|m| m.id).collect::<Vec<_>>(), [1, 2]);
+        // After a gap the framing is lost for good
+        d.handle(&StreamEvent::Gap {
+            stream: Rc::clone(&stream),
+            side: Side::Client,
+            offset: 0,
+            len: 3,
+        });
+        assert!(d.handle(&data(&framed)).is_empty());
+    }
+}
@@ -0,0 +1,487 @@
+//! DNS query/response matching and resolver health.
+//!
+//! [`DnsTracker`] pairs each query with its response by transaction id and 5-tuple,
+//! stamps the response with the resolution latency, and reports queries that stay
+//! unanswered for [`QUERY_TIMEOUT`]. Per resolver it counts queries, responses,
+//! timeouts and error responses and keeps the latencies, for a health table at the
+//! end of the capture. A retried query keeps the time of the first attempt, so the
+//! latency is what the client waited for.
+
+use std::collections::{BTreeMap, HashMap};
+use std::time::Duration;
+
+use serde::ser::SerializeMap;
+use serde::{Serialize, Serializer};
+
+use crate::dns::{Question, Rcode};
+use crate::flows::{Endpoint, FlowKey, Protocol};
+use crate::model::NetEvent;
+use crate::render;
+
+/// Queries without a response for this long (in capture time) count as timed out;
+/// five seconds is the classic resolver timeout.
+pub const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct QueryKey {
+    protocol: Protocol,
+    client: Endpoint,
+    resolver: Endpoint,
+    id: u16,
+}
+
+#[derive(Debug, Clone)]
+struct Pending {
+    interface: String,
+    sent_at: Duration,
+    question: Option<Question>,
+}
+
+/// A query that went unanswered, ready to be printed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Timeout {
+    /// When the query timed out, [`QUERY_TIMEOUT`] after it was sent.
+    pub timestamp: Duration,
+    pub interface: String,
+    pub client: Endpoint,
+    pub resolver: Endpoint,
+    pub id: u16,
+    pub question: Option<Question>,
+}
+
+impl render::Report for Timeout {
+    fn timestamp(&self) -> Duration {
+        self.timestamp
+    }
+
+    fn render_line(&self) -> String {
+        let question = self
+            .question
+            .as_ref()
+            .map(|q| format!(" {} {}", q.qtype, q.name))
+            .unwrap_or_default();
+        format!(
+            "[{}]: DNS timeout{} ({:#06x}) {} ====> {}; no response within {:.3}s",
+            self.interface,
+            question,
+            self.id,
+            self.client,
+            self.resolver,
+            QUERY_TIMEOUT.as_secs_f64()
+        )
+    }
+}
+
+/// Tagged with `"event": "dns_timeout"` to tell it apart from packet objects.
+impl Serialize for Timeout {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(None)?;
+        map.serialize_entry("event", "dns_timeout")?;
+        map.serialize_entry("timestamp", &render::format_utc(self.timestamp))?;
+        map.serialize_entry("interface", &self.interface)?;
+        map.serialize_entry("client", &self.client)?;
+        map.serialize_entry("resolver", &self.resolver)?;
+        map.serialize_entry("id", &self.id)?;
+        if let Some(q) = &self.question {
+            map.serialize_entry("question", q)?;
+        }
+        map.end()
+    }
+}
+
+/// Health counters for one resolver.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResolverStats {
+    pub resolver: Endpoint,
+    pub queries: u64,
+    /// Responses matched to a query; copies of a response and responses to queries
+    /// sent before the capture started are not counted.
+    pub responses: u64,
+    pub timeouts: u64,
+    pub nxdomain: u64,
+    pub servfail: u64,
+    /// Latencies of the responses matched to a query.
+    pub latencies: Vec<Duration>,
+}
+
+impl ResolverStats {
+    fn new(resolver: Endpoint) -> Self {
+        Self {
+            resolver,
+            queries: 0,
+            responses: 0,
+            timeouts: 0,
+            nxdomain: 0,
+            servfail: 0,
+            latencies: Vec::new(),
+        }
+    }
+
+    pub fn average(&self) -> Option<Duration> {
+        let n = self.latencies.len() as u32;
+        (n > 0).then(|| self.latencies.iter().sum::<Duration>() / n)
+    }
+
+    /// Nearest-rank percentile, `p` in 0..=100.
+    pub fn percentile(&self, p: u32) -> Option<Duration> {
+        let mut sorted = self.latencies.clone();
+        sorted.sort();
+        let rank = (sorted.len() * p as usize).div_ceil(100).max(1);
+        sorted.get(rank - 1).copied()
+    }
+
+    pub fn max(&self) -> Option<Duration> {
+        self.latencies.iter().max().copied()
+    }
+}
+
+/// Tagged with `"event": "dns_resolver"`; latencies are summarised in seconds.
+impl Serialize for ResolverStats {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let secs = |d: Option<Duration>| d.map(|d| d.as_secs_f64());
+        let mut map = serializer.serialize_map(None)?;
+        map.serialize_entry("event", "dns_resolver")?;
+        map.serialize_entry("resolver", &self.resolver)?;
+        map.serialize_entry("queries", &self.queries)?;
+        map.serialize_entry("responses", &self.responses)?;
+        map.serialize_entry("timeouts", &self.timeouts)?;
+        map.serialize_entry("nxdomain", &self.nxdomain)?;
+        map.serialize_entry("servfail", &self.servfail)?;
+        map.serialize_entry("latency_avg", &secs(self.average()))?;
+        map.serialize_entry("latency_p95", &secs(self.percentile(95)))?;
+        map.serialize_entry("latency_max", &secs(self.max()))?;
+        map.end()
+    }
+}
+
+/// Matches DNS queries with responses across the capture.
+#[derive(Debug, Default)]
+pub struct DnsTracker {
+    pending: HashMap<QueryKey, Pending>,
+    resolvers: BTreeMap<Endpoint, ResolverStats>,
+    last_seen: Duration,
+    last_purge: Duration,
+}
+
+impl DnsTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Whether any DNS traffic has been seen.
+    pub fn is_empty(&self) -> bool {
+        self.resolvers.is_empty()
+    }
+
+    pub fn get(&self, resolver: &Endpoint) -> Option<&ResolverStats> {
+        self.resolvers.get(resolver)
+    }
+
+    /// Matches the DNS messages on `ev`, setting `latency` on answered responses.
+    /// Returns the queries that timed out meanwhile.
+    pub fn process(&mut self, ev: &mut NetEvent) -> Vec<Timeout> {
+        let ts = ev.timestamp;
+        self.last_seen = self.last_seen.max(ts);
+        let timeouts = self.purge(ts);
+        if ev.dns.is_empty() {
+            return timeouts;
+        }
+        let (key, src, dst) = FlowKey::from_event(ev);
+        for message in ev.dns.iter_mut() {
+            if !message.response {
+                let query = QueryKey {
+                    protocol: key.protocol,
+                    client: src,
+                    resolver: dst,
+                    id: message.id,
+                };
+                if self.pending.contains_key(&query) {
+                    continue;
+                }
+                self.pending.insert(
+                    query,
+                    Pending {
+                        interface: ev.interface.clone(),
+                        sent_at: ts,
+                        question: message.questions.first().cloned(),
+                    },
+                );
+                self.stats(dst).queries += 1;
+                continue;
+            }
+            let query = QueryKey {
+                protocol: key.protocol,
+                client: dst,
+                resolver: src,
+                id: message.id,
+            };
+            let Some(pending) = self.pending.remove(&query) else {
+                continue;
+            };
+            let latency = ts.saturating_sub(pending.sent_at);
+            message.latency = Some(latency);
+            let stats = self.stats(src);
+            stats.responses += 1;
+            match message.rcode {
+                Rcode::NXDOMAIN => stats.nxdomain += 1,
+                Rcode::SERVFAIL => stats.servfail += 1,
+                _ => {}
+            }
+            stats.latencies.push(latency);
+        }
+        timeouts
+    }
+
+    /// Reports the queries still unanswered [`QUERY_TIMEOUT`] after they were sent,
+    /// as of the last packet seen. Call once the capture has ended; queries sent
+    /// shortly before the end are not counted either way.
+    pub fn finish(&mut self) -> Vec<Timeout> {
+        self.expire(self.last_seen)
+    }
+
+    /// Expires queries at most once per second of capture time.
+    fn purge(&mut self, now: Duration) -> Vec<Timeout> {
+        if now.saturating_sub(self.last_purge) < Duration::from_secs(1) {
+            return Vec::new();
+        }
+        self.last_purge = now;
+        self.expire(now)
+    }
+
+    fn expire(&mut self, now: Duration) -> Vec<Timeout> {
+        let expired: Vec<QueryKey> = self
+            .pending
+            .iter()
+            .filter(|(_, p)| now.saturating_sub(p.sent_at) >= QUERY_TIMEOUT)
+            .map(|(k, _)| *k)
+            .collect();
+        let mut timeouts: Vec<Timeout> = expired
+            .into_iter()
+            .map(|key| {
+                let pending = self.pending.remove(&key).expect("collected above");
+                self.stats(key.resolver).timeouts += 1;
+                Timeout {
+                    timestamp: pending.sent_at + QUERY_TIMEOUT,
+                    interface: pending.interface,
+                    client: key.client,
+                    resolver: key.resolver,
+                    id: key.id,
+                    question: pending.question,
+                }
+            })
+            .collect();
+        timeouts.sort_by_key(|t| (t.timestamp, t.id));
+        timeouts
+    }
+
+    fn stats(&mut self, resolver: Endpoint) -> &mut ResolverStats {
+        self.resolvers
+            .entry(resolver)
+            .or_insert_with(|| ResolverStats::new(resolver))
+    }
+
+    /// Aligned table, one row per resolver. Error rates are relative to responses,
+    /// the timeout rate to queries.
+    pub fn render_table(&self) -> String {
+        let rows: Vec<Vec<String>> = self
+            .resolvers
+            .values()
+            .map(|s| {
+                vec![
+                    s.resolver.to_string(),
+                    s.queries.to_string(),
+                    s.responses.to_string(),
+                    render::with_rate(s.timeouts, s.queries),
+                    render::with_rate(s.nxdomain, s.responses),
+                    render::with_rate(s.servfail, s.responses),
+                    render::millis(s.average()),
+                    render::millis(s.percentile(95)),
+                    render::millis(s.max()),
+                ]
+            })
+            .collect();
+        let header = [
+            "RESOLVER",
+            "QUERIES",
+            "RESPONSES",
+            "TIMEOUTS",
+            "NXDOMAIN",
+            "SERVFAIL",
+            "AVG",
+            "P95",
+            "MAX",
+        ];
+        format!("{} resolver(s)\n", rows.len()) + &render::table(&header, &rows)
+    }
+
+    /// One JSON object per resolver.
+    pub fn render_json(&self) -> String {
+        self.resolvers
+            .values()
+            .map(|s| serde_json::to_string(s).expect("ResolverStats is always serialisable") + "\n")
+            .collect()
+    }
+
+    /// Table or JSON Lines depending on `format`.
+    pub fn render(&self, format: render::OutputFormat) -> String {
+        match format {
+            render::OutputFormat::Text => self.render_table(),
+            render::OutputFormat::Json => self.render_json(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::{Message, RecordType};
+    use crate::model::{Direction, Transport};
+    use crate::render::Report;
+    use std::net::{IpAddr, Ipv4Addr};
+
+    const CLIENT: ([u8; 4], u16) = ([10, 0, 0, 5], 40000);
+    const RESOLVER: ([u8; 4], u16) = ([10, 0, 0, 53], 53);
+
+    fn dns(response: bool, id: u16, rcode: Rcode, ms: u64) -> NetEvent {
+        let (src, dst) = if response {
+            (RESOLVER, CLIENT)
+        } else {
+            (CLIENT, RESOLVER)
+        };
+        let mut ev = NetEvent::new(
+            "eth0",
+            Direction::Outbound,
+            IpAddr::V4(Ipv4Addr::from(src.0)),
+            IpAddr::V4(Ipv4Addr::from(dst.0)),
+            Transport::Udp {
+                src_port: src.1,
+                dst_port: dst.1,
+                length: 40,
+            },
+        )
+        .with_timestamp(Duration::from_millis(ms));
+        ev.dns.push(Message {
+            id,
+            response,
+            opcode: 0,
+            rcode,
+            authoritative: false,
+            truncated: false,
+            recursion_desired: true,
+            recursion_available: response,
+            questions: vec![Question {
+                name: "example.com".to_string(),
+                qtype: RecordType::A,
+                class: 1,
+            }],
+            answers: Vec::new(),
+            authority_count: 0,
+            additional_count: 0,
+            latency: None,
+        });
+        ev
+    }
+
+    fn resolver() -> Endpoint {
+        Endpoint {
+            addr: IpAddr::V4(Ipv4Addr::from(RESOLVER.0)),
+            port: Some(RESOLVER.1),
+        }
+    }
+
+    #[test]
+    fn matches_responses_and_counts_errors() {
+        let mut t = DnsTracker::new();
+        assert!(t
+            .process(&mut dns(false, 1, Rcode::NOERROR, 1000))
+            .is_empty());
+        // A retry keeps the time of the first attempt
+        t.process(&mut dns(false, 1, Rcode::NOERROR, 1500));
+        let mut answer = dns(true, 1, Rcode::NOERROR, 1520);
+        t.process(&mut answer);
+        assert_eq!(answer.dns[0].latency, Some(Duration::from_millis(520)));
+        assert!(answer.dns[0].to_string().contains("(0x0001) in 520.000ms"));
+
+        t.process(&mut dns(false, 2, Rcode::NOERROR, 1600));
+        t.process(&mut dns(true, 2, Rcode::NXDOMAIN, 1610));
+        // A response to a query sent before the capture started has no latency and is
+        // not counted
+        let mut orphan = dns(true, 3, Rcode::SERVFAIL, 1700);
+        t.process(&mut orphan);
+        assert_eq!(orphan.dns[0].latency, None);
+        // Same id from a different client port is a different query
+        let mut other = dns(true, 1, Rcode::NOERROR, 1800);
+        other.transport = Transport::Udp {
+            src_port: 53,
+            dst_port: 40001,
+            length: 40,
+        };
+        t.process(&mut other);
+        assert_eq!(other.dns[0].latency, None);
+
+        let s = t.get(&resolver()).unwrap();
+        assert_eq!((s.queries, s.responses, s.timeouts), (2, 2, 0));
+        assert_eq!((s.nxdomain, s.servfail), (1, 0));
+        assert_eq!(s.average(), Some(Duration::from_millis(265)));
+        assert_eq!(s.percentile(95), Some(Duration::from_millis(520)));
+        assert_eq!(s.percentile(50), Some(Duration::from_millis(10)));
+        assert_eq!(
+            t.render_table(),
+            "1 resolver(s)\n\
+             RESOLVER      QUERIES  RESPONSES  TIMEOUTS  NXDOMAIN   SERVFAIL  AVG        P95        MAX\n\
+             10.0.0.53:53  2        2          0 (0.0%)  1 (50.0%)  0 (0.0%)  265.000ms  520.000ms  520.000ms\n"
+        );
+    }
+
+    #[test]
+    fn counts_a_duplicated_response_once() {
+        // The same response captured twice, e.g. on a bridge and its port
+        let mut t = DnsTracker::new();
+        t.process(&mut dns(false, 4, Rcode::NOERROR, 1000));
+        let mut first = dns(true, 4, Rcode::SERVFAIL, 1030);
+        t.process(&mut first);
+        let mut copy = dns(true, 4, Rcode::SERVFAIL, 1030);
+        t.process(&mut copy);
+        assert_eq!(first.dns[0].latency, Some(Duration::from_millis(30)));
+        assert_eq!(copy.dns[0].latency, None);
+        let s = t.get(&resolver()).unwrap();
+        assert_eq!((s.queries, s.responses, s.servfail), (1, 1, 1));
+        assert_eq!(s.latencies, [Duration::from_millis(30)]);
+    }
+
+    #[test]
+    fn reports_unanswered_queries() {
+        let mut t = DnsTracker::new();
+        assert!(t
+            .process(&mut dns(false, 7, Rcode::NOERROR, 1000))
+            .is_empty());
+        assert!(t
+            .process(&mut dns(false, 8, Rcode::NOERROR, 5500))
+            .is_empty());
+        let timeouts = t.process(&mut dns(false, 9, Rcode::NOERROR, 6600));
+        assert_eq!(timeouts.len(), 1);
+        assert_eq!(
+            timeouts[0].render_line(),
+            "[eth0]: DNS timeout A example.com (0x0007) 10.0.0.5:40000 ====> 10.0.0.53:53; \
+             no response within 5.000s"
+        );
+        let json = serde_json::to_string(&timeouts[0]).unwrap();
+        assert!(
+            json.starts_with(r#"{"event":"dns_timeout","timestamp":"1970-01-01T00:00:06.000000Z""#)
+        );
+        // Queries sent shortly before the end of the capture are not counted
+        t.process(&mut dns(true, 11, Rcode::NOERROR, 7000));
+        assert!(t.finish().is_empty());
+        let s = t.get(&resolver()).unwrap();
+        assert_eq!((s.queries, s.timeouts), (3, 1));
+
+        // Older ones are, even when the last purge was less than a second earlier
+        let mut t = DnsTracker::new();
+        t.process(&mut dns(false, 7, Rcode::NOERROR, 1000));
+        assert!(t
+            .process(&mut dns(true, 11, Rcode::NOERROR, 5800))
+            .is_empty());
+        t.process(&mut dns(true, 12, Rcode::NOERROR, 6200));
+        assert_eq!(t.finish().iter().map(|t| t.id).collect::<Vec<_>>(), [7]);
+    }
+}
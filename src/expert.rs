@@ -0,0 +1,390 @@
+//! Wireshark-style expert analysis of TCP flows.
+//!
+//! [`TcpAnalyzer`] keeps a little sequence and acknowledgement state for each
+//! direction of every TCP flow and annotates events with [`Expert`] findings:
+//! retransmissions, out-of-order segments, duplicate ACKs, zero windows and segments
+//! that fill the receiver's window. It only sees what the capture point sees, so a
+//! segment lost upstream of the capture shows up as a gap and its retransmission as
+//! out-of-order or retransmitted data depending on how long the gap was open.
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use crate::flows::FlowKey;
+use crate::model::{Expert, NetEvent, TcpFlags, Transport};
+
+/// A gap filled within this long is reordering rather than a retransmission
+/// (Wireshark's default out-of-order threshold).
+pub const OUT_OF_ORDER_THRESHOLD: Duration = Duration::from_millis(3);
+/// Flows idle for this long (in capture time) are forgotten.
+pub const IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+/// Gaps remembered per direction; older ones are dropped first.
+const MAX_HOLES: usize = 16;
+
+/// `a < b` in 32-bit sequence space.
+fn seq_lt(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) < 0
+}
+
+/// A run of sequence space that was skipped, and when that was noticed.
+#[derive(Debug, Clone, Copy)]
+struct Hole {
+    start: u32,
+    end: u32,
+    opened: Duration,
+}
+
+/// What one side of a flow has sent so far.
+#[derive(Debug, Default)]
+struct Side {
+    /// Sequence number just past the highest byte sent.
+    next_seq: Option<u32>,
+    holes: Vec<Hole>,
+    last_ack: Option<u32>,
+    /// Last advertised receive window, already scaled.
+    window: u64,
+    last_raw_window: u16,
+    /// The last ACK came on a SYN-ACK, which a following pure ACK does not duplicate.
+    last_ack_on_syn: bool,
+    dup_acks: u32,
+    /// Window scale offered on this side's SYN.
+    wscale: Option<u8>,
+    syn_seen: bool,
+}
+
+#[derive(Debug, Default)]
+struct FlowState {
+    /// Indexed by whether the segment came from the lower endpoint of the key.
+    sides: [Side; 2],
+    last_seen: Duration,
+}
+
+impl FlowState {
+    /// Window scaling only applies when both SYNs offered it; without seeing the
+    /// handshake the real window is unknown.
+    fn shift(&self, side: usize) -> Option<u8> {
+        let [a, b] = &self.sides;
+        if !(a.syn_seen && b.syn_seen) {
+            return None;
+        }
+        match (a.wscale, b.wscale) {
+            (Some(_), Some(_)) => self.sides[side].wscale.map(|s| s.min(14)),
+            _ => Some(0),
+        }
+    }
+}
+
+/// Annotates TCP events with expert findings.
+#[derive(Debug, Default)]
+pub struct TcpAnalyzer {
+    flows: HashMap<FlowKey, FlowState>,
+    last_purge: Duration,
+}
+
+impl TcpAnalyzer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Analyses `ev` in the context of its flow and appends findings to `ev.expert`.
+    /// Non-TCP events are left alone.
+    pub fn process(&mut self, ev: &mut NetEvent) {
+        let Transport::Tcp {
+            flags,
+            seq,
+            ack,
+            window,
+            payload_len,
+            options,
+            ..
+        } = &ev.transport
+        else {
+            return;
+        };
+        let (flags, seq, ack, window) = (*flags, *seq, *ack, *window);
+        let now = ev.timestamp;
+        self.purge(now);
+
+        let (key, src, _) = FlowKey::from_event(ev);
+        let me = usize::from(src != key.a);
+        let flow = self.flows.entry(key).or_default();
+        flow.last_seen = now;
+        let peer = 1 - me;
+        if flags.contains(TcpFlags::RST) {
+            return;
+        }
+        if flags.contains(TcpFlags::SYN) {
+            let side = &mut flow.sides[me];
+            side.syn_seen = true;
+            side.wscale = options.window_scale;
+        }
+
+        let mut found = Vec::new();
+        if window == 0 && !flags.contains(TcpFlags::SYN) {
+            found.push(Expert::ZeroWindow);
+        }
+
+        // Sequence space: SYN and FIN each consume one number
+        let seg_len = *payload_len as u32
+            + u32::from(flags.contains(TcpFlags::SYN))
+            + u32::from(flags.contains(TcpFlags::FIN));
+        let end = seq.wrapping_add(seg_len);
+        let side = &mut flow.sides[me];
+        match side.next_seq {
+            _ if seg_len == 0 => {}
+            None => side.next_seq = Some(end),
+            Some(next) if seq_lt(seq, next) => {
+                let keep_alive = *payload_len <= 1
+                    && seq == next.wrapping_sub(1)
+                    && !flags.contains(TcpFlags::SYN)
+                    && !flags.contains(TcpFlags::FIN);
+                if !keep_alive {
+                    let hole = side
+                        .holes
+                        .iter()
+                        .position(|h| !seq_lt(seq, h.start) && seq_lt(seq, h.end));
+                    match hole {
+                        Some(i)
+                            if now.saturating_sub(side.holes[i].opened)
+                                <= OUT_OF_ORDER_THRESHOLD =>
+                        {
+                            found.push(Expert::OutOfOrder)
+                        }
+                        _ => found.push(Expert::Retransmission),
+                    }
+                    if let Some(i) = hole {
+                        let h = side.holes[i];
+                        side.holes.remove(i);
+                        if seq_lt(h.start, seq) {
+                            side.holes.push(Hole { end: seq, ..h });
+                        }
+                        if seq_lt(end, h.end) {
+                            side.holes.push(Hole { start: end, ..h });
+                        }
+                    }
+                }
+                if seq_lt(next, end) {
+                    side.next_seq = Some(end);
+                }
+            }
+            Some(next) => {
+                if seq_lt(next, seq) {
+                    if side.holes.len() == MAX_HOLES {
+                        side.holes.remove(0);
+                    }
+                    side.holes.push(Hole {
+                        start: next,
+                        end: seq,
+                        opened: now,
+                    });
+                }
+                side.next_seq = Some(end);
+            }
+        }
+
+        // Window full: data reaching the right edge of the peer's advertised window
+        let receiver = &flow.sides[peer];
+        if *payload_len > 0 && flow.shift(peer).is_some() && receiver.window > 0 {
+            if let Some(acked) = receiver.last_ack {
+                if u64::from(end.wrapping_sub(acked)) >= receiver.window
+                    && !found.contains(&Expert::Retransmission)
+                {
+                    found.push(Expert::WindowFull);
+                }
+            }
+        }
+
+        if flags.contains(TcpFlags::ACK) {
+            let shift = if flags.contains(TcpFlags::SYN) {
+                Some(0)
+            } else {
+                flow.shift(me)
+            };
+            let side = &mut flow.sides[me];
+            let pure_ack = *payload_len == 0
+                && !flags.contains(TcpFlags::SYN)
+                && !flags.contains(TcpFlags::FIN);
+            if pure_ack
+                && !side.last_ack_on_syn
+                && side.last_ack == Some(ack)
+                && side.last_raw_window == window
+                && window != 0
+            {
+                side.dup_acks += 1;
+                found.push(Expert::DuplicateAck(side.dup_acks));
+            } else if side.last_ack != Some(ack) {
+                side.dup_acks = 0;
+            }
+            side.last_ack = Some(ack);
+            side.last_ack_on_syn = flags.contains(TcpFlags::SYN);
+            side.last_raw_window = window;
+            side.window = shift.map_or(0, |s| u64::from(window) << s);
+        }
+        ev.expert.extend(found);
+    }
+
+    /// Forgets flows idle for longer than [`IDLE_TIMEOUT`]. Runs at most once per
+    /// second of capture time.
+    fn purge(&mut self, now: Duration) {
+        if now.saturating_sub(self.last_purge) < Duration::from_secs(1) {
+            return;
+        }
+        self.last_purge = now;
+        self.flows
+            .retain(|_, f| now.saturating_sub(f.last_seen) < IDLE_TIMEOUT);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::{Direction, TcpOptions};
+    use std::net::{IpAddr, Ipv4Addr};
+
+    struct Seg {
+        from_client: bool,
+        flags: TcpFlags,
+        seq: u32,
+        ack: u32,
+        window: u16,
+        len: usize,
+        wscale: Option<u8>,
+        interface: &'static str,
+    }
+
+    fn seg(from_client: bool, flags: TcpFlags, seq: u32, ack: u32, len: usize) -> Seg {
+        Seg {
+            from_client,
+            flags,
+            seq,
+            ack,
+            window: 1000,
+            len,
+            wscale: None,
+            interface: "eth0",
+        }
+    }
+
+    fn run(a: &mut TcpAnalyzer, ms: u64, s: Seg) -> Vec<Expert> {
+        let (src, dst, sport, dport) = if s.from_client {
+            ([10, 0, 0, 2], [10, 0, 0, 1], 50000, 80)
+        } else {
+            ([10, 0, 0, 1], [10, 0, 0, 2], 80, 50000)
+        };
+        let mut ev = NetEvent::new(
+            s.interface,
+            Direction::Outbound,
+            IpAddr::V4(Ipv4Addr::from(src)),
+            IpAddr::V4(Ipv4Addr::from(dst)),
+            Transport::Tcp {
+                src_port: sport,
+                dst_port: dport,
+                flags: s.flags,
+                seq: s.seq,
+                ack: s.ack,
+                window: s.window,
+                header_len: 20,
+                payload_len: s.len,
+                options: TcpOptions {
+                    window_scale: s.wscale,
+                    ..TcpOptions::default()
+                },
+            },
+        )
+        .with_timestamp(Duration::from_millis(ms));
+        a.process(&mut ev);
+        ev.expert
+    }
+
+    const ACK: TcpFlags = TcpFlags::ACK;
+
+    fn handshake(a: &mut TcpAnalyzer) {
+        let syn = TcpFlags::SYN;
+        assert!(run(a, 0, seg(true, syn, 100, 0, 0)).is_empty());
+        assert!(run(a, 1, seg(false, syn | ACK, 500, 101, 0)).is_empty());
+        assert!(run(a, 2, seg(true, ACK, 101, 501, 0)).is_empty());
+    }
+
+    #[test]
+    fn flags_retransmission_and_out_of_order() {
+        let mut a = TcpAnalyzer::new();
+        handshake(&mut a);
+        assert!(run(&mut a, 10, seg(true, ACK, 101, 501, 100)).is_empty());
+        assert_eq!(
+            run(&mut a, 300, seg(true, ACK, 101, 501, 100)),
+            vec![Expert::Retransmission]
+        );
+        // 301..401 skipped, then filled within the reordering threshold
+        assert!(run(&mut a, 400, seg(true, ACK, 301, 501, 100)).is_empty());
+        assert_eq!(
+            run(&mut a, 401, seg(true, ACK, 201, 501, 100)),
+            vec![Expert::OutOfOrder]
+        );
+        // A gap filled much later is a retransmission of a segment lost upstream
+        assert!(run(&mut a, 500, seg(true, ACK, 501, 501, 100)).is_empty());
+        assert_eq!(
+            run(&mut a, 700, seg(true, ACK, 401, 501, 100)),
+            vec![Expert::Retransmission]
+        );
+        // A one-byte keep-alive just below the next sequence number is not flagged
+        assert!(run(&mut a, 800, seg(true, ACK, 600, 501, 1)).is_empty());
+    }
+
+    #[test]
+    fn segments_seen_on_two_interfaces_are_not_flagged() {
+        // A bridge and its port both capture every segment of the connection
+        let mut a = TcpAnalyzer::new();
+        let segs = [
+            (0, seg(true, TcpFlags::SYN, 100, 0, 0)),
+            (1, seg(false, TcpFlags::SYN | ACK, 500, 101, 0)),
+            (2, seg(true, ACK, 101, 501, 0)),
+            (10, seg(true, ACK, 101, 501, 100)),
+            (20, seg(false, ACK, 501, 201, 0)),
+        ];
+        for (ms, s) in segs {
+            for interface in ["br0", "eth0"] {
+                let s = Seg { interface, ..s };
+                assert!(run(&mut a, ms, s).is_empty());
+            }
+        }
+    }
+
+    #[test]
+    fn flags_duplicate_acks_and_zero_window() {
+        let mut a = TcpAnalyzer::new();
+        handshake(&mut a);
+        assert!(run(&mut a, 10, seg(false, ACK, 501, 101, 0)).is_empty());
+        assert_eq!(
+            run(&mut a, 11, seg(false, ACK, 501, 101, 0)),
+            vec![Expert::DuplicateAck(1)]
+        );
+        assert_eq!(
+            run(&mut a, 12, seg(false, ACK, 501, 101, 0)),
+            vec![Expert::DuplicateAck(2)]
+        );
+        assert!(run(&mut a, 13, seg(false, ACK, 501, 201, 0)).is_empty());
+        let mut zero = seg(false, ACK, 501, 201, 0);
+        zero.window = 0;
+        assert_eq!(run(&mut a, 14, zero), vec![Expert::ZeroWindow]);
+    }
+
+    #[test]
+    fn flags_window_full_with_scaling() {
+        let mut a = TcpAnalyzer::new();
+        let mut syn = seg(true, TcpFlags::SYN, 100, 0, 0);
+        syn.wscale = Some(2);
+        run(&mut a, 0, syn);
+        let mut syn_ack = seg(false, TcpFlags::SYN | ACK, 500, 101, 0);
+        syn_ack.wscale = Some(1);
+        run(&mut a, 1, syn_ack);
+        // Server advertises 500 << 1 = 1000 bytes
+        let mut ack = seg(false, ACK, 501, 101, 0);
+        ack.window = 500;
+        run(&mut a, 2, ack);
+        assert!(run(&mut a, 3, seg(true, ACK, 101, 501, 600)).is_empty());
+        assert_eq!(
+            run(&mut a, 4, seg(true, ACK, 701, 501, 400)),
+            vec![Expert::WindowFull]
+        );
+    }
+}
@@ -0,0 +1,1096 @@
+//! Wireshark-like display filters evaluated against decoded [`NetEvent`]s.
+//!
+//! ```text
+//! tcp.dst_port == 443 && direction == inbound
+//! ip.src in 10.0.0.0/8 || arp.operation == 2
+//! udp.is_dns and not interface == "lo"
+//! tcp.port in {80 443 8080}
+//! ```
+//!
+//! A comparison against a field the event does not have is false, so `tcp.port == 80`
+//! never matches UDP traffic. Multi-valued fields such as `ip.addr` and `tcp.port`
+//! match `==` when any value matches and `!=` when none does.
+
+use std::net::IpAddr;
+
+use crate::dns::{self, Question, RData};
+use crate::icmpv6::Icmpv6Kind;
+use crate::model::{Direction, IcmpKind, NetEvent, TcpFlags, Transport};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Bool,
+    Int,
+    Ip,
+    Str,
+    Direction,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Field {
+    Interface,
+    Direction,
+    Vlan,
+    VlanId,
+    VlanPcp,
+    Ip,
+    Ipv6,
+    IpSrc,
+    IpDst,
+    IpAddr,
+    Ipv6Ext,
+    IpFragments,
+    IpTtl,
+    IpDscp,
+    IpEcn,
+    IpId,
+    IpLength,
+    IpDontFragment,
+    Ipv6FlowLabel,
+    ChecksumBad,
+    Tcp,
+    TcpSrcPort,
+    TcpDstPort,
+    TcpPort,
+    TcpLength,
+    TcpSeq,
+    TcpAck,
+    TcpWindow,
+    TcpHeaderLength,
+    TcpFlags,
+    TcpFlag(TcpFlags),
+    TcpMss,
+    TcpWindowScale,
+    TcpSackPermitted,
+    /// Any expert finding (`tcp.analysis.flags`) or one by [`crate::model::Expert::name`].
+    TcpAnalysis(Option<&'static str>),
+    Udp,
+    UdpSrcPort,
+    UdpDstPort,
+    UdpPort,
+    UdpLength,
+    UdpIsDns,
+    Dns,
+    DnsId,
+    DnsResponse,
+    DnsRcode,
+    DnsQueryName,
+    DnsQueryType,
+    DnsA,
+    DnsAaaa,
+    DnsCname,
+    Icmp,
+    IcmpType,
+    IcmpCode,
+    IcmpMtu,
+    IcmpId,
+    IcmpSeq,
+    Icmpv6,
+    Icmpv6Type,
+    Icmpv6NdTarget,
+    Icmpv6RaPrefix,
+    Arp,
+    ArpOperation,
+    ArpSenderIp,
+    ArpTargetIp,
+}
+
+const FIELDS: &[(&str, Field)] = &[
+    ("interface", Field::Interface),
+    ("direction", Field::Direction),
+    ("vlan", Field::Vlan),
+    ("vlan.id", Field::VlanId),
+    ("vlan.pcp", Field::VlanPcp),
+    ("ip", Field::Ip),
+    ("ipv6", Field::Ipv6),
+    ("ip.src", Field::IpSrc),
+    ("ip.dst", Field::IpDst),
+    ("ip.addr", Field::IpAddr),
+    ("ipv6.ext", Field::Ipv6Ext),
+    ("ip.fragments", Field::IpFragments),
+    ("ip.ttl", Field::IpTtl),
+    ("ip.dscp", Field::IpDscp),
+    ("ip.ecn", Field::IpEcn),
+    ("ip.id", Field::IpId),
+    ("ip.len", Field::IpLength),
+    ("ip.flags.df", Field::IpDontFragment),
+    ("ipv6.flow_label", Field::Ipv6FlowLabel),
+    ("checksum.bad", Field::ChecksumBad),
+    ("tcp", Field::Tcp),
+    ("tcp.src_port", Field::TcpSrcPort),
+    ("tcp.dst_port", Field::TcpDstPort),
+    ("tcp.port", Field::TcpPort),
+    ("tcp.length", Field::TcpLength),
+    ("tcp.seq", Field::TcpSeq),
+    ("tcp.ack", Field::TcpAck),
+    ("tcp.window", Field::TcpWindow),
+    ("tcp.header_length", Field::TcpHeaderLength),
+    ("tcp.flags", Field::TcpFlags),
+    ("tcp.flags.fin", Field::TcpFlag(TcpFlags::FIN)),
+    ("tcp.flags.syn", Field::TcpFlag(TcpFlags::SYN)),
+    ("tcp.flags.rst", Field::TcpFlag(TcpFlags::RST)),
+    ("tcp.flags.psh", Field::TcpFlag(TcpFlags::PSH)),
+    ("tcp.flags.ack", Field::TcpFlag(TcpFlags::ACK)),
+    ("tcp.flags.urg", Field::TcpFlag(TcpFlags::URG)),
+    ("tcp.flags.ece", Field::TcpFlag(TcpFlags::ECE)),
+    ("tcp.flags.cwr", Field::TcpFlag(TcpFlags::CWR)),
+    ("tcp.options.mss", Field::TcpMss),
+    ("tcp.options.wscale", Field::TcpWindowScale),
+    ("tcp.options.sack_perm", Field::TcpSackPermitted),
+    ("tcp.analysis.flags", Field::TcpAnalysis(None)),
+    (
+        "tcp.analysis.retransmission",
+        Field::TcpAnalysis(Some("retransmission")),
+    ),
+    (
+        "tcp.analysis.out_of_order",
+        Field::TcpAnalysis(Some("out_of_order")),
+    ),
+    (
+        "tcp.analysis.duplicate_ack",
+        Field::TcpAnalysis(Some("duplicate_ack")),
+    ),
+    (
+        "tcp.analysis.zero_window",
+        Field::TcpAnalysis(Some("zero_window")),
+    ),
+    (
+        "tcp.analysis.window_full",
+        Field::TcpAnalysis(Some("window_full")),
+    ),
+    ("udp", Field::Udp),
+    ("udp.src_port", Field::UdpSrcPort),
+    ("udp.dst_port", Field::UdpDstPort),
+    ("udp.port", Field::UdpPort),
+    ("udp.length", Field::UdpLength),
+    ("udp.is_dns", Field::UdpIsDns),
+    ("dns", Field::Dns),
+    ("dns.id", Field::DnsId),
+    ("dns.flags.response", Field::DnsResponse),
+    ("dns.flags.rcode", Field::DnsRcode),
+    ("dns.qry.name", Field::DnsQueryName),
+    ("dns.qry.type", Field::DnsQueryType),
+    ("dns.a", Field::DnsA),
+    ("dns.aaaa", Field::DnsAaaa),
+    ("dns.cname", Field::DnsCname),
+    ("icmp", Field::Icmp),
+    ("icmp.type", Field::IcmpType),
+    ("icmp.code", Field::IcmpCode),
+    ("icmp.mtu", Field::IcmpMtu),
+    ("icmp.id", Field::IcmpId),
+    ("icmp.seq", Field::IcmpSeq),
+    ("icmpv6", Field::Icmpv6),
+    ("icmpv6.type", Field::Icmpv6Type),
+    ("icmpv6.nd.target", Field::Icmpv6NdTarget),
+    ("icmpv6.ra.prefix", Field::Icmpv6RaPrefix),
+    ("arp", Field::Arp),
+    ("arp.operation", Field::ArpOperation),
+    ("arp.sender_ip", Field::ArpSenderIp),
+    ("arp.target_ip", Field::ArpTargetIp),
+];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Value {
+    Bool(bool),
+    Int(u64),
+    Ip(IpAddr),
+    Str(String),
+}
+
+impl Field {
+    fn lookup(name: &str) -> Option<Self> {
+        FIELDS.iter().find(|(n, _)| *n == name).map(|(_, f)| *f)
+    }
+
+    fn kind(self) -> Kind {
+        match self {
+            Field::Interface | Field::DnsQueryName | Field::DnsCname => Kind::Str,
+            Field::Direction => Kind::Direction,
+            Field::Vlan
+            | Field::Ip
+            | Field::Ipv6
+            | Field::IpDontFragment
+            | Field::ChecksumBad
+            | Field::Tcp
+            | Field::TcpFlag(_)
+            | Field::TcpSackPermitted
+            | Field::TcpAnalysis(_)
+            | Field::Udp
+            | Field::UdpIsDns
+            | Field::Dns
+            | Field::DnsResponse
+            | Field::Icmp
+            | Field::Icmpv6
+            | Field::Arp => Kind::Bool,
+            Field::IpSrc
+            | Field::IpDst
+            | Field::IpAddr
+            | Field::DnsA
+            | Field::DnsAaaa
+            | Field::Icmpv6NdTarget
+            | Field::Icmpv6RaPrefix
+            | Field::ArpSenderIp
+            | Field::ArpTargetIp => Kind::Ip,
+            _ => Kind::Int,
+        }
+    }
+
+    /// Values of this field on `ev`; empty when the event does not carry it.
+    fn values(self, ev: &NetEvent) -> Vec<Value> {
+        let is_arp = matches!(ev.transport, Transport::Arp { .. });
+        let int = |v: u64| vec![Value::Int(v)];
+        match (self, &ev.transport) {
+            (Field::Interface, _) => vec![Value::Str(ev.interface.clone())],
+            (Field::Direction, _) => vec![Value::Str(
+                match ev.direction {
+                    Direction::Inbound => "inbound",
+                    Direction::Outbound => "outbound",
+                }
+                .to_string(),
+            )],
+            (Field::Vlan, _) => vec![Value::Bool(!ev.vlans.is_empty())],
+            (Field::VlanId, _) => ev.vlans.iter().map(|t| Value::Int(t.id as u64)).collect(),
+            (Field::VlanPcp, _) => ev.vlans.iter().map(|t| Value::Int(t.pcp as u64)).collect(),
+            (Field::Ip, _) if !is_arp && ev.source.is_ipv4() => vec![Value::Bool(true)],
+            (Field::Ipv6, _) if ev.source.is_ipv6() => vec![Value::Bool(true)],
+            (Field::IpSrc, _) if !is_arp => vec![Value::Ip(ev.source)],
+            (Field::IpDst, _) if !is_arp => vec![Value::Ip(ev.destination)],
+            (Field::IpAddr, _) if !is_arp => {
+                vec![Value::Ip(ev.source), Value::Ip(ev.destination)]
+            }
+            (Field::IpFragments, _) => ev
+                .reassembly
+                .map(|r| Value::Int(r.fragments as u64))
+                .into_iter()
+                .collect(),
+            (Field::IpTtl, _) => ev
+                .ip
+                .map(|ip| Value::Int(ip.ttl as u64))
+                .into_iter()
+                .collect(),
+            (Field::IpDscp, _) => ev
+                .ip
+                .map(|ip| Value::Int(ip.dscp as u64))
+                .into_iter()
+                .collect(),
+            (Field::IpEcn, _) => ev
+                .ip
+                .map(|ip| Value::Int(ip.ecn as u64))
+                .into_iter()
+                .collect(),
+            (Field::IpId, _) => ev
+                .ip
+                .and_then(|ip| ip.id)
+                .map(|id| Value::Int(id as u64))
+                .into_iter()
+                .collect(),
+            (Field::IpLength, _) => ev
+                .ip
+                .map(|ip| Value::Int(ip.length as u64))
+                .into_iter()
+                .collect(),
+            (Field::IpDontFragment, _) if ev.source.is_ipv4() => ev
+                .ip
+                .map(|ip| Value::Bool(ip.dont_fragment))
+                .into_iter()
+                .collect(),
+            (Field::Ipv6FlowLabel, _) => ev
+                .ip
+                .and_then(|ip| ip.flow_label)
+                .map(|label| Value::Int(label as u64))
+                .into_iter()
+                .collect(),
+            (Field::ChecksumBad, _) => vec![Value::Bool(!ev.bad_checksums.is_empty())],
+            (Field::Ipv6Ext, _) => ev
+                .ipv6_ext
+                .iter()
+                .map(|x| Value::Int(x.next_header() as u64))
+                .collect(),
+            (Field::Tcp, Transport::Tcp { .. }) => vec![Value::Bool(true)],
+            (Field::TcpSrcPort, Transport::Tcp { src_port, .. }) => int(*src_port as u64),
+            (Field::TcpDstPort, Transport::Tcp { dst_port, .. }) => int(*dst_port as u64),
+            (
+                Field::TcpPort,
+                Transport::Tcp {
+                    src_port, dst_port, ..
+                },
+            ) => vec![Value::Int(*src_port as u64), Value::Int(*dst_port as u64)],
+            (Field::TcpLength, Transport::Tcp { payload_len, .. }) => int(*payload_len as u64),
+            (Field::TcpSeq, Transport::Tcp { seq, .. }) => int(*seq as u64),
+            (Field::TcpAck, Transport::Tcp { ack, .. }) => int(*ack as u64),
+            (Field::TcpWindow, Transport::Tcp { window, .. }) => int(*window as u64),
+            (Field::TcpHeaderLength, Transport::Tcp { header_len, .. }) => int(*header_len as u64),
+            (Field::TcpFlags, Transport::Tcp { flags, .. }) => int(flags.0 as u64),
+            (Field::TcpFlag(flag), Transport::Tcp { flags, .. }) => {
+                vec![Value::Bool(flags.contains(flag))]
+            }
+            (Field::TcpMss, Transport::Tcp { options, .. }) => options
+                .mss
+                .map(|v| Value::Int(v as u64))
+                .into_iter()
+                .collect(),
+            (Field::TcpWindowScale, Transport::Tcp { options, .. }) => options
+                .window_scale
+                .map(|v| Value::Int(v as u64))
+                .into_iter()
+                .collect(),
+            (Field::TcpSackPermitted, Transport::Tcp { options, .. }) => {
+                vec![Value::Bool(options.sack_permitted)]
+            }
+            (Field::TcpAnalysis(name), Transport::Tcp { .. }) => vec![Value::Bool(
+                ev.expert.iter().any(|x| name.is_none_or(|n| x.name() == n)),
+            )],
+            (Field::Udp, Transport::Udp { .. }) => vec![Value::Bool(true)],
+            (Field::UdpSrcPort, Transport::Udp { src_port, .. }) => int(*src_port as u64),
+            (Field::UdpDstPort, Transport::Udp { dst_port, .. }) => int(*dst_port as u64),
+            (
+                Field::UdpPort,
+                Transport::Udp {
+                    src_port, dst_port, ..
+                },
+            ) => vec![Value::Int(*src_port as u64), Value::Int(*dst_port as u64)],
+            (Field::UdpLength, Transport::Udp { length, .. }) => int(*length as u64),
+            (
+                Field::UdpIsDns,
+                Transport::Udp {
+                    src_port, dst_port, ..
+                },
+            ) => vec![Value::Bool(dns::uses_port(*src_port, *dst_port))],
+            (Field::Dns, Transport::Udp { .. } | Transport::Tcp { .. }) => {
+                let udp_dns = matches!(ev.transport, Transport::Udp { src_port, dst_port, .. }
+                    if dns::uses_port(src_port, dst_port));
+                vec![Value::Bool(udp_dns || !ev.dns.is_empty())]
+            }
+            (Field::DnsId, _) => ev.dns.iter().map(|m| Value::Int(m.id as u64)).collect(),
+            (Field::DnsResponse, _) => ev.dns.iter().map(|m| Value::Bool(m.response)).collect(),
+            (Field::DnsRcode, _) => ev
+                .dns
+                .iter()
+                .map(|m| Value::Int(m.rcode.0 as u64))
+                .collect(),
+            (Field::DnsQueryName, _) => dns_questions(ev)
+                .map(|q| Value::Str(q.name.clone()))
+                .collect(),
+            (Field::DnsQueryType, _) => dns_questions(ev)
+                .map(|q| Value::Int(q.qtype.0 as u64))
+                .collect(),
+            (Field::DnsA | Field::DnsAaaa | Field::DnsCname, _) => ev
+                .dns
+                .iter()
+                .flat_map(|m| &m.answers)
+                .filter_map(|r| match (self, &r.data) {
+                    (Field::DnsA, RData::A(a)) => Some(Value::Ip(IpAddr::V4(*a))),
+                    (Field::DnsAaaa, RData::Aaaa(a)) => Some(Value::Ip(IpAddr::V6(*a))),
+                    (Field::DnsCname, RData::Cname(name)) => Some(Value::Str(name.clone())),
+                    _ => None,
+                })
+                .collect(),
+            (Field::Icmp, Transport::Icmp(_)) => vec![Value::Bool(true)],
+            (Field::IcmpType, Transport::Icmp(kind)) => int(kind.icmp_type() as u64),
+            (Field::IcmpCode, Transport::Icmp(kind)) => kind
+                .code()
+                .map(|c| Value::Int(c as u64))
+                .into_iter()
+                .collect(),
+            (Field::IcmpMtu, Transport::Icmp(IcmpKind::Unreachable { mtu: Some(mtu), .. })) => {
+                int(*mtu as u64)
+            }
+            (
+                Field::IcmpId,
+                Transport::Icmp(IcmpKind::EchoReply { id, .. } | IcmpKind::EchoRequest { id, .. }),
+            ) => int(*id as u64),
+            (
+                Field::IcmpSeq,
+                Transport::Icmp(
+                    IcmpKind::EchoReply { seq, .. } | IcmpKind::EchoRequest { seq, .. },
+                ),
+            ) => int(*seq as u64),
+            (Field::Icmpv6, Transport::Icmpv6 { .. }) => vec![Value::Bool(true)],
+            (Field::Icmpv6Type, Transport::Icmpv6(kind)) => int(kind.icmpv6_type() as u64),
+            (
+                Field::Icmpv6NdTarget,
+                Transport::Icmpv6(
+                    Icmpv6Kind::NeighborSolicitation { target, .. }
+                    | Icmpv6Kind::NeighborAdvertisement { target, .. }
+                    | Icmpv6Kind::Redirect { target, .. },
+                ),
+            ) => vec![Value::Ip(IpAddr::V6(*target))],
+            (Field::Icmpv6RaPrefix, Transport::Icmpv6(Icmpv6Kind::RouterAdvertisement(ra))) => ra
+                .prefixes
+                .iter()
+                .map(|p| Value::Ip(IpAddr::V6(p.prefix)))
+                .collect(),
+            (Field::Arp, Transport::Arp { .. }) => vec![Value::Bool(true)],
+            (Field::ArpOperation, Transport::Arp { operation, .. }) => int(*operation as u64),
+            (Field::ArpSenderIp, Transport::Arp { sender_ip, .. }) => {
+                vec![Value::Ip(IpAddr::V4(*sender_ip))]
+            }
+            (Field::ArpTargetIp, Transport::Arp { target_ip, .. }) => {
+                vec![Value::Ip(IpAddr::V4(*target_ip))]
+            }
+            _ => Vec::new(),
+        }
+    }
+}
+
+fn dns_questions(ev: &NetEvent) -> impl Iterator<Item = &Question> {
+    ev.dns.iter().flat_map(|m| &m.questions)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Literal {
+    Bool(bool),
+    Int(u64),
+    Net(IpAddr, u8),
+    Str(String),
+}
+
+impl Literal {
+    fn matches(&self, value: &Value) -> bool {
+        match (self, value) {
+            (Literal::Bool(a), Value::Bool(b)) => a == b,
+            (Literal::Int(a), Value::Int(b)) => a == b,
+            (Literal::Str(a), Value::Str(b)) => a == b,
+            (Literal::Net(net, prefix), Value::Ip(ip)) => in_net(*ip, *net, *prefix),
+            _ => false,
+        }
+    }
+}
+
+fn in_net(ip: IpAddr, net: IpAddr, prefix: u8) -> bool {
+    match (ip, net) {
+        (IpAddr::V4(ip), IpAddr::V4(net)) => {
+            let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+            u32::from(ip) & mask == u32::from(net) & mask
+        }
+        (IpAddr::V6(ip), IpAddr::V6(net)) => {
+            let mask = u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0);
+            u128::from(ip) & mask == u128::from(net) & mask
+        }
+        _ => false,
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Expr {
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    Present(Field),
+    Cmp(Field, Op, Literal),
+    In(Field, Vec<Literal>),
+}
+
+impl Expr {
+    fn eval(&self, ev: Option<&NetEvent>) -> bool {
+        let values = |f: &Field| ev.map(|e| f.values(e)).unwrap_or_default();
+        match self {
+            Expr::And(a, b) => a.eval(ev) && b.eval(ev),
+            Expr::Or(a, b) => a.eval(ev) || b.eval(ev),
+            Expr::Not(a) => !a.eval(ev),
+            Expr::Present(f) => {
+                let vals = values(f);
+                match f.kind() {
+                    Kind::Bool => vals.contains(&Value::Bool(true)),
+                    _ => !vals.is_empty(),
+                }
+            }
+            Expr::Cmp(f, op, lit) => {
+                let vals = values(f);
+                match op {
+                    Op::Eq => vals.iter().any(|v| lit.matches(v)),
+                    Op::Ne => !vals.is_empty() && !vals.iter().any(|v| lit.matches(v)),
+                    _ => {
+                        let Literal::Int(k) = lit else {
+                            return false;
+                        };
+                        vals.iter().any(|v| match v {
+                            Value::Int(x) => match op {
+                                Op::Lt => x < k,
+                                Op::Le => x <= k,
+                                Op::Gt => x > k,
+                                _ => x >= k,
+                            },
+                            _ => false,
+                        })
+                    }
+                }
+            }
+            Expr::In(f, set) => values(f).iter().any(|v| set.iter().any(|l| l.matches(v))),
+        }
+    }
+}
+
+/// A parsed display filter.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Filter {
+    expr: Expr,
+}
+
+impl Filter {
+    pub fn parse(text: &str) -> anyhow::Result<Self> {
+        let tokens = tokenize(text)?;
+        if tokens.is_empty() {
+            return Err(anyhow::anyhow!("display filter: empty expression"));
+        }
+        let mut parser = Parser {
+            tokens,
+            pos: 0,
+            end: text.chars().count() + 1,
+        };
+        let expr = parser.parse_or()?;
+        if let Some(tok) = parser.tokens.get(parser.pos) {
+            return Err(parser.error(tok.col, format!("unexpected '{}'", tok.text)));
+        }
+        Ok(Self { expr })
+    }
+
+    /// Filter that hides UDP, as selected by `--noudp`.
+    pub fn no_udp() -> Self {
+        Self {
+            expr: Expr::Not(Box::new(Expr::Present(Field::Udp))),
+        }
+    }
+
+    /// Combines two filters so both must match.
+    pub fn and(self, other: Filter) -> Self {
+        Self {
+            expr: Expr::And(Box::new(self.expr), Box::new(other.expr)),
+        }
+    }
+
+    pub fn matches(&self, ev: &NetEvent) -> bool {
+        self.expr.eval(Some(ev))
+    }
+
+    /// Evaluates the filter for a frame that could not be decoded into an event,
+    /// treating every field as absent. `!udp` keeps such frames, `tcp` drops them.
+    pub fn matches_undecoded(&self) -> bool {
+        self.expr.eval(None)
+    }
+
+    /// [`matches`](Self::matches) or [`matches_undecoded`](Self::matches_undecoded),
+    /// depending on whether the frame was decoded.
+    pub fn accepts(&self, ev: Option<&NetEvent>) -> bool {
+        self.expr.eval(ev)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Token {
+    text: String,
+    col: usize,
+    quoted: bool,
+}
+
+fn tokenize(text: &str) -> anyhow::Result<Vec<Token>> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let col = i + 1;
+        if c.is_whitespace() {
+            i += 1;
+            continue;
+        }
+        if c == '"' {
+            let start = i + 1;
+            let Some(len) = chars[start..].iter().position(|&c| c == '"') else {
+                return Err(anyhow::anyhow!(
+                    "display filter: unterminated string (column {})",
+                    col
+                ));
+            };
+            tokens.push(Token {
+                text: chars[start..start + len].iter().collect(),
+                col,
+                quoted: true,
+            });
+            i = start + len + 1;
+            continue;
+        }
+        let two: String = chars[i..(i + 2).min(chars.len())].iter().collect();
+        let op = if ["==", "!=", "<=", ">=", "&&", "||"].contains(&two.as_str()) {
+            Some(two)
+        } else if "!<>(){}".contains(c) {
+            Some(c.to_string())
+        } else {
+            None
+        };
+        if let Some(op) = op {
+            i += op.len();
+            tokens.push(Token {
+                text: op,
+                col,
+                quoted: false,
+            });
+            continue;
+        }
+        let start = i;
+        while i < chars.len() && (chars[i].is_alphanumeric() || "._:/-".contains(chars[i])) {
+            i += 1;
+        }
+        if i == start {
+            return Err(anyhow::anyhow!(
+                "display filter: unexpected character '{}' (column {})",
+                c,
+                col
+            ));
+        }
+        tokens.push(Token {
+            text: chars[start..i].iter().collect(),
+            col,
+            quoted: false,
+        });
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+    end: usize,
+}
+
+impl Parser {
+    fn error(&self, col: usize, msg: String) -> anyhow::Error {
+        anyhow::anyhow!("display filter: {} (column {})", msg, col)
+    }
+
+    fn peek(&self) -> Option<&str> {
+        self.tokens
+            .get(self.pos)
+            .filter(|t| !t.quoted)
+            .map(|t| t.text.as_str())
+    }
+
+    fn eat(&mut self, words: &[&str]) -> bool {
+        if self.peek().is_some_and(|t| words.contains(&t)) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn next(&mut self, what: &str) -> anyhow::Result<Token> {
+        let tok = self
+            .tokens
+            .get(self.pos)
+            .cloned()
+            .ok_or_else(|| self.error(self.end, format!("expected {}", what)))?;
+        self.pos += 1;
+        Ok(tok)
+    }
+
+    fn parse_or(&mut self) -> anyhow::Result<Expr> {
+        let mut lhs = self.parse_and()?;
+        while self.eat(&["||", "or"]) {
+            lhs = Expr::Or(Box::new(lhs), Box::new(self.parse_and()?));
+        }
+        Ok(lhs)
+    }
+
+    fn parse_and(&mut self) -> anyhow::Result<Expr> {
+        let mut lhs = self.parse_unary()?;
+        while self.eat(&["&&", "and"]) {
+            lhs = Expr::And(Box::new(lhs), Box::new(self.parse_unary()?));
+        }
+        Ok(lhs)
+    }
+
+    fn parse_unary(&mut self) -> anyhow::Result<Expr> {
+        if self.eat(&["!", "not"]) {
+            return Ok(Expr::Not(Box::new(self.parse_unary()?)));
+        }
+        if self.eat(&["("]) {
+            let inner = self.parse_or()?;
+            if !self.eat(&[")"]) {
+                let col = self.tokens.get(self.pos).map_or(self.end, |t| t.col);
+                return Err(self.error(col, "expected ')'".to_string()));
+            }
+            return Ok(inner);
+        }
+        self.parse_test()
+    }
+
+    fn parse_test(&mut self) -> anyhow::Result<Expr> {
+        let tok = self.next("a field name")?;
+        let field = Field::lookup(&tok.text)
+            .filter(|_| !tok.quoted)
+            .ok_or_else(|| self.error(tok.col, format!("unknown field '{}'", tok.text)))?;
+        let op = match self.peek() {
+            Some("==") => Op::Eq,
+            Some("!=") => Op::Ne,
+            Some("<") => Op::Lt,
+            Some("<=") => Op::Le,
+            Some(">") => Op::Gt,
+            Some(">=") => Op::Ge,
+            Some("in") => {
+                self.pos += 1;
+                return self.parse_in(field, &tok.text);
+            }
+            _ => return Ok(Expr::Present(field)),
+        };
+        let op_tok = self.next("an operator")?;
+        if !matches!(op, Op::Eq | Op::Ne) && field.kind() != Kind::Int {
+            return Err(self.error(
+                op_tok.col,
+                format!(
+                    "'{}' needs a numeric field, '{}' is not",
+                    op_tok.text, tok.text
+                ),
+            ));
+        }
+        let lit = self.parse_literal(field, &tok.text)?;
+        Ok(Expr::Cmp(field, op, lit))
+    }
+
+    fn parse_in(&mut self, field: Field, name: &str) -> anyhow::Result<Expr> {
+        if !self.eat(&["{"]) {
+            return Ok(Expr::In(field, vec![self.parse_literal(field, name)?]));
+        }
+        let mut set = Vec::new();
+        while !self.eat(&["}"]) {
+            set.push(self.parse_literal(field, name)?);
+        }
+        Ok(Expr::In(field, set))
+    }
+
+    fn parse_literal(&mut self, field: Field, name: &str) -> anyhow::Result<Literal> {
+        let tok = self.next(&format!("a value for '{}'", name))?;
+        let bad = |what: &str| {
+            self.error(
+                tok.col,
+                format!("expected {} for '{}', found '{}'", what, name, tok.text),
+            )
+        };
+        match field.kind() {
+            Kind::Bool => match tok.text.as_str() {
+                "true" | "1" => Ok(Literal::Bool(true)),
+                "false" | "0" => Ok(Literal::Bool(false)),
+                _ => Err(bad("true or false")),
+            },
+            Kind::Int => {
+                let parsed = match tok.text.strip_prefix("0x") {
+                    Some(hex) => u64::from_str_radix(hex, 16),
+                    None => tok.text.parse::<u64>(),
+                };
+                parsed.map(Literal::Int).map_err(|_| bad("a number"))
+            }
+            Kind::Ip => {
+                let (addr, prefix) = match tok.text.split_once('/') {
+                    Some((a, p)) => (a, Some(p)),
+                    None => (tok.text.as_str(), None),
+                };
+                let addr: IpAddr = addr.parse().map_err(|_| bad("an IP address or CIDR"))?;
+                let max = if addr.is_ipv4() { 32 } else { 128 };
+                let prefix = match prefix {
+                    Some(p) => p
+                        .parse::<u8>()
+                        .ok()
+                        .filter(|p| *p <= max)
+                        .ok_or_else(|| bad("an IP address or CIDR"))?,
+                    None => max,
+                };
+                Ok(Literal::Net(addr, prefix))
+            }
+            Kind::Str => Ok(Literal::Str(tok.text.clone())),
+            Kind::Direction => match tok.text.as_str() {
+                "inbound" | "outbound" => Ok(Literal::Str(tok.text.clone())),
+                _ => Err(bad("inbound or outbound")),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::TcpOptions;
+    use pnet::util::MacAddr;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    fn tcp_event(dir: Direction, src: [u8; 4], dst_port: u16) -> NetEvent {
+        NetEvent::new(
+            "eth0",
+            dir,
+            IpAddr::V4(Ipv4Addr::from(src)),
+            IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)),
+            Transport::Tcp {
+                src_port: 50000,
+                dst_port,
+                flags: TcpFlags::SYN,
+                seq: 1,
+                ack: 0,
+                window: 64240,
+                header_len: 20,
+                payload_len: 20,
+                options: TcpOptions::default(),
+            },
+        )
+    }
+
+    fn dns_event() -> NetEvent {
+        NetEvent::new(
+            "eth0",
+            Direction::Inbound,
+            IpAddr::V4(Ipv4Addr::new(1, 1, 1, 1)),
+            IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)),
+            Transport::Udp {
+                src_port: 53,
+                dst_port: 53000,
+                length: 42,
+            },
+        )
+    }
+
+    fn matches(filter: &str, ev: &NetEvent) -> bool {
+        Filter::parse(filter).unwrap().matches(ev)
+    }
+
+    #[test]
+    fn compares_ports_direction_and_addresses() {
+        let ev = tcp_event(Direction::Inbound, [10, 1, 2, 3], 443);
+        assert!(matches("tcp.dst_port == 443 && direction == inbound", &ev));
+        assert!(!matches(
+            "tcp.dst_port == 443 && direction == outbound",
+            &ev
+        ));
+        assert!(matches("ip.src in 10.0.0.0/8", &ev));
+        assert!(!matches("ip.src in 192.168.0.0/16", &ev));
+        assert!(matches("tcp.port in {80 443}", &ev));
+        assert!(matches("tcp.port != 22 and tcp.length >= 20", &ev));
+        assert!(!matches("tcp.port != 443", &ev));
+        assert!(matches("tcp.flags.syn && !tcp.flags.ack", &ev));
+        assert!(matches("tcp.flags == 2 && tcp.window > 1000", &ev));
+        assert!(!matches("tcp.flags.rst", &ev));
+        // Absent options compare false either way
+        assert!(!matches("tcp.options.mss < 1400", &ev));
+        assert!(!matches("tcp.options.mss >= 1400", &ev));
+        assert!(!matches("tcp.analysis.flags", &ev));
+        let mut retx = ev.clone();
+        retx.expert.push(crate::model::Expert::Retransmission);
+        assert!(matches("tcp.analysis.retransmission", &retx));
+        assert!(matches(
+            "tcp.analysis.flags && !tcp.analysis.zero_window",
+            &retx
+        ));
+        assert!(matches("interface == \"eth0\" || udp", &ev));
+        assert!(matches("not (udp or icmp)", &ev));
+    }
+
+    #[test]
+    fn absent_fields_never_match() {
+        let ev = dns_event();
+        assert!(matches("udp.is_dns", &ev));
+        assert!(matches("dns && udp.src_port == 53", &ev));
+        assert!(!matches("tcp.port == 53", &ev));
+        assert!(!matches("tcp.port != 53", &ev));
+        assert!(!matches("arp.operation == 2", &ev));
+
+        let arp = NetEvent::new(
+            "eth0",
+            Direction::Inbound,
+            IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)),
+            IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)),
+            Transport::Arp {
+                operation: 2,
+                sender_mac: MacAddr(0, 1, 2, 3, 4, 5),
+                sender_ip: Ipv4Addr::new(10, 0, 0, 1),
+                target_mac: MacAddr(0, 0, 0, 0, 0, 0),
+                target_ip: Ipv4Addr::new(10, 0, 0, 2),
+            },
+        );
+        assert!(matches("arp.operation == 2", &arp));
+        assert!(!matches("ip", &arp));
+        assert!(!matches("ip.src == 10.0.0.1", &arp));
+    }
+
+    #[test]
+    fn ip_header_fields() {
+        let mut ev = dns_event();
+        assert!(!matches("ip.ttl > 0 || ip.flags.df", &ev));
+        ev.ip = Some(crate::model::IpInfo {
+            ttl: 64,
+            dscp: 34,
+            ecn: 0,
+            length: 60,
+            id: Some(7),
+            dont_fragment: true,
+            more_fragments: false,
+            flow_label: None,
+        });
+        assert!(matches("ip.dscp == 34 && ip.ttl < 65 && ip.flags.df", &ev));
+        assert!(!matches("ipv6.flow_label == 0", &ev));
+    }
+
+    #[test]
+    fn ipv6_extension_header_fields() {
+        let mut ev = dns_event();
+        assert!(!matches("ipv6.ext == 0", &ev));
+        ev.ipv6_ext.push(crate::model::Ipv6Ext::HopByHop);
+        assert!(matches("ipv6.ext == 0 && ipv6.ext != 44", &ev));
+    }
+
+    #[test]
+    fn neighbor_discovery_fields() {
+        let router: Ipv6Addr = "fe80::1".parse().unwrap();
+        let ns = NetEvent::new(
+            "eth0",
+            Direction::Inbound,
+            IpAddr::V6("fe80::2".parse().unwrap()),
+            IpAddr::V6("ff02::1:ff00:1".parse().unwrap()),
+            Transport::Icmpv6(Icmpv6Kind::NeighborSolicitation {
+                target: router,
+                source_lladdr: None,
+            }),
+        );
+        assert!(matches(
+            "icmpv6.type == 135 && icmpv6.nd.target == fe80::1",
+            &ns
+        ));
+        assert!(!matches("icmpv6.ra.prefix in 2001:db8::/32", &ns));
+
+        let ra = NetEvent::new(
+            "eth0",
+            Direction::Inbound,
+            IpAddr::V6(router),
+            IpAddr::V6("ff02::1".parse().unwrap()),
+            Transport::Icmpv6(Icmpv6Kind::RouterAdvertisement(Box::new(
+                crate::icmpv6::RouterAdvertisement {
+                    hop_limit: 64,
+                    managed: false,
+                    other: false,
+                    router_lifetime: 1800,
+                    reachable_time: 0,
+                    retrans_timer: 0,
+                    source_lladdr: None,
+                    mtu: None,
+                    prefixes: vec![crate::icmpv6::Prefix {
+                        prefix: "2001:db8:1::".parse().unwrap(),
+                        length: 64,
+                        on_link: true,
+                        autonomous: true,
+                        valid_lifetime: 86400,
+                        preferred_lifetime: 14400,
+                    }],
+                    rdnss: Vec::new(),
+                },
+            ))),
+        );
+        assert!(matches("icmpv6.ra.prefix in 2001:db8::/32", &ra));
+        assert!(!matches("icmpv6.nd.target == fe80::1", &ra));
+        assert!(!matches("icmpv6.nd.target", &dns_event()));
+    }
+
+    #[test]
+    fn icmp_error_fields() {
+        let frag_needed = NetEvent::new(
+            "eth0",
+            Direction::Inbound,
+            IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)),
+            IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)),
+            Transport::Icmp(IcmpKind::Unreachable {
+                code: 4,
+                mtu: Some(1400),
+                original: None,
+            }),
+        );
+        assert!(matches("icmp.type == 3 && icmp.code == 4", &frag_needed));
+        assert!(matches("icmp.mtu < 1500", &frag_needed));
+        assert!(!matches("icmp.id == 0", &frag_needed));
+        assert!(!matches("icmp.code == 0", &dns_event()));
+    }
+
+    #[test]
+    fn dns_fields_match_decoded_messages() {
+        let mut answered = dns_event();
+        assert!(!matches("dns.flags.response", &answered));
+        answered.dns.push(crate::dns::Message {
+            id: 0x1a2b,
+            response: true,
+            opcode: 0,
+            rcode: crate::dns::Rcode::NXDOMAIN,
+            authoritative: false,
+            truncated: false,
+            recursion_desired: true,
+            recursion_available: true,
+            questions: vec![Question {
+                name: "example.com".to_string(),
+                qtype: crate::dns::RecordType::AAAA,
+                class: 1,
+            }],
+            answers: vec![crate::dns::Record {
+                name: "example.com".to_string(),
+                rtype: crate::dns::RecordType::A,
+                ttl: 60,
+                data: RData::A(Ipv4Addr::new(192, 0, 2, 1)),
+            }],
+            authority_count: 0,
+            additional_count: 0,
+            latency: None,
+        });
+        assert!(matches(
+            "dns.flags.response && dns.id == 0x1a2b && dns.flags.rcode == 3",
+            &answered
+        ));
+        assert!(matches(
+            "dns.qry.name == \"example.com\" && dns.qry.type == 28",
+            &answered
+        ));
+        assert!(matches("dns.a in 192.0.2.0/24 && !dns.aaaa", &answered));
+    }
+
+    #[test]
+    fn no_udp_and_undecoded_frames() {
+        let f = Filter::no_udp();
+        assert!(!f.matches(&dns_event()));
+        assert!(f.matches(&tcp_event(Direction::Outbound, [10, 0, 0, 2], 80)));
+        assert!(f.matches_undecoded());
+        let f = f.and(Filter::parse("tcp").unwrap());
+        assert!(!f.matches_undecoded());
+    }
+
+    #[test]
+    fn parse_errors_name_the_problem_and_column() {
+        let err = |s: &str| format!("{}", Filter::parse(s).unwrap_err());
+        assert_eq!(
+            err("tcp.dstport == 443"),
+            "display filter: unknown field 'tcp.dstport' (column 1)"
+        );
+        assert_eq!(
+            err("tcp.port == https"),
+            "display filter: expected a number for 'tcp.port', found 'https' (column 13)"
+        );
+        assert_eq!(
+            err("ip.src in 10.0.0.0/33"),
+            "display filter: expected an IP address or CIDR for 'ip.src', found '10.0.0.0/33' (column 11)"
+        );
+        assert_eq!(
+            err("direction < inbound"),
+            "display filter: '<' needs a numeric field, 'direction' is not (column 11)"
+        );
+        assert_eq!(err("(tcp"), "display filter: expected ')' (column 5)");
+        assert_eq!(
+            err("tcp.port =="),
+            "display filter: expected a value for 'tcp.port' (column 12)"
+        );
+        assert_eq!(
+            err("tcp udp"),
+            "display filter: unexpected 'udp' (column 5)"
+        );
+        assert!(err("").contains("empty"));
+    }
+}
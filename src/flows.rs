@@ -0,0 +1,578 @@
+//! Bidirectional flow table built from decoded events.
+//!
+//! Packets are grouped by interface, VLAN tags, protocol and the two endpoints,
+//! normalised so that both directions of a conversation land in the same entry. The
+//! side that sent the first packet seen is treated as the initiator. Flows idle for
+//! longer than [`IDLE_TIMEOUT`] leave the live table; the busiest of them are kept
+//! for the final report and the rest only add to its totals.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::time::Duration;
+
+use serde::Serialize;
+
+use crate::expert::IDLE_TIMEOUT;
+use crate::icmpv6::Icmpv6Kind;
+use crate::model::{Expert, IcmpKind, NetEvent, TcpFlags, Transport};
+use crate::render;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    Tcp,
+    Udp,
+    Icmp,
+    Icmpv6,
+    Arp,
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Protocol::Tcp => "TCP",
+            Protocol::Udp => "UDP",
+            Protocol::Icmp => "ICMP",
+            Protocol::Icmpv6 => "ICMPv6",
+            Protocol::Arp => "ARP",
+        })
+    }
+}
+
+/// One side of a flow. Protocols without ports (ICMP, ARP) leave `port` empty.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Endpoint {
+    pub addr: IpAddr,
+    pub port: Option<u16>,
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.addr, self.port) {
+            (addr, None) => write!(f, "{}", addr),
+            (IpAddr::V4(addr), Some(port)) => write!(f, "{}:{}", addr, port),
+            (IpAddr::V6(addr), Some(port)) => write!(f, "[{}]:{}", addr, port),
+        }
+    }
+}
+
+impl Serialize for Endpoint {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// Direction-independent identity of a flow: `a` is always the lower endpoint.
+///
+/// The interface and VLAN IDs are part of the key, so a packet captured on several
+/// interfaces (a bridge and its uplink, or `-i any`) is counted and analysed once per
+/// interface rather than looking like a retransmission of itself. Every per-flow
+/// table (flows, TCP analysis, connections, stream reassembly) uses this key.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct FlowKey {
+    pub interface: String,
+    pub vlans: Vec<u16>,
+    pub protocol: Protocol,
+    pub a: Endpoint,
+    pub b: Endpoint,
+}
+
+impl FlowKey {
+    /// Builds the key for `ev` together with the event's own (source, destination).
+    pub fn from_event(ev: &NetEvent) -> (Self, Endpoint, Endpoint) {
+        let (protocol, src_port, dst_port) = match &ev.transport {
+            Transport::Tcp {
+                src_port, dst_port, ..
+            } => (Protocol::Tcp, Some(*src_port), Some(*dst_port)),
+            Transport::Udp {
+                src_port, dst_port, ..
+            } => (Protocol::Udp, Some(*src_port), Some(*dst_port)),
+            Transport::Icmp(_) => (Protocol::Icmp, None, None),
+            Transport::Icmpv6(_) => (Protocol::Icmpv6, None, None),
+            Transport::Arp { .. } => (Protocol::Arp, None, None),
+        };
+        let src = Endpoint {
+            addr: ev.source,
+            port: src_port,
+        };
+        let dst = Endpoint {
+            addr: ev.destination,
+            port: dst_port,
+        };
+        let (a, b) = if src <= dst { (src, dst) } else { (dst, src) };
+        let key = Self {
+            interface: ev.interface.clone(),
+            vlans: ev.vlans.iter().map(|t| t.id).collect(),
+            protocol,
+            a,
+            b,
+        };
+        (key, src, dst)
+    }
+}
+
+/// Coarse TCP connection state as far as it can be told from the segments seen.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TcpState {
+    /// Only the initiator has sent anything so far.
+    Opening,
+    /// Both sides have sent segments.
+    Established,
+    /// One side has sent a FIN.
+    Closing,
+    /// Both sides have sent a FIN.
+    Closed,
+    /// Either side sent a RST.
+    Reset,
+}
+
+impl fmt::Display for TcpState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            TcpState::Opening => "opening",
+            TcpState::Established => "established",
+            TcpState::Closing => "closing",
+            TcpState::Closed => "closed",
+            TcpState::Reset => "reset",
+        })
+    }
+}
+
+/// How often each kind of [`Expert`] finding was seen on a flow.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct ExpertCounts {
+    pub retransmissions: u64,
+    pub out_of_order: u64,
+    pub duplicate_acks: u64,
+    pub zero_windows: u64,
+    pub window_full: u64,
+    pub bad_checksums: u64,
+}
+
+impl ExpertCounts {
+    fn add(&mut self, finding: Expert) {
+        *match finding {
+            Expert::Retransmission => &mut self.retransmissions,
+            Expert::OutOfOrder => &mut self.out_of_order,
+            Expert::DuplicateAck(_) => &mut self.duplicate_acks,
+            Expert::ZeroWindow => &mut self.zero_windows,
+            Expert::WindowFull => &mut self.window_full,
+        } += 1;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// Compact summary for the flow table, e.g. `retx 3, dupack 7`.
+impl fmt::Display for ExpertCounts {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let parts: Vec<String> = [
+            ("retx", self.retransmissions),
+            ("ooo", self.out_of_order),
+            ("dupack", self.duplicate_acks),
+            ("zerowin", self.zero_windows),
+            ("winfull", self.window_full),
+            ("badsum", self.bad_checksums),
+        ]
+        .iter()
+        .filter(|(_, n)| *n > 0)
+        .map(|(label, n)| format!("{} {}", label, n))
+        .collect();
+        f.write_str(&parts.join(", "))
+    }
+}
+
+/// Accumulated statistics for one conversation. "Forward" is the direction of the
+/// first packet seen, from `initiator` to `responder`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Flow {
+    /// The capture interface, followed by any VLAN tags.
+    pub interface: String,
+    pub protocol: Protocol,
+    pub initiator: Endpoint,
+    pub responder: Endpoint,
+    #[serde(serialize_with = "crate::model::serialize_timestamp")]
+    pub first_seen: Duration,
+    #[serde(serialize_with = "crate::model::serialize_timestamp")]
+    pub last_seen: Duration,
+    pub packets_fwd: u64,
+    pub bytes_fwd: u64,
+    pub packets_rev: u64,
+    pub bytes_rev: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tcp_state: Option<TcpState>,
+    #[serde(skip_serializing_if = "ExpertCounts::is_empty")]
+    pub expert: ExpertCounts,
+    #[serde(skip)]
+    fin_fwd: bool,
+    #[serde(skip)]
+    fin_rev: bool,
+}
+
+impl Flow {
+    pub fn packets(&self) -> u64 {
+        self.packets_fwd + self.packets_rev
+    }
+
+    pub fn bytes(&self) -> u64 {
+        self.bytes_fwd + self.bytes_rev
+    }
+
+    pub fn duration(&self) -> Duration {
+        self.last_seen.saturating_sub(self.first_seen)
+    }
+
+    fn update(&mut self, ev: &NetEvent, forward: bool, bytes: u32) {
+        self.first_seen = self.first_seen.min(ev.timestamp);
+        self.last_seen = self.last_seen.max(ev.timestamp);
+        if forward {
+            self.packets_fwd += 1;
+            self.bytes_fwd += u64::from(bytes);
+        } else {
+            self.packets_rev += 1;
+            self.bytes_rev += u64::from(bytes);
+        }
+        for finding in &ev.expert {
+            self.expert.add(*finding);
+        }
+        self.expert.bad_checksums += ev.bad_checksums.len() as u64;
+        let (Some(state), Transport::Tcp { flags, .. }) = (self.tcp_state, &ev.transport) else {
+            return;
+        };
+        if flags.contains(TcpFlags::FIN) {
+            if forward {
+                self.fin_fwd = true;
+            } else {
+                self.fin_rev = true;
+            }
+        }
+        self.tcp_state = Some(match state {
+            TcpState::Reset => TcpState::Reset,
+            _ if flags.contains(TcpFlags::RST) => TcpState::Reset,
+            _ if self.fin_fwd && self.fin_rev => TcpState::Closed,
+            _ if self.fin_fwd || self.fin_rev => TcpState::Closing,
+            TcpState::Opening if self.packets_rev > 0 => TcpState::Established,
+            other => other,
+        });
+    }
+}
+
+/// Expired flows kept for the final report; beyond this only their totals are.
+const MAX_EXPIRED: usize = 1000;
+
+/// Flows keyed on the normalised 5-tuple.
+#[derive(Debug, Default)]
+pub struct FlowTable {
+    flows: HashMap<FlowKey, Flow>,
+    /// Flows that went idle, busiest first once trimmed to [`MAX_EXPIRED`].
+    expired: Vec<Flow>,
+    /// Expired flows dropped from `expired`.
+    forgotten: u64,
+    last_purge: Duration,
+}
+
+impl FlowTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds one event; `bytes` is the frame's length on the wire.
+    pub fn record(&mut self, ev: &NetEvent, bytes: u32) {
+        self.purge(ev.timestamp);
+        let (key, src, dst) = FlowKey::from_event(ev);
+        let protocol = key.protocol;
+        let flow = self.flows.entry(key).or_insert_with(|| Flow {
+            interface: render::interface_label(ev),
+            protocol,
+            initiator: src,
+            responder: dst,
+            first_seen: ev.timestamp,
+            last_seen: ev.timestamp,
+            packets_fwd: 0,
+            bytes_fwd: 0,
+            packets_rev: 0,
+            bytes_rev: 0,
+            tcp_state: (protocol == Protocol::Tcp).then_some(TcpState::Opening),
+            expert: ExpertCounts::default(),
+            fin_fwd: false,
+            fin_rev: false,
+        });
+        let forward = match &ev.transport {
+            // A host pinging itself: only the ICMP type tells the two sides apart
+            Transport::Icmp(IcmpKind::EchoReply { .. })
+            | Transport::Icmpv6(Icmpv6Kind::EchoReply { .. })
+                if src == dst =>
+            {
+                false
+            }
+            _ => src == flow.initiator && dst == flow.responder,
+        };
+        flow.update(ev, forward, bytes);
+    }
+
+    /// Moves flows idle for longer than [`IDLE_TIMEOUT`] out of the live table. Runs
+    /// at most once per second of capture time.
+    fn purge(&mut self, now: Duration) {
+        if now.saturating_sub(self.last_purge) < Duration::from_secs(1) {
+            return;
+        }
+        self.last_purge = now;
+        self.expired.extend(
+            self.flows
+                .extract_if(|_, f| now.saturating_sub(f.last_seen) >= IDLE_TIMEOUT)
+                .map(|(_, f)| f),
+        );
+        // Trim in batches so that a steady trickle of expiring flows doesn't sort
+        // the whole list every second
+        if self.expired.len() > 2 * MAX_EXPIRED {
+            self.expired.sort_by(busiest_first);
+            self.forgotten += (self.expired.len() - MAX_EXPIRED) as u64;
+            self.expired.truncate(MAX_EXPIRED);
+        }
+    }
+
+    /// Flows in the live table.
+    pub fn len(&self) -> usize {
+        self.flows.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.flows.is_empty()
+    }
+
+    /// Looks up a flow in the live table.
+    pub fn get(&self, key: &FlowKey) -> Option<&Flow> {
+        self.flows.get(key)
+    }
+
+    /// Live and kept expired flows, busiest (by bytes) first.
+    pub fn sorted(&self) -> Vec<&Flow> {
+        let mut flows: Vec<&Flow> = self.flows.values().chain(&self.expired).collect();
+        flows.sort_by(|x, y| busiest_first(x, y));
+        flows
+    }
+
+    /// Formats the busiest `limit` flows (all of them when `None`) as a table.
+    pub fn render_table(&self, limit: Option<usize>) -> String {
+        let flows = self.sorted();
+        let total = flows.len() as u64 + self.forgotten;
+        let shown = &flows[..limit.map_or(flows.len(), |n| n.min(flows.len()))];
+        let rows: Vec<Vec<String>> = shown
+            .iter()
+            .map(|f| {
+                vec![
+                    f.interface.clone(),
+                    f.protocol.to_string(),
+                    f.initiator.to_string(),
+                    f.responder.to_string(),
+                    format!("{}/{}", f.packets_fwd, f.packets_rev),
+                    format!("{}/{}", f.bytes_fwd, f.bytes_rev),
+                    format!("{:.3}s", f.duration().as_secs_f64()),
+                    f.tcp_state.map(|s| s.to_string()).unwrap_or_default(),
+                    f.expert.to_string(),
+                ]
+            })
+            .collect();
+        let header = [
+            "INTERFACE",
+            "PROTO",
+            "INITIATOR",
+            "RESPONDER",
+            "PACKETS",
+            "BYTES",
+            "DURATION",
+            "STATE",
+            "EXPERT",
+        ];
+        let title = format!(
+            "{} flow(s){}\n",
+            total,
+            if (shown.len() as u64) < total {
+                format!(", top {} by bytes", shown.len())
+            } else {
+                String::new()
+            }
+        );
+        title + &render::table(&header, &rows)
+    }
+
+    /// One JSON object per flow, busiest first.
+    pub fn render_json(&self) -> String {
+        self.sorted()
+            .into_iter()
+            .map(|f| serde_json::to_string(f).expect("Flow is always serialisable") + "\n")
+            .collect()
+    }
+
+    /// Table or JSON Lines depending on `format`.
+    pub fn render(&self, format: render::OutputFormat, limit: Option<usize>) -> String {
+        match format {
+            render::OutputFormat::Text => self.render_table(limit),
+            render::OutputFormat::Json => self.render_json(),
+        }
+    }
+}
+
+fn busiest_first(x: &Flow, y: &Flow) -> std::cmp::Ordering {
+    y.bytes()
+        .cmp(&x.bytes())
+        .then(x.first_seen.cmp(&y.first_seen))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::{Direction, TcpOptions};
+    use std::net::Ipv4Addr;
+
+    fn tcp(src: [u8; 4], sport: u16, dst: [u8; 4], dport: u16, ms: u64) -> NetEvent {
+        segment(src, sport, dst, dport, ms, TcpFlags::ACK)
+    }
+
+    fn segment(
+        src: [u8; 4],
+        sport: u16,
+        dst: [u8; 4],
+        dport: u16,
+        ms: u64,
+        flags: TcpFlags,
+    ) -> NetEvent {
+        NetEvent::new(
+            "eth0",
+            Direction::Outbound,
+            IpAddr::V4(Ipv4Addr::from(src)),
+            IpAddr::V4(Ipv4Addr::from(dst)),
+            Transport::Tcp {
+                src_port: sport,
+                dst_port: dport,
+                flags,
+                seq: 0,
+                ack: 0,
+                window: 1024,
+                header_len: 20,
+                payload_len: 0,
+                options: TcpOptions::default(),
+            },
+        )
+        .with_timestamp(Duration::from_millis(ms))
+    }
+
+    #[test]
+    fn both_directions_share_one_flow() {
+        let mut table = FlowTable::new();
+        table.record(&tcp([10, 0, 0, 9], 40000, [10, 0, 0, 1], 443, 100), 74);
+        assert_eq!(table.sorted()[0].tcp_state, Some(TcpState::Opening));
+        table.record(&tcp([10, 0, 0, 1], 443, [10, 0, 0, 9], 40000, 150), 74);
+        table.record(&tcp([10, 0, 0, 9], 40000, [10, 0, 0, 1], 443, 400), 1500);
+        table.record(&tcp([10, 0, 0, 9], 40001, [10, 0, 0, 1], 443, 500), 60);
+        assert_eq!(table.len(), 2);
+
+        let flow = table.sorted()[0];
+        assert_eq!(flow.initiator.to_string(), "10.0.0.9:40000");
+        assert_eq!(flow.responder.to_string(), "10.0.0.1:443");
+        assert_eq!((flow.packets_fwd, flow.bytes_fwd), (2, 1574));
+        assert_eq!((flow.packets_rev, flow.bytes_rev), (1, 74));
+        assert_eq!(flow.duration(), Duration::from_millis(300));
+        assert_eq!(flow.tcp_state, Some(TcpState::Established));
+        assert!(flow.expert.is_empty());
+
+        let mut retx = tcp([10, 0, 0, 9], 40000, [10, 0, 0, 1], 443, 600);
+        retx.expert = vec![Expert::Retransmission];
+        table.record(&retx, 1500);
+        let flow = table.sorted()[0];
+        assert_eq!(flow.expert.retransmissions, 1);
+        assert_eq!(flow.expert.to_string(), "retx 1");
+    }
+
+    #[test]
+    fn tcp_state_follows_flags() {
+        let (client, server) = ([10, 0, 0, 9], [10, 0, 0, 1]);
+        let state = |table: &FlowTable| table.sorted()[0].tcp_state.unwrap();
+        let mut table = FlowTable::new();
+        table.record(&segment(client, 1, server, 80, 0, TcpFlags::SYN), 60);
+        assert_eq!(state(&table), TcpState::Opening);
+        let syn_ack = TcpFlags::SYN | TcpFlags::ACK;
+        table.record(&segment(server, 80, client, 1, 1, syn_ack), 60);
+        assert_eq!(state(&table), TcpState::Established);
+        let fin = TcpFlags::FIN | TcpFlags::ACK;
+        table.record(&segment(client, 1, server, 80, 2, fin), 60);
+        assert_eq!(state(&table), TcpState::Closing);
+        table.record(&segment(server, 80, client, 1, 3, fin), 60);
+        assert_eq!(state(&table), TcpState::Closed);
+
+        let mut table = FlowTable::new();
+        table.record(&segment(client, 2, server, 81, 0, TcpFlags::SYN), 60);
+        table.record(&segment(server, 81, client, 2, 1, TcpFlags::RST), 60);
+        table.record(&segment(client, 2, server, 81, 2, TcpFlags::ACK), 60);
+        assert_eq!(state(&table), TcpState::Reset);
+    }
+
+    #[test]
+    fn interfaces_keep_separate_flows() {
+        // One segment captured on a bridge and on its uplink
+        let mut on_bridge = tcp([10, 0, 0, 9], 40000, [10, 0, 0, 1], 443, 0);
+        on_bridge.interface = "br0".to_string();
+        let on_uplink = tcp([10, 0, 0, 9], 40000, [10, 0, 0, 1], 443, 0);
+        let mut table = FlowTable::new();
+        table.record(&on_bridge, 60);
+        table.record(&on_uplink, 60);
+        assert_eq!(table.len(), 2);
+        let (key, _, _) = FlowKey::from_event(&on_bridge);
+        let flow = table.get(&key).unwrap();
+        assert_eq!((flow.interface.as_str(), flow.packets()), ("br0", 1));
+    }
+
+    #[test]
+    fn table_lists_busiest_first() {
+        let mut table = FlowTable::new();
+        table.record(&tcp([10, 0, 0, 9], 1, [10, 0, 0, 1], 22, 0), 100);
+        table.record(&tcp([10, 0, 0, 9], 2, [10, 0, 0, 1], 80, 0), 900);
+        let text = table.render_table(Some(1));
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines[0], "2 flow(s), top 1 by bytes");
+        assert!(lines[1].starts_with("INTERFACE  PROTO  INITIATOR"));
+        assert!(lines[2].contains("10.0.0.1:80"));
+        assert_eq!(lines.len(), 3);
+
+        let json: serde_json::Value =
+            serde_json::from_str(table.render_json().lines().next().unwrap()).unwrap();
+        assert_eq!(json["protocol"], "tcp");
+        assert_eq!(json["responder"], "10.0.0.1:80");
+        assert_eq!(json["tcp_state"], "opening");
+    }
+
+    #[test]
+    fn idle_flows_leave_the_live_table() {
+        let idle = IDLE_TIMEOUT.as_millis() as u64;
+        let mut table = FlowTable::new();
+        table.record(&tcp([10, 0, 0, 9], 1, [10, 0, 0, 1], 22, 0), 900);
+        table.record(&tcp([10, 0, 0, 9], 2, [10, 0, 0, 1], 80, idle - 1000), 100);
+        table.record(&tcp([10, 0, 0, 9], 2, [10, 0, 0, 1], 80, idle + 1000), 100);
+        assert_eq!(table.len(), 1);
+        let text = table.render_table(None);
+        assert!(text.starts_with("2 flow(s)\n"));
+        assert!(text.lines().nth(2).unwrap().contains("10.0.0.1:22"));
+
+        // Past MAX_EXPIRED only the busiest expired flows are listed
+        let mut table = FlowTable::new();
+        for port in 0..=2 * MAX_EXPIRED as u16 {
+            table.record(
+                &tcp([10, 0, 0, 9], port, [10, 0, 0, 1], 80, 0),
+                60 + u32::from(port),
+            );
+        }
+        table.record(&tcp([10, 0, 0, 8], 1, [10, 0, 0, 1], 80, idle), 60);
+        assert_eq!(table.len(), 1);
+        assert_eq!(table.sorted().len(), MAX_EXPIRED + 1);
+        assert_eq!(
+            table.sorted()[0].initiator.port,
+            Some(2 * MAX_EXPIRED as u16)
+        );
+        let text = table.render_table(Some(5));
+        assert_eq!(text.lines().next().unwrap(), "2002 flow(s), top 5 by bytes");
+    }
+}
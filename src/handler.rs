@@ -1,25 +1,26 @@
 mod direction;
-mod packets;
+pub(crate) mod packets;
 
 extern crate pnet;
 
-use pnet::datalink::NetworkInterface;
-
 use pnet::packet::arp::ArpPacket;
 use pnet::packet::ethernet::{EtherTypes, EthernetPacket};
 use pnet::packet::ip::{IpNextHeaderProtocol, IpNextHeaderProtocols};
-use pnet::packet::ipv4::Ipv4Packet;
+use pnet::packet::ipv4::{Ipv4Flags, Ipv4Packet};
 use pnet::packet::ipv6::Ipv6Packet;
 use pnet::packet::Packet;
 
+use crate::filter::Filter;
 use crate::handler::packets::{
     build_icmp_event, build_icmpv6_event, build_tcp_event, build_udp_event,
 };
-use crate::model::{Direction as FlowDir, NetEvent, Transport};
-use crate::render;
+use crate::model::{Direction as FlowDir, IpInfo, NetEvent, Transport};
+use crate::render::{self, OutputFormat, Printer, TimeFormat};
+use crate::vlan::VlanTag;
 use std::collections::HashSet;
 use std::net::IpAddr;
 use std::sync::Arc;
+use std::time::Duration;
 
 pub fn handle_transport_protocol(
     interface_name: &str,
@@ -28,13 +29,10 @@ pub fn handle_transport_protocol(
     protocol: IpNextHeaderProtocol,
     packet: &[u8],
     ips: Arc<HashSet<IpAddr>>,
-    noudp: bool,
 ) {
     match protocol {
         IpNextHeaderProtocols::Udp => {
-            if !noudp {
-                packets::handle_udp_packet(interface_name, source, destination, packet, ips)
-            }
+            packets::handle_udp_packet(interface_name, source, destination, packet, ips)
         }
         IpNextHeaderProtocols::Tcp => {
             packets::handle_tcp_packet(interface_name, source, destination, packet, ips)
@@ -64,50 +62,63 @@ pub(crate) fn build_ipv4_event(
     interface_name: &str,
     ethernet: &EthernetPacket,
     ips: &HashSet<IpAddr>,
-    noudp: bool,
 ) -> Option<NetEvent> {
     let header = Ipv4Packet::new(ethernet.payload())?;
+    // Later fragments carry no transport header; see crate::defrag for reassembly
+    if header.get_fragment_offset() != 0 {
+        return None;
+    }
     let src = IpAddr::V4(header.get_source());
     let dst = IpAddr::V4(header.get_destination());
     let proto = header.get_next_level_protocol();
     let payload = header.payload();
-    match proto {
-        IpNextHeaderProtocols::Udp => {
-            if noudp {
-                None
-            } else {
-                build_udp_event(interface_name, src, dst, payload, ips)
-            }
-        }
+    let mut event = match proto {
+        IpNextHeaderProtocols::Udp => build_udp_event(interface_name, src, dst, payload, ips),
         IpNextHeaderProtocols::Tcp => build_tcp_event(interface_name, src, dst, payload, ips),
         IpNextHeaderProtocols::Icmp => build_icmp_event(interface_name, src, dst, payload, ips),
         _ => None,
-    }
+    }?;
+    event.ip = Some(IpInfo {
+        ttl: header.get_ttl(),
+        dscp: header.get_dscp(),
+        ecn: header.get_ecn(),
+        length: header.get_total_length() as u32,
+        id: Some(header.get_identification()),
+        dont_fragment: header.get_flags() & Ipv4Flags::DontFragment != 0,
+        more_fragments: header.get_flags() & Ipv4Flags::MoreFragments != 0,
+        flow_label: None,
+    });
+    Some(event)
 }
 
 pub(crate) fn build_ipv6_event(
     interface_name: &str,
     ethernet: &EthernetPacket,
     ips: &HashSet<IpAddr>,
-    noudp: bool,
 ) -> Option<NetEvent> {
     let header = Ipv6Packet::new(ethernet.payload())?;
     let src = IpAddr::V6(header.get_source());
     let dst = IpAddr::V6(header.get_destination());
-    let next = header.get_next_header();
-    let payload = header.payload();
-    match next {
-        IpNextHeaderProtocols::Udp => {
-            if noudp {
-                None
-            } else {
-                build_udp_event(interface_name, src, dst, payload, ips)
-            }
-        }
+    let (next, payload, ext) =
+        packets::walk_ipv6_extensions(header.get_next_header().0, header.payload())?;
+    let mut event = match IpNextHeaderProtocol(next) {
+        IpNextHeaderProtocols::Udp => build_udp_event(interface_name, src, dst, payload, ips),
         IpNextHeaderProtocols::Tcp => build_tcp_event(interface_name, src, dst, payload, ips),
         IpNextHeaderProtocols::Icmpv6 => build_icmpv6_event(interface_name, src, dst, payload, ips),
         _ => None,
-    }
+    }?;
+    event.ip = Some(IpInfo {
+        ttl: header.get_hop_limit(),
+        dscp: header.get_traffic_class() >> 2,
+        ecn: header.get_traffic_class() & 0x3,
+        length: 40 + header.get_payload_length() as u32,
+        id: None,
+        dont_fragment: false,
+        more_fragments: false,
+        flow_label: Some(header.get_flow_label()),
+    });
+    event.ipv6_ext = ext;
+    Some(event)
 }
 
 pub fn handle_arp_packet(
@@ -152,40 +163,111 @@ pub(crate) fn build_ethernet_event(
     interface_name: &str,
     ethernet: &EthernetPacket,
     ips: &HashSet<IpAddr>,
-    noudp: bool,
 ) -> Option<NetEvent> {
     match ethernet.get_ethertype() {
-        EtherTypes::Ipv4 => build_ipv4_event(interface_name, ethernet, ips, noudp),
-        EtherTypes::Ipv6 => build_ipv6_event(interface_name, ethernet, ips, noudp),
+        EtherTypes::Ipv4 => build_ipv4_event(interface_name, ethernet, ips),
+        EtherTypes::Ipv6 => build_ipv6_event(interface_name, ethernet, ips),
         EtherTypes::Arp => build_arp_event(interface_name, ethernet, ips),
         _ => None,
     }
 }
 
+/// Decodes one Ethernet frame into an event stamped with its capture time.
+pub fn decode_ethernet_frame(
+    interface_name: &str,
+    timestamp: Duration,
+    ethernet: &EthernetPacket,
+    ips: &HashSet<IpAddr>,
+) -> Option<NetEvent> {
+    Some(build_ethernet_event(interface_name, ethernet, ips)?.with_timestamp(timestamp))
+}
+
+/// The generic "Unknown" line (or JSON object) for a frame that could not be decoded;
+/// `vlans` are the tags already stripped from it.
+pub fn describe_undecoded(
+    interface_name: &str,
+    vlans: &[VlanTag],
+    timestamp: Duration,
+    ethernet: &EthernetPacket,
+    printer: &mut Printer,
+) -> String {
+    match printer.format() {
+        OutputFormat::Json => {
+            let mut object = serde_json::json!({
+                "timestamp": render::format_utc(timestamp),
+                "interface": interface_name,
+                "protocol": "unknown",
+                "source": ethernet.get_source(),
+                "destination": ethernet.get_destination(),
+                "ethertype": ethernet.get_ethertype().0,
+                "length": ethernet.packet().len(),
+            });
+            if !vlans.is_empty() {
+                object["vlans"] = serde_json::json!(vlans);
+            }
+            object.to_string()
+        }
+        OutputFormat::Text => printer.text(
+            timestamp,
+            &format!(
+                "[{}]: {} ===== [Unknown] =====> {}; ethertype: {:?} length: {}",
+                std::iter::once(interface_name.to_string())
+                    .chain(vlans.iter().map(|t| t.to_string()))
+                    .collect::<Vec<_>>()
+                    .join(" "),
+                ethernet.get_source(),
+                ethernet.get_destination(),
+                ethernet.get_ethertype(),
+                ethernet.packet().len()
+            ),
+        ),
+    }
+}
+
+/// Decodes one Ethernet frame, applies the display filter and returns the line to
+/// print, falling back to a generic "Unknown" line when the frame cannot be decoded.
+/// Returns `None` when the filter rejects the frame.
+pub fn describe_ethernet_frame(
+    interface_name: &str,
+    timestamp: Duration,
+    ethernet: &EthernetPacket,
+    ips: &HashSet<IpAddr>,
+    filter: Option<&Filter>,
+    printer: &mut Printer,
+) -> Option<String> {
+    let event = decode_ethernet_frame(interface_name, timestamp, ethernet, ips);
+    if !filter.is_none_or(|f| f.accepts(event.as_ref())) {
+        return None;
+    }
+    Some(match event {
+        Some(ev) => printer.event(&ev),
+        None => describe_undecoded(interface_name, &[], timestamp, ethernet, printer),
+    })
+}
+
 pub fn handle_ethernet_frame(
-    interface: &NetworkInterface,
+    interface_name: &str,
     ethernet: &EthernetPacket,
     ips: Arc<HashSet<IpAddr>>,
-    noudp: bool,
+    filter: Option<&Filter>,
 ) {
-    let interface_name = &interface.name[..];
-    if let Some(ev) = build_ethernet_event(interface_name, ethernet, &ips, noudp) {
-        render::print_event(&ev);
-    } else {
-        println!(
-            "[{}]: {} ===== [Unknown] =====> {}; ethertype: {:?} length: {}",
-            interface_name,
-            ethernet.get_source(),
-            ethernet.get_destination(),
-            ethernet.get_ethertype(),
-            ethernet.packet().len()
-        );
+    let mut printer = Printer::new(OutputFormat::Text, TimeFormat::None);
+    if let Some(line) = describe_ethernet_frame(
+        interface_name,
+        Duration::ZERO,
+        ethernet,
+        &ips,
+        filter,
+        &mut printer,
+    ) {
+        println!("{}", line);
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::model::Ipv6Ext;
     use pnet::packet::arp::{ArpHardwareTypes, ArpOperations, MutableArpPacket};
     use pnet::packet::ethernet::MutableEthernetPacket;
     use pnet::packet::ipv4::MutableIpv4Packet;
@@ -228,15 +310,63 @@ mod tests {
             eth.set_payload(&ip_buf);
         }
         let eth = EthernetPacket::new(&eth_buf[..]).unwrap();
-        // With noudp=true, builder should filter out
-        let ev = build_ipv4_event("eth0", &eth, &ips_set(), true);
-        assert!(ev.is_none());
-        // With noudp=false, event exists
-        let ev = build_ipv4_event("eth0", &eth, &ips_set(), false).expect("event");
-        match ev.transport {
-            Transport::Udp { is_dns, .. } => assert!(is_dns),
-            _ => panic!("not udp"),
+        // The builder always decodes; --noudp is applied by the display filter stage
+        let ev = build_ipv4_event("eth0", &eth, &ips_set()).expect("event");
+        assert!(matches!(ev.transport, Transport::Udp { src_port: 53, .. }));
+        let noudp = Filter::no_udp();
+        let mut printer = Printer::new(OutputFormat::Text, TimeFormat::None);
+        let ts = Duration::ZERO;
+        assert!(
+            describe_ethernet_frame("eth0", ts, &eth, &ips_set(), Some(&noudp), &mut printer)
+                .is_none()
+        );
+        assert!(
+            describe_ethernet_frame("eth0", ts, &eth, &ips_set(), None, &mut printer).is_some()
+        );
+    }
+
+    #[test]
+    fn test_build_ipv4_keeps_header_fields() {
+        let mut ip_buf = vec![0u8; 20 + 8];
+        {
+            let mut ip = MutableIpv4Packet::new(&mut ip_buf[..]).unwrap();
+            ip.set_version(4);
+            ip.set_header_length(5);
+            ip.set_dscp(46);
+            ip.set_ecn(2);
+            ip.set_total_length((20 + 8) as u16);
+            ip.set_identification(0x1c46);
+            ip.set_flags(Ipv4Flags::DontFragment);
+            ip.set_ttl(57);
+            ip.set_next_level_protocol(IpNextHeaderProtocols::Udp);
+            ip.set_source(std::net::Ipv4Addr::new(10, 0, 0, 2));
+            ip.set_destination(std::net::Ipv4Addr::new(10, 0, 0, 9));
+            MutableUdpPacket::new(ip.payload_mut())
+                .unwrap()
+                .set_length(8);
+        }
+        let mut eth_buf = vec![0u8; 14 + ip_buf.len()];
+        {
+            let mut eth = MutableEthernetPacket::new(&mut eth_buf[..]).unwrap();
+            eth.set_ethertype(EtherTypes::Ipv4);
+            eth.set_payload(&ip_buf);
         }
+        let eth = EthernetPacket::new(&eth_buf[..]).unwrap();
+        let ev = build_ipv4_event("eth0", &eth, &ips_set()).expect("event");
+        let ip = ev.ip.expect("ip info");
+        assert_eq!((ip.ttl, ip.dscp, ip.ecn, ip.length), (57, 46, 2, 28));
+        assert_eq!(
+            (ip.id, ip.dont_fragment, ip.more_fragments),
+            (Some(0x1c46), true, false)
+        );
+        render::set_color_enabled(false);
+        let mut printer = Printer::new(OutputFormat::Text, TimeFormat::None).with_verbose(true);
+        let line =
+            describe_ethernet_frame("eth0", Duration::ZERO, &eth, &ips_set(), None, &mut printer)
+                .unwrap();
+        assert!(
+            line.ends_with("; ip: ttl=57, dscp=EF, ecn=ECT(0), id=0x1c46, flags=[DF], length=28")
+        );
     }
 
     #[test]
@@ -254,6 +384,7 @@ mod tests {
             let mut tcp = MutableTcpPacket::new(ip6.payload_mut()).unwrap();
             tcp.set_source(50000);
             tcp.set_destination(443);
+            tcp.set_data_offset(5);
         }
         let mut eth_buf = vec![0u8; 14 + ip6_buf.len()];
         {
@@ -264,13 +395,92 @@ mod tests {
             eth.set_payload(&ip6_buf);
         }
         let eth = EthernetPacket::new(&eth_buf[..]).unwrap();
-        let ev = build_ipv6_event("eth0", &eth, &ips_set(), false).expect("event");
+        let ev = build_ipv6_event("eth0", &eth, &ips_set()).expect("event");
         match ev.transport {
             Transport::Tcp { dst_port, .. } => assert_eq!(dst_port, 443),
             _ => panic!("not tcp"),
         }
     }
 
+    fn ipv6_frame(next_header: u8, payload: &[u8]) -> Vec<u8> {
+        let mut ip6_buf = vec![0u8; 40 + payload.len()];
+        {
+            let mut ip6 = MutableIpv6Packet::new(&mut ip6_buf[..]).unwrap();
+            ip6.set_version(6);
+            ip6.set_payload_length(payload.len() as u16);
+            ip6.set_next_header(IpNextHeaderProtocol(next_header));
+            ip6.set_hop_limit(1);
+            ip6.set_source("fe80::1".parse().unwrap());
+            ip6.set_destination("ff02::16".parse().unwrap());
+            ip6.set_payload(payload);
+        }
+        let mut eth_buf = vec![0u8; 14 + ip6_buf.len()];
+        {
+            let mut eth = MutableEthernetPacket::new(&mut eth_buf[..]).unwrap();
+            eth.set_ethertype(EtherTypes::Ipv6);
+            eth.set_payload(&ip6_buf);
+        }
+        eth_buf
+    }
+
+    #[test]
+    fn test_build_ipv6_walks_extension_headers() {
+        // MLDv2 reports travel behind a hop-by-hop header (router alert)
+        let mut mld = vec![58, 0, 5, 2, 0, 0, 1, 0];
+        mld.extend([143, 0, 0, 0, 0, 0, 0, 0]);
+        let frame = ipv6_frame(0, &mld);
+        let ev = build_ethernet_event("eth0", &EthernetPacket::new(&frame).unwrap(), &ips_set())
+            .expect("event");
+        assert_eq!(ev.ipv6_ext, [Ipv6Ext::HopByHop]);
+        let ip = ev.ip.expect("ip info");
+        assert_eq!((ip.ttl, ip.flow_label, ip.length), (1, Some(0), 56));
+        assert!(matches!(ev.transport, Transport::Icmpv6(_)));
+
+        // SRv6 segment routing header, then the first fragment of a UDP datagram
+        let mut srh = vec![44, 2, 4, 1, 0, 0, 0, 0];
+        srh.extend(
+            "2001:db8::2"
+                .parse::<std::net::Ipv6Addr>()
+                .unwrap()
+                .octets(),
+        );
+        let mut payload = srh.clone();
+        payload.extend([17, 0, 0, 0x01, 0, 0, 0x12, 0x34]);
+        payload.extend([0x13, 0x88, 0x13, 0x89, 0, 8, 0, 0]);
+        let frame = ipv6_frame(43, &payload);
+        let ev = build_ethernet_event("eth0", &EthernetPacket::new(&frame).unwrap(), &ips_set())
+            .expect("event");
+        assert!(matches!(
+            ev.transport,
+            Transport::Udp { dst_port: 5001, .. }
+        ));
+        assert_eq!(ev.ipv6_ext[0].next_header(), 43);
+        render::set_color_enabled(false);
+        assert!(render::render_line(&ev).ends_with(
+            "; ext: srh(segments_left=1, segments=[2001:db8::2]) fragment(id=0x1234, offset=0, more)"
+        ));
+
+        // TLVs after the segment list are not segments
+        let mut with_tlv = srh.clone();
+        with_tlv[1] = 4;
+        with_tlv.extend([4, 14]);
+        with_tlv.extend([0; 14]);
+        with_tlv.extend(&payload[srh.len()..]);
+        let frame = ipv6_frame(43, &with_tlv);
+        let ev = build_ethernet_event("eth0", &EthernetPacket::new(&frame).unwrap(), &ips_set())
+            .expect("event");
+        assert!(render::render_line(&ev).contains("srh(segments_left=1, segments=[2001:db8::2])"));
+
+        // A later fragment has no upper-layer header to decode
+        let mut later = srh;
+        later.extend([17, 0, 0x05, 0xa8, 0, 0, 0x12, 0x34, 0, 0]);
+        let frame = ipv6_frame(43, &later);
+        assert!(
+            build_ethernet_event("eth0", &EthernetPacket::new(&frame).unwrap(), &ips_set())
+                .is_none()
+        );
+    }
+
     #[test]
     fn test_build_ethernet_arp_inbound() {
         // Build ARP request targeting our host IP
@@ -296,7 +506,7 @@ mod tests {
             eth.set_payload(&arp_buf);
         }
         let eth = EthernetPacket::new(&eth_buf[..]).unwrap();
-        let ev = build_ethernet_event("eth0", &eth, &ips_set(), false).expect("event");
+        let ev = build_ethernet_event("eth0", &eth, &ips_set()).expect("event");
         match ev.transport {
             Transport::Arp {
                 operation,
@@ -1,16 +1,22 @@
 use std::collections::HashSet;
-use std::net::IpAddr;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 use std::sync::Arc;
 
 use pnet::packet::{
     icmp::{echo_reply, echo_request, IcmpPacket, IcmpTypes},
-    icmpv6::Icmpv6Packet,
+    ipv4::Ipv4Packet,
+    ipv6::Ipv6Packet,
     tcp::TcpPacket,
     udp::UdpPacket,
+    Packet,
 };
 
+use crate::dns;
 use crate::handler::direction;
-use crate::model::{Direction as FlowDir, IcmpKind, NetEvent, Transport};
+use crate::icmpv6;
+use crate::model::{
+    Direction as FlowDir, IcmpKind, Ipv6Ext, NetEvent, Quoted, TcpFlags, TcpOptions, Transport,
+};
 use crate::render;
 
 pub(crate) fn build_udp_event(
@@ -23,12 +29,17 @@ pub(crate) fn build_udp_event(
     let udp = UdpPacket::new(packet)?;
     let udp_source = udp.get_source();
     let udp_destination = udp.get_destination();
+    // The length field is authoritative; anything after it is link-layer padding
+    let udp_payload_len = (udp.get_length() as usize)
+        .saturating_sub(8)
+        .min(udp.payload().len());
     let dir = if direction::is_destination(destination, ips) {
         FlowDir::Inbound
     } else {
         FlowDir::Outbound
     };
-    Some(NetEvent::new(
+    let payload = &udp.payload()[..udp_payload_len];
+    let mut ev = NetEvent::new(
         interface_name,
         dir,
         source,
@@ -37,9 +48,13 @@ pub(crate) fn build_udp_event(
             src_port: udp_source,
             dst_port: udp_destination,
             length: udp.get_length(),
-            is_dns: udp_source == 53 || udp_destination == 53,
         },
-    ))
+    )
+    .with_payload(payload);
+    if dns::uses_port(udp_source, udp_destination) {
+        ev.dns.extend(dns::parse(payload));
+    }
+    Some(ev)
 }
 
 pub fn handle_udp_packet(
@@ -64,22 +79,73 @@ pub(crate) fn build_tcp_event(
     ips: &HashSet<IpAddr>,
 ) -> Option<NetEvent> {
     let tcp = TcpPacket::new(packet)?;
+    // A data offset below 5 would put the payload inside the fixed header
+    if tcp.get_data_offset() < 5 {
+        return None;
+    }
+    let header_len = (tcp.get_data_offset() as usize * 4).min(packet.len());
     let dir = if direction::is_destination(destination, ips) {
         FlowDir::Inbound
     } else {
         FlowDir::Outbound
     };
-    Some(NetEvent::new(
-        interface_name,
-        dir,
-        source,
-        destination,
-        Transport::Tcp {
-            src_port: tcp.get_source(),
-            dst_port: tcp.get_destination(),
-            length: packet.len(),
-        },
-    ))
+    Some(
+        NetEvent::new(
+            interface_name,
+            dir,
+            source,
+            destination,
+            Transport::Tcp {
+                src_port: tcp.get_source(),
+                dst_port: tcp.get_destination(),
+                flags: TcpFlags(tcp.get_flags()),
+                seq: tcp.get_sequence(),
+                ack: tcp.get_acknowledgement(),
+                window: tcp.get_window(),
+                header_len: header_len as u8,
+                payload_len: packet.len() - header_len,
+                options: parse_tcp_options(packet.get(20..header_len).unwrap_or(&[])),
+            },
+        )
+        .with_payload(&packet[header_len..]),
+    )
+}
+
+/// Walks the TCP option list, stopping quietly at the first malformed entry.
+fn parse_tcp_options(mut bytes: &[u8]) -> TcpOptions {
+    let mut options = TcpOptions::default();
+    while let Some(&kind) = bytes.first() {
+        match kind {
+            0 => break,
+            1 => {
+                bytes = &bytes[1..];
+                continue;
+            }
+            _ => {}
+        }
+        let Some(&len) = bytes.get(1) else { break };
+        let len = len as usize;
+        if len < 2 || len > bytes.len() {
+            break;
+        }
+        let value = &bytes[2..len];
+        let be32 = |b: &[u8]| u32::from_be_bytes([b[0], b[1], b[2], b[3]]);
+        match (kind, value.len()) {
+            (2, 2) => options.mss = Some(u16::from_be_bytes([value[0], value[1]])),
+            (3, 1) => options.window_scale = Some(value[0]),
+            (4, 0) => options.sack_permitted = true,
+            (5, n) if n % 8 == 0 => {
+                options.sack = value
+                    .chunks_exact(8)
+                    .map(|b| (be32(&b[..4]), be32(&b[4..])))
+                    .collect()
+            }
+            (8, 8) => options.timestamps = Some((be32(&value[..4]), be32(&value[4..]))),
+            _ => {}
+        }
+        bytes = &bytes[len..];
+    }
+    options
 }
 
 pub fn handle_tcp_packet(
@@ -110,18 +176,52 @@ pub(crate) fn build_icmp_event(
         FlowDir::Outbound
     };
     let kind = match icmp_packet.get_icmp_type() {
-        IcmpTypes::EchoReply => {
-            let p = echo_reply::EchoReplyPacket::new(packet).unwrap();
-            IcmpKind::EchoReply {
+        // An echo message too short for its identifier and sequence number is left as
+        // a bare type
+        IcmpTypes::EchoReply => match echo_reply::EchoReplyPacket::new(packet) {
+            Some(p) => IcmpKind::EchoReply {
                 seq: p.get_sequence_number(),
                 id: p.get_identifier(),
-            }
-        }
-        IcmpTypes::EchoRequest => {
-            let p = echo_request::EchoRequestPacket::new(packet).unwrap();
-            IcmpKind::EchoRequest {
+            },
+            None => IcmpKind::Other(IcmpTypes::EchoReply.0),
+        },
+        IcmpTypes::EchoRequest => match echo_request::EchoRequestPacket::new(packet) {
+            Some(p) => IcmpKind::EchoRequest {
                 seq: p.get_sequence_number(),
                 id: p.get_identifier(),
+            },
+            None => IcmpKind::Other(IcmpTypes::EchoRequest.0),
+        },
+        // Error messages: 4 type-specific bytes, then the start of the original datagram
+        IcmpTypes::DestinationUnreachable
+        | IcmpTypes::TimeExceeded
+        | IcmpTypes::RedirectMessage
+        | IcmpTypes::ParameterProblem
+            if packet.len() >= 8 =>
+        {
+            let code = icmp_packet.get_icmp_code().0;
+            let original = parse_quoted(&packet[8..]);
+            match icmp_packet.get_icmp_type() {
+                IcmpTypes::DestinationUnreachable => {
+                    let mtu = u16::from_be_bytes([packet[6], packet[7]]);
+                    IcmpKind::Unreachable {
+                        code,
+                        // Pre-RFC 1191 routers leave the field zero
+                        mtu: (code == 4 && mtu != 0).then_some(mtu),
+                        original,
+                    }
+                }
+                IcmpTypes::TimeExceeded => IcmpKind::TimeExceeded { code, original },
+                IcmpTypes::RedirectMessage => IcmpKind::Redirect {
+                    code,
+                    gateway: Ipv4Addr::new(packet[4], packet[5], packet[6], packet[7]),
+                    original,
+                },
+                _ => IcmpKind::ParameterProblem {
+                    code,
+                    pointer: packet[4],
+                    original,
+                },
             }
         }
         other => IcmpKind::Other(other.0),
@@ -135,6 +235,113 @@ pub(crate) fn build_icmp_event(
     ))
 }
 
+/// Follows the IPv6 extension header chain starting at `next_header`, returning the
+/// upper-layer protocol, its bytes and the headers that were skipped. A fragment other
+/// than the first does not carry the upper-layer header, so the walk stops there and
+/// reports the fragment header (44) as the protocol. ESP (50) and No Next Header (59)
+/// end the chain too.
+pub(crate) fn walk_ipv6_extensions(
+    mut next_header: u8,
+    mut data: &[u8],
+) -> Option<(u8, &[u8], Vec<Ipv6Ext>)> {
+    let mut headers = Vec::new();
+    loop {
+        let len = match next_header {
+            // Fixed-size fragment header
+            44 => 8,
+            // The AH length is in 4-octet units, minus 2
+            51 => (*data.get(1)? as usize + 2) * 4,
+            0 | 43 | 60 | 135 | 139 | 140 => (*data.get(1)? as usize + 1) * 8,
+            _ => return Some((next_header, data, headers)),
+        };
+        let header = data.get(..len)?;
+        let ext = match next_header {
+            0 => Ipv6Ext::HopByHop,
+            43 => Ipv6Ext::Routing {
+                routing_type: header[2],
+                segments_left: header[3],
+                segments: match header[2] {
+                    // Last Entry indexes the final segment; TLVs may follow the list
+                    4 => header[8..len.min(8 + (header[4] as usize + 1) * 16)]
+                        .chunks_exact(16)
+                        .map(|s| {
+                            let octets: [u8; 16] = s.try_into().expect("16 bytes");
+                            Ipv6Addr::from(octets)
+                        })
+                        .collect(),
+                    _ => Vec::new(),
+                },
+            },
+            44 => {
+                let offset_flags = u16::from_be_bytes([header[2], header[3]]);
+                Ipv6Ext::Fragment {
+                    id: u32::from_be_bytes([header[4], header[5], header[6], header[7]]),
+                    offset: offset_flags & !0x7,
+                    more: offset_flags & 0x1 != 0,
+                }
+            }
+            60 => Ipv6Ext::DestinationOptions,
+            51 => Ipv6Ext::Authentication,
+            other => Ipv6Ext::Other { next_header: other },
+        };
+        let first_fragment = !matches!(ext, Ipv6Ext::Fragment { offset, .. } if offset != 0);
+        headers.push(ext);
+        if !first_fragment {
+            return Some((44, &data[len..], headers));
+        }
+        next_header = header[0];
+        data = &data[len..];
+    }
+}
+
+/// Decodes the IP header (and TCP or UDP ports) quoted in an ICMP error message. The
+/// quote is often cut short, so only the fixed IP header is required.
+pub(crate) fn parse_quoted(data: &[u8]) -> Option<Quoted> {
+    let (source, destination, protocol, header_len, has_ports) = match data.first()? >> 4 {
+        4 => {
+            let ip = Ipv4Packet::new(data)?;
+            let header_len = ip.get_header_length() as usize * 4;
+            (
+                IpAddr::V4(ip.get_source()),
+                IpAddr::V4(ip.get_destination()),
+                ip.get_next_level_protocol().0,
+                header_len,
+                // An IHL below 5 puts the "ports" inside the quoted IP header
+                ip.get_fragment_offset() == 0 && header_len >= 20,
+            )
+        }
+        6 => {
+            let ip = Ipv6Packet::new(data)?;
+            let after_fixed = &data[Ipv6Packet::minimum_packet_size()..];
+            let (protocol, header_len) =
+                match walk_ipv6_extensions(ip.get_next_header().0, after_fixed) {
+                    Some((protocol, upper, _)) => (protocol, data.len() - upper.len()),
+                    // The quote ends inside the extension headers
+                    None => (ip.get_next_header().0, data.len()),
+                };
+            (
+                IpAddr::V6(ip.get_source()),
+                IpAddr::V6(ip.get_destination()),
+                protocol,
+                header_len,
+                protocol != 44,
+            )
+        }
+        _ => return None,
+    };
+    // TCP and UDP both start with the two ports
+    let ports = data
+        .get(header_len..header_len + 4)
+        .filter(|_| has_ports && matches!(protocol, 6 | 17));
+    Some(Quoted {
+        source,
+        destination,
+        protocol,
+        src_port: ports.map(|p| u16::from_be_bytes([p[0], p[1]])),
+        dst_port: ports.map(|p| u16::from_be_bytes([p[2], p[3]])),
+    })
+}
+
 pub fn handle_icmp_packet(
     interface_name: &str,
     source: IpAddr,
@@ -156,7 +363,7 @@ pub(crate) fn build_icmpv6_event(
     packet: &[u8],
     ips: &HashSet<IpAddr>,
 ) -> Option<NetEvent> {
-    let icmpv6_packet = Icmpv6Packet::new(packet)?;
+    let kind = icmpv6::parse(packet)?;
     let dir = if direction::is_destination(destination, ips) {
         FlowDir::Inbound
     } else {
@@ -167,9 +374,7 @@ pub(crate) fn build_icmpv6_event(
         dir,
         source,
         destination,
-        Transport::Icmpv6 {
-            type_u8: icmpv6_packet.get_icmpv6_type().0,
-        },
+        Transport::Icmpv6(kind),
     ))
 }
 
@@ -205,13 +410,17 @@ mod tests {
 
     #[test]
     fn test_build_udp_event_dns_inbound() {
-        let mut buf = vec![0u8; 8];
+        // Response header (one question, no answers) for "example.com" A, plus padding
+        let dns = b"\x12\x34\x81\x83\x00\x01\x00\x00\x00\x00\x00\x00\
+                    \x07example\x03com\x00\x00\x01\x00\x01";
+        let mut buf = vec![0u8; 8 + dns.len() + 4];
         {
             let mut p = MutableUdpPacket::new(&mut buf[..]).unwrap();
             p.set_source(53);
             p.set_destination(53000);
-            p.set_length(8);
+            p.set_length(8 + dns.len() as u16);
         }
+        buf[8..8 + dns.len()].copy_from_slice(dns);
         let ev = build_udp_event(
             "eth0",
             IpAddr::V4(Ipv4Addr::new(1, 1, 1, 1)),
@@ -220,17 +429,21 @@ mod tests {
             &ips_set(),
         )
         .expect("event");
+        assert_eq!(ev.payload, dns);
+        assert_eq!(ev.dns.len(), 1);
+        assert_eq!(
+            ev.dns[0].to_string(),
+            "DNS response NXDOMAIN A example.com (0x1234)"
+        );
         match ev.transport {
             Transport::Udp {
-                is_dns,
                 src_port,
                 dst_port,
                 length,
             } => {
-                assert!(is_dns);
                 assert_eq!(src_port, 53);
                 assert_eq!(dst_port, 53000);
-                assert_eq!(length, 8);
+                assert_eq!(length, 8 + dns.len() as u16);
             }
             _ => panic!("not udp"),
         }
@@ -238,12 +451,18 @@ mod tests {
 
     #[test]
     fn test_build_tcp_event_outbound() {
-        let mut buf = vec![0u8; 20];
+        let mut buf = vec![0u8; 20 + 5];
         {
             let mut p = MutableTcpPacket::new(&mut buf[..]).unwrap();
             p.set_source(55555);
             p.set_destination(80);
+            p.set_data_offset(5);
+            p.set_flags((TcpFlags::PSH | TcpFlags::ACK).0);
+            p.set_sequence(1000);
+            p.set_acknowledgement(2000);
+            p.set_window(512);
         }
+        buf[20..].copy_from_slice(b"hello");
         let ev = build_tcp_event(
             "eth0",
             IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)),
@@ -252,20 +471,136 @@ mod tests {
             &ips_set(),
         )
         .expect("event");
+        assert_eq!(ev.payload, b"hello");
         match ev.transport {
             Transport::Tcp {
                 src_port,
                 dst_port,
-                length,
+                flags,
+                seq,
+                ack,
+                window,
+                header_len,
+                payload_len,
+                options,
             } => {
                 assert_eq!(src_port, 55555);
                 assert_eq!(dst_port, 80);
-                assert_eq!(length, 20);
+                assert_eq!(flags.to_string(), "P.");
+                assert_eq!((seq, ack, window), (1000, 2000, 512));
+                assert_eq!((header_len, payload_len), (20, 5));
+                assert_eq!(options, TcpOptions::default());
             }
             _ => panic!("not tcp"),
         }
     }
 
+    #[test]
+    fn test_build_tcp_event_large_segment() {
+        let mut buf = vec![0u8; 32 + 1400];
+        MutableTcpPacket::new(&mut buf[..])
+            .unwrap()
+            .set_data_offset(8);
+        let ev = build_tcp_event(
+            "eth0",
+            IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)),
+            IpAddr::V4(Ipv4Addr::new(93, 184, 216, 34)),
+            &buf,
+            &ips_set(),
+        )
+        .expect("event");
+        assert!(matches!(
+            ev.transport,
+            Transport::Tcp {
+                header_len: 32,
+                payload_len: 1400,
+                ..
+            }
+        ));
+        assert_eq!(ev.payload.len(), 1400);
+    }
+
+    #[test]
+    fn test_build_tcp_event_header_len_clamp() {
+        // 256 bytes wraps to 0 as a u8; the header must still be 20 bytes
+        let mut buf = vec![0u8; 256];
+        MutableTcpPacket::new(&mut buf[..])
+            .unwrap()
+            .set_data_offset(5);
+        let ev = build_tcp_event(
+            "eth0",
+            IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)),
+            IpAddr::V4(Ipv4Addr::new(93, 184, 216, 34)),
+            &buf,
+            &ips_set(),
+        )
+        .expect("event");
+        assert!(matches!(
+            ev.transport,
+            Transport::Tcp {
+                header_len: 20,
+                payload_len: 236,
+                ..
+            }
+        ));
+
+        // A data offset past the end of the segment is clamped to what was captured
+        let mut buf = vec![0u8; 24];
+        MutableTcpPacket::new(&mut buf[..])
+            .unwrap()
+            .set_data_offset(15);
+        let ev = build_tcp_event(
+            "eth0",
+            IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)),
+            IpAddr::V4(Ipv4Addr::new(93, 184, 216, 34)),
+            &buf,
+            &ips_set(),
+        )
+        .expect("event");
+        assert!(matches!(
+            ev.transport,
+            Transport::Tcp {
+                header_len: 24,
+                payload_len: 0,
+                ..
+            }
+        ));
+
+        // A data offset below the fixed header's five words is malformed
+        MutableTcpPacket::new(&mut buf[..])
+            .unwrap()
+            .set_data_offset(2);
+        assert!(build_tcp_event(
+            "eth0",
+            IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)),
+            IpAddr::V4(Ipv4Addr::new(93, 184, 216, 34)),
+            &buf,
+            &ips_set(),
+        )
+        .is_none());
+    }
+
+    #[test]
+    fn test_parse_tcp_options() {
+        let bytes = [
+            2, 4, 0x05, 0xb4, // mss 1460
+            1,    // nop
+            3, 3, 7, // wscale 7
+            4, 2, // sackOK
+            8, 10, 0, 0, 0, 9, 0, 0, 0, 0, // TS val 9 ecr 0
+            5, 10, 0, 0, 0, 1, 0, 0, 0, 2, // sack {1:2}
+            0, 0xff, // end of list, then garbage
+        ];
+        let options = parse_tcp_options(&bytes);
+        assert_eq!(options.mss, Some(1460));
+        assert_eq!(options.window_scale, Some(7));
+        assert!(options.sack_permitted);
+        assert_eq!(options.timestamps, Some((9, 0)));
+        assert_eq!(options.sack, vec![(1, 2)]);
+        // A length running past the header is ignored rather than read out of bounds
+        assert_eq!(parse_tcp_options(&[2, 40, 1]), TcpOptions::default());
+    }
+
     #[test]
     fn test_build_icmp_event_echo_request_outbound() {
         let mut buf = vec![0u8; 8];
@@ -293,13 +628,96 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_build_icmp_event_truncated_echo() {
+        for (icmp_type, len) in [(IcmpTypes::EchoRequest, 4), (IcmpTypes::EchoReply, 7)] {
+            let mut buf = vec![0u8; len];
+            MutableIcmpPacket::new(&mut buf[..])
+                .unwrap()
+                .set_icmp_type(icmp_type);
+            let ev = build_icmp_event(
+                "eth0",
+                IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)),
+                IpAddr::V4(Ipv4Addr::new(1, 1, 1, 1)),
+                &buf,
+                &ips_set(),
+            )
+            .expect("event");
+            assert_eq!(ev.transport, Transport::Icmp(IcmpKind::Other(icmp_type.0)));
+        }
+    }
+
+    #[test]
+    fn test_build_icmp_event_fragmentation_needed() {
+        // Type 3 code 4, next-hop MTU 1400, quoting an IPv4 + TCP header
+        let mut buf = vec![3, 4, 0, 0, 0, 0, 0x05, 0x78];
+        buf.extend([0x45, 0, 0x05, 0xdc, 0, 0, 0x40, 0, 64, 6, 0, 0]);
+        buf.extend([10, 0, 0, 2, 93, 184, 216, 34]);
+        buf.extend([0x9c, 0x40, 0x01, 0xbb, 0, 0, 0, 1]);
+        let ev = build_icmp_event(
+            "eth0",
+            IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)),
+            IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)),
+            &buf,
+            &ips_set(),
+        )
+        .expect("event");
+        let original = Quoted {
+            source: IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)),
+            destination: IpAddr::V4(Ipv4Addr::new(93, 184, 216, 34)),
+            protocol: 6,
+            src_port: Some(40000),
+            dst_port: Some(443),
+        };
+        assert_eq!(
+            ev.transport,
+            Transport::Icmp(IcmpKind::Unreachable {
+                code: 4,
+                mtu: Some(1400),
+                original: Some(original),
+            })
+        );
+        render::set_color_enabled(false);
+        assert_eq!(
+            render::render_line(&ev),
+            "[eth0]: 10.0.0.2 <==== [ICMP unreachable] ===== 192.0.2.1 \
+             (fragmentation needed, mtu=1400); original: TCP 10.0.0.2:40000 > 93.184.216.34:443"
+        );
+
+        // A quote cut short after the IP header still names the hosts
+        buf[0] = 11;
+        buf[1] = 0;
+        buf.truncate(28);
+        let ev = build_icmp_event(
+            "eth0",
+            IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)),
+            IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)),
+            &buf,
+            &ips_set(),
+        )
+        .expect("event");
+        assert_eq!(
+            render::render_json(&ev),
+            r#"{"timestamp":"1970-01-01T00:00:00.000000Z","interface":"eth0","direction":"inbound","source":"192.0.2.1","destination":"10.0.0.2","protocol":"icmp","kind":"time_exceeded","code":0,"reason":"TTL exceeded in transit","original":{"source":"10.0.0.2","destination":"93.184.216.34","protocol":6}}"#
+        );
+
+        // A quoted header claiming fewer than five words has no ports to offer
+        let mut quote = buf[8..].to_vec();
+        quote[0] = 0x42;
+        quote.extend([0x9c, 0x40, 0x01, 0xbb]);
+        let original = parse_quoted(&quote).expect("quote");
+        assert_eq!(original.protocol, 6);
+        assert_eq!((original.src_port, original.dst_port), (None, None));
+    }
+
     #[test]
     fn test_build_icmpv6_event_inbound() {
-        let mut buf = vec![0u8; 4];
+        let mut buf = vec![0u8; 8];
         {
             let mut p = MutableIcmpv6Packet::new(&mut buf[..]).unwrap();
             p.set_icmpv6_type(Icmpv6Types::EchoReply);
         }
+        buf[4..8].copy_from_slice(&[0, 7, 0, 3]);
         let ev = build_icmpv6_event(
             "eth0",
             IpAddr::V4(Ipv4Addr::new(1, 1, 1, 1)),
@@ -309,7 +727,10 @@ mod tests {
         )
         .expect("event");
         match ev.transport {
-            Transport::Icmpv6 { type_u8 } => assert_eq!(type_u8, Icmpv6Types::EchoReply.0),
+            Transport::Icmpv6(kind) => {
+                assert_eq!(kind.icmpv6_type(), Icmpv6Types::EchoReply.0);
+                assert_eq!(kind, icmpv6::Icmpv6Kind::EchoReply { seq: 3, id: 7 });
+            }
             _ => panic!("not icmpv6"),
         }
     }
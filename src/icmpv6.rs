@@ -0,0 +1,539 @@
+//! ICMPv6 message decoding.
+//!
+//! [`parse`] decodes echo, Neighbor Discovery (RFC 4861: router and neighbor
+//! solicitations and advertisements, redirects, with their link-layer address, prefix,
+//! MTU and RDNSS options) and MLD listener reports. Like the DNS decoder it is lenient:
+//! a truncated option list ends quietly and keeps what was read, and a message too
+//! short for its type is reported by type number only.
+
+use std::fmt;
+use std::net::Ipv6Addr;
+
+use pnet::util::MacAddr;
+use serde::Serialize;
+
+pub const ECHO_REQUEST: u8 = 128;
+pub const ECHO_REPLY: u8 = 129;
+pub const MLD_REPORT: u8 = 131;
+pub const ROUTER_SOLICITATION: u8 = 133;
+pub const ROUTER_ADVERTISEMENT: u8 = 134;
+pub const NEIGHBOR_SOLICITATION: u8 = 135;
+pub const NEIGHBOR_ADVERTISEMENT: u8 = 136;
+pub const REDIRECT: u8 = 137;
+pub const MLD2_REPORT: u8 = 143;
+
+/// A Prefix Information option of a router advertisement. Lifetimes are in seconds,
+/// with `u32::MAX` meaning infinity.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Prefix {
+    pub prefix: Ipv6Addr,
+    pub length: u8,
+    /// L flag: addresses in the prefix are on-link.
+    pub on_link: bool,
+    /// A flag: hosts may form addresses in the prefix with SLAAC.
+    pub autonomous: bool,
+    pub valid_lifetime: u32,
+    pub preferred_lifetime: u32,
+}
+
+/// A Recursive DNS Server option (RFC 8106); a zero lifetime withdraws the servers.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Rdnss {
+    pub lifetime: u32,
+    pub servers: Vec<Ipv6Addr>,
+}
+
+/// Router advertisement body and options. Times are as sent: the router lifetime in
+/// seconds, the reachable time and retransmission timer in milliseconds, zero meaning
+/// unspecified.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct RouterAdvertisement {
+    pub hop_limit: u8,
+    /// M flag: addresses are available from DHCPv6.
+    pub managed: bool,
+    /// O flag: other configuration is available from DHCPv6.
+    pub other: bool,
+    /// Zero means the sender is not a default router.
+    pub router_lifetime: u16,
+    pub reachable_time: u32,
+    pub retrans_timer: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source_lladdr: Option<MacAddr>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mtu: Option<u32>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub prefixes: Vec<Prefix>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub rdnss: Vec<Rdnss>,
+}
+
+/// One multicast address record of an MLD report.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct MldRecord {
+    /// MLDv2 record type (RFC 3810 section 5.2.12); absent in MLDv1 reports, which
+    /// always mean "listening".
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub record_type: Option<u8>,
+    pub group: Ipv6Addr,
+    pub sources: u16,
+}
+
+impl MldRecord {
+    /// Record type as tcpdump names it, e.g. `to_ex` for a join.
+    pub fn mode(&self) -> &'static str {
+        match self.record_type {
+            None => "listen",
+            Some(1) => "is_in",
+            Some(2) => "is_ex",
+            Some(3) => "to_in",
+            Some(4) => "to_ex",
+            Some(5) => "allow",
+            Some(6) => "block",
+            Some(_) => "unknown",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Icmpv6Kind {
+    EchoRequest {
+        seq: u16,
+        id: u16,
+    },
+    EchoReply {
+        seq: u16,
+        id: u16,
+    },
+    RouterSolicitation {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        source_lladdr: Option<MacAddr>,
+    },
+    RouterAdvertisement(Box<RouterAdvertisement>),
+    NeighborSolicitation {
+        target: Ipv6Addr,
+        /// Absent in the solicitations of duplicate address detection.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        source_lladdr: Option<MacAddr>,
+    },
+    NeighborAdvertisement {
+        target: Ipv6Addr,
+        router: bool,
+        solicited: bool,
+        #[serde(rename = "override")]
+        override_flag: bool,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        target_lladdr: Option<MacAddr>,
+    },
+    /// A router telling the sender that `target` is a better first hop for packets to
+    /// `destination`; the two are equal when the destination is on-link.
+    Redirect {
+        target: Ipv6Addr,
+        destination: Ipv6Addr,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        target_lladdr: Option<MacAddr>,
+    },
+    MldReport {
+        version: u8,
+        records: Vec<MldRecord>,
+    },
+    Other {
+        #[serde(rename = "type")]
+        type_u8: u8,
+    },
+}
+
+impl Icmpv6Kind {
+    /// The ICMPv6 type number.
+    pub fn icmpv6_type(&self) -> u8 {
+        match self {
+            Icmpv6Kind::EchoRequest { .. } => ECHO_REQUEST,
+            Icmpv6Kind::EchoReply { .. } => ECHO_REPLY,
+            Icmpv6Kind::RouterSolicitation { .. } => ROUTER_SOLICITATION,
+            Icmpv6Kind::RouterAdvertisement(_) => ROUTER_ADVERTISEMENT,
+            Icmpv6Kind::NeighborSolicitation { .. } => NEIGHBOR_SOLICITATION,
+            Icmpv6Kind::NeighborAdvertisement { .. } => NEIGHBOR_ADVERTISEMENT,
+            Icmpv6Kind::Redirect { .. } => REDIRECT,
+            Icmpv6Kind::MldReport { version: 1, .. } => MLD_REPORT,
+            Icmpv6Kind::MldReport { .. } => MLD2_REPORT,
+            Icmpv6Kind::Other { type_u8 } => *type_u8,
+        }
+    }
+
+    /// Short name shown between the arrows, e.g. `NDP NS`.
+    pub fn label(&self) -> &'static str {
+        match self {
+            Icmpv6Kind::EchoRequest { .. } => "ICMPv6 echo",
+            Icmpv6Kind::EchoReply { .. } => "ICMPv6 echo reply",
+            Icmpv6Kind::RouterSolicitation { .. } => "NDP RS",
+            Icmpv6Kind::RouterAdvertisement(_) => "NDP RA",
+            Icmpv6Kind::NeighborSolicitation { .. } => "NDP NS",
+            Icmpv6Kind::NeighborAdvertisement { .. } => "NDP NA",
+            Icmpv6Kind::Redirect { .. } => "NDP redirect",
+            Icmpv6Kind::MldReport { version: 1, .. } => "MLD report",
+            Icmpv6Kind::MldReport { .. } => "MLDv2 report",
+            Icmpv6Kind::Other { .. } => "ICMPv6",
+        }
+    }
+}
+
+/// The details shown after the addresses, e.g. `target=fe80::1, flags=[R,S,O],
+/// lladdr=02:00:00:00:00:01` for a neighbor advertisement.
+impl fmt::Display for Icmpv6Kind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let lladdr = |addr: &Option<MacAddr>| match addr {
+            Some(mac) => format!(", lladdr={}", mac),
+            None => String::new(),
+        };
+        match self {
+            Icmpv6Kind::EchoRequest { seq, id } | Icmpv6Kind::EchoReply { seq, id } => {
+                write!(f, "seq={}, id={}", seq, id)
+            }
+            Icmpv6Kind::RouterSolicitation { source_lladdr } => match source_lladdr {
+                Some(mac) => write!(f, "lladdr={}", mac),
+                None => f.write_str("no lladdr"),
+            },
+            Icmpv6Kind::RouterAdvertisement(ra) => {
+                let ra_flags = flags(&[(ra.managed, "M"), (ra.other, "O")]);
+                write!(
+                    f,
+                    "hop_limit={}, flags={}, router_lifetime={}s, reachable={}ms, retrans={}ms",
+                    ra.hop_limit, ra_flags, ra.router_lifetime, ra.reachable_time, ra.retrans_timer
+                )?;
+                if let Some(mtu) = ra.mtu {
+                    write!(f, ", mtu={}", mtu)?;
+                }
+                f.write_str(&lladdr(&ra.source_lladdr))?;
+                for p in &ra.prefixes {
+                    write!(
+                        f,
+                        ", prefix {}/{} {} valid={} preferred={}",
+                        p.prefix,
+                        p.length,
+                        flags(&[(p.on_link, "L"), (p.autonomous, "A")]),
+                        lifetime(p.valid_lifetime),
+                        lifetime(p.preferred_lifetime)
+                    )?;
+                }
+                for r in &ra.rdnss {
+                    let servers: Vec<String> = r.servers.iter().map(|s| s.to_string()).collect();
+                    write!(
+                        f,
+                        ", rdnss [{}] lifetime={}",
+                        servers.join(" "),
+                        lifetime(r.lifetime)
+                    )?;
+                }
+                Ok(())
+            }
+            Icmpv6Kind::NeighborSolicitation {
+                target,
+                source_lladdr,
+            } => write!(f, "target={}{}", target, lladdr(source_lladdr)),
+            Icmpv6Kind::NeighborAdvertisement {
+                target,
+                router,
+                solicited,
+                override_flag,
+                target_lladdr,
+            } => write!(
+                f,
+                "target={}, flags={}{}",
+                target,
+                flags(&[(*router, "R"), (*solicited, "S"), (*override_flag, "O")]),
+                lladdr(target_lladdr)
+            ),
+            Icmpv6Kind::Redirect {
+                target,
+                destination,
+                target_lladdr,
+            } => write!(
+                f,
+                "destination={}, target={}{}",
+                destination,
+                target,
+                lladdr(target_lladdr)
+            ),
+            Icmpv6Kind::MldReport { records, .. } => {
+                let records: Vec<String> = records
+                    .iter()
+                    .map(|r| match r.sources {
+                        0 => format!("{} {}", r.mode(), r.group),
+                        n => format!("{} {} ({} sources)", r.mode(), r.group, n),
+                    })
+                    .collect();
+                f.write_str(&records.join(", "))
+            }
+            Icmpv6Kind::Other { type_u8 } => write!(f, "type={}", type_u8),
+        }
+    }
+}
+
+/// `[R,S]`-style list of the flags that are set.
+fn flags(set: &[(bool, &str)]) -> String {
+    let names: Vec<&str> = set
+        .iter()
+        .filter(|(on, _)| *on)
+        .map(|(_, name)| *name)
+        .collect();
+    format!("[{}]", names.join(","))
+}
+
+fn lifetime(secs: u32) -> String {
+    match secs {
+        u32::MAX => "infinity".to_string(),
+        s => format!("{}s", s),
+    }
+}
+
+/// Options of an ND message (RFC 4861 section 4.6) that are decoded.
+#[derive(Default)]
+struct NdOptions {
+    source_lladdr: Option<MacAddr>,
+    target_lladdr: Option<MacAddr>,
+    mtu: Option<u32>,
+    prefixes: Vec<Prefix>,
+    rdnss: Vec<Rdnss>,
+}
+
+fn parse_options(mut data: &[u8]) -> NdOptions {
+    let mut options = NdOptions::default();
+    while data.len() >= 2 {
+        let len = data[1] as usize * 8;
+        // A zero length would loop forever; RFC 4861 says to drop the packet
+        if len == 0 || len > data.len() {
+            break;
+        }
+        let option = &data[..len];
+        let mac = || {
+            (len >= 8).then(|| {
+                MacAddr::new(
+                    option[2], option[3], option[4], option[5], option[6], option[7],
+                )
+            })
+        };
+        match option[0] {
+            1 => options.source_lladdr = mac(),
+            2 => options.target_lladdr = mac(),
+            3 if len >= 32 => options.prefixes.push(Prefix {
+                length: option[2],
+                on_link: option[3] & 0x80 != 0,
+                autonomous: option[3] & 0x40 != 0,
+                valid_lifetime: be32(&option[4..8]),
+                preferred_lifetime: be32(&option[8..12]),
+                prefix: ipv6(&option[16..32]),
+            }),
+            5 => options.mtu = Some(be32(&option[4..8])),
+            25 if len >= 8 => options.rdnss.push(Rdnss {
+                lifetime: be32(&option[4..8]),
+                servers: option[8..].chunks_exact(16).map(ipv6).collect(),
+            }),
+            _ => {}
+        }
+        data = &data[len..];
+    }
+    options
+}
+
+fn parse_mld2_records(mut data: &[u8], count: u16) -> Vec<MldRecord> {
+    let mut records = Vec::new();
+    for _ in 0..count {
+        if data.len() < 20 {
+            break;
+        }
+        let sources = u16::from_be_bytes([data[2], data[3]]);
+        records.push(MldRecord {
+            record_type: Some(data[0]),
+            group: ipv6(&data[4..20]),
+            sources,
+        });
+        let len = 20 + sources as usize * 16 + data[1] as usize * 4;
+        data = data.get(len..).unwrap_or_default();
+    }
+    records
+}
+
+fn be32(b: &[u8]) -> u32 {
+    u32::from_be_bytes([b[0], b[1], b[2], b[3]])
+}
+
+fn ipv6(b: &[u8]) -> Ipv6Addr {
+    let octets: [u8; 16] = b[..16].try_into().expect("16 bytes");
+    Ipv6Addr::from(octets)
+}
+
+/// Decodes an ICMPv6 message, starting at its type byte. The checksum is not checked.
+pub fn parse(data: &[u8]) -> Option<Icmpv6Kind> {
+    let type_u8 = *data.first()?;
+    let u16_at = |i: usize| u16::from_be_bytes([data[i], data[i + 1]]);
+    // Minimum length of each decoded type, options excluded
+    let min_len = match type_u8 {
+        ECHO_REQUEST | ECHO_REPLY | ROUTER_SOLICITATION | MLD2_REPORT => 8,
+        ROUTER_ADVERTISEMENT => 16,
+        NEIGHBOR_SOLICITATION | NEIGHBOR_ADVERTISEMENT | MLD_REPORT => 24,
+        REDIRECT => 40,
+        _ => 0,
+    };
+    if data.len() < min_len {
+        return Some(Icmpv6Kind::Other { type_u8 });
+    }
+    let kind = match type_u8 {
+        ECHO_REQUEST => Icmpv6Kind::EchoRequest {
+            id: u16_at(4),
+            seq: u16_at(6),
+        },
+        ECHO_REPLY => Icmpv6Kind::EchoReply {
+            id: u16_at(4),
+            seq: u16_at(6),
+        },
+        ROUTER_SOLICITATION => Icmpv6Kind::RouterSolicitation {
+            source_lladdr: parse_options(&data[8..]).source_lladdr,
+        },
+        ROUTER_ADVERTISEMENT => {
+            let options = parse_options(&data[16..]);
+            Icmpv6Kind::RouterAdvertisement(Box::new(RouterAdvertisement {
+                hop_limit: data[4],
+                managed: data[5] & 0x80 != 0,
+                other: data[5] & 0x40 != 0,
+                router_lifetime: u16_at(6),
+                reachable_time: be32(&data[8..12]),
+                retrans_timer: be32(&data[12..16]),
+                source_lladdr: options.source_lladdr,
+                mtu: options.mtu,
+                prefixes: options.prefixes,
+                rdnss: options.rdnss,
+            }))
+        }
+        NEIGHBOR_SOLICITATION => Icmpv6Kind::NeighborSolicitation {
+            target: ipv6(&data[8..24]),
+            source_lladdr: parse_options(&data[24..]).source_lladdr,
+        },
+        NEIGHBOR_ADVERTISEMENT => Icmpv6Kind::NeighborAdvertisement {
+            target: ipv6(&data[8..24]),
+            router: data[4] & 0x80 != 0,
+            solicited: data[4] & 0x40 != 0,
+            override_flag: data[4] & 0x20 != 0,
+            target_lladdr: parse_options(&data[24..]).target_lladdr,
+        },
+        REDIRECT => Icmpv6Kind::Redirect {
+            target: ipv6(&data[8..24]),
+            destination: ipv6(&data[24..40]),
+            target_lladdr: parse_options(&data[40..]).target_lladdr,
+        },
+        MLD_REPORT => Icmpv6Kind::MldReport {
+            version: 1,
+            records: vec![MldRecord {
+                record_type: None,
+                group: ipv6(&data[8..24]),
+                sources: 0,
+            }],
+        },
+        MLD2_REPORT => Icmpv6Kind::MldReport {
+            version: 2,
+            records: parse_mld2_records(&data[8..], u16_at(6)),
+        },
+        _ => Icmpv6Kind::Other { type_u8 },
+    };
+    Some(kind)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(s: &str) -> Ipv6Addr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn decodes_router_advertisement_options() {
+        let mut ra = vec![134, 0, 0, 0, 64, 0x40, 0x07, 0x08];
+        ra.extend([0, 0, 0, 0, 0, 0, 0, 0]);
+        // Source link-layer address
+        ra.extend([1, 1, 0x02, 0, 0, 0, 0, 0x01]);
+        // MTU
+        ra.extend([5, 1, 0, 0, 0, 0, 0x05, 0xdc]);
+        // Prefix 2001:db8:1::/64, on-link and autonomous
+        ra.extend([
+            3, 4, 64, 0xc0, 0, 0x27, 0x8d, 0, 0, 0, 0x0e, 0x10, 0, 0, 0, 0,
+        ]);
+        ra.extend(addr("2001:db8:1::").octets());
+        // RDNSS with one server
+        ra.extend([25, 3, 0, 0, 0xff, 0xff, 0xff, 0xff]);
+        ra.extend(addr("2001:db8:1::53").octets());
+        // Unknown option, then a zero-length one that ends the list
+        ra.extend([31, 1, 0, 0, 0, 0, 0, 0, 1, 0]);
+
+        let kind = parse(&ra).unwrap();
+        let Icmpv6Kind::RouterAdvertisement(ra) = &kind else {
+            panic!("not an RA: {:?}", kind);
+        };
+        assert!(ra.other && !ra.managed);
+        assert_eq!(ra.router_lifetime, 1800);
+        assert_eq!(ra.mtu, Some(1500));
+        assert_eq!(ra.source_lladdr, Some(MacAddr::new(2, 0, 0, 0, 0, 1)));
+        assert_eq!(
+            ra.prefixes,
+            [Prefix {
+                prefix: addr("2001:db8:1::"),
+                length: 64,
+                on_link: true,
+                autonomous: true,
+                valid_lifetime: 2_592_000,
+                preferred_lifetime: 3600,
+            }]
+        );
+        assert_eq!(
+            kind.to_string(),
+            "hop_limit=64, flags=[O], router_lifetime=1800s, reachable=0ms, retrans=0ms, \
+             mtu=1500, lladdr=02:00:00:00:00:01, prefix 2001:db8:1::/64 [L,A] \
+             valid=2592000s preferred=3600s, rdnss [2001:db8:1::53] lifetime=infinity"
+        );
+        assert_eq!(kind.icmpv6_type(), ROUTER_ADVERTISEMENT);
+    }
+
+    #[test]
+    fn decodes_neighbor_discovery_and_mld() {
+        let mut ns = vec![135, 0, 0, 0, 0, 0, 0, 0];
+        ns.extend(addr("fe80::1").octets());
+        ns.extend([1, 1, 0x02, 0, 0, 0, 0, 0x02]);
+        let ns = parse(&ns).unwrap();
+        assert_eq!(ns.label(), "NDP NS");
+        assert_eq!(ns.to_string(), "target=fe80::1, lladdr=02:00:00:00:00:02");
+
+        let mut na = vec![136, 0, 0, 0, 0xe0, 0, 0, 0];
+        na.extend(addr("fe80::1").octets());
+        na.extend([2, 1, 0x02, 0, 0, 0, 0, 0x01]);
+        let na = parse(&na).unwrap();
+        assert_eq!(
+            na.to_string(),
+            "target=fe80::1, flags=[R,S,O], lladdr=02:00:00:00:00:01"
+        );
+        assert_eq!(
+            serde_json::to_string(&na).unwrap(),
+            r#"{"kind":"neighbor_advertisement","target":"fe80::1","router":true,"solicited":true,"override":true,"target_lladdr":"02:00:00:00:00:01"}"#
+        );
+
+        // MLDv2: a join and a record with sources and auxiliary data
+        let mut mld = vec![143, 0, 0, 0, 0, 0, 0, 2];
+        mld.extend([4, 0, 0, 0]);
+        mld.extend(addr("ff02::1:ff00:1").octets());
+        mld.extend([1, 1, 0, 1]);
+        mld.extend(addr("ff05::fb").octets());
+        mld.extend(addr("2001:db8::1").octets());
+        mld.extend([0, 0, 0, 0]);
+        let mld = parse(&mld).unwrap();
+        assert_eq!(mld.label(), "MLDv2 report");
+        assert_eq!(
+            mld.to_string(),
+            "to_ex ff02::1:ff00:1, is_in ff05::fb (1 sources)"
+        );
+
+        // Too short for its type
+        assert_eq!(
+            parse(&[135, 0, 0, 0]),
+            Some(Icmpv6Kind::Other { type_u8: 135 })
+        );
+        assert_eq!(parse(&[]), None);
+    }
+}
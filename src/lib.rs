@@ -1,45 +1,726 @@
+pub mod bpf;
 pub mod capture;
+pub mod checksum;
+pub mod conntrack;
+pub mod defrag;
+pub mod dns;
+pub mod dnstrack;
+pub mod expert;
+pub mod filter;
+pub mod flows;
 pub mod handler;
+pub mod icmpv6;
 pub mod model;
+pub mod pcap;
+pub mod pingtrack;
+pub mod reassembly;
 pub mod render;
+pub mod source;
+pub mod vlan;
+
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::io::Write;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use pnet::packet::ethernet::EthernetPacket;
+
+use crate::source::{Next, PacketSource};
+
+/// How often `--flows` redraws the summary while capturing.
+const FLOW_REFRESH: Duration = Duration::from_secs(2);
+/// Rows shown in each periodic `--flows` summary; the final table lists every flow kept.
+const FLOW_ROWS: usize = 20;
+
+/// What [`run_source`] prints for the decoded traffic.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum View {
+    /// One line per packet.
+    #[default]
+    Packets,
+    /// A periodically refreshed table of conversations.
+    Flows,
+    /// One line when a TCP connection opens and one when it closes.
+    Connections,
+}
 
 /// Runtime configuration passed from the CLI layer.
 pub struct Config {
-    pub interface: String,
-    pub noudp: bool,
+    /// Interfaces to capture on; `any` selects every interface that is up.
+    pub interfaces: Vec<String>,
+    /// Read frames from this pcap file (`-` for stdin) instead of a live interface.
+    pub read_file: Option<PathBuf>,
+    /// Also save every frame to this file (pcapng unless it ends in `.pcap`).
+    pub write_file: Option<PathBuf>,
+    /// Write each reassembled TCP stream into files in this directory.
+    pub export_streams: Option<PathBuf>,
+    /// tcpdump-style capture filter, applied in the kernel where possible.
+    pub capture_filter: Option<bpf::Filter>,
+    /// Display filter applied to decoded events (`--noudp` is folded in here).
+    pub display_filter: Option<filter::Filter>,
+    pub format: render::OutputFormat,
+    /// How timestamps are shown in text output.
+    pub time_format: render::TimeFormat,
+    /// Show IP header fields on each text line.
+    pub verbose: bool,
+    /// Recompute IP, TCP, UDP and ICMP checksums and flag the packets that fail.
+    pub verify_checksums: bool,
+    pub view: View,
     pub no_color: bool,
 }
 
 /// Main runtime loop. Returns error instead of panicking.
 pub fn run(config: Config) -> anyhow::Result<()> {
-    let mut cap: capture::Capture = capture::Capture::open(&config.interface)?;
-
     // Configure rendering
     let disable_color = config.no_color || std::env::var_os("NO_COLOR").is_some();
     crate::render::set_color_enabled(!disable_color);
 
-    let ips_vec: Vec<std::net::IpAddr> = cap.host_ips();
-    println!("IP address of this device:{:?}", ips_vec);
-    let ips_set: std::collections::HashSet<std::net::IpAddr> = ips_vec.into_iter().collect();
-    let ips = std::sync::Arc::new(ips_set);
-    let iface_owned: pnet::datalink::NetworkInterface = cap.interface().clone();
+    let mut source: Box<dyn PacketSource> = match (&config.read_file, &config.interfaces[..]) {
+        // Offline sources need no datalink channel, so they run unprivileged
+        (Some(path), _) if path.as_os_str() == "-" => Box::new(source::PcapSource::stdin()?),
+        (Some(path), _) => Box::new(source::PcapSource::open(path)?),
+        (None, []) => return Err(anyhow::anyhow!("no network interface given")),
+        (None, [name]) if name != "any" => Box::new(capture::Capture::open(
+            name,
+            config.capture_filter.as_ref(),
+        )?),
+        (None, names) if names.iter().any(|n| n == "any") => Box::new(capture::MultiCapture::open(
+            &capture::all_interface_names(),
+            config.capture_filter.as_ref(),
+            true,
+        )?),
+        (None, names) => Box::new(capture::MultiCapture::open(
+            names,
+            config.capture_filter.as_ref(),
+            false,
+        )?),
+    };
+    if config.read_file.is_none() {
+        // Keep stdout pure JSON Lines so it can be piped straight into jq
+        match config.format {
+            render::OutputFormat::Text => {
+                println!("IP address of this device:{:?}", source.host_ips())
+            }
+            render::OutputFormat::Json => {
+                eprintln!("IP address of this device:{:?}", source.host_ips())
+            }
+        }
+    }
 
     // Install Ctrl-C handler for graceful shutdown
-    let terminate = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
-    let t2 = std::sync::Arc::clone(&terminate);
+    let terminate = Arc::new(AtomicBool::new(false));
+    let t2 = Arc::clone(&terminate);
     ctrlc::set_handler(move || {
-        t2.store(true, std::sync::atomic::Ordering::SeqCst);
+        t2.store(true, Ordering::SeqCst);
     })?;
 
-    while !terminate.load(std::sync::atomic::Ordering::Relaxed) {
-        if let Some(frame) = cap.next_ethernet()? {
-            crate::handler::handle_ethernet_frame(
-                &iface_owned,
-                &frame,
-                std::sync::Arc::clone(&ips),
-                config.noudp,
-            );
+    let stdout = std::io::stdout();
+    match run_source(source.as_mut(), &config, &mut stdout.lock(), &terminate) {
+        // Piping into `head` and friends is a normal way to stop
+        Err(e)
+            if e.downcast_ref::<std::io::Error>()
+                .is_some_and(|io| io.kind() == std::io::ErrorKind::BrokenPipe) =>
+        {
+            Ok(())
+        }
+        other => other,
+    }
+}
+
+/// Drives frames from `source` through decoding and rendering into `out` until the
+/// source ends or `terminate` is set.
+pub fn run_source<W: Write>(
+    source: &mut dyn PacketSource,
+    config: &Config,
+    out: &mut W,
+    terminate: &AtomicBool,
+) -> anyhow::Result<()> {
+    let ips: HashSet<IpAddr> = source.host_ips().into_iter().collect();
+    let mut writer = None;
+    let several_interfaces = config.read_file.is_none()
+        && (config.interfaces.len() > 1 || config.interfaces.iter().any(|n| n == "any"));
+    let mut unsupported: HashSet<u32> = HashSet::new();
+    let mut printer =
+        render::Printer::new(config.format, config.time_format).with_verbose(config.verbose);
+    let mut flows = (config.view == View::Flows).then(flows::FlowTable::new);
+    let mut conns = (config.view == View::Connections).then(conntrack::ConnTracker::new);
+    let mut analyzer = expert::TcpAnalyzer::new();
+    let mut exporter = match &config.export_streams {
+        Some(dir) => Some(reassembly::StreamExporter::create(dir)?),
+        None => None,
+    };
+    let mut defragmenter = defrag::Defragmenter::new();
+    let mut reassembler = reassembly::Reassembler::new();
+    let mut dns_tcp = dns::TcpDecoder::new();
+    let mut dns_tracker = dnstrack::DnsTracker::new();
+    let mut ping_tracker = pingtrack::PingTracker::new();
+    let mut checksums = checksum::ChecksumSummary::new();
+    let mut last_refresh = Instant::now();
+    // Frames are normalised to Ethernet below, so one program covers every source.
+    // The kernel program on live Linux captures lets fragments and tagged frames
+    // through for this one to decide on.
+    let program = match &config.capture_filter {
+        Some(filter) => Some(filter.compile(pcap::LINKTYPE_ETHERNET)?),
+        None => None,
+    };
+
+    while !terminate.load(Ordering::Relaxed) {
+        if let Some(table) = &flows {
+            if last_refresh.elapsed() >= FLOW_REFRESH {
+                last_refresh = Instant::now();
+                if config.format == render::OutputFormat::Text && render::color_enabled() {
+                    // Redraw in place rather than scrolling a new table every time
+                    write!(out, "\x1b[2J\x1b[H")?;
+                }
+                write!(out, "{}", table.render(config.format, Some(FLOW_ROWS)))?;
+                out.flush()?;
+            }
+        }
+        let frame = match source.next_frame()? {
+            Next::Frame(frame) => frame,
+            Next::Idle => continue,
+            Next::End => break,
+        };
+        let Some(data) = pcap::to_ethernet(frame.link_type, &frame.data) else {
+            if unsupported.insert(frame.link_type) {
+                let note = format!(
+                    "[{}]: Unsupported link type {}",
+                    frame.interface, frame.link_type
+                );
+                match config.format {
+                    render::OutputFormat::Text => writeln!(out, "{}", note)?,
+                    render::OutputFormat::Json => eprintln!("{}", note),
+                }
+            }
+            continue;
+        };
+        // Tags are stripped first so that the capture filter and every decoder below
+        // see tagged and untagged traffic alike
+        let (data, vlans) = match vlan::strip(&data) {
+            Some((untagged, tags)) => (Cow::Owned(untagged), tags),
+            None => (data, Vec::new()),
+        };
+        let incomplete = defragmenter.expire(frame.timestamp);
+        if config.view == View::Packets {
+            print_notices(out, &mut printer, incomplete)?;
+        }
+        // Fragments are held back and their datagram decoded once it is complete. Only
+        // the first fragment carries the transport header, so the capture filter is
+        // applied to the whole datagram. Under a capture filter the fragments are held
+        // too and written out only if their datagram is accepted
+        let keep = config.write_file.is_some() && program.is_some();
+        let (data, reassembly, captured) = match defragmenter.process(
+            &frame.interface,
+            frame.timestamp,
+            &data,
+            keep.then_some(&frame),
+        ) {
+            defrag::Outcome::Unfragmented => (Some(data), None, Vec::new()),
+            defrag::Outcome::Held => (None, None, Vec::new()),
+            defrag::Outcome::Complete {
+                frame,
+                reassembly,
+                captured,
+            } => (Some(Cow::Owned(frame)), Some(reassembly), captured),
+        };
+        let accepted = data
+            .as_ref()
+            .is_some_and(|d| program.as_ref().is_none_or(|p| p.matches(d)));
+        let written = match (keep, accepted) {
+            (false, _) => std::slice::from_ref(&frame),
+            (true, true) if !captured.is_empty() => &captured[..],
+            (true, true) => std::slice::from_ref(&frame),
+            (true, false) => &[],
+        };
+        if let (Some(path), false) = (&config.write_file, written.is_empty()) {
+            if writer.is_none() {
+                // Interfaces of different link types can only share a classic pcap file
+                // as Ethernet
+                let link_type = if several_interfaces {
+                    pcap::LINKTYPE_ETHERNET
+                } else {
+                    frame.link_type
+                };
+                writer = Some(pcap::PcapWriter::create(path, link_type)?);
+            }
         }
+        if let Some(w) = writer.as_mut() {
+            for f in written {
+                w.write_frame(&f.interface, f.link_type, f.timestamp, f.orig_len, &f.data)?;
+            }
+        }
+        let Some(data) = data.filter(|_| accepted) else {
+            continue;
+        };
+        let wire_len = match reassembly {
+            Some(_) => data.len() as u32,
+            None => frame.orig_len,
+        };
+        let Some(ethernet) = EthernetPacket::new(&data) else {
+            continue;
+        };
+        let mut event =
+            handler::decode_ethernet_frame(&frame.interface, frame.timestamp, &ethernet, &ips);
+        // Analysis sees every segment, so that filtering cannot hide the context it needs
+        if let Some(ev) = event.as_mut() {
+            ev.reassembly = reassembly;
+            ev.vlans = vlans.clone();
+            if config.verify_checksums {
+                // Frames this host sent may be captured before offload fills them in
+                let local = ips.contains(&ev.source);
+                ev.bad_checksums = checksum::verify(&ethernet, local);
+                checksums.record(ev.timestamp, &ev.bad_checksums);
+            }
+            analyzer.process(ev);
+            // Streams are only needed for DNS over TCP unless they are being exported
+            let tcp_dns = matches!(ev.transport, model::Transport::Tcp { src_port, dst_port, .. }
+                if dns::uses_port(src_port, dst_port));
+            if exporter.is_some() || tcp_dns {
+                let streams = reassembler.process(ev);
+                let messages = dispatch_streams(&streams, exporter.as_mut(), &mut dns_tcp);
+                ev.dns.extend(messages);
+            }
+            let timeouts = dns_tracker.process(ev);
+            ping_tracker.process(ev);
+            if config.view == View::Packets {
+                print_notices(out, &mut printer, timeouts)?;
+            }
+        }
+        let shown = config
+            .display_filter
+            .as_ref()
+            .is_none_or(|f| f.accepts(event.as_ref()));
+        // Connections are followed through hidden segments too; the filter only picks
+        // which of them are reported
+        if let (Some(tracker), Some(ev)) = (conns.as_mut(), event.as_ref()) {
+            let notices = if shown {
+                tracker.process(ev)
+            } else {
+                tracker.follow(ev)
+            };
+            print_notices(out, &mut printer, notices)?;
+        }
+        if !shown {
+            continue;
+        }
+        match (config.view, event) {
+            (View::Packets, Some(ev)) => writeln!(out, "{}", printer.event(&ev))?,
+            (View::Packets, None) => writeln!(
+                out,
+                "{}",
+                handler::describe_undecoded(
+                    &frame.interface,
+                    &vlans,
+                    frame.timestamp,
+                    &ethernet,
+                    &mut printer
+                )
+            )?,
+            (_, Some(ev)) => {
+                if let Some(table) = flows.as_mut() {
+                    table.record(&ev, wire_len);
+                }
+            }
+            (_, None) => {}
+        }
+    }
+    if let Some(table) = &flows {
+        write!(out, "{}", table.render(config.format, None))?;
+    }
+    if let Some(tracker) = conns.as_mut() {
+        print_notices(out, &mut printer, tracker.finish())?;
+    }
+    if config.view == View::Packets {
+        print_notices(out, &mut printer, defragmenter.finish())?;
+        print_notices(out, &mut printer, dns_tracker.finish())?;
+        if !checksums.is_empty() {
+            print_notices(out, &mut printer, vec![checksums])?;
+        }
+        if !dns_tracker.is_empty() {
+            if config.format == render::OutputFormat::Text {
+                writeln!(out)?;
+            }
+            write!(out, "{}", dns_tracker.render(config.format))?;
+        }
+        ping_tracker.finish();
+        if !ping_tracker.is_empty() {
+            if config.format == render::OutputFormat::Text {
+                writeln!(out)?;
+            }
+            write!(out, "{}", ping_tracker.render(config.format))?;
+        }
+    }
+    dispatch_streams(&reassembler.finish(), exporter.as_mut(), &mut dns_tcp);
+    if let Some(Err(e)) = exporter.as_mut().map(|e| e.flush()) {
+        eprintln!("packet-flow: not exporting a stream: {:#}", e);
     }
+    if let Some(w) = writer.as_mut() {
+        w.flush()?;
+    }
+    out.flush()?;
     Ok(())
 }
+
+/// Hands reassembled stream data to the exporter and the DNS-over-TCP decoder, and
+/// returns the DNS messages it completed. A file the exporter cannot write only
+/// loses that stream, so it is a warning rather than the end of the capture.
+fn dispatch_streams(
+    events: &[reassembly::StreamEvent],
+    mut exporter: Option<&mut reassembly::StreamExporter>,
+    dns_tcp: &mut dns::TcpDecoder,
+) -> Vec<dns::Message> {
+    let mut messages = Vec::new();
+    for event in events {
+        if let Some(Err(e)) = exporter.as_mut().map(|x| x.handle(event)) {
+            eprintln!("packet-flow: not exporting a stream: {:#}", e);
+        }
+        messages.extend(dns_tcp.handle(event));
+    }
+    messages
+}
+
+fn print_notices<W: Write, N: render::Report>(
+    out: &mut W,
+    printer: &mut render::Printer,
+    notices: Vec<N>,
+) -> std::io::Result<()> {
+    for notice in notices {
+        let line = match printer.format() {
+            render::OutputFormat::Text => printer.text(notice.timestamp(), &notice.render_line()),
+            render::OutputFormat::Json => {
+                serde_json::to_string(&notice).expect("reports are always serialisable")
+            }
+        };
+        writeln!(out, "{}", line)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::TcpFlags;
+    use crate::source::{Frame, MemorySource};
+    use pnet::packet::ethernet::{EtherTypes, MutableEthernetPacket};
+    use pnet::packet::ip::IpNextHeaderProtocols;
+    use pnet::packet::ipv4::MutableIpv4Packet;
+    use pnet::packet::tcp::MutableTcpPacket;
+    use pnet::packet::MutablePacket;
+    use std::net::Ipv4Addr;
+    use std::time::Duration;
+
+    fn config() -> Config {
+        Config {
+            interfaces: Vec::new(),
+            read_file: None,
+            write_file: None,
+            export_streams: None,
+            capture_filter: None,
+            display_filter: None,
+            format: render::OutputFormat::Text,
+            time_format: render::TimeFormat::None,
+            verbose: false,
+            verify_checksums: false,
+            view: View::Packets,
+            no_color: true,
+        }
+    }
+
+    fn tcp_frame(src: Ipv4Addr, dst: Ipv4Addr, sport: u16, dport: u16) -> Vec<u8> {
+        let mut ip_buf = vec![0u8; 20 + 20];
+        {
+            let mut ip = MutableIpv4Packet::new(&mut ip_buf[..]).unwrap();
+            ip.set_version(4);
+            ip.set_header_length(5);
+            ip.set_total_length(40);
+            ip.set_next_level_protocol(IpNextHeaderProtocols::Tcp);
+            ip.set_source(src);
+            ip.set_destination(dst);
+            let mut tcp = MutableTcpPacket::new(ip.payload_mut()).unwrap();
+            tcp.set_source(sport);
+            tcp.set_destination(dport);
+            tcp.set_data_offset(5);
+            tcp.set_flags(TcpFlags::ACK.0);
+            tcp.set_window(512);
+        }
+        let mut eth_buf = vec![0u8; 14 + ip_buf.len()];
+        {
+            let mut eth = MutableEthernetPacket::new(&mut eth_buf[..]).unwrap();
+            eth.set_ethertype(EtherTypes::Ipv4);
+            eth.set_payload(&ip_buf);
+        }
+        eth_buf
+    }
+
+    #[test]
+    fn run_source_renders_every_frame() {
+        render::set_color_enabled(false);
+        let local = Ipv4Addr::new(10, 0, 0, 2);
+        let remote = Ipv4Addr::new(93, 184, 216, 34);
+        let mut src = MemorySource::new(vec![
+            Frame::new(
+                Duration::from_secs(1),
+                pcap::LINKTYPE_ETHERNET,
+                "eth0",
+                tcp_frame(local, remote, 50000, 443),
+            ),
+            Frame::new(
+                Duration::from_secs(2),
+                pcap::LINKTYPE_RAW,
+                "tun0",
+                tcp_frame(remote, local, 443, 50000)[14..].to_vec(),
+            ),
+            Frame::new(Duration::from_secs(3), 9999, "odd0", vec![0; 4]),
+            Frame::new(Duration::from_secs(4), 9999, "odd0", vec![0; 4]),
+        ])
+        .with_host_ips(vec![IpAddr::V4(local)]);
+        let mut out = Vec::new();
+        run_source(&mut src, &config(), &mut out, &AtomicBool::new(false)).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(
+            lines,
+            vec![
+                "[eth0]: 10.0.0.2:50000 ===== [TCP] =====> 93.184.216.34:443; [.] seq: 0, ack: 0, win: 512, length: 0",
+                "[tun0]: 10.0.0.2:50000 <==== [TCP] ===== 93.184.216.34:443; [.] seq: 0, ack: 0, win: 512, length: 0",
+                "[odd0]: Unsupported link type 9999",
+            ]
+        );
+    }
+
+    #[test]
+    fn run_source_applies_capture_filter() {
+        render::set_color_enabled(false);
+        let a = Ipv4Addr::new(10, 0, 0, 5);
+        let b = Ipv4Addr::new(10, 0, 0, 9);
+        let mut src = MemorySource::new(vec![
+            Frame::new(
+                Duration::from_secs(1),
+                pcap::LINKTYPE_ETHERNET,
+                "eth0",
+                tcp_frame(a, b, 40000, 22),
+            ),
+            Frame::new(
+                Duration::from_secs(2),
+                pcap::LINKTYPE_RAW,
+                "wg0",
+                tcp_frame(a, b, 40000, 443)[14..].to_vec(),
+            ),
+        ]);
+        let mut cfg = config();
+        cfg.capture_filter = Some(bpf::Filter::parse("host 10.0.0.5 and tcp port 443").unwrap());
+        let mut out = Vec::new();
+        run_source(&mut src, &cfg, &mut out, &AtomicBool::new(false)).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "[wg0]: 10.0.0.5:40000 ===== [TCP] =====> 10.0.0.9:443; [.] seq: 0, ack: 0, win: 512, length: 0\n"
+        );
+    }
+
+    #[test]
+    fn run_source_filters_reassembled_datagrams() {
+        render::set_color_enabled(false);
+        let (a, b) = (Ipv4Addr::new(10, 0, 0, 5), Ipv4Addr::new(10, 0, 0, 9));
+        // Splits the TCP header after 16 bytes; only the first piece has the ports
+        let fragments = |dport: u16, id: u16| {
+            let whole = tcp_frame(a, b, 40000, dport);
+            let (head, tail) = whole.split_at(14 + 20 + 16);
+            let mut first = head.to_vec();
+            let mut last = [&whole[..14 + 20], tail].concat();
+            for (frame, flags_offset) in [(&mut first, 0x2000u16), (&mut last, 2)] {
+                let length = (frame.len() - 14) as u16;
+                let mut ip = MutableIpv4Packet::new(&mut frame[14..]).unwrap();
+                ip.set_total_length(length);
+                ip.set_identification(id);
+                ip.set_flags((flags_offset >> 13) as u8);
+                ip.set_fragment_offset(flags_offset & 0x1fff);
+            }
+            [first, last]
+        };
+        // The last fragment of each datagram arrives first
+        let frames: Vec<Frame> = [fragments(443, 1), fragments(22, 2)]
+            .into_iter()
+            .flat_map(|[first, last]| [last, first])
+            .enumerate()
+            .map(|(i, data)| {
+                Frame::new(
+                    Duration::from_secs(i as u64),
+                    pcap::LINKTYPE_ETHERNET,
+                    "eth0",
+                    data,
+                )
+            })
+            .collect();
+        let mut src = MemorySource::new(frames.clone());
+        let mut cfg = config();
+        cfg.capture_filter = Some(bpf::Filter::parse("tcp port 443").unwrap());
+        let path =
+            std::env::temp_dir().join(format!("packet-flow-fragments-{}.pcap", std::process::id()));
+        cfg.write_file = Some(path.clone());
+        let mut out = Vec::new();
+        run_source(&mut src, &cfg, &mut out, &AtomicBool::new(false)).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "[eth0]: 10.0.0.5:40000 ===== [TCP] =====> 10.0.0.9:443; [.] seq: 0, ack: 0, win: 512, length: 0; \
+             reassembled from 2 fragments\n"
+        );
+        // Only the fragments of the accepted datagram are written, as they were captured
+        let mut reader = pcap::PcapReader::open(&path).unwrap();
+        let mut written = Vec::new();
+        while let Some(record) = reader.next_record().unwrap() {
+            written.push(record.data);
+        }
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(written, [frames[0].data.clone(), frames[1].data.clone()]);
+    }
+
+    #[test]
+    fn run_source_writes_json_lines() {
+        let local = Ipv4Addr::new(10, 0, 0, 2);
+        let mut src = MemorySource::new(vec![
+            Frame::new(
+                Duration::from_secs(1),
+                pcap::LINKTYPE_ETHERNET,
+                "eth0",
+                tcp_frame(local, Ipv4Addr::new(1, 1, 1, 1), 50000, 443),
+            ),
+            Frame::new(Duration::from_secs(2), 9999, "odd0", vec![0; 4]),
+        ])
+        .with_host_ips(vec![IpAddr::V4(local)]);
+        let mut cfg = config();
+        cfg.format = render::OutputFormat::Json;
+        let mut out = Vec::new();
+        run_source(&mut src, &cfg, &mut out, &AtomicBool::new(false)).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        let lines: Vec<serde_json::Value> = text
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 1);
+        assert_eq!(lines[0]["protocol"], "tcp");
+        assert_eq!(lines[0]["direction"], "outbound");
+        assert_eq!(lines[0]["dst_port"], 443);
+        assert_eq!(lines[0]["timestamp"], "1970-01-01T00:00:01.000000Z");
+    }
+
+    #[test]
+    fn run_source_strips_vlan_tags() {
+        render::set_color_enabled(false);
+        let (a, b) = (Ipv4Addr::new(10, 0, 0, 5), Ipv4Addr::new(10, 0, 0, 9));
+        let tag = |frame: Vec<u8>, tags: &[[u8; 4]]| {
+            let mut tagged = frame[..12].to_vec();
+            tagged.extend(tags.concat());
+            tagged.extend(&frame[12..]);
+            tagged
+        };
+        let mut src = MemorySource::new(vec![
+            Frame::new(
+                Duration::from_secs(1),
+                pcap::LINKTYPE_ETHERNET,
+                "eth0",
+                tag(
+                    tcp_frame(a, b, 40000, 443),
+                    &[[0x88, 0xa8, 0x00, 0xc8], [0x81, 0x00, 0xa0, 0x64]],
+                ),
+            ),
+            Frame::new(
+                Duration::from_secs(2),
+                pcap::LINKTYPE_ETHERNET,
+                "eth0",
+                tag(tcp_frame(a, b, 40000, 443), &[[0x81, 0x00, 0x00, 0x65]]),
+            ),
+        ]);
+        let mut cfg = config();
+        cfg.capture_filter = Some(bpf::Filter::parse("tcp port 443").unwrap());
+        cfg.display_filter = Some(filter::Filter::parse("vlan.id == 100").unwrap());
+        let mut out = Vec::new();
+        run_source(&mut src, &cfg, &mut out, &AtomicBool::new(false)).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "[eth0 vlan 200 vlan 100 pcp 5]: 10.0.0.5:40000 ===== [TCP] =====> 10.0.0.9:443; \
+             [.] seq: 0, ack: 0, win: 512, length: 0\n"
+        );
+    }
+
+    #[test]
+    fn run_source_prints_delta_timestamps() {
+        render::set_color_enabled(false);
+        let (a, b) = (Ipv4Addr::new(10, 0, 0, 5), Ipv4Addr::new(10, 0, 0, 9));
+        let mut src = MemorySource::new(vec![
+            Frame::new(
+                Duration::from_millis(5_000),
+                pcap::LINKTYPE_ETHERNET,
+                "eth0",
+                tcp_frame(a, b, 40000, 22),
+            ),
+            Frame::new(
+                Duration::from_millis(5_125),
+                pcap::LINKTYPE_ETHERNET,
+                "eth0",
+                tcp_frame(b, a, 22, 40000),
+            ),
+        ]);
+        let mut cfg = config();
+        cfg.time_format = render::TimeFormat::Delta;
+        let mut out = Vec::new();
+        run_source(&mut src, &cfg, &mut out, &AtomicBool::new(false)).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        let stamps: Vec<&str> = text.lines().map(|l| l.split(' ').next().unwrap()).collect();
+        assert_eq!(stamps, vec!["0.000000", "0.125000"]);
+    }
+
+    #[test]
+    fn run_source_prints_flow_table_at_end() {
+        let (a, b) = (Ipv4Addr::new(10, 0, 0, 5), Ipv4Addr::new(10, 0, 0, 9));
+        let frames = [(a, b, 40000, 22), (b, a, 22, 40000), (a, b, 40000, 22)];
+        let mut src = MemorySource::new(frames.iter().enumerate().map(
+            |(i, &(src, dst, sport, dport))| {
+                Frame::new(
+                    Duration::from_secs(i as u64),
+                    pcap::LINKTYPE_ETHERNET,
+                    "eth0",
+                    tcp_frame(src, dst, sport, dport),
+                )
+            },
+        ));
+        let mut cfg = config();
+        cfg.view = View::Flows;
+        let mut out = Vec::new();
+        run_source(&mut src, &cfg, &mut out, &AtomicBool::new(false)).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines[0], "1 flow(s)");
+        assert_eq!(
+            lines[2].split_whitespace().collect::<Vec<_>>(),
+            vec![
+                "eth0",
+                "TCP",
+                "10.0.0.5:40000",
+                "10.0.0.9:22",
+                "2/1",
+                "108/54",
+                "2.000s",
+                "established",
+                "dupack",
+                "1"
+            ]
+        );
+    }
+
+    #[test]
+    fn run_source_stops_when_terminated() {
+        let mut src = MemorySource::new(vec![Frame::new(
+            Duration::ZERO,
+            pcap::LINKTYPE_ETHERNET,
+            "eth0",
+            vec![0; 14],
+        )]);
+        let mut out = Vec::new();
+        run_source(&mut src, &config(), &mut out, &AtomicBool::new(true)).unwrap();
+        assert!(out.is_empty());
+    }
+}
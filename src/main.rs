@@ -1,31 +1,116 @@
 use clap::Parser;
+use packet_flow::render::{OutputFormat, TimeFormat};
+use std::path::PathBuf;
 use std::process::ExitCode;
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 struct Cli {
-    /// Name of the network interface
-    #[arg(short, long, value_name = "NETWORK INTERFACE")]
-    interface: String,
-    /// Do not display UDP
+    /// Name of the network interface; repeat for several, or use "any"
+    #[arg(
+        short,
+        long,
+        value_name = "NETWORK INTERFACE",
+        required_unless_present = "read"
+    )]
+    interface: Vec<String>,
+    /// Read packets from a pcap file ('-' for stdin) instead of a live interface
+    #[arg(short, long, value_name = "FILE", conflicts_with = "interface")]
+    read: Option<PathBuf>,
+    /// Also write captured frames to a file (pcapng, or classic pcap for *.pcap)
+    #[arg(short, long, value_name = "FILE")]
+    write: Option<PathBuf>,
+    /// Write each reassembled TCP stream (one file per direction) into this directory
+    #[arg(long, value_name = "DIR")]
+    export_streams: Option<PathBuf>,
+    /// Do not display UDP (same as --filter '!udp')
     #[arg(long)]
     noudp: bool,
+    /// Display filter, e.g. "tcp.dst_port == 443 && direction == inbound"
+    #[arg(short, long, value_name = "EXPR")]
+    filter: Option<String>,
+    /// Output format
+    #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+    format: OutputFormat,
+    /// Timestamp shown in front of each line
+    #[arg(short, long, value_enum, value_name = "MODE", default_value_t = TimeFormat::Absolute)]
+    time: TimeFormat,
+    /// Also show IP header fields: TTL/hop limit, DSCP/ECN, ID, flags, flow label and length
+    #[arg(short, long)]
+    verbose: bool,
+    /// Verify IP, TCP, UDP and ICMP checksums and flag packets that fail
+    #[arg(long)]
+    checksums: bool,
+    /// Show a refreshing table of conversations instead of one line per packet
+    #[arg(long, conflicts_with = "connections")]
+    flows: bool,
+    /// Show TCP connection open/close lines instead of one line per packet
+    #[arg(long)]
+    connections: bool,
     /// Disable ANSI colors in output (or set NO_COLOR)
     #[arg(long = "no-color")]
     no_color: bool,
+    /// Capture filter in tcpdump syntax, e.g. "host 10.0.0.5 and tcp port 443"
+    #[arg(value_name = "EXPRESSION", trailing_var_arg = true)]
+    expression: Vec<String>,
 }
 
 fn main() -> ExitCode {
     let cli: Cli = Cli::parse();
+    let capture_filter = if cli.expression.is_empty() {
+        None
+    } else {
+        match packet_flow::bpf::Filter::parse(&cli.expression.join(" ")) {
+            Ok(filter) => Some(filter),
+            Err(err) => {
+                eprintln!("packet-flow error: {:#}", err);
+                return ExitCode::from(1);
+            }
+        }
+    };
+    let mut display_filter = match cli
+        .filter
+        .as_deref()
+        .map(packet_flow::filter::Filter::parse)
+    {
+        Some(Ok(filter)) => Some(filter),
+        Some(Err(err)) => {
+            eprintln!("packet-flow error: {:#}", err);
+            return ExitCode::from(1);
+        }
+        None => None,
+    };
+    if cli.noudp {
+        let noudp = packet_flow::filter::Filter::no_udp();
+        display_filter = Some(match display_filter {
+            Some(f) => noudp.and(f),
+            None => noudp,
+        });
+    }
     let config = packet_flow::Config {
-        interface: cli.interface,
-        noudp: cli.noudp,
+        interfaces: cli.interface,
+        read_file: cli.read,
+        write_file: cli.write,
+        export_streams: cli.export_streams,
+        capture_filter,
+        display_filter,
+        format: cli.format,
+        time_format: cli.time,
+        verbose: cli.verbose,
+        verify_checksums: cli.checksums,
+        view: if cli.flows {
+            packet_flow::View::Flows
+        } else if cli.connections {
+            packet_flow::View::Connections
+        } else {
+            packet_flow::View::Packets
+        },
         no_color: cli.no_color,
     };
     if let Err(err) = packet_flow::run(config) {
         eprintln!("packet-flow error: {:#}", err);
         return ExitCode::from(1);
     }
-    // run() currently never returns; OK for future extensibility.
+    // run() returns once a pcap file is exhausted; live capture runs until Ctrl-C.
     ExitCode::SUCCESS
 }
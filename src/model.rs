@@ -1,8 +1,12 @@
 use pnet::util::MacAddr;
+use serde::ser::SerializeMap;
+use serde::{Serialize, Serializer};
 use std::net::IpAddr;
-use std::net::Ipv4Addr;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::time::Duration;
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
 pub enum Direction {
     Inbound,
     Outbound,
@@ -10,28 +14,328 @@ pub enum Direction {
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum IcmpKind {
-    EchoReply { seq: u16, id: u16 },
-    EchoRequest { seq: u16, id: u16 },
+    EchoReply {
+        seq: u16,
+        id: u16,
+    },
+    EchoRequest {
+        seq: u16,
+        id: u16,
+    },
+    /// Type 3; `mtu` is the next-hop MTU of a "fragmentation needed" (code 4) message,
+    /// when the router filled it in.
+    Unreachable {
+        code: u8,
+        mtu: Option<u16>,
+        original: Option<Quoted>,
+    },
+    /// Type 11: the TTL ran out in transit (code 0) or reassembly timed out (code 1).
+    TimeExceeded {
+        code: u8,
+        original: Option<Quoted>,
+    },
+    /// Type 5: a router pointing the sender at a better first hop.
+    Redirect {
+        code: u8,
+        gateway: Ipv4Addr,
+        original: Option<Quoted>,
+    },
+    /// Type 12; `pointer` is the offset of the offending octet in the original header.
+    ParameterProblem {
+        code: u8,
+        pointer: u8,
+        original: Option<Quoted>,
+    },
     Other(u8),
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+impl IcmpKind {
+    /// The ICMP type number.
+    pub fn icmp_type(&self) -> u8 {
+        match self {
+            IcmpKind::EchoReply { .. } => 0,
+            IcmpKind::Unreachable { .. } => 3,
+            IcmpKind::Redirect { .. } => 5,
+            IcmpKind::EchoRequest { .. } => 8,
+            IcmpKind::TimeExceeded { .. } => 11,
+            IcmpKind::ParameterProblem { .. } => 12,
+            IcmpKind::Other(t) => *t,
+        }
+    }
+
+    /// The ICMP code of an error message.
+    pub fn code(&self) -> Option<u8> {
+        match self {
+            IcmpKind::Unreachable { code, .. }
+            | IcmpKind::TimeExceeded { code, .. }
+            | IcmpKind::Redirect { code, .. }
+            | IcmpKind::ParameterProblem { code, .. } => Some(*code),
+            _ => None,
+        }
+    }
+
+    /// The datagram an error message was sent about, as far as it could be decoded.
+    pub fn original(&self) -> Option<&Quoted> {
+        match self {
+            IcmpKind::Unreachable { original, .. }
+            | IcmpKind::TimeExceeded { original, .. }
+            | IcmpKind::Redirect { original, .. }
+            | IcmpKind::ParameterProblem { original, .. } => original.as_ref(),
+            _ => None,
+        }
+    }
+
+    /// What the code of an error message means, in the words of RFC 792 and RFC 1812.
+    pub fn reason(&self) -> Option<&'static str> {
+        let reason = match (self, self.code()?) {
+            (IcmpKind::Unreachable { .. }, code) => match code {
+                0 => "net unreachable",
+                1 => "host unreachable",
+                2 => "protocol unreachable",
+                3 => "port unreachable",
+                4 => "fragmentation needed",
+                5 => "source route failed",
+                6 => "destination network unknown",
+                7 => "destination host unknown",
+                8 => "source host isolated",
+                9 => "network administratively prohibited",
+                10 => "host administratively prohibited",
+                11 => "network unreachable for TOS",
+                12 => "host unreachable for TOS",
+                13 => "communication administratively prohibited",
+                14 => "host precedence violation",
+                15 => "precedence cutoff in effect",
+                _ => return None,
+            },
+            (IcmpKind::TimeExceeded { .. }, 0) => "TTL exceeded in transit",
+            (IcmpKind::TimeExceeded { .. }, 1) => "fragment reassembly time exceeded",
+            (IcmpKind::Redirect { .. }, 0) => "redirect for network",
+            (IcmpKind::Redirect { .. }, 1) => "redirect for host",
+            (IcmpKind::Redirect { .. }, 2) => "redirect for TOS and network",
+            (IcmpKind::Redirect { .. }, 3) => "redirect for TOS and host",
+            (IcmpKind::ParameterProblem { .. }, 0) => "pointer indicates the error",
+            (IcmpKind::ParameterProblem { .. }, 1) => "missing a required option",
+            (IcmpKind::ParameterProblem { .. }, 2) => "bad length",
+            _ => return None,
+        };
+        Some(reason)
+    }
+}
+
+/// Serialised as a flat map with a `kind` tag so that it nests cleanly inside the
+/// `protocol`-tagged [`Transport`] object.
+impl Serialize for IcmpKind {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(None)?;
+        match self {
+            IcmpKind::EchoReply { seq, id } => {
+                map.serialize_entry("kind", "echo_reply")?;
+                map.serialize_entry("seq", seq)?;
+                map.serialize_entry("id", id)?;
+            }
+            IcmpKind::EchoRequest { seq, id } => {
+                map.serialize_entry("kind", "echo_request")?;
+                map.serialize_entry("seq", seq)?;
+                map.serialize_entry("id", id)?;
+            }
+            IcmpKind::Unreachable { mtu, .. } => {
+                map.serialize_entry("kind", "unreachable")?;
+                if let Some(mtu) = mtu {
+                    map.serialize_entry("mtu", mtu)?;
+                }
+            }
+            IcmpKind::TimeExceeded { .. } => map.serialize_entry("kind", "time_exceeded")?,
+            IcmpKind::Redirect { gateway, .. } => {
+                map.serialize_entry("kind", "redirect")?;
+                map.serialize_entry("gateway", gateway)?;
+            }
+            IcmpKind::ParameterProblem { pointer, .. } => {
+                map.serialize_entry("kind", "parameter_problem")?;
+                map.serialize_entry("pointer", pointer)?;
+            }
+            IcmpKind::Other(t) => {
+                map.serialize_entry("kind", "other")?;
+                map.serialize_entry("type", t)?;
+            }
+        }
+        if let Some(code) = self.code() {
+            map.serialize_entry("code", &code)?;
+            if let Some(reason) = self.reason() {
+                map.serialize_entry("reason", reason)?;
+            }
+        }
+        if let Some(original) = self.original() {
+            map.serialize_entry("original", original)?;
+        }
+        map.end()
+    }
+}
+
+/// The start of the datagram an ICMP error was sent about, quoted in its body: enough
+/// to tell which flow triggered the error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Quoted {
+    pub source: IpAddr,
+    pub destination: IpAddr,
+    /// IP protocol number (or IPv6 next header) of the quoted datagram.
+    pub protocol: u8,
+    /// Ports, for TCP and UDP when the quote reaches them.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub src_port: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dst_port: Option<u16>,
+}
+
+/// tcpdump style, e.g. `TCP 10.0.0.5:40000 > 93.184.216.34:443`.
+impl std::fmt::Display for Quoted {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&protocol_name(self.protocol))?;
+        let endpoint = |addr: IpAddr, port: Option<u16>| match port {
+            Some(p) => format!("{}:{}", addr, p),
+            None => addr.to_string(),
+        };
+        write!(
+            f,
+            " {} > {}",
+            endpoint(self.source, self.src_port),
+            endpoint(self.destination, self.dst_port)
+        )
+    }
+}
+
+/// TCP control bits, as carried in the flags byte of the header.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct TcpFlags(pub u8);
+
+impl TcpFlags {
+    pub const FIN: TcpFlags = TcpFlags(0x01);
+    pub const SYN: TcpFlags = TcpFlags(0x02);
+    pub const RST: TcpFlags = TcpFlags(0x04);
+    pub const PSH: TcpFlags = TcpFlags(0x08);
+    pub const ACK: TcpFlags = TcpFlags(0x10);
+    pub const URG: TcpFlags = TcpFlags(0x20);
+    pub const ECE: TcpFlags = TcpFlags(0x40);
+    pub const CWR: TcpFlags = TcpFlags(0x80);
+
+    /// Names in the order tcpdump prints them, with ACK last.
+    const NAMES: [(TcpFlags, char, &'static str); 8] = [
+        (TcpFlags::FIN, 'F', "fin"),
+        (TcpFlags::SYN, 'S', "syn"),
+        (TcpFlags::RST, 'R', "rst"),
+        (TcpFlags::PSH, 'P', "psh"),
+        (TcpFlags::URG, 'U', "urg"),
+        (TcpFlags::ECE, 'E', "ece"),
+        (TcpFlags::CWR, 'W', "cwr"),
+        (TcpFlags::ACK, '.', "ack"),
+    ];
+
+    pub fn contains(self, other: TcpFlags) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl std::ops::BitOr for TcpFlags {
+    type Output = TcpFlags;
+
+    fn bitor(self, rhs: TcpFlags) -> TcpFlags {
+        TcpFlags(self.0 | rhs.0)
+    }
+}
+
+/// tcpdump notation: `S` for a SYN, `S.` for a SYN-ACK, `none` when no bit is set.
+impl std::fmt::Display for TcpFlags {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.0 == 0 {
+            return f.write_str("none");
+        }
+        for (flag, letter, _) in TcpFlags::NAMES {
+            if self.contains(flag) {
+                write!(f, "{}", letter)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Serialised as a list of lowercase flag names, e.g. `["syn", "ack"]`.
+impl Serialize for TcpFlags {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(
+            TcpFlags::NAMES
+                .iter()
+                .filter(|(flag, _, _)| self.contains(*flag))
+                .map(|(_, _, name)| name),
+        )
+    }
+}
+
+/// TCP options that matter when debugging handshakes. Unknown options are skipped.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct TcpOptions {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mss: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub window_scale: Option<u8>,
+    pub sack_permitted: bool,
+    /// SACK blocks as (left edge, right edge) sequence numbers.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub sack: Vec<(u32, u32)>,
+    /// Timestamp option as (TSval, TSecr).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timestamps: Option<(u32, u32)>,
+}
+
+/// tcpdump-like summary, e.g. `mss 1460, sackOK, TS val 1 ecr 0, wscale 7`.
+impl std::fmt::Display for TcpOptions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut parts = Vec::new();
+        if let Some(mss) = self.mss {
+            parts.push(format!("mss {}", mss));
+        }
+        if self.sack_permitted {
+            parts.push("sackOK".to_string());
+        }
+        if let Some((val, ecr)) = self.timestamps {
+            parts.push(format!("TS val {} ecr {}", val, ecr));
+        }
+        if let Some(shift) = self.window_scale {
+            parts.push(format!("wscale {}", shift));
+        }
+        if !self.sack.is_empty() {
+            let blocks: Vec<String> = self
+                .sack
+                .iter()
+                .map(|(l, r)| format!("{{{}:{}}}", l, r))
+                .collect();
+            parts.push(format!("sack {}", blocks.join("")));
+        }
+        f.write_str(&parts.join(", "))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "protocol", rename_all = "lowercase")]
 pub enum Transport {
     Udp {
         src_port: u16,
         dst_port: u16,
         length: u16,
-        is_dns: bool,
     },
     Tcp {
         src_port: u16,
         dst_port: u16,
-        length: usize,
+        flags: TcpFlags,
+        seq: u32,
+        ack: u32,
+        window: u16,
+        /// TCP header length in bytes, options included.
+        header_len: u8,
+        /// Segment payload length, excluding the TCP header.
+        payload_len: usize,
+        options: TcpOptions,
     },
     Icmp(IcmpKind),
-    Icmpv6 {
-        type_u8: u8,
-    },
+    Icmpv6(crate::icmpv6::Icmpv6Kind),
     Arp {
         operation: u16,
         sender_mac: MacAddr,
@@ -41,13 +345,278 @@ pub enum Transport {
     },
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+/// Name of an IP protocol number, e.g. `TCP` or `proto 47`.
+pub(crate) fn protocol_name(protocol: u8) -> String {
+    match protocol {
+        1 => "ICMP".to_string(),
+        6 => "TCP".to_string(),
+        17 => "UDP".to_string(),
+        58 => "ICMPv6".to_string(),
+        p => format!("proto {}", p),
+    }
+}
+
+/// How a datagram that arrived in fragments was put back together.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Reassembly {
+    pub fragments: u16,
+    /// Fragments that carried bytes already received; the first copy was kept.
+    pub overlaps: u16,
+}
+
+/// IP header fields beyond the addresses and protocol, for checking QoS marking and
+/// routing. `id` and the fragment flags are IPv4 only, `flow_label` IPv6 only.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct IpInfo {
+    /// TTL, or the hop limit for IPv6.
+    pub ttl: u8,
+    /// Differentiated Services code point, the upper six bits of the TOS or traffic
+    /// class byte.
+    pub dscp: u8,
+    /// Explicit Congestion Notification, the lower two bits.
+    pub ecn: u8,
+    /// Datagram length on the wire, IP header included.
+    pub length: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<u16>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub dont_fragment: bool,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub more_fragments: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub flow_label: Option<u32>,
+}
+
+impl IpInfo {
+    /// Per-hop behaviour name (`EF`, `AF41`, `CS6`, ...) or the number itself.
+    pub fn dscp_name(&self) -> String {
+        match self.dscp {
+            46 => "EF".to_string(),
+            44 => "VA".to_string(),
+            d if d % 8 == 0 => format!("CS{}", d / 8),
+            d if (10..=38).contains(&d) && d % 2 == 0 && (d % 8) / 2 <= 3 => {
+                format!("AF{}{}", d / 8, (d % 8) / 2)
+            }
+            d => d.to_string(),
+        }
+    }
+
+    /// Codepoint name from RFC 3168.
+    pub fn ecn_name(&self) -> &'static str {
+        match self.ecn & 0x3 {
+            0 => "Not-ECT",
+            1 => "ECT(1)",
+            2 => "ECT(0)",
+            _ => "CE",
+        }
+    }
+}
+
+/// tcpdump-like, e.g. `ttl=64, dscp=EF, ecn=ECT(0), id=0x1c46, flags=[DF], length=60`.
+impl std::fmt::Display for IpInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let ttl = if self.flow_label.is_some() {
+            "hlim"
+        } else {
+            "ttl"
+        };
+        write!(
+            f,
+            "{}={}, dscp={}, ecn={}",
+            ttl,
+            self.ttl,
+            self.dscp_name(),
+            self.ecn_name()
+        )?;
+        if let Some(id) = self.id {
+            write!(f, ", id={:#06x}", id)?;
+            let flags: Vec<&str> = [(self.dont_fragment, "DF"), (self.more_fragments, "MF")]
+                .iter()
+                .filter(|(set, _)| *set)
+                .map(|(_, name)| *name)
+                .collect();
+            if !flags.is_empty() {
+                write!(f, ", flags=[{}]", flags.join(","))?;
+            }
+        }
+        if let Some(label) = self.flow_label {
+            write!(f, ", flow={:#07x}", label)?;
+        }
+        write!(f, ", length={}", self.length)
+    }
+}
+
+/// An IPv6 extension header found between the fixed header and the upper-layer
+/// protocol, in the order it appeared.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Ipv6Ext {
+    HopByHop,
+    /// `segments` is the segment list of an SRv6 segment routing header (type 4),
+    /// last segment first, as carried on the wire.
+    Routing {
+        routing_type: u8,
+        segments_left: u8,
+        #[serde(skip_serializing_if = "Vec::is_empty")]
+        segments: Vec<Ipv6Addr>,
+    },
+    /// `offset` is in bytes; `more` is the M flag.
+    Fragment {
+        id: u32,
+        offset: u16,
+        more: bool,
+    },
+    DestinationOptions,
+    Authentication,
+    /// Mobility, HIP, Shim6 and other headers that are only skipped.
+    Other {
+        next_header: u8,
+    },
+}
+
+impl Ipv6Ext {
+    /// The next header value that announces this header.
+    pub fn next_header(&self) -> u8 {
+        match self {
+            Ipv6Ext::HopByHop => 0,
+            Ipv6Ext::Routing { .. } => 43,
+            Ipv6Ext::Fragment { .. } => 44,
+            Ipv6Ext::DestinationOptions => 60,
+            Ipv6Ext::Authentication => 51,
+            Ipv6Ext::Other { next_header } => *next_header,
+        }
+    }
+}
+
+/// tcpdump-like, e.g. `hop-by-hop` or `fragment(id=0x1234, offset=1448, more)`.
+impl std::fmt::Display for Ipv6Ext {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Ipv6Ext::HopByHop => f.write_str("hop-by-hop"),
+            Ipv6Ext::Routing {
+                routing_type: 4,
+                segments_left,
+                segments,
+            } => {
+                let segments: Vec<String> = segments.iter().map(|s| s.to_string()).collect();
+                write!(
+                    f,
+                    "srh(segments_left={}, segments=[{}])",
+                    segments_left,
+                    segments.join(" ")
+                )
+            }
+            Ipv6Ext::Routing {
+                routing_type,
+                segments_left,
+                ..
+            } => write!(
+                f,
+                "routing(type={}, segments_left={})",
+                routing_type, segments_left
+            ),
+            Ipv6Ext::Fragment { id, offset, more } => write!(
+                f,
+                "fragment(id={:#x}, offset={}{})",
+                id,
+                offset,
+                if *more { ", more" } else { "" }
+            ),
+            Ipv6Ext::DestinationOptions => f.write_str("dest-opts"),
+            Ipv6Ext::Authentication => f.write_str("ah"),
+            Ipv6Ext::Other { next_header } => write!(f, "ext({})", next_header),
+        }
+    }
+}
+
+/// Anomalies found by looking at a packet in the context of its flow, in the spirit
+/// of Wireshark's expert info.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Expert {
+    /// Data the sender had already sent before.
+    Retransmission,
+    /// A segment that arrived after a later one, filling a gap shortly after it opened.
+    OutOfOrder,
+    /// A pure ACK repeating the previous ACK; the count is the n-th duplicate in a row.
+    DuplicateAck(u32),
+    /// The sender advertises a zero receive window.
+    ZeroWindow,
+    /// The segment fills the receiver's advertised window.
+    WindowFull,
+}
+
+impl Expert {
+    /// Short identifier, also used for `tcp.analysis.*` display filter fields.
+    pub fn name(self) -> &'static str {
+        match self {
+            Expert::Retransmission => "retransmission",
+            Expert::OutOfOrder => "out_of_order",
+            Expert::DuplicateAck(_) => "duplicate_ack",
+            Expert::ZeroWindow => "zero_window",
+            Expert::WindowFull => "window_full",
+        }
+    }
+}
+
+/// Wireshark wording, e.g. `TCP Dup ACK #2`.
+impl std::fmt::Display for Expert {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Expert::Retransmission => f.write_str("TCP Retransmission"),
+            Expert::OutOfOrder => f.write_str("TCP Out-Of-Order"),
+            Expert::DuplicateAck(n) => write!(f, "TCP Dup ACK #{}", n),
+            Expert::ZeroWindow => f.write_str("TCP ZeroWindow"),
+            Expert::WindowFull => f.write_str("TCP Window Full"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
 pub struct NetEvent {
+    /// Capture time since the Unix epoch; zero when the event was not built from a frame.
+    #[serde(serialize_with = "serialize_timestamp")]
+    pub timestamp: Duration,
     pub interface: String,
+    /// VLAN tags the frame carried, outermost first.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub vlans: Vec<crate::vlan::VlanTag>,
     pub direction: Direction,
     pub source: IpAddr,
     pub destination: IpAddr,
+    #[serde(flatten)]
     pub transport: Transport,
+    /// Set by the IP decoders; `None` for ARP.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ip: Option<IpInfo>,
+    /// IPv6 extension headers between the fixed header and `transport`.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub ipv6_ext: Vec<Ipv6Ext>,
+    /// Set when the datagram was reassembled from fragments by [`crate::defrag`].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reassembly: Option<Reassembly>,
+    /// Checksums that did not match, when verification is enabled; see
+    /// [`crate::checksum`].
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub bad_checksums: Vec<crate::checksum::BadChecksum>,
+    /// Filled in by flow-aware analysis after decoding; empty from the decoders.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub expert: Vec<Expert>,
+    /// DNS messages carried by this packet: the UDP payload, or for DNS over TCP the
+    /// messages this segment completed.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub dns: Vec<crate::dns::Message>,
+    /// On an echo reply matched to its request, the time since the request; filled in
+    /// by [`crate::pingtrack::PingTracker`].
+    #[serde(
+        skip_serializing_if = "Option::is_none",
+        serialize_with = "serialize_secs"
+    )]
+    pub rtt: Option<Duration>,
+    /// Transport payload (TCP segment data or UDP datagram body), kept for stream
+    /// reassembly and application-layer dissectors.
+    #[serde(skip)]
+    pub payload: Vec<u8>,
 }
 
 impl NetEvent {
@@ -59,11 +628,49 @@ impl NetEvent {
         transport: Transport,
     ) -> Self {
         Self {
+            timestamp: Duration::ZERO,
             interface: interface.into(),
+            vlans: Vec::new(),
             direction,
             source,
             destination,
             transport,
+            ip: None,
+            ipv6_ext: Vec::new(),
+            reassembly: None,
+            bad_checksums: Vec::new(),
+            expert: Vec::new(),
+            dns: Vec::new(),
+            rtt: None,
+            payload: Vec::new(),
         }
     }
+
+    pub fn with_timestamp(mut self, timestamp: Duration) -> Self {
+        self.timestamp = timestamp;
+        self
+    }
+
+    pub fn with_payload(mut self, payload: impl Into<Vec<u8>>) -> Self {
+        self.payload = payload.into();
+        self
+    }
+}
+
+/// Optional durations as fractional seconds.
+pub(crate) fn serialize_secs<S: Serializer>(
+    d: &Option<Duration>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    match d {
+        Some(d) => serializer.serialize_f64(d.as_secs_f64()),
+        None => serializer.serialize_none(),
+    }
+}
+
+pub(crate) fn serialize_timestamp<S: Serializer>(
+    ts: &Duration,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(&crate::render::format_utc(*ts))
 }
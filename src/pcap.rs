@@ -0,0 +1,503 @@
+use std::borrow::Cow;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Write};
+use std::path::Path;
+use std::time::Duration;
+
+use pnet::packet::ethernet::{EtherType, EtherTypes, MutableEthernetPacket};
+use pnet::util::MacAddr;
+
+pub const LINKTYPE_ETHERNET: u32 = 1;
+pub const LINKTYPE_RAW: u32 = 101;
+pub const LINKTYPE_LINUX_SLL: u32 = 113;
+
+const MAGIC_MICROS: u32 = 0xa1b2_c3d4;
+const MAGIC_NANOS: u32 = 0xa1b2_3c4d;
+const SNAPLEN: u32 = 65535;
+
+const PCAPNG_SHB: u32 = 0x0a0d_0d0a;
+const PCAPNG_IDB: u32 = 0x0000_0001;
+const PCAPNG_EPB: u32 = 0x0000_0006;
+const PCAPNG_BYTE_ORDER_MAGIC: u32 = 0x1a2b_3c4d;
+const PCAPNG_OPT_END: u16 = 0;
+const PCAPNG_OPT_IF_NAME: u16 = 2;
+
+/// One record from a capture file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    /// Capture time since the Unix epoch.
+    pub timestamp: Duration,
+    /// Length of the packet on the wire, which may exceed `data.len()`.
+    pub orig_len: u32,
+    pub data: Vec<u8>,
+}
+
+/// Reader for classic libpcap files (both byte orders, micro- and nanosecond variants).
+pub struct PcapReader<R> {
+    reader: R,
+    swapped: bool,
+    nanos: bool,
+    link_type: u32,
+}
+
+impl PcapReader<BufReader<File>> {
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let file = File::open(path)
+            .map_err(|e| anyhow::anyhow!("unable to open {}: {}", path.display(), e))?;
+        Self::new(BufReader::new(file))
+    }
+}
+
+impl<R: Read> PcapReader<R> {
+    pub fn new(mut reader: R) -> anyhow::Result<Self> {
+        let mut header = [0u8; 24];
+        reader
+            .read_exact(&mut header)
+            .map_err(|e| anyhow::anyhow!("unable to read pcap header: {}", e))?;
+        let magic = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+        let (swapped, nanos) = match magic {
+            MAGIC_MICROS => (false, false),
+            MAGIC_NANOS => (false, true),
+            m if m.swap_bytes() == MAGIC_MICROS => (true, false),
+            m if m.swap_bytes() == MAGIC_NANOS => (true, true),
+            m => return Err(anyhow::anyhow!("not a pcap file (magic {:#010x})", m)),
+        };
+        let mut r = Self {
+            reader,
+            swapped,
+            nanos,
+            link_type: 0,
+        };
+        r.link_type = r.u32_at(&header, 20);
+        Ok(r)
+    }
+
+    pub fn link_type(&self) -> u32 {
+        self.link_type
+    }
+
+    /// Returns the next record, or `None` at a clean end of file.
+    pub fn next_record(&mut self) -> anyhow::Result<Option<Record>> {
+        let mut header = [0u8; 16];
+        match self.reader.read_exact(&mut header) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(anyhow::anyhow!("unable to read pcap record: {}", e)),
+        }
+        let secs = self.u32_at(&header, 0);
+        let frac = self.u32_at(&header, 4);
+        let incl_len = self.u32_at(&header, 8);
+        let orig_len = self.u32_at(&header, 12);
+        if incl_len > 0x0400_0000 {
+            return Err(anyhow::anyhow!("pcap record too large: {} bytes", incl_len));
+        }
+        let mut data = vec![0u8; incl_len as usize];
+        self.reader
+            .read_exact(&mut data)
+            .map_err(|e| anyhow::anyhow!("truncated pcap record: {}", e))?;
+        let frac_nanos = if self.nanos {
+            frac as u64
+        } else {
+            frac as u64 * 1000
+        };
+        Ok(Some(Record {
+            timestamp: Duration::from_secs(secs as u64) + Duration::from_nanos(frac_nanos),
+            orig_len,
+            data,
+        }))
+    }
+
+    fn u32_at(&self, buf: &[u8], at: usize) -> u32 {
+        let b = [buf[at], buf[at + 1], buf[at + 2], buf[at + 3]];
+        if self.swapped {
+            u32::from_be_bytes(b)
+        } else {
+            u32::from_le_bytes(b)
+        }
+    }
+}
+
+/// On-disk format produced by [`PcapWriter`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Pcap,
+    Pcapng,
+}
+
+impl Format {
+    /// `.pcap` selects the classic format; anything else gets pcapng.
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some(ext) if ext.eq_ignore_ascii_case("pcap") => Format::Pcap,
+            _ => Format::Pcapng,
+        }
+    }
+}
+
+/// Writer for classic pcap and pcapng files. pcapng output gets one interface
+/// description block per distinct interface, written the first time it is seen.
+/// Classic pcap has a single link type per file: frames of another link type are
+/// converted to Ethernet when the file is Ethernet, and rejected otherwise.
+pub struct PcapWriter<W: Write> {
+    out: W,
+    format: Format,
+    link_type: u32,
+    interfaces: Vec<(String, u32)>,
+}
+
+impl PcapWriter<BufWriter<File>> {
+    pub fn create(path: &Path, link_type: u32) -> anyhow::Result<Self> {
+        let file = File::create(path)
+            .map_err(|e| anyhow::anyhow!("unable to create {}: {}", path.display(), e))?;
+        Self::new(BufWriter::new(file), Format::from_path(path), link_type)
+    }
+}
+
+impl<W: Write> PcapWriter<W> {
+    /// Writes the file header: the global header for pcap, or the section header for
+    /// pcapng. `link_type` only matters for classic pcap, which has one per file.
+    pub fn new(mut out: W, format: Format, link_type: u32) -> anyhow::Result<Self> {
+        match format {
+            Format::Pcap => {
+                let mut header = Vec::with_capacity(24);
+                header.extend_from_slice(&MAGIC_MICROS.to_le_bytes());
+                header.extend_from_slice(&2u16.to_le_bytes());
+                header.extend_from_slice(&4u16.to_le_bytes());
+                header.extend_from_slice(&0i32.to_le_bytes());
+                header.extend_from_slice(&0u32.to_le_bytes());
+                header.extend_from_slice(&SNAPLEN.to_le_bytes());
+                header.extend_from_slice(&link_type.to_le_bytes());
+                out.write_all(&header)?;
+            }
+            Format::Pcapng => {
+                let mut shb = Vec::with_capacity(16);
+                shb.extend_from_slice(&PCAPNG_BYTE_ORDER_MAGIC.to_le_bytes());
+                shb.extend_from_slice(&1u16.to_le_bytes());
+                shb.extend_from_slice(&0u16.to_le_bytes());
+                shb.extend_from_slice(&(-1i64).to_le_bytes());
+                write_block(&mut out, PCAPNG_SHB, &shb)?;
+            }
+        }
+        Ok(Self {
+            out,
+            format,
+            link_type,
+            interfaces: Vec::new(),
+        })
+    }
+
+    /// Appends one frame seen on `if_name` at `timestamp` (since the Unix epoch).
+    pub fn write_frame(
+        &mut self,
+        if_name: &str,
+        link_type: u32,
+        timestamp: Duration,
+        orig_len: u32,
+        data: &[u8],
+    ) -> anyhow::Result<()> {
+        match self.format {
+            Format::Pcap => {
+                let (data, orig_len) = match link_type {
+                    l if l == self.link_type => (Cow::Borrowed(data), orig_len),
+                    l if self.link_type == LINKTYPE_ETHERNET => {
+                        let frame = to_ethernet(l, data).ok_or_else(|| {
+                            anyhow::anyhow!(
+                                "link type {} cannot be converted to Ethernet for a classic pcap file",
+                                l
+                            )
+                        })?;
+                        let orig_len = (orig_len as usize + frame.len()).saturating_sub(data.len());
+                        (frame, orig_len as u32)
+                    }
+                    l => anyhow::bail!(
+                        "a classic pcap file holds one link type ({}), not {}; write pcapng instead",
+                        self.link_type,
+                        l
+                    ),
+                };
+                let data = &data[..data.len().min(SNAPLEN as usize)];
+                let mut header = Vec::with_capacity(16);
+                header.extend_from_slice(&(timestamp.as_secs() as u32).to_le_bytes());
+                header.extend_from_slice(&timestamp.subsec_micros().to_le_bytes());
+                header.extend_from_slice(&(data.len() as u32).to_le_bytes());
+                header.extend_from_slice(&orig_len.to_le_bytes());
+                self.out.write_all(&header)?;
+                self.out.write_all(data)?;
+            }
+            Format::Pcapng => {
+                let data = &data[..data.len().min(SNAPLEN as usize)];
+                let if_id = self.interface_id(if_name, link_type)?;
+                // Default if_tsresol is microseconds
+                let micros = timestamp.as_micros() as u64;
+                let mut epb = Vec::with_capacity(20 + data.len() + 3);
+                epb.extend_from_slice(&if_id.to_le_bytes());
+                epb.extend_from_slice(&((micros >> 32) as u32).to_le_bytes());
+                epb.extend_from_slice(&(micros as u32).to_le_bytes());
+                epb.extend_from_slice(&(data.len() as u32).to_le_bytes());
+                epb.extend_from_slice(&orig_len.to_le_bytes());
+                epb.extend_from_slice(data);
+                pad32(&mut epb);
+                write_block(&mut self.out, PCAPNG_EPB, &epb)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> anyhow::Result<()> {
+        self.out.flush()?;
+        Ok(())
+    }
+
+    fn interface_id(&mut self, if_name: &str, link_type: u32) -> anyhow::Result<u32> {
+        if let Some(pos) = self
+            .interfaces
+            .iter()
+            .position(|(n, l)| n == if_name && *l == link_type)
+        {
+            return Ok(pos as u32);
+        }
+        let mut idb = Vec::new();
+        idb.extend_from_slice(&(link_type as u16).to_le_bytes());
+        idb.extend_from_slice(&0u16.to_le_bytes());
+        idb.extend_from_slice(&SNAPLEN.to_le_bytes());
+        if !if_name.is_empty() {
+            push_option(&mut idb, PCAPNG_OPT_IF_NAME, if_name.as_bytes());
+            push_option(&mut idb, PCAPNG_OPT_END, &[]);
+        }
+        write_block(&mut self.out, PCAPNG_IDB, &idb)?;
+        self.interfaces.push((if_name.to_string(), link_type));
+        Ok((self.interfaces.len() - 1) as u32)
+    }
+}
+
+fn write_block<W: Write>(out: &mut W, block_type: u32, body: &[u8]) -> std::io::Result<()> {
+    let total = (12 + body.len()) as u32;
+    out.write_all(&block_type.to_le_bytes())?;
+    out.write_all(&total.to_le_bytes())?;
+    out.write_all(body)?;
+    out.write_all(&total.to_le_bytes())
+}
+
+fn push_option(buf: &mut Vec<u8>, code: u16, value: &[u8]) {
+    buf.extend_from_slice(&code.to_le_bytes());
+    buf.extend_from_slice(&(value.len() as u16).to_le_bytes());
+    buf.extend_from_slice(value);
+    pad32(buf);
+}
+
+fn pad32(buf: &mut Vec<u8>) {
+    buf.resize(buf.len().next_multiple_of(4), 0);
+}
+
+/// Converts a frame of the given link type into an Ethernet frame so it can be fed
+/// to the handler. Non-Ethernet link types get a zeroed Ethernet header, the same way
+/// the live capture treats loopback and TUN interfaces.
+pub fn to_ethernet(link_type: u32, data: &[u8]) -> Option<Cow<'_, [u8]>> {
+    match link_type {
+        LINKTYPE_ETHERNET => Some(Cow::Borrowed(data)),
+        LINKTYPE_RAW => {
+            let ethertype = match data.first()? >> 4 {
+                4 => EtherTypes::Ipv4,
+                6 => EtherTypes::Ipv6,
+                _ => return None,
+            };
+            Some(Cow::Owned(fake_ethernet(ethertype, data)))
+        }
+        LINKTYPE_LINUX_SLL => {
+            if data.len() < 16 {
+                return None;
+            }
+            let ethertype = EtherType(u16::from_be_bytes([data[14], data[15]]));
+            Some(Cow::Owned(fake_ethernet(ethertype, &data[16..])))
+        }
+        _ => None,
+    }
+}
+
+fn fake_ethernet(ethertype: EtherType, payload: &[u8]) -> Vec<u8> {
+    let mut buf = vec![0u8; 14 + payload.len()];
+    let mut fake = MutableEthernetPacket::new(&mut buf[..]).unwrap();
+    fake.set_destination(MacAddr(0, 0, 0, 0, 0, 0));
+    fake.set_source(MacAddr(0, 0, 0, 0, 0, 0));
+    fake.set_ethertype(ethertype);
+    fake.set_payload(payload);
+    buf
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pnet::packet::ethernet::EthernetPacket;
+
+    fn pcap_bytes(magic: u32, link_type: u32, records: &[(u32, u32, &[u8])]) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend_from_slice(&magic.to_be_bytes());
+        out.extend_from_slice(&2u16.to_be_bytes());
+        out.extend_from_slice(&4u16.to_be_bytes());
+        out.extend_from_slice(&0u32.to_be_bytes());
+        out.extend_from_slice(&0u32.to_be_bytes());
+        out.extend_from_slice(&65535u32.to_be_bytes());
+        out.extend_from_slice(&link_type.to_be_bytes());
+        for (secs, frac, data) in records {
+            out.extend_from_slice(&secs.to_be_bytes());
+            out.extend_from_slice(&frac.to_be_bytes());
+            out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+            out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+            out.extend_from_slice(data);
+        }
+        out
+    }
+
+    #[test]
+    fn reads_big_endian_micros() {
+        let bytes = pcap_bytes(MAGIC_MICROS, LINKTYPE_ETHERNET, &[(10, 250, &[1, 2, 3])]);
+        let mut r = PcapReader::new(&bytes[..]).unwrap();
+        assert_eq!(r.link_type(), LINKTYPE_ETHERNET);
+        let rec = r.next_record().unwrap().expect("record");
+        assert_eq!(rec.timestamp, Duration::new(10, 250_000));
+        assert_eq!(rec.orig_len, 3);
+        assert_eq!(rec.data, vec![1, 2, 3]);
+        assert!(r.next_record().unwrap().is_none());
+    }
+
+    #[test]
+    fn reads_little_endian_nanos() {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&MAGIC_NANOS.to_le_bytes());
+        bytes.extend_from_slice(&[2, 0, 4, 0]);
+        bytes.extend_from_slice(&[0u8; 8]);
+        bytes.extend_from_slice(&65535u32.to_le_bytes());
+        bytes.extend_from_slice(&LINKTYPE_RAW.to_le_bytes());
+        bytes.extend_from_slice(&7u32.to_le_bytes());
+        bytes.extend_from_slice(&42u32.to_le_bytes());
+        bytes.extend_from_slice(&1u32.to_le_bytes());
+        bytes.extend_from_slice(&60u32.to_le_bytes());
+        bytes.push(0x45);
+        let mut r = PcapReader::new(&bytes[..]).unwrap();
+        assert_eq!(r.link_type(), LINKTYPE_RAW);
+        let rec = r.next_record().unwrap().expect("record");
+        assert_eq!(rec.timestamp, Duration::new(7, 42));
+        assert_eq!(rec.orig_len, 60);
+    }
+
+    #[test]
+    fn rejects_bad_magic_and_truncation() {
+        assert!(PcapReader::new(&[0u8; 24][..]).is_err());
+        let mut bytes = pcap_bytes(MAGIC_MICROS, LINKTYPE_ETHERNET, &[(1, 0, &[1, 2, 3, 4])]);
+        bytes.truncate(bytes.len() - 2);
+        let mut r = PcapReader::new(&bytes[..]).unwrap();
+        assert!(r.next_record().is_err());
+    }
+
+    #[test]
+    fn to_ethernet_wraps_raw_and_sll() {
+        let ip = [0x60u8, 0, 0, 0];
+        let eth = to_ethernet(LINKTYPE_RAW, &ip).expect("frame");
+        let p = EthernetPacket::new(&eth).unwrap();
+        assert_eq!(p.get_ethertype(), EtherTypes::Ipv6);
+        assert_eq!(&eth[14..], &ip);
+
+        let mut sll = vec![0u8; 16];
+        sll[14..16].copy_from_slice(&0x0806u16.to_be_bytes());
+        sll.extend_from_slice(&[9, 9]);
+        let eth = to_ethernet(LINKTYPE_LINUX_SLL, &sll).expect("frame");
+        let p = EthernetPacket::new(&eth).unwrap();
+        assert_eq!(p.get_ethertype(), EtherTypes::Arp);
+        assert_eq!(&eth[14..], &[9, 9]);
+
+        assert!(to_ethernet(999, &ip).is_none());
+    }
+
+    #[test]
+    fn pcap_writer_round_trips_through_reader() {
+        let mut out = Vec::new();
+        {
+            let mut w = PcapWriter::new(&mut out, Format::Pcap, LINKTYPE_ETHERNET).unwrap();
+            w.write_frame(
+                "eth0",
+                LINKTYPE_ETHERNET,
+                Duration::new(5, 123_456_000),
+                1514,
+                &[7u8; 20],
+            )
+            .unwrap();
+        }
+        let mut r = PcapReader::new(&out[..]).unwrap();
+        assert_eq!(r.link_type(), LINKTYPE_ETHERNET);
+        let rec = r.next_record().unwrap().expect("record");
+        assert_eq!(rec.timestamp, Duration::new(5, 123_456_000));
+        assert_eq!(rec.orig_len, 1514);
+        assert_eq!(rec.data, vec![7u8; 20]);
+    }
+
+    #[test]
+    fn pcap_writer_converts_other_link_types_to_ethernet() {
+        let mut out = Vec::new();
+        {
+            let mut w = PcapWriter::new(&mut out, Format::Pcap, LINKTYPE_ETHERNET).unwrap();
+            w.write_frame(
+                "wg0",
+                LINKTYPE_RAW,
+                Duration::new(1, 0),
+                4,
+                &[0x45, 0, 0, 4],
+            )
+            .unwrap();
+            assert!(w
+                .write_frame("odd0", 999, Duration::new(2, 0), 1, &[0])
+                .is_err());
+        }
+        let mut r = PcapReader::new(&out[..]).unwrap();
+        let rec = r.next_record().unwrap().expect("record");
+        assert_eq!(rec.orig_len, 18);
+        assert_eq!(&rec.data[12..], &[0x08, 0x00, 0x45, 0, 0, 4]);
+        assert!(r.next_record().unwrap().is_none());
+
+        let mut raw = PcapWriter::new(Vec::new(), Format::Pcap, LINKTYPE_RAW).unwrap();
+        assert!(raw
+            .write_frame("eth0", LINKTYPE_ETHERNET, Duration::ZERO, 14, &[0; 14])
+            .is_err());
+    }
+
+    #[test]
+    fn pcapng_writer_emits_shb_idb_epb() {
+        let mut out = Vec::new();
+        {
+            let mut w = PcapWriter::new(&mut out, Format::Pcapng, LINKTYPE_ETHERNET).unwrap();
+            w.write_frame(
+                "eth0",
+                LINKTYPE_ETHERNET,
+                Duration::new(1, 500),
+                60,
+                &[1, 2, 3],
+            )
+            .unwrap();
+            w.write_frame("wg0", LINKTYPE_RAW, Duration::new(2, 0), 3, &[4, 5, 6])
+                .unwrap();
+        }
+        let u32_at = |at: usize| u32::from_le_bytes(out[at..at + 4].try_into().unwrap());
+        assert_eq!(u32_at(0), PCAPNG_SHB);
+        assert_eq!(u32_at(4), 28);
+        assert_eq!(u32_at(8), PCAPNG_BYTE_ORDER_MAGIC);
+        let idb = 28;
+        assert_eq!(u32_at(idb), PCAPNG_IDB);
+        let idb_len = u32_at(idb + 4) as usize;
+        assert_eq!(&out[idb + 20..idb + 24], b"eth0");
+        let epb = idb + idb_len;
+        assert_eq!(u32_at(epb), PCAPNG_EPB);
+        assert_eq!(u32_at(epb + 4), 32 + 4);
+        assert_eq!(u32_at(epb + 16), 1_000_000);
+        assert_eq!(u32_at(epb + 20), 3);
+        assert_eq!(u32_at(epb + 24), 60);
+        let idb2 = epb + 36;
+        assert_eq!(u32_at(idb2), PCAPNG_IDB);
+        assert_eq!(u32_at(idb2 + 8) as u16, LINKTYPE_RAW as u16);
+        assert_eq!(&out[idb2 + 20..idb2 + 23], b"wg0");
+        let epb2 = idb2 + u32_at(idb2 + 4) as usize;
+        assert_eq!(u32_at(epb2), PCAPNG_EPB);
+        assert_eq!(u32_at(epb2 + 8), 1);
+        assert_eq!(out.len(), epb2 + 36);
+        assert_eq!(Format::from_path(Path::new("x.PCAP")), Format::Pcap);
+        assert_eq!(Format::from_path(Path::new("x.pcapng")), Format::Pcapng);
+    }
+}
@@ -0,0 +1,333 @@
+//! Passive ping monitoring.
+//!
+//! [`PingTracker`] matches ICMP and ICMPv6 echo requests with their replies by the two addresses,
+//! identifier and sequence number, stamps each reply with the round-trip time, and
+//! keeps per-target statistics in the style of ping's summary: requests, replies,
+//! loss and min/avg/max/mdev RTT. It works for any host whose pings cross the capture
+//! point, not just pings sent from this machine.
+
+use std::collections::{BTreeMap, HashMap};
+use std::net::IpAddr;
+use std::time::Duration;
+
+use serde::ser::SerializeMap;
+use serde::{Serialize, Serializer};
+
+use crate::icmpv6::Icmpv6Kind;
+use crate::model::{IcmpKind, NetEvent, Transport};
+use crate::render;
+
+/// A request without a reply for this long (in capture time) counts as lost.
+pub const ECHO_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct EchoKey {
+    pinger: IpAddr,
+    target: IpAddr,
+    id: u16,
+    seq: u16,
+}
+
+/// Ping statistics for one target address.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TargetStats {
+    pub target: IpAddr,
+    pub requests: u64,
+    /// Replies matched to a request; duplicates are not counted.
+    pub replies: u64,
+    /// Requests left unanswered for [`ECHO_TIMEOUT`].
+    pub lost: u64,
+    pub rtts: Vec<Duration>,
+}
+
+impl TargetStats {
+    fn new(target: IpAddr) -> Self {
+        Self {
+            target,
+            requests: 0,
+            replies: 0,
+            lost: 0,
+            rtts: Vec::new(),
+        }
+    }
+
+    /// Share of the requests that had their chance to be answered which were not.
+    pub fn loss(&self) -> f64 {
+        let settled = self.replies + self.lost;
+        if settled == 0 {
+            return 0.0;
+        }
+        self.lost as f64 / settled as f64
+    }
+
+    pub fn min(&self) -> Option<Duration> {
+        self.rtts.iter().min().copied()
+    }
+
+    pub fn max(&self) -> Option<Duration> {
+        self.rtts.iter().max().copied()
+    }
+
+    pub fn average(&self) -> Option<Duration> {
+        let n = self.rtts.len() as u32;
+        (n > 0).then(|| self.rtts.iter().sum::<Duration>() / n)
+    }
+
+    /// Mean deviation as ping reports it: the standard deviation of the RTTs.
+    pub fn mdev(&self) -> Option<Duration> {
+        let n = self.rtts.len() as f64;
+        let avg = self.average()?.as_secs_f64();
+        let square = self
+            .rtts
+            .iter()
+            .map(|d| d.as_secs_f64().powi(2))
+            .sum::<f64>()
+            / n;
+        Some(Duration::from_secs_f64(
+            (square - avg * avg).max(0.0).sqrt(),
+        ))
+    }
+}
+
+/// Tagged with `"event": "ping_target"`; RTTs are in seconds.
+impl Serialize for TargetStats {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let secs = |d: Option<Duration>| d.map(|d| d.as_secs_f64());
+        let mut map = serializer.serialize_map(None)?;
+        map.serialize_entry("event", "ping_target")?;
+        map.serialize_entry("target", &self.target)?;
+        map.serialize_entry("requests", &self.requests)?;
+        map.serialize_entry("replies", &self.replies)?;
+        map.serialize_entry("lost", &self.lost)?;
+        map.serialize_entry("loss", &self.loss())?;
+        map.serialize_entry("rtt_min", &secs(self.min()))?;
+        map.serialize_entry("rtt_avg", &secs(self.average()))?;
+        map.serialize_entry("rtt_max", &secs(self.max()))?;
+        map.serialize_entry("rtt_mdev", &secs(self.mdev()))?;
+        map.end()
+    }
+}
+
+/// Matches echo requests with replies across the capture.
+#[derive(Debug, Default)]
+pub struct PingTracker {
+    pending: HashMap<EchoKey, Duration>,
+    targets: BTreeMap<IpAddr, TargetStats>,
+    last_seen: Duration,
+    last_purge: Duration,
+}
+
+impl PingTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Whether any echo request has been seen.
+    pub fn is_empty(&self) -> bool {
+        self.targets.is_empty()
+    }
+
+    pub fn get(&self, target: &IpAddr) -> Option<&TargetStats> {
+        self.targets.get(target)
+    }
+
+    /// Records a request, or sets `rtt` on a reply that matches one. Other events
+    /// only advance the clock used to expire requests.
+    pub fn process(&mut self, ev: &mut NetEvent) {
+        let ts = ev.timestamp;
+        self.last_seen = self.last_seen.max(ts);
+        self.purge(ts);
+        let (request, seq, id) = match ev.transport {
+            Transport::Icmp(IcmpKind::EchoRequest { seq, id })
+            | Transport::Icmpv6(Icmpv6Kind::EchoRequest { seq, id }) => (true, seq, id),
+            Transport::Icmp(IcmpKind::EchoReply { seq, id })
+            | Transport::Icmpv6(Icmpv6Kind::EchoReply { seq, id }) => (false, seq, id),
+            _ => return,
+        };
+        if request {
+            let key = EchoKey {
+                pinger: ev.source,
+                target: ev.destination,
+                id,
+                seq,
+            };
+            // A request seen twice (e.g. on two interfaces) is still one request
+            if self.pending.contains_key(&key) {
+                return;
+            }
+            self.pending.insert(key, ts);
+            self.stats(ev.destination).requests += 1;
+        } else {
+            let key = EchoKey {
+                pinger: ev.destination,
+                target: ev.source,
+                id,
+                seq,
+            };
+            let Some(sent_at) = self.pending.remove(&key) else {
+                return;
+            };
+            let rtt = ts.saturating_sub(sent_at);
+            ev.rtt = Some(rtt);
+            let stats = self.stats(ev.source);
+            stats.replies += 1;
+            stats.rtts.push(rtt);
+        }
+    }
+
+    /// Counts the requests still unanswered [`ECHO_TIMEOUT`] after they were sent, as
+    /// of the last packet seen, as lost. Call once the capture has ended; requests sent
+    /// shortly before the end are not counted either way.
+    pub fn finish(&mut self) {
+        self.expire(self.last_seen);
+    }
+
+    /// Expires requests at most once per second of capture time.
+    fn purge(&mut self, now: Duration) {
+        if now.saturating_sub(self.last_purge) < Duration::from_secs(1) {
+            return;
+        }
+        self.last_purge = now;
+        self.expire(now);
+    }
+
+    fn expire(&mut self, now: Duration) {
+        let mut lost: Vec<IpAddr> = Vec::new();
+        self.pending.retain(|key, sent_at| {
+            let expired = now.saturating_sub(*sent_at) >= ECHO_TIMEOUT;
+            if expired {
+                lost.push(key.target);
+            }
+            !expired
+        });
+        for target in lost {
+            self.stats(target).lost += 1;
+        }
+    }
+
+    fn stats(&mut self, target: IpAddr) -> &mut TargetStats {
+        self.targets
+            .entry(target)
+            .or_insert_with(|| TargetStats::new(target))
+    }
+
+    /// Aligned table, one row per target, in the spirit of ping's summary.
+    pub fn render_table(&self) -> String {
+        let rows: Vec<Vec<String>> = self
+            .targets
+            .values()
+            .map(|s| {
+                vec![
+                    s.target.to_string(),
+                    s.requests.to_string(),
+                    s.replies.to_string(),
+                    render::with_rate(s.lost, s.replies + s.lost),
+                    render::millis(s.min()),
+                    render::millis(s.average()),
+                    render::millis(s.max()),
+                    render::millis(s.mdev()),
+                ]
+            })
+            .collect();
+        let header = [
+            "TARGET", "REQUESTS", "REPLIES", "LOST", "MIN", "AVG", "MAX", "MDEV",
+        ];
+        format!("{} ping target(s)\n", rows.len()) + &render::table(&header, &rows)
+    }
+
+    /// One JSON object per target.
+    pub fn render_json(&self) -> String {
+        self.targets
+            .values()
+            .map(|s| serde_json::to_string(s).expect("TargetStats is always serialisable") + "\n")
+            .collect()
+    }
+
+    /// Table or JSON Lines depending on `format`.
+    pub fn render(&self, format: render::OutputFormat) -> String {
+        match format {
+            render::OutputFormat::Text => self.render_table(),
+            render::OutputFormat::Json => self.render_json(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::Direction;
+    use std::net::Ipv4Addr;
+
+    const PINGER: [u8; 4] = [10, 0, 0, 5];
+    const TARGET: [u8; 4] = [10, 0, 0, 1];
+
+    fn echo(reply: bool, seq: u16, ms: u64) -> NetEvent {
+        let (src, dst, kind) = if reply {
+            (TARGET, PINGER, IcmpKind::EchoReply { seq, id: 77 })
+        } else {
+            (PINGER, TARGET, IcmpKind::EchoRequest { seq, id: 77 })
+        };
+        NetEvent::new(
+            "eth0",
+            Direction::Outbound,
+            IpAddr::V4(Ipv4Addr::from(src)),
+            IpAddr::V4(Ipv4Addr::from(dst)),
+            Transport::Icmp(kind),
+        )
+        .with_timestamp(Duration::from_millis(ms))
+    }
+
+    #[test]
+    fn matches_replies_and_summarises_rtt() {
+        render::set_color_enabled(false);
+        let mut t = PingTracker::new();
+        let mut events = [
+            echo(false, 1, 1000),
+            echo(true, 1, 1010),
+            echo(false, 2, 2000),
+            echo(true, 2, 2030),
+            // Duplicate reply
+            echo(true, 2, 2031),
+            echo(false, 3, 3000),
+            echo(true, 3, 3020),
+        ];
+        for ev in events.iter_mut() {
+            t.process(ev);
+        }
+        let rtts: Vec<Option<u128>> = events
+            .iter()
+            .map(|e| e.rtt.map(|d| d.as_millis()))
+            .collect();
+        assert_eq!(rtts, [None, Some(10), None, Some(30), None, None, Some(20)]);
+        let s = t.get(&IpAddr::V4(Ipv4Addr::from(TARGET))).unwrap();
+        assert_eq!((s.requests, s.replies, s.lost), (3, 3, 0));
+        assert_eq!(s.min(), Some(Duration::from_millis(10)));
+        assert_eq!(s.average(), Some(Duration::from_millis(20)));
+        assert_eq!(s.max(), Some(Duration::from_millis(30)));
+        // sqrt((100 + 900 + 400) / 3 - 400) ms
+        assert_eq!(render::millis(s.mdev()), "8.165ms");
+        assert_eq!(
+            render::render_line(&events[1]),
+            "[eth0]: 10.0.0.1 ===== [ICMP echo reply] =====> 10.0.0.5 (seq=1, id=77); rtt: 10.000ms"
+        );
+    }
+
+    #[test]
+    fn counts_unanswered_requests_as_lost() {
+        let mut t = PingTracker::new();
+        for seq in 0..4 {
+            t.process(&mut echo(false, seq, 1000 + seq as u64 * 1000));
+        }
+        t.process(&mut echo(true, 3, 4005));
+        // seq 0 expires as the capture goes on, seq 1 at the end
+        t.process(&mut echo(false, 4, 11_500));
+        t.process(&mut echo(false, 5, 12_000));
+        t.finish();
+        let s = t.get(&IpAddr::V4(Ipv4Addr::from(TARGET))).unwrap();
+        assert_eq!((s.requests, s.replies, s.lost), (6, 1, 2));
+        assert!((s.loss() - 2.0 / 3.0).abs() < 1e-9);
+        assert!(t
+            .render_table()
+            .contains("10.0.0.1  6         1        2 (66.7%)"));
+    }
+}
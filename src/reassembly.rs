@@ -0,0 +1,735 @@
+//! TCP stream reassembly.
+//!
+//! [`Reassembler`] puts the segments of each TCP connection back in sequence order,
+//! separately for the two directions, and hands the result on as [`StreamEvent`]s:
+//! in-order data, gaps, and the end of a stream. Retransmitted and overlapping bytes
+//! are delivered once, the first copy seen wins. Segments that arrive ahead of a hole
+//! are buffered until the hole fills; a hole that never fills (the segment was lost
+//! before the capture point) is skipped and reported as a gap once too much data is
+//! waiting behind it or the stream ends.
+//!
+//! Consumers are the stream exporter behind `--export-streams` and application-layer
+//! dissectors that need a byte stream rather than individual segments.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::Duration;
+
+use serde::Serialize;
+
+use crate::conntrack::TIME_WAIT;
+use crate::expert::IDLE_TIMEOUT;
+use crate::flows::{Endpoint, FlowKey};
+use crate::model::{NetEvent, TcpFlags, Transport};
+
+/// Out-of-order bytes buffered per direction before the hole in front of them is
+/// given up on.
+pub const MAX_BUFFERED: usize = 1 << 20;
+
+/// Files [`StreamExporter`] keeps open at once; the least recently written is closed
+/// to make room and reopened when its stream carries more data.
+pub const MAX_OPEN_FILES: usize = 64;
+
+/// The end of the connection that sent a piece of stream data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Side {
+    Client,
+    Server,
+}
+
+impl fmt::Display for Side {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Side::Client => "client",
+            Side::Server => "server",
+        })
+    }
+}
+
+/// Identity of a reassembled connection. The client is whoever sent the SYN; for
+/// connections picked up mid-stream it is guessed from the port numbers.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct StreamInfo {
+    /// Sequential stream number, starting at 1 in the order streams were first seen.
+    pub id: u64,
+    pub interface: String,
+    pub client: Endpoint,
+    pub server: Endpoint,
+    #[serde(serialize_with = "crate::model::serialize_timestamp")]
+    pub first_seen: Duration,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StreamEvent {
+    /// In-order bytes sent by `side`, starting `offset` bytes into that direction.
+    Data {
+        stream: Rc<StreamInfo>,
+        side: Side,
+        offset: u64,
+        data: Vec<u8>,
+    },
+    /// `len` bytes at `offset` that were never captured and have been skipped.
+    Gap {
+        stream: Rc<StreamInfo>,
+        side: Side,
+        offset: u64,
+        len: u64,
+    },
+    /// No more events follow for this stream.
+    Closed { stream: Rc<StreamInfo> },
+}
+
+/// A piece of one direction's output, before it is tagged with stream and side.
+enum Piece {
+    Data(u64, Vec<u8>),
+    Gap(u64, u64),
+}
+
+/// Reassembly state for one direction.
+#[derive(Debug, Default)]
+struct HalfStream {
+    /// Sequence number of the next byte to deliver; unknown until the SYN or the
+    /// first data segment.
+    next_seq: Option<u32>,
+    /// Stream offset of the next byte to deliver.
+    offset: u64,
+    /// Segments that arrived ahead of `offset`, keyed by their stream offset.
+    pending: BTreeMap<u64, Vec<u8>>,
+    buffered: usize,
+    fin: bool,
+}
+
+impl HalfStream {
+    fn syn(&mut self, seq: u32) {
+        if self.next_seq.is_none() {
+            self.next_seq = Some(seq.wrapping_add(1));
+        }
+    }
+
+    fn segment(&mut self, seq: u32, data: &[u8], out: &mut Vec<Piece>) {
+        if data.is_empty() {
+            return;
+        }
+        let next = *self.next_seq.get_or_insert(seq);
+        let ahead = seq.wrapping_sub(next) as i32;
+        if ahead <= 0 {
+            let overlap = ahead.unsigned_abs() as usize;
+            if overlap < data.len() {
+                self.deliver(&data[overlap..], out);
+                self.drain(out);
+            }
+            return;
+        }
+        let at = self.offset + ahead as u64;
+        // Keep the longer copy when the same segment is buffered twice
+        if self
+            .pending
+            .get(&at)
+            .is_none_or(|held| held.len() < data.len())
+        {
+            let previous = self.pending.insert(at, data.to_vec());
+            self.buffered += data.len() - previous.map_or(0, |p| p.len());
+        }
+        while self.buffered > MAX_BUFFERED {
+            self.skip_gap(out);
+        }
+    }
+
+    fn deliver(&mut self, data: &[u8], out: &mut Vec<Piece>) {
+        out.push(Piece::Data(self.offset, data.to_vec()));
+        self.advance(data.len() as u64);
+    }
+
+    fn advance(&mut self, len: u64) {
+        self.offset += len;
+        self.next_seq = self.next_seq.map(|s| s.wrapping_add(len as u32));
+    }
+
+    /// Delivers buffered segments that are now in order.
+    fn drain(&mut self, out: &mut Vec<Piece>) {
+        while let Some(entry) = self.pending.first_entry() {
+            if *entry.key() > self.offset {
+                break;
+            }
+            let (at, data) = entry.remove_entry();
+            self.buffered -= data.len();
+            let overlap = (self.offset - at) as usize;
+            if overlap < data.len() {
+                self.deliver(&data[overlap..], out);
+            }
+        }
+    }
+
+    /// Gives up on the hole in front of the first buffered segment.
+    fn skip_gap(&mut self, out: &mut Vec<Piece>) {
+        let Some(&at) = self.pending.keys().next() else {
+            return;
+        };
+        out.push(Piece::Gap(self.offset, at - self.offset));
+        self.advance(at - self.offset);
+        self.drain(out);
+    }
+
+    fn flush(&mut self, out: &mut Vec<Piece>) {
+        while !self.pending.is_empty() {
+            self.skip_gap(out);
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Stream {
+    info: Rc<StreamInfo>,
+    client: HalfStream,
+    server: HalfStream,
+    last_seen: Duration,
+    /// Set once the stream has been closed; kept for [`TIME_WAIT`] so that late
+    /// retransmissions do not start a new mid-stream stream.
+    closed: bool,
+}
+
+impl Stream {
+    fn half(&mut self, side: Side) -> &mut HalfStream {
+        match side {
+            Side::Client => &mut self.client,
+            Side::Server => &mut self.server,
+        }
+    }
+
+    /// Flushes both directions and marks the stream closed.
+    fn close(&mut self, events: &mut Vec<StreamEvent>) {
+        for side in [Side::Client, Side::Server] {
+            let mut pieces = Vec::new();
+            self.half(side).flush(&mut pieces);
+            tag(&self.info, side, pieces, events);
+        }
+        events.push(StreamEvent::Closed {
+            stream: Rc::clone(&self.info),
+        });
+        self.closed = true;
+    }
+}
+
+fn tag(stream: &Rc<StreamInfo>, side: Side, pieces: Vec<Piece>, events: &mut Vec<StreamEvent>) {
+    events.extend(pieces.into_iter().map(|piece| match piece {
+        Piece::Data(offset, data) => StreamEvent::Data {
+            stream: Rc::clone(stream),
+            side,
+            offset,
+            data,
+        },
+        Piece::Gap(offset, len) => StreamEvent::Gap {
+            stream: Rc::clone(stream),
+            side,
+            offset,
+            len,
+        },
+    }));
+}
+
+/// Reassembles every TCP connection in the capture.
+#[derive(Debug, Default)]
+pub struct Reassembler {
+    streams: HashMap<FlowKey, Stream>,
+    next_id: u64,
+    last_purge: Duration,
+}
+
+impl Reassembler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Feeds one event and returns the stream data it completes. Non-TCP events are
+    /// ignored.
+    pub fn process(&mut self, ev: &NetEvent) -> Vec<StreamEvent> {
+        let Transport::Tcp { flags, seq, .. } = &ev.transport else {
+            return Vec::new();
+        };
+        let (flags, seq) = (*flags, *seq);
+        let ts = ev.timestamp;
+        let mut events = self.purge(ts);
+
+        let (key, src, dst) = FlowKey::from_event(ev);
+        let syn = flags.contains(TcpFlags::SYN);
+        // A fresh SYN on a finished 4-tuple is port reuse: start over
+        if syn && !flags.contains(TcpFlags::ACK) && self.streams.get(&key).is_some_and(|s| s.closed)
+        {
+            self.streams.remove(&key);
+        }
+        if !self.streams.contains_key(&key) {
+            // Pure ACKs and resets for unknown connections carry nothing to reassemble
+            if !syn && ev.payload.is_empty() {
+                return events;
+            }
+            let (client, server) = match (syn, flags.contains(TcpFlags::ACK)) {
+                (true, false) => (src, dst),
+                (true, true) => (dst, src),
+                _ if src.port > dst.port => (src, dst),
+                _ => (dst, src),
+            };
+            self.next_id += 1;
+            let info = StreamInfo {
+                id: self.next_id,
+                interface: ev.interface.clone(),
+                client,
+                server,
+                first_seen: ts,
+            };
+            self.streams.insert(
+                key.clone(),
+                Stream {
+                    info: Rc::new(info),
+                    client: HalfStream::default(),
+                    server: HalfStream::default(),
+                    last_seen: ts,
+                    closed: false,
+                },
+            );
+        }
+        let stream = self.streams.get_mut(&key).expect("inserted above");
+        if stream.closed {
+            return events;
+        }
+        stream.last_seen = ts;
+        let side = if src == stream.info.client {
+            Side::Client
+        } else {
+            Side::Server
+        };
+
+        let half = stream.half(side);
+        let mut pieces = Vec::new();
+        let mut data_seq = seq;
+        if syn {
+            half.syn(seq);
+            // Data on a SYN (TCP Fast Open) starts after the SYN's sequence number
+            data_seq = seq.wrapping_add(1);
+        }
+        half.segment(data_seq, &ev.payload, &mut pieces);
+        half.fin |= flags.contains(TcpFlags::FIN);
+        tag(&stream.info, side, pieces, &mut events);
+
+        let finished = |h: &HalfStream| h.fin && h.pending.is_empty();
+        if flags.contains(TcpFlags::RST) || (finished(&stream.client) && finished(&stream.server)) {
+            stream.close(&mut events);
+        }
+        events
+    }
+
+    /// Flushes and closes every open stream, in the order they were first seen. Call
+    /// once the capture has ended.
+    pub fn finish(&mut self) -> Vec<StreamEvent> {
+        let mut open: Vec<&mut Stream> = self.streams.values_mut().filter(|s| !s.closed).collect();
+        open.sort_by_key(|s| s.info.id);
+        let mut events = Vec::new();
+        for stream in open {
+            stream.close(&mut events);
+        }
+        events
+    }
+
+    /// Number of streams still open.
+    pub fn open_count(&self) -> usize {
+        self.streams.values().filter(|s| !s.closed).count()
+    }
+
+    /// Closes streams idle for longer than [`IDLE_TIMEOUT`] and forgets closed ones
+    /// after [`TIME_WAIT`]. Runs at most once per second of capture time.
+    fn purge(&mut self, now: Duration) -> Vec<StreamEvent> {
+        let mut events = Vec::new();
+        if now.saturating_sub(self.last_purge) < Duration::from_secs(1) {
+            return events;
+        }
+        self.last_purge = now;
+        let mut idle: Vec<&mut Stream> = self
+            .streams
+            .values_mut()
+            .filter(|s| !s.closed && now.saturating_sub(s.last_seen) >= IDLE_TIMEOUT)
+            .collect();
+        idle.sort_by_key(|s| s.info.id);
+        for stream in idle {
+            stream.close(&mut events);
+        }
+        self.streams
+            .retain(|_, s| !s.closed || now.saturating_sub(s.last_seen) < TIME_WAIT);
+        events
+    }
+}
+
+/// Writes each direction of every stream to its own file, named after the stream
+/// number and endpoints, e.g. `00001_10.0.0.5.40000_10.0.0.9.80_c2s.bin`. Skipped
+/// gaps are left as holes, so file offsets match stream offsets.
+///
+/// A file that cannot be created or written is reported once and the rest of that
+/// direction is skipped; the other streams carry on.
+#[derive(Debug)]
+pub struct StreamExporter {
+    dir: PathBuf,
+    /// Open files by stream number and side, at most [`MAX_OPEN_FILES`].
+    files: HashMap<(u64, Side), OpenFile>,
+    /// Stream offset of every direction written so far, open or not.
+    offsets: HashMap<(u64, Side), u64>,
+    /// Directions whose file failed; their remaining events are dropped.
+    failed: HashSet<(u64, Side)>,
+    uses: u64,
+}
+
+#[derive(Debug)]
+struct OpenFile {
+    writer: BufWriter<File>,
+    path: PathBuf,
+    last_used: u64,
+}
+
+impl OpenFile {
+    fn close(mut self) -> anyhow::Result<()> {
+        self.writer
+            .flush()
+            .map_err(|e| anyhow::anyhow!("unable to write {}: {}", self.path.display(), e))
+    }
+}
+
+impl StreamExporter {
+    /// Creates `dir` if needed.
+    pub fn create(dir: &Path) -> anyhow::Result<Self> {
+        std::fs::create_dir_all(dir)
+            .map_err(|e| anyhow::anyhow!("unable to create {}: {}", dir.display(), e))?;
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            files: HashMap::new(),
+            offsets: HashMap::new(),
+            failed: HashSet::new(),
+            uses: 0,
+        })
+    }
+
+    /// File name used for the data `side` sent on `stream`.
+    pub fn file_name(stream: &StreamInfo, side: Side) -> String {
+        // Keep names portable: no brackets or colons from IPv6 addresses
+        let endpoint = |e: &Endpoint| {
+            let addr = match e.addr {
+                IpAddr::V4(a) => a.to_string(),
+                IpAddr::V6(a) => a.to_string().replace(':', "-"),
+            };
+            format!("{}.{}", addr, e.port.unwrap_or(0))
+        };
+        let direction = match side {
+            Side::Client => "c2s",
+            Side::Server => "s2c",
+        };
+        format!(
+            "{:05}_{}_{}_{}.bin",
+            stream.id,
+            endpoint(&stream.client),
+            endpoint(&stream.server),
+            direction
+        )
+    }
+
+    /// Writes one event. An error concerns a single file and is returned only once;
+    /// later events for that direction are dropped.
+    pub fn handle(&mut self, event: &StreamEvent) -> anyhow::Result<()> {
+        let (stream, side, data, len) = match event {
+            StreamEvent::Data {
+                stream, side, data, ..
+            } => (stream, *side, &data[..], data.len() as u64),
+            StreamEvent::Gap {
+                stream, side, len, ..
+            } => (stream, *side, &[][..], *len),
+            StreamEvent::Closed { stream } => {
+                let mut result = Ok(());
+                for side in [Side::Client, Side::Server] {
+                    let key = (stream.id, side);
+                    self.offsets.remove(&key);
+                    self.failed.remove(&key);
+                    if let Some(file) = self.files.remove(&key) {
+                        result = result.and(file.close());
+                    }
+                }
+                return result;
+            }
+        };
+        let key = (stream.id, side);
+        if self.failed.contains(&key) {
+            return Ok(());
+        }
+        let mut result = Ok(());
+        if !self.files.contains_key(&key) && self.files.len() >= MAX_OPEN_FILES {
+            result = self.close_oldest();
+        }
+        let path = self.dir.join(Self::file_name(stream, side));
+        let written = self.file(key, &path).and_then(|file| {
+            if data.is_empty() {
+                file.seek(SeekFrom::Current(len as i64))?;
+            } else {
+                file.write_all(data)?;
+            }
+            Ok(())
+        });
+        match written {
+            Ok(()) => *self.offsets.entry(key).or_default() += len,
+            Err(e) => {
+                self.failed.insert(key);
+                self.files.remove(&key);
+                result = result.and(Err(anyhow::anyhow!(
+                    "unable to write {}: {}",
+                    path.display(),
+                    e
+                )));
+            }
+        }
+        result
+    }
+
+    /// Flushes every file still open, carrying on past failures and returning the
+    /// first.
+    pub fn flush(&mut self) -> anyhow::Result<()> {
+        let mut result = Ok(());
+        for (_, file) in self.files.drain() {
+            result = result.and(file.close());
+        }
+        result
+    }
+
+    /// Closes the least recently written file to make room for another.
+    fn close_oldest(&mut self) -> anyhow::Result<()> {
+        let Some(&oldest) = self
+            .files
+            .iter()
+            .min_by_key(|(_, f)| f.last_used)
+            .map(|(key, _)| key)
+        else {
+            return Ok(());
+        };
+        let file = self.files.remove(&oldest).expect("found above");
+        file.close().inspect_err(|_| {
+            self.failed.insert(oldest);
+        })
+    }
+
+    /// The open file for one direction: created on first use, and reopened at the
+    /// stream's offset if it was closed to make room.
+    fn file(&mut self, key: (u64, Side), path: &Path) -> std::io::Result<&mut BufWriter<File>> {
+        self.uses += 1;
+        if !self.files.contains_key(&key) {
+            let file = match self.offsets.get(&key) {
+                None => File::create(path)?,
+                Some(&offset) => {
+                    let mut file = OpenOptions::new().write(true).open(path)?;
+                    file.seek(SeekFrom::Start(offset))?;
+                    file
+                }
+            };
+            let file = OpenFile {
+                writer: BufWriter::new(file),
+                path: path.to_path_buf(),
+                last_used: 0,
+            };
+            self.files.insert(key, file);
+        }
+        let file = self.files.get_mut(&key).expect("inserted above");
+        file.last_used = self.uses;
+        Ok(&mut file.writer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::{Direction, TcpOptions};
+    use std::net::Ipv4Addr;
+
+    const CLIENT: ([u8; 4], u16) = ([10, 0, 0, 5], 40000);
+    const SERVER: ([u8; 4], u16) = ([10, 0, 0, 9], 80);
+
+    fn seg(from_client: bool, flags: TcpFlags, seq: u32, payload: &[u8], ms: u64) -> NetEvent {
+        let (src, dst) = if from_client {
+            (CLIENT, SERVER)
+        } else {
+            (SERVER, CLIENT)
+        };
+        NetEvent::new(
+            "eth0",
+            Direction::Outbound,
+            IpAddr::V4(Ipv4Addr::from(src.0)),
+            IpAddr::V4(Ipv4Addr::from(dst.0)),
+            Transport::Tcp {
+                src_port: src.1,
+                dst_port: dst.1,
+                flags,
+                seq,
+                ack: 0,
+                window: 1024,
+                header_len: 20,
+                payload_len: payload.len(),
+                options: TcpOptions::default(),
+            },
+        )
+        .with_timestamp(Duration::from_millis(ms))
+        .with_payload(payload)
+    }
+
+    /// Concatenated data per side, with gaps written as `_` per missing byte.
+    fn collect(events: &[StreamEvent], side: Side) -> String {
+        let mut text = String::new();
+        for ev in events {
+            match ev {
+                StreamEvent::Data { side: s, data, .. } if *s == side => {
+                    text.push_str(std::str::from_utf8(data).unwrap())
+                }
+                StreamEvent::Gap { side: s, len, .. } if *s == side => {
+                    text.push_str(&"_".repeat(*len as usize))
+                }
+                _ => {}
+            }
+        }
+        text
+    }
+
+    #[test]
+    fn reorders_and_trims_overlapping_segments() {
+        let ack = TcpFlags::ACK;
+        let psh = TcpFlags::PSH | ack;
+        let mut r = Reassembler::new();
+        let mut events = Vec::new();
+        events.extend(r.process(&seg(true, TcpFlags::SYN, 999, b"", 0)));
+        events.extend(r.process(&seg(false, TcpFlags::SYN | ack, 4999, b"", 1)));
+        events.extend(r.process(&seg(true, psh, 1000, b"GET ", 2)));
+        // The segment after a hole arrives first
+        events.extend(r.process(&seg(true, psh, 1010, b" HTTP/1.1", 3)));
+        assert_eq!(collect(&events, Side::Client), "GET ");
+        // Retransmission overlapping what was delivered and what is buffered
+        events.extend(r.process(&seg(true, psh, 1002, b"T /index HT", 4)));
+        events.extend(r.process(&seg(false, psh, 5000, b"HTTP/1.1 200", 5)));
+        events.extend(r.process(&seg(false, psh, 5000, b"HTTP/1.1 200", 6)));
+        assert_eq!(collect(&events, Side::Client), "GET /index HTTP/1.1");
+        assert_eq!(collect(&events, Side::Server), "HTTP/1.1 200");
+        assert!(!events
+            .iter()
+            .any(|e| matches!(e, StreamEvent::Closed { .. })));
+
+        let fin = TcpFlags::FIN | ack;
+        events.extend(r.process(&seg(true, fin, 1019, b"", 7)));
+        events.extend(r.process(&seg(false, fin, 5012, b"", 8)));
+        match events.last() {
+            Some(StreamEvent::Closed { stream }) => {
+                assert_eq!(stream.id, 1);
+                assert_eq!(stream.client.to_string(), "10.0.0.5:40000");
+                assert_eq!(stream.server.to_string(), "10.0.0.9:80");
+            }
+            other => panic!("expected close, got {:?}", other),
+        }
+        assert_eq!(r.open_count(), 0);
+        // A late retransmission does not resurrect the stream
+        assert!(r.process(&seg(true, psh, 1000, b"GET ", 9)).is_empty());
+    }
+
+    #[test]
+    fn skips_gaps_that_never_fill() {
+        let psh = TcpFlags::PSH | TcpFlags::ACK;
+        let mut r = Reassembler::new();
+        let mut events = Vec::new();
+        // Picked up mid-stream: the higher port is taken to be the client
+        events.extend(r.process(&seg(false, psh, 7000, b"abc", 0)));
+        events.extend(r.process(&seg(false, psh, 7005, b"fgh", 1)));
+        assert_eq!(collect(&events, Side::Server), "abc");
+        events.extend(r.finish());
+        assert_eq!(collect(&events, Side::Server), "abc__fgh");
+        assert!(matches!(events.last(), Some(StreamEvent::Closed { .. })));
+
+        // Too much data waiting behind a hole forces the hole to be skipped
+        let mut r = Reassembler::new();
+        let mut events = r.process(&seg(true, psh, 0, b"x", 0));
+        let chunk = vec![b'y'; MAX_BUFFERED / 2 + 1];
+        events.extend(r.process(&seg(true, psh, 2, &chunk, 1)));
+        events.extend(r.process(&seg(true, psh, 2 + chunk.len() as u32, &chunk, 2)));
+        let text = collect(&events, Side::Client);
+        assert_eq!(&text[..3], "x_y");
+        assert_eq!(text.len(), 2 + 2 * chunk.len());
+    }
+
+    #[test]
+    fn exports_each_direction_to_a_file() {
+        let dir = std::env::temp_dir().join(format!("packet-flow-streams-{}", std::process::id()));
+        let psh = TcpFlags::PSH | TcpFlags::ACK;
+        let mut r = Reassembler::new();
+        let mut events = r.process(&seg(true, TcpFlags::SYN, 99, b"", 0));
+        events.extend(r.process(&seg(true, psh, 100, b"ping", 1)));
+        events.extend(r.process(&seg(false, psh, 500, b"po", 2)));
+        events.extend(r.process(&seg(false, psh, 504, b"!", 3)));
+        events.extend(r.finish());
+
+        let mut exporter = StreamExporter::create(&dir).unwrap();
+        for ev in &events {
+            exporter.handle(ev).unwrap();
+        }
+        exporter.flush().unwrap();
+        let c2s = dir.join("00001_10.0.0.5.40000_10.0.0.9.80_c2s.bin");
+        let s2c = dir.join("00001_10.0.0.5.40000_10.0.0.9.80_s2c.bin");
+        assert_eq!(std::fs::read(c2s).unwrap(), b"ping");
+        assert_eq!(std::fs::read(s2c).unwrap(), b"po\0\0!");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn exporter_bounds_open_files_and_skips_failed_ones() {
+        let dir = std::env::temp_dir().join(format!("packet-flow-many-{}", std::process::id()));
+        let endpoint = |last: u8, port: u16| Endpoint {
+            addr: IpAddr::V4(Ipv4Addr::new(10, 0, 0, last)),
+            port: Some(port),
+        };
+        let streams: Vec<Rc<StreamInfo>> = (1..=MAX_OPEN_FILES as u64 + 2)
+            .map(|id| {
+                Rc::new(StreamInfo {
+                    id,
+                    interface: "eth0".to_string(),
+                    client: endpoint(5, 40000 + id as u16),
+                    server: endpoint(9, 80),
+                    first_seen: Duration::ZERO,
+                })
+            })
+            .collect();
+        let data = |stream: &Rc<StreamInfo>, offset: u64, data: &[u8]| StreamEvent::Data {
+            stream: stream.clone(),
+            side: Side::Client,
+            offset,
+            data: data.to_vec(),
+        };
+        let mut exporter = StreamExporter::create(&dir).unwrap();
+        // Stream 2's file name is taken by a directory, so it cannot be created
+        let blocked = dir.join(StreamExporter::file_name(&streams[1], Side::Client));
+        std::fs::create_dir_all(&blocked).unwrap();
+        for stream in &streams {
+            let result = exporter.handle(&data(stream, 0, b"a"));
+            assert_eq!(result.is_err(), stream.id == 2);
+        }
+        assert_eq!(exporter.files.len(), MAX_OPEN_FILES);
+        assert!(!exporter.files.contains_key(&(1, Side::Client)));
+        assert!(exporter.handle(&data(&streams[1], 1, b"b")).is_ok());
+        // Stream 1 was closed to make room and is reopened where it left off
+        let gap = StreamEvent::Gap {
+            stream: streams[0].clone(),
+            side: Side::Client,
+            offset: 1,
+            len: 2,
+        };
+        exporter.handle(&gap).unwrap();
+        exporter.handle(&data(&streams[0], 3, b"b")).unwrap();
+        exporter.flush().unwrap();
+        let first = dir.join(StreamExporter::file_name(&streams[0], Side::Client));
+        assert_eq!(std::fs::read(first).unwrap(), b"a\0\0b");
+        let last = dir.join(StreamExporter::file_name(
+            &streams[MAX_OPEN_FILES],
+            Side::Client,
+        ));
+        assert_eq!(std::fs::read(last).unwrap(), b"a");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
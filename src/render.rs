@@ -1,33 +1,263 @@
-use crate::model::{Direction, IcmpKind, NetEvent, Transport};
+use crate::dns;
+use crate::model::{Direction, IcmpKind, NetEvent, TcpFlags, Transport};
 use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
 
 static COLOR_ENABLED: AtomicBool = AtomicBool::new(true);
 
+/// How each decoded event is written out.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum OutputFormat {
+    /// Colored arrow-art lines for humans.
+    #[default]
+    Text,
+    /// One JSON object per line (JSON Lines) for jq and log shippers.
+    Json,
+}
+
+/// How the capture time is shown in front of each text line.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum TimeFormat {
+    /// Wall-clock time in UTC (RFC 3339).
+    #[default]
+    Absolute,
+    /// Seconds since the first printed packet.
+    Relative,
+    /// Seconds since the previous printed packet.
+    Delta,
+    /// No timestamp.
+    None,
+}
+
+/// Something printed alongside packets that is not a packet itself, such as a
+/// connection closing or a DNS query timing out.
+pub trait Report: serde::Serialize {
+    /// When it happened, for the timestamp column.
+    fn timestamp(&self) -> Duration;
+    /// Text form without a timestamp, in the style of [`render_line`].
+    fn render_line(&self) -> String;
+}
+
+/// Renders events in the chosen format, remembering the times needed for
+/// relative and delta timestamps.
+#[derive(Debug, Clone, Default)]
+pub struct Printer {
+    format: OutputFormat,
+    time: TimeFormat,
+    verbose: bool,
+    first: Option<Duration>,
+    previous: Option<Duration>,
+}
+
+impl Printer {
+    pub fn new(format: OutputFormat, time: TimeFormat) -> Self {
+        Self {
+            format,
+            time,
+            verbose: false,
+            first: None,
+            previous: None,
+        }
+    }
+
+    /// Also show the IP header fields (TTL, DSCP/ECN, ID, flags, length) on text lines.
+    pub fn with_verbose(mut self, verbose: bool) -> Self {
+        self.verbose = verbose;
+        self
+    }
+
+    pub fn format(&self) -> OutputFormat {
+        self.format
+    }
+
+    pub fn event(&mut self, e: &NetEvent) -> String {
+        match self.format {
+            OutputFormat::Text if self.verbose => self.text(e.timestamp, &render_verbose_line(e)),
+            OutputFormat::Text => self.text(e.timestamp, &render_line(e)),
+            OutputFormat::Json => render_json(e),
+        }
+    }
+
+    /// Prefixes an already formatted text line with the packet's timestamp.
+    pub fn text(&mut self, timestamp: Duration, line: &str) -> String {
+        let first = *self.first.get_or_insert(timestamp);
+        let previous = self.previous.replace(timestamp).unwrap_or(timestamp);
+        let stamp = match self.time {
+            TimeFormat::Absolute => format_utc(timestamp),
+            // Merged live captures may still deliver a frame slightly out of order
+            TimeFormat::Relative => format_seconds(timestamp.saturating_sub(first)),
+            TimeFormat::Delta => format_seconds(timestamp.saturating_sub(previous)),
+            TimeFormat::None => return line.to_string(),
+        };
+        format!("{} {}", stamp, line)
+    }
+}
+
+/// Formats a time since the Unix epoch as RFC 3339 in UTC with microseconds,
+/// e.g. `2024-05-01T12:34:56.789012Z`.
+pub fn format_utc(ts: Duration) -> String {
+    let secs = ts.as_secs();
+    let (year, month, day) = civil_from_days(secs / 86_400);
+    let rem = secs % 86_400;
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:06}Z",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem / 60 % 60,
+        rem % 60,
+        ts.subsec_micros()
+    )
+}
+
+fn format_seconds(d: Duration) -> String {
+    format!("{}.{:06}", d.as_secs(), d.subsec_micros())
+}
+
+/// Converts days since 1970-01-01 to a proleptic Gregorian (year, month, day),
+/// following Howard Hinnant's `civil_from_days`.
+fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z % 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+    (year, month, day)
+}
+
 pub fn set_color_enabled(enabled: bool) {
     COLOR_ENABLED.store(enabled, Ordering::Relaxed);
 }
 
+pub fn color_enabled() -> bool {
+    COLOR_ENABLED.load(Ordering::Relaxed)
+}
+
 fn col(code: &'static str) -> &'static str {
-    if COLOR_ENABLED.load(Ordering::Relaxed) {
+    if color_enabled() {
         code
     } else {
         ""
     }
 }
 
+/// `n (r%)` with the rate relative to `of`, for summary tables.
+pub(crate) fn with_rate(n: u64, of: u64) -> String {
+    if of == 0 {
+        return n.to_string();
+    }
+    format!("{} ({:.1}%)", n, n as f64 * 100.0 / of as f64)
+}
+
+/// Milliseconds with microsecond precision, or `-` when unknown.
+pub(crate) fn millis(d: Option<Duration>) -> String {
+    d.map(|d| format!("{:.3}ms", d.as_secs_f64() * 1000.0))
+        .unwrap_or_else(|| "-".to_string())
+}
+
+/// Left-aligned columns separated by two spaces, one line per row after the header.
+pub(crate) fn table(header: &[&str], rows: &[Vec<String>]) -> String {
+    let mut widths: Vec<usize> = header.iter().map(|h| h.len()).collect();
+    for row in rows {
+        for (w, cell) in widths.iter_mut().zip(row) {
+            *w = (*w).max(cell.len());
+        }
+    }
+    let header: Vec<String> = header.iter().map(|h| h.to_string()).collect();
+    let mut out = String::new();
+    for row in std::iter::once(&header).chain(rows) {
+        let line: Vec<String> = row
+            .iter()
+            .zip(&widths)
+            .map(|(cell, w)| format!("{:<w$}", cell, w = w))
+            .collect();
+        out.push_str(line.join("  ").trim_end());
+        out.push('\n');
+    }
+    out
+}
+
 pub fn render_line(e: &NetEvent) -> String {
+    render_line_with(e, false)
+}
+
+/// [`render_line`] with the IP header fields after the transport, e.g.
+/// `; ip: ttl=64, dscp=EF, ecn=Not-ECT, id=0x1c46, flags=[DF], length=60`.
+pub fn render_verbose_line(e: &NetEvent) -> String {
+    render_line_with(e, true)
+}
+
+fn render_line_with(e: &NetEvent, verbose: bool) -> String {
+    let mut line = render_transport(e);
+    if let Some(ip) = e.ip.filter(|_| verbose) {
+        line.push_str(&format!("; ip: {}", ip));
+    }
+    if !e.ipv6_ext.is_empty() {
+        let ext: Vec<String> = e.ipv6_ext.iter().map(|x| x.to_string()).collect();
+        line.push_str(&format!("; ext: {}", ext.join(" ")));
+    }
+    if let Some(r) = e.reassembly {
+        line.push_str(&format!("; reassembled from {} fragments", r.fragments));
+        if r.overlaps > 0 {
+            line.push_str(&format!(" ({} overlapping)", r.overlaps));
+        }
+    }
+    for message in &e.dns {
+        line.push_str(&format!("; {}", message));
+    }
+    if let Some(rtt) = e.rtt {
+        line.push_str(&format!("; rtt: {}", millis(Some(rtt))));
+    }
+    if e.bad_checksums.is_empty() && e.expert.is_empty() {
+        return line;
+    }
+    let notes: Vec<String> = e
+        .bad_checksums
+        .iter()
+        .map(|x| format!("[{}]", x))
+        .chain(e.expert.iter().map(|x| format!("[{}]", x)))
+        .collect();
+    format!(
+        "{} {}{}{}",
+        line,
+        col("\x1b[1;31m"),
+        notes.join(" "),
+        col("\x1b[0m")
+    )
+}
+
+/// The interface in the line prefix, followed by any VLAN tags, e.g.
+/// `eth0 vlan 200 vlan 100 pcp 5`.
+pub(crate) fn interface_label(e: &NetEvent) -> String {
+    let mut label = e.interface.clone();
+    for tag in &e.vlans {
+        label.push_str(&format!(" {}", tag));
+    }
+    label
+}
+
+fn render_transport(e: &NetEvent) -> String {
+    let iface = interface_label(e);
     match &e.transport {
         Transport::Udp {
             src_port,
             dst_port,
             length,
-            is_dns,
         } => {
-            let label = if *is_dns { "UDP/DNS" } else { "UDP" };
+            let label = if dns::uses_port(*src_port, *dst_port) {
+                "UDP/DNS"
+            } else {
+                "UDP"
+            };
             match e.direction {
                 Direction::Inbound => format!(
                     "[{}]: {}:{} {}<===== [{}] ====={} {}:{}; length: {}",
-                    e.interface,
+                    iface,
                     e.destination,
                     dst_port,
                     col("\x1b[33m"),
@@ -39,7 +269,7 @@ pub fn render_line(e: &NetEvent) -> String {
                 ),
                 Direction::Outbound => format!(
                     "[{}]: {}:{} {}====== [{}] =====>{} {}:{}; length: {}",
-                    e.interface,
+                    iface,
                     e.source,
                     src_port,
                     col("\x1b[33m"),
@@ -54,38 +284,69 @@ pub fn render_line(e: &NetEvent) -> String {
         Transport::Tcp {
             src_port,
             dst_port,
-            length,
-        } => match e.direction {
-            Direction::Inbound => format!(
-                "[{}]: {}:{} {}<==== [TCP] ====={} {}:{}; length: {}",
-                e.interface,
-                e.destination,
-                dst_port,
-                col("\x1b[34m"),
-                col("\x1b[0m"),
-                e.source,
-                src_port,
-                length
-            ),
-            Direction::Outbound => format!(
-                "[{}]: {}:{} {}===== [TCP] =====>{} {}:{}; length: {}",
-                e.interface,
-                e.source,
-                src_port,
-                col("\x1b[34m"),
-                col("\x1b[0m"),
-                e.destination,
-                dst_port,
-                length
-            ),
-        },
+            flags,
+            seq,
+            ack,
+            window,
+            payload_len,
+            options,
+            ..
+        } => {
+            // tcpdump-style: the ack number is meaningless unless the ACK bit is set
+            let ack = if flags.contains(TcpFlags::ACK) {
+                format!(", ack: {}", ack)
+            } else {
+                String::new()
+            };
+            // Handshake parameters are negotiated on SYN and SYN-ACK only; an empty
+            // list there usually means a middlebox stripped them
+            let options = if flags.contains(TcpFlags::SYN) {
+                format!(", options: [{}]", options)
+            } else {
+                String::new()
+            };
+            match e.direction {
+                Direction::Inbound => format!(
+                    "[{}]: {}:{} {}<==== [TCP] ====={} {}:{}; [{}] seq: {}{}, win: {}, length: {}{}",
+                    iface,
+                    e.destination,
+                    dst_port,
+                    col("\x1b[34m"),
+                    col("\x1b[0m"),
+                    e.source,
+                    src_port,
+                    flags,
+                    seq,
+                    ack,
+                    window,
+                    payload_len,
+                    options
+                ),
+                Direction::Outbound => format!(
+                    "[{}]: {}:{} {}===== [TCP] =====>{} {}:{}; [{}] seq: {}{}, win: {}, length: {}{}",
+                    iface,
+                    e.source,
+                    src_port,
+                    col("\x1b[34m"),
+                    col("\x1b[0m"),
+                    e.destination,
+                    dst_port,
+                    flags,
+                    seq,
+                    ack,
+                    window,
+                    payload_len,
+                    options
+                ),
+            }
+        }
         Transport::Icmp(kind) => {
             match kind {
                 IcmpKind::EchoReply { seq, id } => {
                     match e.direction {
                         Direction::Inbound => format!(
                             "[{}]: {} {}<==== [ICMP echo reply] ====={} {} (seq={:?}, id={:?})",
-                            e.interface,
+                            iface,
                             e.destination,
                             col("\x1b[35m"),
                             col("\x1b[0m"),
@@ -96,7 +357,7 @@ pub fn render_line(e: &NetEvent) -> String {
                         Direction::Outbound => {
                             format!(
                     "[{}]: {} {}===== [ICMP echo reply] =====>{} {} (seq={:?}, id={:?})",
-                    e.interface, e.source, col("\x1b[35m"), col("\x1b[0m"), e.destination, seq, id
+                    iface, e.source, col("\x1b[35m"), col("\x1b[0m"), e.destination, seq, id
                 )
                         }
                     }
@@ -104,7 +365,7 @@ pub fn render_line(e: &NetEvent) -> String {
                 IcmpKind::EchoRequest { seq, id } => match e.direction {
                     Direction::Inbound => format!(
                         "[{}]: {} {}<==== [ICMP echo] ====={} {} (seq={:?}, id={:?})",
-                        e.interface,
+                        iface,
                         e.destination,
                         col("\x1b[35m"),
                         col("\x1b[0m"),
@@ -114,7 +375,7 @@ pub fn render_line(e: &NetEvent) -> String {
                     ),
                     Direction::Outbound => format!(
                         "[{}]: {} {}===== [ICMP echo] =====>{} {} (seq={:?}, id={:?})",
-                        e.interface,
+                        iface,
                         e.source,
                         col("\x1b[35m"),
                         col("\x1b[0m"),
@@ -123,10 +384,14 @@ pub fn render_line(e: &NetEvent) -> String {
                         id
                     ),
                 },
+                IcmpKind::Unreachable { .. }
+                | IcmpKind::TimeExceeded { .. }
+                | IcmpKind::Redirect { .. }
+                | IcmpKind::ParameterProblem { .. } => render_icmp_error(e, kind),
                 IcmpKind::Other(t) => match e.direction {
                     Direction::Inbound => format!(
                         "[{}]: {} {}<==== [ICMP] ====={} {} (type={:?})",
-                        e.interface,
+                        iface,
                         e.destination,
                         col("\x1b[35m"),
                         col("\x1b[0m"),
@@ -135,7 +400,7 @@ pub fn render_line(e: &NetEvent) -> String {
                     ),
                     Direction::Outbound => format!(
                         "[{}]: {} {}===== [ICMP] =====>{} {} (type={:?})",
-                        e.interface,
+                        iface,
                         e.source,
                         col("\x1b[35m"),
                         col("\x1b[0m"),
@@ -145,24 +410,26 @@ pub fn render_line(e: &NetEvent) -> String {
                 },
             }
         }
-        Transport::Icmpv6 { type_u8 } => match e.direction {
+        Transport::Icmpv6(kind) => match e.direction {
             Direction::Inbound => format!(
-                "[{}]: {} {}<==== [ICMPv6] ====={} {} (type={:?})",
-                e.interface,
+                "[{}]: {} {}<==== [{}] ====={} {} ({})",
+                iface,
                 e.destination,
                 col("\x1b[95m"),
+                kind.label(),
                 col("\x1b[0m"),
                 e.source,
-                type_u8
+                kind
             ),
             Direction::Outbound => format!(
-                "[{}]: {} {}===== [ICMPv6] =====>{} {} (type={:?})",
-                e.interface,
+                "[{}]: {} {}===== [{}] =====>{} {} ({})",
+                iface,
                 e.source,
                 col("\x1b[95m"),
+                kind.label(),
                 col("\x1b[0m"),
                 e.destination,
-                type_u8
+                kind
             ),
         },
         Transport::Arp {
@@ -174,7 +441,7 @@ pub fn render_line(e: &NetEvent) -> String {
         } => match e.direction {
             Direction::Inbound => format!(
                 "[{}]: {}({}) {}<==== [ARP] ======{} {}({}); operation: {:?}",
-                e.interface,
+                iface,
                 target_mac,
                 target_ip,
                 col("\x1b[31m"),
@@ -185,7 +452,7 @@ pub fn render_line(e: &NetEvent) -> String {
             ),
             Direction::Outbound => format!(
                 "[{}]: {}({}) {}===== [ARP] =====>{} {}({}); operation: {:?}",
-                e.interface,
+                iface,
                 sender_mac,
                 sender_ip,
                 col("\x1b[31m"),
@@ -198,14 +465,75 @@ pub fn render_line(e: &NetEvent) -> String {
     }
 }
 
+/// Serialises an event as a single-line JSON object. The `Transport` variant is
+/// flattened into the top level and tagged with `protocol`.
+pub fn render_json(e: &NetEvent) -> String {
+    // NetEvent only holds strings, numbers and addresses, none of which can fail
+    serde_json::to_string(e).expect("NetEvent is always serialisable")
+}
+
 pub fn print_event(e: &NetEvent) {
     println!("{}", render_line(e));
 }
 
+/// ICMP errors name the reason and, when quoted, the flow that triggered them, e.g.
+/// `(fragmentation needed, mtu=1400); original: TCP 10.0.0.5:40000 > 93.184.216.34:443`.
+fn render_icmp_error(e: &NetEvent, kind: &IcmpKind) -> String {
+    let iface = interface_label(e);
+    let (label, extra) = match kind {
+        IcmpKind::Unreachable { mtu, .. } => {
+            ("ICMP unreachable", mtu.map(|m| format!("mtu={}", m)))
+        }
+        IcmpKind::TimeExceeded { .. } => ("ICMP time exceeded", None),
+        IcmpKind::Redirect { gateway, .. } => {
+            ("ICMP redirect", Some(format!("gateway={}", gateway)))
+        }
+        IcmpKind::ParameterProblem { pointer, .. } => (
+            "ICMP parameter problem",
+            Some(format!("pointer={}", pointer)),
+        ),
+        _ => ("ICMP", None),
+    };
+    let reason = match kind.reason() {
+        Some(reason) => reason.to_string(),
+        None => format!("code={}", kind.code().unwrap_or_default()),
+    };
+    let detail = match extra {
+        Some(extra) => format!("{}, {}", reason, extra),
+        None => reason,
+    };
+    let line = match e.direction {
+        Direction::Inbound => format!(
+            "[{}]: {} {}<==== [{}] ====={} {} ({})",
+            iface,
+            e.destination,
+            col("\x1b[35m"),
+            label,
+            col("\x1b[0m"),
+            e.source,
+            detail
+        ),
+        Direction::Outbound => format!(
+            "[{}]: {} {}===== [{}] =====>{} {} ({})",
+            iface,
+            e.source,
+            col("\x1b[35m"),
+            label,
+            col("\x1b[0m"),
+            e.destination,
+            detail
+        ),
+    };
+    match kind.original() {
+        Some(original) => format!("{}; original: {}", line, original),
+        None => line,
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::model::{Direction, IcmpKind, NetEvent, Transport};
+    use crate::model::{Direction, Expert, IcmpKind, NetEvent, TcpOptions, Transport};
     use pnet::util::MacAddr;
     use std::net::{IpAddr, Ipv4Addr};
 
@@ -221,7 +549,6 @@ mod tests {
                 src_port: 53,
                 dst_port: 53000,
                 length: 42,
-                is_dns: true,
             },
         );
         let line = render_line(&e);
@@ -231,6 +558,32 @@ mod tests {
         );
     }
 
+    #[test]
+    fn render_decoded_dns_summary() {
+        set_color_enabled(false);
+        let mut e = NetEvent::new(
+            "eth0",
+            Direction::Inbound,
+            IpAddr::V4(Ipv4Addr::new(1, 1, 1, 1)),
+            IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)),
+            Transport::Udp {
+                src_port: 53,
+                dst_port: 53000,
+                length: 42,
+            },
+        );
+        e.dns.extend(crate::dns::parse(
+            b"\x00\x07\x81\x80\x00\x01\x00\x01\x00\x00\x00\x00\
+              \x01a\x07example\x00\x00\x01\x00\x01\
+              \xc0\x0c\x00\x01\x00\x01\x00\x00\x00\x3c\x00\x04\xc0\x00\x02\x01",
+        ));
+        assert_eq!(
+            render_line(&e),
+            "[eth0]: 10.0.0.2:53000 <===== [UDP/DNS] ===== 1.1.1.1:53; length: 42; \
+             DNS response A a.example (0x0007): A 192.0.2.1"
+        );
+    }
+
     #[test]
     fn render_tcp_outbound_no_color() {
         set_color_enabled(false);
@@ -242,13 +595,109 @@ mod tests {
             Transport::Tcp {
                 src_port: 50123,
                 dst_port: 443,
-                length: 1200,
+                flags: TcpFlags::PSH | TcpFlags::ACK,
+                seq: 1,
+                ack: 7,
+                window: 502,
+                header_len: 20,
+                payload_len: 1200,
+                options: TcpOptions::default(),
             },
         );
         let line = render_line(&e);
         assert_eq!(
             line,
-            "[eth0]: 10.0.0.2:50123 ===== [TCP] =====> 93.184.216.34:443; length: 1200"
+            "[eth0]: 10.0.0.2:50123 ===== [TCP] =====> 93.184.216.34:443; [P.] seq: 1, ack: 7, win: 502, length: 1200"
+        );
+    }
+
+    #[test]
+    fn render_tcp_syn_shows_options() {
+        set_color_enabled(false);
+        let e = NetEvent::new(
+            "eth0",
+            Direction::Outbound,
+            IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)),
+            IpAddr::V4(Ipv4Addr::new(93, 184, 216, 34)),
+            Transport::Tcp {
+                src_port: 50123,
+                dst_port: 443,
+                flags: TcpFlags::SYN,
+                seq: 1,
+                ack: 0,
+                window: 64240,
+                header_len: 40,
+                payload_len: 0,
+                options: TcpOptions {
+                    mss: Some(1460),
+                    window_scale: Some(7),
+                    sack_permitted: true,
+                    sack: Vec::new(),
+                    timestamps: Some((10, 0)),
+                },
+            },
+        );
+        assert_eq!(
+            render_line(&e),
+            "[eth0]: 10.0.0.2:50123 ===== [TCP] =====> 93.184.216.34:443; [S] seq: 1, win: 64240, length: 0, options: [mss 1460, sackOK, TS val 10 ecr 0, wscale 7]"
+        );
+    }
+
+    #[test]
+    fn render_tcp_reset_inbound_no_color() {
+        set_color_enabled(false);
+        let e = NetEvent::new(
+            "eth0",
+            Direction::Inbound,
+            IpAddr::V4(Ipv4Addr::new(93, 184, 216, 34)),
+            IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)),
+            Transport::Tcp {
+                src_port: 443,
+                dst_port: 50123,
+                flags: TcpFlags::RST,
+                seq: 99,
+                ack: 12345,
+                window: 0,
+                header_len: 20,
+                payload_len: 0,
+                options: TcpOptions::default(),
+            },
+        );
+        assert_eq!(
+            render_line(&e),
+            "[eth0]: 10.0.0.2:50123 <==== [TCP] ===== 93.184.216.34:443; [R] seq: 99, win: 0, length: 0"
+        );
+        let v: serde_json::Value = serde_json::from_str(&render_json(&e)).unwrap();
+        assert_eq!(v["flags"], serde_json::json!(["rst"]));
+        assert!(v.get("expert").is_none());
+    }
+
+    #[test]
+    fn render_appends_expert_notes() {
+        set_color_enabled(false);
+        let mut e = NetEvent::new(
+            "eth0",
+            Direction::Inbound,
+            IpAddr::V4(Ipv4Addr::new(93, 184, 216, 34)),
+            IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)),
+            Transport::Tcp {
+                src_port: 443,
+                dst_port: 50123,
+                flags: TcpFlags::ACK,
+                seq: 1,
+                ack: 1,
+                window: 0,
+                header_len: 20,
+                payload_len: 0,
+                options: TcpOptions::default(),
+            },
+        );
+        e.expert = vec![Expert::DuplicateAck(2), Expert::ZeroWindow];
+        assert!(render_line(&e).ends_with("length: 0 [TCP Dup ACK #2] [TCP ZeroWindow]"));
+        let v: serde_json::Value = serde_json::from_str(&render_json(&e)).unwrap();
+        assert_eq!(
+            v["expert"],
+            serde_json::json!([{"duplicate_ack": 2}, "zero_window"])
         );
     }
 
@@ -288,4 +737,75 @@ mod tests {
         let line = render_line(&e);
         assert_eq!(line, "[eth0]: 00:01:02:03:04:05(10.0.0.2) ===== [ARP] =====> 00:00:00:00:00:00(10.0.0.1); operation: 1");
     }
+
+    #[test]
+    fn render_json_flattens_transport() {
+        let e = NetEvent::new(
+            "eth0",
+            Direction::Inbound,
+            IpAddr::V4(Ipv4Addr::new(8, 8, 8, 8)),
+            IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)),
+            Transport::Icmp(IcmpKind::EchoReply { seq: 1, id: 99 }),
+        );
+        assert_eq!(
+            render_json(&e),
+            r#"{"timestamp":"1970-01-01T00:00:00.000000Z","interface":"eth0","direction":"inbound","source":"8.8.8.8","destination":"10.0.0.2","protocol":"icmp","kind":"echo_reply","seq":1,"id":99}"#
+        );
+        let arp = NetEvent::new(
+            "eth0",
+            Direction::Outbound,
+            IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)),
+            IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)),
+            Transport::Arp {
+                operation: 1,
+                sender_mac: MacAddr(0, 1, 2, 3, 4, 5),
+                sender_ip: Ipv4Addr::new(10, 0, 0, 2),
+                target_mac: MacAddr(0, 0, 0, 0, 0, 0),
+                target_ip: Ipv4Addr::new(10, 0, 0, 1),
+            },
+        );
+        let v: serde_json::Value = serde_json::from_str(&render_json(&arp)).unwrap();
+        assert_eq!(v["protocol"], "arp");
+        assert_eq!(v["sender_mac"], "00:01:02:03:04:05");
+        assert_eq!(v["direction"], "outbound");
+    }
+
+    #[test]
+    fn format_utc_handles_leap_years() {
+        assert_eq!(format_utc(Duration::ZERO), "1970-01-01T00:00:00.000000Z");
+        assert_eq!(
+            format_utc(Duration::new(951_827_696, 5_000)),
+            "2000-02-29T12:34:56.000005Z"
+        );
+        assert_eq!(
+            format_utc(Duration::from_secs(1_704_067_199)),
+            "2023-12-31T23:59:59.000000Z"
+        );
+    }
+
+    #[test]
+    fn printer_time_modes() {
+        let stamps = [
+            Duration::from_millis(10_000),
+            Duration::from_millis(10_250),
+            Duration::from_millis(11_000),
+        ];
+        let run = |time| {
+            let mut p = Printer::new(OutputFormat::Text, time);
+            stamps.map(|ts| p.text(ts, "x"))
+        };
+        assert_eq!(
+            run(TimeFormat::Relative),
+            ["0.000000 x", "0.250000 x", "1.000000 x"]
+        );
+        assert_eq!(
+            run(TimeFormat::Delta),
+            ["0.000000 x", "0.250000 x", "0.750000 x"]
+        );
+        assert_eq!(run(TimeFormat::None), ["x", "x", "x"]);
+        assert_eq!(
+            run(TimeFormat::Absolute)[1],
+            "1970-01-01T00:00:10.250000Z x"
+        );
+    }
 }
@@ -0,0 +1,103 @@
+//! 802.1Q VLAN tags, including stacked 802.1ad (QinQ) tags.
+//!
+//! [`strip`] removes every tag between the MAC addresses and the real ethertype, so the
+//! rest of the pipeline, which expects a plain Ethernet header, can decode the frame,
+//! and returns the tags outermost first for the event.
+
+use std::fmt;
+
+use serde::Serialize;
+
+const TAG_LEN: usize = 4;
+
+/// 802.1Q, 802.1ad, and the pre-standard QinQ ethertype some switches still use.
+pub(crate) const TPIDS: [u16; 3] = [0x8100, 0x88a8, 0x9100];
+
+/// One VLAN tag.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct VlanTag {
+    pub id: u16,
+    /// Priority code point (802.1p class of service).
+    pub pcp: u8,
+    /// Drop eligible indicator.
+    pub dei: bool,
+}
+
+/// `vlan 100`, with ` pcp 5` when the priority is not the default.
+impl fmt::Display for VlanTag {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "vlan {}", self.id)?;
+        if self.pcp != 0 {
+            write!(f, " pcp {}", self.pcp)?;
+        }
+        Ok(())
+    }
+}
+
+/// Whether an ethertype introduces a VLAN tag.
+fn is_tag(ethertype: u16) -> bool {
+    TPIDS.contains(&ethertype)
+}
+
+/// The frame without its VLAN tags, and the tags outermost first; `None` when the
+/// frame is untagged or a tag is cut short.
+pub fn strip(frame: &[u8]) -> Option<(Vec<u8>, Vec<VlanTag>)> {
+    let mut tags = Vec::new();
+    let mut at = 12;
+    loop {
+        let ethertype = u16::from_be_bytes([*frame.get(at)?, *frame.get(at + 1)?]);
+        if !is_tag(ethertype) {
+            break;
+        }
+        let tci = u16::from_be_bytes([*frame.get(at + 2)?, *frame.get(at + 3)?]);
+        tags.push(VlanTag {
+            id: tci & 0x0fff,
+            pcp: (tci >> 13) as u8,
+            dei: tci & 0x1000 != 0,
+        });
+        at += TAG_LEN;
+    }
+    if tags.is_empty() {
+        return None;
+    }
+    let mut untagged = Vec::with_capacity(frame.len() - tags.len() * TAG_LEN);
+    untagged.extend_from_slice(&frame[..12]);
+    untagged.extend_from_slice(&frame[at..]);
+    Some((untagged, tags))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strips_stacked_tags() {
+        let mut frame = vec![0xaa; 12];
+        // Service tag 200, then customer tag 100 with priority 5
+        frame.extend([0x88, 0xa8, 0x00, 0xc8]);
+        frame.extend([0x81, 0x00, 0xa0, 0x64]);
+        frame.extend([0x08, 0x00, 0x45]);
+        let (untagged, tags) = strip(&frame).unwrap();
+        assert_eq!(untagged, [&[0xaa; 12][..], &[0x08, 0x00, 0x45]].concat());
+        assert_eq!(
+            tags,
+            [
+                VlanTag {
+                    id: 200,
+                    pcp: 0,
+                    dei: false
+                },
+                VlanTag {
+                    id: 100,
+                    pcp: 5,
+                    dei: false
+                }
+            ]
+        );
+        assert_eq!(tags[1].to_string(), "vlan 100 pcp 5");
+
+        assert_eq!(strip(&untagged), None);
+        // A tag cut short by the snap length
+        assert_eq!(strip(&frame[..16]), None);
+    }
+}